@@ -195,7 +195,7 @@ void timing_lammps_full_custom(int DIM1, int icount, int* list, int outer_loop,
 
   MPI_Datatype type;
   MPI_Type_create_custom(state_cb, NULL, query_cb, pack_cb, unpack_cb,
-                         NULL, NULL, &info, 0, &type);
+                         NULL, NULL, NULL, &info, 0, &type);
 
   for( int i=0 ; i<outer_loop ; i++ ) {
     MPI_Status status;
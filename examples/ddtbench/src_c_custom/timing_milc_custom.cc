@@ -183,7 +183,7 @@ void timing_milc_su3_zdown_custom( int DIM2, int DIM3, int DIM4, int DIM5, int o
 
   MPI_Datatype type;
   MPI_Type_create_custom(state_cb, NULL, query_cb, pack_cb, unpack_cb,
-                         NULL, NULL, &info, 1, &type);
+                         NULL, NULL, NULL, &info, 1, &type);
 
   MPI_Status status;
 
@@ -264,7 +264,7 @@ void timing_milc_su3_zdown_custom_region( int DIM2, int DIM3, int DIM4, int DIM5
 
   MPI_Datatype type;
   MPI_Type_create_custom(state_cb, NULL, NULL, NULL, NULL,
-                         &region_count_cb, &region_query_cb, &info, 0, &type);
+                         &region_count_cb, &region_query_cb, NULL, &info, 0, &type);
 
   MPI_Status status;
 
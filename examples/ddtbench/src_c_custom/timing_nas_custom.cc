@@ -101,7 +101,7 @@ MPI_Datatype create_region_datatype(MemInfoT* info)
   MPI_Type_create_custom(state_mem_cb<mem_info>,
                          NULL, NULL, NULL, NULL,
                          region_count_cb<mem_info>, region_query_cb<mem_info>,
-                         info, 1, &res);
+                         NULL, info, 1, &res);
   return res;
 }
 
@@ -355,7 +355,7 @@ MPI_Datatype create_mpi_datatype(PackInfoT* info)
   MPI_Type_create_custom(state_pack_cb<pack_info>, NULL,
                          query_pack_cb<pack_info>, pack_cb<pack_info>,
                          unpack_cb<pack_info>,
-                         NULL, NULL, info, 1, &res);
+                         NULL, NULL, NULL, info, 1, &res);
   return res;
 }
 
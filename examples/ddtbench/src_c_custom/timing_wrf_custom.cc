@@ -595,7 +595,7 @@ void timing_wrf_custom ( int number_2D, int number_3D, int number_4D, int ims, i
 
     MPI_Datatype type;
     MPI_Type_create_custom(state_cb, NULL, query_cb, pack_cb, unpack_cb,
-                            NULL, NULL, &info, 1, &type);
+                            NULL, NULL, NULL, &info, 1, &type);
     MPI_Status status;
 
     for( int j=0 ; j<inner_loop ; j++ ) {
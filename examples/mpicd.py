@@ -92,7 +92,7 @@ def create_custom_datatype(bufs):
     ctype = ffi.new('MPI_Datatype*')
     CHKERR( lib.MPI_Type_create_custom(
         NULL, NULL, NULL, NULL, NULL,
-        region_count_fn, region_fn, NULL, 0, ctype) )
+        region_count_fn, region_fn, NULL, NULL, 0, ctype) )
     return ctype[0]
 
 
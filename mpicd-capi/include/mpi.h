@@ -13,23 +13,75 @@ typedef size_t MPI_Count;
 /* For simplicity MPI_Comm and other handles are defined to be integers */
 typedef int MPI_Comm;
 typedef int MPI_Datatype;
+typedef int MPI_Op;
 
 /* MPI_Request corresponds to Rust's isize */
 typedef intptr_t MPI_Request;
 
+typedef intptr_t MPI_Aint;
+typedef int MPI_Info;
+typedef int MPI_Win;
+
 /* Handle constants */
 #define MPI_COMM_WORLD 1
 
+/*
+ * Predefined datatypes. Counts of these are in elements of the C type; they're
+ * sent as raw bytes in the native representation, so all processes must share
+ * the same type sizes and byte order.
+ */
 #define MPI_BYTE 1
+#define MPI_CHAR 6
+#define MPI_SIGNED_CHAR 7
+#define MPI_UNSIGNED_CHAR 8
+#define MPI_SHORT 9
+#define MPI_UNSIGNED_SHORT 10
+#define MPI_INT 11
+#define MPI_UNSIGNED 12
+#define MPI_LONG 13
+#define MPI_UNSIGNED_LONG 14
+#define MPI_LONG_LONG 15
+#define MPI_LONG_LONG_INT 15
+#define MPI_UNSIGNED_LONG_LONG 16
+#define MPI_FLOAT 17
+#define MPI_DOUBLE 18
+#define MPI_INT8_T 19
+#define MPI_UINT8_T 20
+#define MPI_INT16_T 21
+#define MPI_UINT16_T 22
+#define MPI_INT32_T 2
+#define MPI_UINT32_T 3
+#define MPI_INT64_T 4
+#define MPI_UINT64_T 5
 
 #define MPI_ANY_SOURCE -1
 
+#define MPI_UNDEFINED -32766
+
+#define MPI_REQUEST_NULL -1
+
+#define MPI_INFO_NULL 0
+#define MPI_WIN_NULL -1
+
+#define MPI_OP_NULL 0
+#define MPI_SUM 1
+#define MPI_PROD 2
+#define MPI_MIN 3
+#define MPI_MAX 4
+#define MPI_BAND 5
+#define MPI_BOR 6
+#define MPI_BXOR 7
+#define MPI_REPLACE 8
+#define MPI_NO_OP 9
+
 typedef struct MPI_Status {
     int count;
     int cancelled;
     int MPI_SOURCE;
     int MPI_TAG;
     int MPI_ERROR;
+    /* Datatype that count is in, which is MPI_BYTE after MPI_Probe */
+    MPI_Datatype datatype;
 } MPI_Status;
 
 int MPI_Init(int *argc, char **argv[]);
@@ -48,12 +100,167 @@ int MPI_Isend(const void *buf, int count, MPI_Datatype datatype, int dest,
               int tag, MPI_Comm comm, MPI_Request *request);
 int MPI_Irecv(void *buf, int count, MPI_Datatype datatype, int source, int tag,
               MPI_Comm comm, MPI_Request *request);
+int MPI_Ssend(const void *buf, int count, MPI_Datatype datatype, int dest,
+              int tag, MPI_Comm comm);
+int MPI_Issend(const void *buf, int count, MPI_Datatype datatype, int dest,
+               int tag, MPI_Comm comm, MPI_Request *request);
+int MPI_Sendrecv(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                 int dest, int sendtag, void *recvbuf, int recvcount,
+                 MPI_Datatype recvtype, int source, int recvtag, MPI_Comm comm,
+                 MPI_Status *status);
+int MPI_Sendrecv_replace(void *buf, int count, MPI_Datatype datatype, int dest,
+                         int sendtag, int source, int recvtag, MPI_Comm comm,
+                         MPI_Status *status);
+
+/*
+ * Buffered sends. Custom datatypes are fully packed into the attached buffer,
+ * including memory regions, so MPI_Bsend returns MPI_ERR_BUFFER if there isn't
+ * enough space left for the whole packed size.
+ */
+#define MPI_BSEND_OVERHEAD 0
+int MPI_Buffer_attach(void *buffer, int size);
+int MPI_Buffer_detach(void *buffer_addr, int *size);
+int MPI_Bsend(const void *buf, int count, MPI_Datatype datatype, int dest,
+              int tag, MPI_Comm comm);
 int MPI_Probe(int source, int tag, MPI_Comm comm, MPI_Status *status);
+/* Counts convert between predefined datatypes through their sizes. A custom
+ * datatype only has a count if a receive with that datatype completed the
+ * status, and MPI_Get_count returns MPI_UNDEFINED otherwise. */
 int MPI_Get_count(MPI_Status *status, MPI_Datatype datatype, int *count);
 
 int MPI_Wait(MPI_Request *request, MPI_Status *status);
 int MPI_Waitall(int count, MPI_Request array_of_requests[], MPI_Status *array_of_statuses);
 
+/*
+ * Persistent requests. These are inactive until started and can be started
+ * again once complete; the memory regions of custom datatypes must not change
+ * between starts. MPI_Startall starts none of the requests if any is invalid
+ * or already active, and active requests must be completed before
+ * MPI_Request_free.
+ */
+int MPI_Send_init(const void *buf, int count, MPI_Datatype datatype, int dest,
+                  int tag, MPI_Comm comm, MPI_Request *request);
+int MPI_Recv_init(void *buf, int count, MPI_Datatype datatype, int source,
+                  int tag, MPI_Comm comm, MPI_Request *request);
+int MPI_Start(MPI_Request *request);
+int MPI_Startall(int count, MPI_Request array_of_requests[]);
+int MPI_Request_free(MPI_Request *request);
+
+/*
+ * Collective functions. Custom datatypes are supported by all collectives
+ * here. MPI_Bcast works with any custom datatype, while the others split the
+ * send or receive buffer into one part per rank, so they need an extent set
+ * with MPI_Type_create_resized and return MPI_ERR_TYPE without one.
+ */
+int MPI_Bcast(void *buffer, int count, MPI_Datatype datatype, int root,
+              MPI_Comm comm);
+int MPI_Gather(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+               void *recvbuf, int recvcount, MPI_Datatype recvtype, int root,
+               MPI_Comm comm);
+int MPI_Scatter(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                void *recvbuf, int recvcount, MPI_Datatype recvtype, int root,
+                MPI_Comm comm);
+int MPI_Allgather(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                  void *recvbuf, int recvcount, MPI_Datatype recvtype,
+                  MPI_Comm comm);
+int MPI_Alltoall(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                 void *recvbuf, int recvcount, MPI_Datatype recvtype,
+                 MPI_Comm comm);
+
+/*
+ * Non-blocking collectives, completed with MPI_Wait or MPI_Waitall. Buffers
+ * must not be touched until the request completes.
+ */
+int MPI_Ibarrier(MPI_Comm comm, MPI_Request *request);
+int MPI_Ibcast(void *buffer, int count, MPI_Datatype datatype, int root,
+               MPI_Comm comm, MPI_Request *request);
+int MPI_Igather(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                void *recvbuf, int recvcount, MPI_Datatype recvtype, int root,
+                MPI_Comm comm, MPI_Request *request);
+int MPI_Iscatter(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                 void *recvbuf, int recvcount, MPI_Datatype recvtype, int root,
+                 MPI_Comm comm, MPI_Request *request);
+int MPI_Iallgather(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                   void *recvbuf, int recvcount, MPI_Datatype recvtype,
+                   MPI_Comm comm, MPI_Request *request);
+int MPI_Ialltoall(const void *sendbuf, int sendcount, MPI_Datatype sendtype,
+                  void *recvbuf, int recvcount, MPI_Datatype recvtype,
+                  MPI_Comm comm, MPI_Request *request);
+
+/*
+ * Reductions. Predefined datatypes support MPI_SUM, MPI_PROD, MPI_MIN and
+ * MPI_MAX (except MPI_BYTE) and the bitwise ops (except floating point types).
+ * User functions are called with len set to the element count. For custom
+ * datatypes, both operands are first unpacked into temporary elements with the
+ * datatype's unpack functions and the result is packed again afterwards, so
+ * these need an extent set with MPI_Type_create_resized and no memory regions;
+ * otherwise MPI_ERR_OP is returned.
+ */
+typedef void (MPI_User_function)(void *invec, void *inoutvec, int *len,
+                                 MPI_Datatype *datatype);
+int MPI_Op_create(MPI_User_function *user_fn, int commute, MPI_Op *op);
+int MPI_Op_free(MPI_Op *op);
+int MPI_Reduce(const void *sendbuf, void *recvbuf, int count,
+               MPI_Datatype datatype, MPI_Op op, int root, MPI_Comm comm);
+int MPI_Allreduce(const void *sendbuf, void *recvbuf, int count,
+                  MPI_Datatype datatype, MPI_Op op, MPI_Comm comm);
+int MPI_Scan(const void *sendbuf, void *recvbuf, int count,
+             MPI_Datatype datatype, MPI_Op op, MPI_Comm comm);
+int MPI_Exscan(const void *sendbuf, void *recvbuf, int count,
+               MPI_Datatype datatype, MPI_Op op, MPI_Comm comm);
+int MPI_Ireduce(const void *sendbuf, void *recvbuf, int count,
+                MPI_Datatype datatype, MPI_Op op, int root, MPI_Comm comm,
+                MPI_Request *request);
+int MPI_Iallreduce(const void *sendbuf, void *recvbuf, int count,
+                   MPI_Datatype datatype, MPI_Op op, MPI_Comm comm,
+                   MPI_Request *request);
+int MPI_Iscan(const void *sendbuf, void *recvbuf, int count,
+              MPI_Datatype datatype, MPI_Op op, MPI_Comm comm,
+              MPI_Request *request);
+int MPI_Iexscan(const void *sendbuf, void *recvbuf, int count,
+                MPI_Datatype datatype, MPI_Op op, MPI_Comm comm,
+                MPI_Request *request);
+
+/*
+ * One-sided communication. The target memory holds the packed representation
+ * of the origin buffer (the packed part followed by each memory region), so
+ * target_datatype must be MPI_BYTE or the origin datatype and target_count is
+ * ignored. Operations are complete after MPI_Win_fence or MPI_Win_flush.
+ */
+int MPI_Win_create(void *base, MPI_Aint size, int disp_unit, MPI_Info info,
+                   MPI_Comm comm, MPI_Win *win);
+int MPI_Win_free(MPI_Win *win);
+int MPI_Put(const void *origin_addr, int origin_count,
+            MPI_Datatype origin_datatype, int target_rank,
+            MPI_Aint target_disp, int target_count,
+            MPI_Datatype target_datatype, MPI_Win win);
+int MPI_Get(void *origin_addr, int origin_count, MPI_Datatype origin_datatype,
+            int target_rank, MPI_Aint target_disp, int target_count,
+            MPI_Datatype target_datatype, MPI_Win win);
+int MPI_Win_fence(int assert, MPI_Win win);
+int MPI_Win_flush(int rank, MPI_Win win);
+
+/*
+ * Remote atomics. MPI_Fetch_and_op and MPI_Compare_and_swap only support the
+ * 32 and 64 bit integer types, with MPI_SUM, MPI_BAND, MPI_BOR, MPI_BXOR,
+ * MPI_REPLACE and MPI_NO_OP. MPI_Accumulate also accepts the other predefined
+ * types and custom datatypes, along with the other reduction ops and
+ * user-defined ops; these are applied in software to the packed representation
+ * (invec is the origin, inoutvec the target memory, unpacked into elements
+ * for user-defined ops as with reductions) while holding a per-process lock,
+ * so they're only atomic with respect to other accumulates.
+ */
+int MPI_Fetch_and_op(const void *origin_addr, void *result_addr,
+                     MPI_Datatype datatype, int target_rank,
+                     MPI_Aint target_disp, MPI_Op op, MPI_Win win);
+int MPI_Compare_and_swap(const void *origin_addr, const void *compare_addr,
+                         void *result_addr, MPI_Datatype datatype,
+                         int target_rank, MPI_Aint target_disp, MPI_Win win);
+int MPI_Accumulate(const void *origin_addr, int origin_count,
+                   MPI_Datatype origin_datatype, int target_rank,
+                   MPI_Aint target_disp, int target_count,
+                   MPI_Datatype target_datatype, MPI_Op op, MPI_Win win);
+
 /*
  * All functions return 0 on success and non-zero on failure.
  */
@@ -122,12 +329,22 @@ typedef int (MPI_Type_custom_region_function)(
     MPI_Count region_count,
     // Pointers to each region (out)
     void *reg_bases[],
-    // Lengths of each region (out)
+    // Lengths of each region (out); in bytes for predefined types, which must
+    // be a multiple of the type size, and in elements for custom types
     MPI_Count reg_lens[],
-    // Types for each region
+    // Types for each region (out, defaults to MPI_BYTE); regions of custom
+    // types are packed with that type's own callbacks, nested at most 16 deep
     MPI_Datatype reg_types[]
 );
 typedef int (MPI_Type_custom_state_free_function)(void *state);
+/* Get the signature of the type, which is checked against the receiver's in
+ * debug builds */
+typedef int (MPI_Type_custom_signature_function)(
+    // Input context, as passed in create function
+    void *context,
+    // Signature of the type (out); 0 disables the check
+    uint64_t *signature
+);
 
 int MPI_Type_create_custom(MPI_Type_custom_state_function *statefn,
                            MPI_Type_custom_state_free_function *state_freefn,
@@ -136,15 +353,33 @@ int MPI_Type_create_custom(MPI_Type_custom_state_function *statefn,
                            MPI_Type_custom_unpack_function *unpackfn,
                            MPI_Type_custom_region_count_function *region_countfn,
                            MPI_Type_custom_region_function *regionfn,
+                           MPI_Type_custom_signature_function *signaturefn,
                            void *context, // Context pointer to be stored for initializing state
                            int inorder, // Flag indicating in-order pack requirement
                            MPI_Datatype *type);
+/* Size and extent of predefined datatypes. Custom datatypes don't have a fixed
+ * size, so MPI_Type_size returns MPI_ERR_TYPE for them, and they only have an
+ * extent after MPI_Type_create_resized (with a lower bound of 0). The extent is
+ * needed to split buffers into per-process parts in MPI_Gather, MPI_Scatter,
+ * MPI_Allgather and MPI_Alltoall. */
+int MPI_Type_size(MPI_Datatype datatype, int *size);
+int MPI_Type_get_extent(MPI_Datatype datatype, MPI_Aint *lb, MPI_Aint *extent);
+int MPI_Type_create_resized(MPI_Datatype oldtype, MPI_Aint lb, MPI_Aint extent,
+                            MPI_Datatype *newtype);
 
 /* Idea: use a builder-like interface */
 
 /* Constants */
 #define MPI_SUCCESS 0
 #define MPI_ERR_INTERNAL 1
+#define MPI_ERR_TYPE 2
+#define MPI_ERR_OP 3
+#define MPI_ERR_REQUEST 4
+#define MPI_ERR_BUFFER 5
+#define MPI_ERR_WIN 6
+#define MPI_ERR_RMA_RANGE 7
+#define MPI_ERR_ROOT 8
+#define MPI_ERR_COUNT 9
 
 #if __cplusplus
 };
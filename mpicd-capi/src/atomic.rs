@@ -0,0 +1,216 @@
+//! Remote atomic functions.
+use mpicd::{AtomicInt, AtomicOp, Window};
+use std::ffi::{c_int, c_long, c_longlong, c_uint, c_ulong, c_ulonglong, c_void};
+use crate::{
+    datatype, reduce,
+    rma::{rma_status, CWindow},
+    c, consts, with_context,
+};
+
+/// Get the hardware atomic operation for the op, returning None for
+/// MPI_NO_OP and an error for unsupported ops.
+fn atomic_op(op: c::Op) -> Result<Option<AtomicOp>, c::ReturnStatus> {
+    match op {
+        consts::SUM => Ok(Some(AtomicOp::Add)),
+        consts::BAND => Ok(Some(AtomicOp::And)),
+        consts::BOR => Ok(Some(AtomicOp::Or)),
+        consts::BXOR => Ok(Some(AtomicOp::Xor)),
+        consts::REPLACE => Ok(Some(AtomicOp::Swap)),
+        consts::NO_OP => Ok(None),
+        _ => Err(consts::ERR_OP),
+    }
+}
+
+/// Call the function with the integer type corresponding to the datatype,
+/// returning MPI_ERR_TYPE for other datatypes.
+macro_rules! with_atomic_type {
+    ($datatype:expr, $f:ident($($arg:expr),*)) => {
+        match $datatype {
+            consts::INT32_T => $f::<i32>($($arg),*),
+            consts::UINT32_T => $f::<u32>($($arg),*),
+            consts::INT64_T => $f::<i64>($($arg),*),
+            consts::UINT64_T => $f::<u64>($($arg),*),
+            consts::INT => $f::<c_int>($($arg),*),
+            consts::UNSIGNED => $f::<c_uint>($($arg),*),
+            consts::LONG => $f::<c_long>($($arg),*),
+            consts::UNSIGNED_LONG => $f::<c_ulong>($($arg),*),
+            consts::LONG_LONG => $f::<c_longlong>($($arg),*),
+            consts::UNSIGNED_LONG_LONG => $f::<c_ulonglong>($($arg),*),
+            _ => consts::ERR_TYPE,
+        }
+    };
+}
+
+unsafe fn fetch_and_op<T: AtomicInt>(
+    window: &Window,
+    origin_addr: *const c_void,
+    result_addr: *mut c_void,
+    op: Option<AtomicOp>,
+    target_rank: c_int,
+    offset: usize,
+) -> c::ReturnStatus {
+    // MPI_NO_OP just fetches the value.
+    let (value, op) = match op {
+        Some(op) => (std::ptr::read_unaligned(origin_addr as *const T), op),
+        None => (T::default(), AtomicOp::Add),
+    };
+    match window.fetch_and_op(value, op, target_rank, offset) {
+        Ok(prev) => {
+            std::ptr::write_unaligned(result_addr as *mut T, prev);
+            consts::SUCCESS
+        }
+        Err(err) => rma_status(Err(err)),
+    }
+}
+
+unsafe fn compare_and_swap<T: AtomicInt>(
+    window: &Window,
+    origin_addr: *const c_void,
+    compare_addr: *const c_void,
+    result_addr: *mut c_void,
+    target_rank: c_int,
+    offset: usize,
+) -> c::ReturnStatus {
+    let value = std::ptr::read_unaligned(origin_addr as *const T);
+    let compare = std::ptr::read_unaligned(compare_addr as *const T);
+    match window.compare_and_swap(compare, value, target_rank, offset) {
+        Ok(prev) => {
+            std::ptr::write_unaligned(result_addr as *mut T, prev);
+            consts::SUCCESS
+        }
+        Err(err) => rma_status(Err(err)),
+    }
+}
+
+/// Apply the hardware atomic operation to each element.
+unsafe fn accumulate_elements<T: AtomicInt>(
+    window: &Window,
+    origin_addr: *const c_void,
+    count: c_int,
+    op: AtomicOp,
+    target_rank: c_int,
+    offset: usize,
+) -> c::ReturnStatus {
+    let size = std::mem::size_of::<T>();
+    for i in 0..count.max(0) as usize {
+        let value = std::ptr::read_unaligned((origin_addr as *const T).add(i));
+        let status = rma_status(window.atomic_op(value, op, target_rank, offset + i * size));
+        if status != consts::SUCCESS {
+            return status;
+        }
+    }
+    consts::SUCCESS
+}
+
+/// Get the window and the byte offset of the target displacement.
+fn window_offset(
+    cwin: Option<&CWindow>,
+    target_rank: c_int,
+    target_disp: c::Aint,
+) -> Result<(&Window, usize), c::ReturnStatus> {
+    let cwin = cwin.ok_or(consts::ERR_WIN)?;
+    let offset = cwin.offset(target_rank, target_disp).ok_or(consts::ERR_RMA_RANGE)?;
+    Ok((&cwin.window, offset))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Fetch_and_op(
+    origin_addr: *const c_void,
+    result_addr: *mut c_void,
+    datatype: c::Datatype,
+    target_rank: c_int,
+    target_disp: c::Aint,
+    op: c::Op,
+    win: c::Win,
+) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        let op = match atomic_op(op) {
+            Ok(op) => op,
+            Err(status) => return status,
+        };
+        let (window, offset) = match window_offset(cctx.get_window(win), target_rank, target_disp) {
+            Ok(window_offset) => window_offset,
+            Err(status) => return status,
+        };
+        with_atomic_type!(datatype, fetch_and_op(window, origin_addr, result_addr, op, target_rank, offset))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Compare_and_swap(
+    origin_addr: *const c_void,
+    compare_addr: *const c_void,
+    result_addr: *mut c_void,
+    datatype: c::Datatype,
+    target_rank: c_int,
+    target_disp: c::Aint,
+    win: c::Win,
+) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        let (window, offset) = match window_offset(cctx.get_window(win), target_rank, target_disp) {
+            Ok(window_offset) => window_offset,
+            Err(status) => return status,
+        };
+        with_atomic_type!(
+            datatype,
+            compare_and_swap(window, origin_addr, compare_addr, result_addr, target_rank, offset)
+        )
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Accumulate(
+    origin_addr: *const c_void,
+    origin_count: c_int,
+    origin_datatype: c::Datatype,
+    target_rank: c_int,
+    target_disp: c::Aint,
+    _target_count: c_int,
+    target_datatype: c::Datatype,
+    op: c::Op,
+    win: c::Win,
+) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        if target_datatype != origin_datatype && target_datatype != consts::BYTE {
+            return consts::ERR_TYPE;
+        }
+        if op == consts::NO_OP {
+            return consts::SUCCESS;
+        }
+        let (window, offset) = match window_offset(cctx.get_window(win), target_rank, target_disp) {
+            Ok(window_offset) => window_offset,
+            Err(status) => return status,
+        };
+
+        // Use hardware atomics for integers if possible.
+        if let Ok(Some(op)) = atomic_op(op) {
+            let status = with_atomic_type!(
+                origin_datatype,
+                accumulate_elements(window, origin_addr, origin_count, op, target_rank, offset)
+            );
+            if status != consts::ERR_TYPE {
+                return status;
+            }
+        }
+
+        // Otherwise fall back to software accumulate on the packed data.
+        if op == consts::REPLACE {
+            if !datatype::is_supported(cctx, origin_datatype) {
+                return consts::ERR_TYPE;
+            }
+            let Ok(sbuf) = reduce::pack_buffer(cctx, origin_addr, origin_count, origin_datatype) else {
+                return consts::ERR_INTERNAL;
+            };
+            let replace = |input: &[u8], inout: &mut [u8]| inout.copy_from_slice(input);
+            return rma_status(window.accumulate(&sbuf[..], &replace, target_rank, offset));
+        }
+        let (sbuf, op) = match reduce::prepare(cctx, origin_addr, origin_count, origin_datatype, op) {
+            Ok(prepared) => prepared,
+            Err(status) => return status,
+        };
+        match rma_status(window.accumulate(&sbuf[..], &op, target_rank, offset)) {
+            consts::SUCCESS => op.check().err().unwrap_or(consts::SUCCESS),
+            status => status,
+        }
+    })
+}
@@ -87,10 +87,40 @@ pub type RegionFn = Option<
     ) -> c_int
 >;
 
+/// Get the signature of a custom datatype from its context.
+pub type SignatureFn = Option<
+    unsafe extern "C" fn(
+        context: *mut c_void,
+        signature: *mut u64,
+    ) -> c_int
+>;
+
+/// User-defined reduction function corresponding to MPI_User_function.
+pub type UserFunction = Option<
+    unsafe extern "C" fn(
+        invec: *mut c_void,
+        inoutvec: *mut c_void,
+        len: *mut c_int,
+        datatype: *mut Datatype,
+    )
+>;
+
+/// Type corresponding to MPI_Op.
+pub type Op = c_int;
+
 pub type Request = isize;
 
 pub type Comm = c_int;
 
+/// Type corresponding to MPI_Aint.
+pub type Aint = isize;
+
+/// Type corresponding to MPI_Info (currently ignored).
+pub type Info = c_int;
+
+/// Type corresponding to MPI_Win.
+pub type Win = c_int;
+
 /// MPI_Status struct.
 #[repr(C)]
 pub struct Status {
@@ -99,4 +129,5 @@ pub struct Status {
     pub source: c_int,
     pub tag: c_int,
     pub error: c_int,
+    pub datatype: Datatype,
 }
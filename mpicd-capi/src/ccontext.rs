@@ -1,32 +1,122 @@
 //! C context data management code.
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::ffi::c_int;
-use crate::{datatype::CustomDatatype, consts, c};
+use crate::{datatype::CustomDatatype, p2p::RecvInfo, reduce::{PendingReduction, UserOp}, rma::CWindow, consts, c};
 
 /// C context struct to hold additional context data specific to the C interface.
 pub(crate) struct CContext {
-    datatypes: Vec<CustomDatatype>,
+    datatypes: Rc<Vec<CustomDatatype>>,
+    ops: Vec<Option<UserOp>>,
+    reductions: HashMap<usize, Box<PendingReduction>>,
+    receives: HashMap<usize, RecvInfo>,
+    windows: Vec<Option<CWindow>>,
 }
 
 impl CContext {
     pub(crate) fn new() -> CContext {
         CContext {
-            datatypes: vec![],
+            datatypes: Rc::new(vec![]),
+            ops: vec![],
+            reductions: HashMap::new(),
+            receives: HashMap::new(),
+            windows: vec![],
         }
     }
 
     /// Add a new datatype, returning it's C datatype integer.
     pub(crate) fn add_custom_datatype(&mut self, datatype: CustomDatatype) -> c::Datatype {
         let id = TryInto::<c_int>::try_into(self.datatypes.len()).unwrap() + consts::MAX_PREDEFINED + 1;
-        self.datatypes.push(datatype);
+        Rc::make_mut(&mut self.datatypes).push(datatype);
         id
     }
 
     pub(crate) fn get_custom_datatype(&self, datatype: c::Datatype) -> Option<CustomDatatype> {
-        if datatype <= consts::BYTE {
+        if datatype <= consts::MAX_PREDEFINED {
             None
         } else {
             let i: usize = (datatype - consts::MAX_PREDEFINED - 1).try_into().unwrap();
             self.datatypes.get(i).copied()
         }
     }
+
+    /// Get the table of custom datatypes, used to look up the types of memory
+    /// regions while packing and unpacking.
+    pub(crate) fn custom_datatypes(&self) -> Rc<Vec<CustomDatatype>> {
+        Rc::clone(&self.datatypes)
+    }
+
+    /// Add a new user-defined operation, returning it's C op integer.
+    pub(crate) fn add_user_op(&mut self, op: UserOp) -> c::Op {
+        let id = TryInto::<c_int>::try_into(self.ops.len()).unwrap() + consts::MAX_PREDEFINED_OP + 1;
+        self.ops.push(Some(op));
+        id
+    }
+
+    pub(crate) fn get_user_op(&self, op: c::Op) -> Option<UserOp> {
+        if op <= consts::MAX_PREDEFINED_OP {
+            None
+        } else {
+            let i: usize = (op - consts::MAX_PREDEFINED_OP - 1).try_into().unwrap();
+            self.ops.get(i).copied().flatten()
+        }
+    }
+
+    /// Free a user-defined operation, returning false if it doesn't exist.
+    pub(crate) fn free_user_op(&mut self, op: c::Op) -> bool {
+        if op <= consts::MAX_PREDEFINED_OP {
+            false
+        } else {
+            let i: usize = (op - consts::MAX_PREDEFINED_OP - 1).try_into().unwrap();
+            self.ops.get_mut(i).and_then(|op| op.take()).is_some()
+        }
+    }
+
+    /// Attach a pending non-blocking reduction to the request.
+    pub(crate) fn add_pending_reduction(&mut self, req: usize, pending: Box<PendingReduction>) {
+        self.reductions.insert(req, pending);
+    }
+
+    /// Remove the pending reduction attached to the request.
+    pub(crate) fn take_pending_reduction(&mut self, req: usize) -> Option<Box<PendingReduction>> {
+        self.reductions.remove(&req)
+    }
+
+    /// Record the info of a receive request, used to fill in its status.
+    pub(crate) fn add_receive(&mut self, req: usize, info: RecvInfo) {
+        self.receives.insert(req, info);
+    }
+
+    /// Return the info of a completed receive request, dropping it unless the
+    /// request is persistent.
+    pub(crate) fn complete_receive(&mut self, req: usize) -> Option<RecvInfo> {
+        let info = *self.receives.get(&req)?;
+        if !info.persistent {
+            self.receives.remove(&req);
+        }
+        Some(info)
+    }
+
+    /// Drop the info of a freed request.
+    pub(crate) fn free_receive(&mut self, req: usize) {
+        self.receives.remove(&req);
+    }
+
+    /// Add a new window, returning its C window integer.
+    pub(crate) fn add_window(&mut self, window: CWindow) -> c::Win {
+        let id = self.windows.len().try_into().unwrap();
+        self.windows.push(Some(window));
+        id
+    }
+
+    pub(crate) fn get_window(&self, win: c::Win) -> Option<&CWindow> {
+        let i: usize = win.try_into().ok()?;
+        self.windows.get(i).and_then(|win| win.as_ref())
+    }
+
+    /// Remove a window, returning None if it doesn't exist.
+    pub(crate) fn take_window(&mut self, win: c::Win) -> Option<CWindow> {
+        let i: usize = win.try_into().ok()?;
+        self.windows.get_mut(i).and_then(|win| win.take())
+    }
 }
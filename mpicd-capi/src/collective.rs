@@ -0,0 +1,344 @@
+//! Collective functions.
+use mpicd::communicator::{Communicator, Error};
+use std::ffi::{c_int, c_void};
+use crate::{
+    datatype::{self, AnyBuffer},
+    ccontext::CContext,
+    c, consts, with_context,
+};
+
+/// Split a contiguous buffer into one part of count elements per rank,
+/// returning None if the datatype doesn't have a known extent.
+unsafe fn parts(
+    cctx: &CContext,
+    buf: *const c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    size: c_int,
+) -> Option<Vec<AnyBuffer>> {
+    let extent = datatype::extent(cctx, datatype)?;
+    let count_usize: usize = count.try_into().ok()?;
+    (0..size as usize)
+        .map(|i| AnyBuffer::new(cctx, (buf as *const u8).add(i * count_usize * extent) as *const _, count, datatype))
+        .collect()
+}
+
+/// Check that the root is a valid rank.
+pub(crate) fn check_root(ctx: &mpicd::Context, root: c_int) -> Result<(), c::ReturnStatus> {
+    if root >= 0 && root < ctx.size() {
+        Ok(())
+    } else {
+        Err(consts::ERR_ROOT)
+    }
+}
+
+/// Convert a communicator error into a C return status.
+pub(crate) fn error_status(err: Error) -> c::ReturnStatus {
+    match err {
+        Error::InvalidRoot => consts::ERR_ROOT,
+        Error::InvalidBufferCount => consts::ERR_COUNT,
+        Error::Datatype(_) => consts::ERR_TYPE,
+        Error::InvalidRequest => consts::ERR_REQUEST,
+        Error::BufferExhausted | Error::InvalidBuffer => consts::ERR_BUFFER,
+        _ => consts::ERR_INTERNAL,
+    }
+}
+
+/// Convert a communicator result into a C return status.
+fn return_status(result: mpicd::communicator::Result<()>) -> c::ReturnStatus {
+    match result {
+        Ok(_) => consts::SUCCESS,
+        Err(err) => error_status(err),
+    }
+}
+
+/// Store the request of a started non-blocking operation, returning the C
+/// status.
+pub(crate) unsafe fn start_status(
+    result: mpicd::communicator::Result<usize>,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    match result {
+        Ok(req) => {
+            *request = req.try_into().unwrap();
+            consts::SUCCESS
+        }
+        Err(err) => error_status(err),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Bcast(
+    buffer: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    root: c_int,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Err(status) = check_root(ctx, root) {
+            return status;
+        }
+        let Some(mut buffer) = AnyBuffer::new(cctx, buffer, count, datatype) else {
+            return consts::ERR_TYPE;
+        };
+        let result = ctx.bcast(&mut buffer, root);
+        return_status(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Gather(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    root: c_int,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Err(status) = check_root(ctx, root) {
+            return status;
+        }
+        let Some(sbuf) = AnyBuffer::new(cctx, sendbuf, sendcount, sendtype) else {
+            return consts::ERR_TYPE;
+        };
+        let rparts = if ctx.rank() == root {
+            parts(cctx, recvbuf, recvcount, recvtype, ctx.size())
+        } else {
+            Some(vec![])
+        };
+        let Some(mut rparts) = rparts else {
+            return consts::ERR_TYPE;
+        };
+        let mut rbufs: Vec<&mut AnyBuffer> = rparts.iter_mut().collect();
+        return_status(ctx.gather(&sbuf, &mut rbufs, root))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Scatter(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    root: c_int,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Err(status) = check_root(ctx, root) {
+            return status;
+        }
+        let sparts = if ctx.rank() == root {
+            parts(cctx, sendbuf, sendcount, sendtype, ctx.size())
+        } else {
+            Some(vec![])
+        };
+        let (Some(sparts), Some(mut rbuf)) = (sparts, AnyBuffer::new(cctx, recvbuf, recvcount, recvtype)) else {
+            return consts::ERR_TYPE;
+        };
+        let sbufs: Vec<&AnyBuffer> = sparts.iter().collect();
+        return_status(ctx.scatter(&sbufs, &mut rbuf, root))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Allgather(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let sbuf = AnyBuffer::new(cctx, sendbuf, sendcount, sendtype);
+        let rparts = parts(cctx, recvbuf, recvcount, recvtype, ctx.size());
+        let (Some(sbuf), Some(mut rparts)) = (sbuf, rparts) else {
+            return consts::ERR_TYPE;
+        };
+        let mut rbufs: Vec<&mut AnyBuffer> = rparts.iter_mut().collect();
+        return_status(ctx.allgather(&sbuf, &mut rbufs))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Alltoall(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let sparts = parts(cctx, sendbuf, sendcount, sendtype, ctx.size());
+        let rparts = parts(cctx, recvbuf, recvcount, recvtype, ctx.size());
+        let (Some(sparts), Some(mut rparts)) = (sparts, rparts) else {
+            return consts::ERR_TYPE;
+        };
+        let sbufs: Vec<&AnyBuffer> = sparts.iter().collect();
+        let mut rbufs: Vec<&mut AnyBuffer> = rparts.iter_mut().collect();
+        return_status(ctx.alltoall(&sbufs, &mut rbufs))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Ibarrier(comm: c::Comm, request: *mut c::Request) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, _cctx| start_status(ctx.ibarrier(), request))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Ibcast(
+    buffer: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    root: c_int,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Err(status) = check_root(ctx, root) {
+            return status;
+        }
+        let Some(mut buffer) = AnyBuffer::new(cctx, buffer, count, datatype) else {
+            return consts::ERR_TYPE;
+        };
+        let result = ctx.ibcast(&mut buffer, root);
+        start_status(result, request)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Igather(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    root: c_int,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Err(status) = check_root(ctx, root) {
+            return status;
+        }
+        let Some(sbuf) = AnyBuffer::new(cctx, sendbuf, sendcount, sendtype) else {
+            return consts::ERR_TYPE;
+        };
+        let rparts = if ctx.rank() == root {
+            parts(cctx, recvbuf, recvcount, recvtype, ctx.size())
+        } else {
+            Some(vec![])
+        };
+        let Some(mut rparts) = rparts else {
+            return consts::ERR_TYPE;
+        };
+        let mut rbufs: Vec<&mut AnyBuffer> = rparts.iter_mut().collect();
+        start_status(ctx.igather(&sbuf, &mut rbufs, root), request)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Iscatter(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    root: c_int,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Err(status) = check_root(ctx, root) {
+            return status;
+        }
+        let sparts = if ctx.rank() == root {
+            parts(cctx, sendbuf, sendcount, sendtype, ctx.size())
+        } else {
+            Some(vec![])
+        };
+        let (Some(sparts), Some(mut rbuf)) = (sparts, AnyBuffer::new(cctx, recvbuf, recvcount, recvtype)) else {
+            return consts::ERR_TYPE;
+        };
+        let sbufs: Vec<&AnyBuffer> = sparts.iter().collect();
+        start_status(ctx.iscatter(&sbufs, &mut rbuf, root), request)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Iallgather(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let sbuf = AnyBuffer::new(cctx, sendbuf, sendcount, sendtype);
+        let rparts = parts(cctx, recvbuf, recvcount, recvtype, ctx.size());
+        let (Some(sbuf), Some(mut rparts)) = (sbuf, rparts) else {
+            return consts::ERR_TYPE;
+        };
+        let mut rbufs: Vec<&mut AnyBuffer> = rparts.iter_mut().collect();
+        start_status(ctx.iallgather(&sbuf, &mut rbufs), request)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Ialltoall(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let sparts = parts(cctx, sendbuf, sendcount, sendtype, ctx.size());
+        let rparts = parts(cctx, recvbuf, recvcount, recvtype, ctx.size());
+        let (Some(sparts), Some(mut rparts)) = (sparts, rparts) else {
+            return consts::ERR_TYPE;
+        };
+        let sbufs: Vec<&AnyBuffer> = sparts.iter().collect();
+        let mut rbufs: Vec<&mut AnyBuffer> = rparts.iter_mut().collect();
+        start_status(ctx.ialltoall(&sbufs, &mut rbufs), request)
+    })
+}
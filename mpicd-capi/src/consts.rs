@@ -8,10 +8,96 @@ pub const SUCCESS: c::ReturnStatus = 0;
 
 pub const ERR_INTERNAL: c::ReturnStatus = 1;
 
+pub const ERR_TYPE: c::ReturnStatus = 2;
+
+pub const ERR_OP: c::ReturnStatus = 3;
+
+pub const ERR_REQUEST: c::ReturnStatus = 4;
+
+pub const ERR_BUFFER: c::ReturnStatus = 5;
+
+pub const ERR_WIN: c::ReturnStatus = 6;
+
+pub const ERR_RMA_RANGE: c::ReturnStatus = 7;
+
+pub const ERR_ROOT: c::ReturnStatus = 8;
+
+pub const ERR_COUNT: c::ReturnStatus = 9;
+
 pub const COMM_WORLD: c::Comm = 1;
 
 pub const BYTE: c::Datatype = 1;
 
-pub const MAX_PREDEFINED: c::Datatype = 1;
+pub const INT32_T: c::Datatype = 2;
+
+pub const UINT32_T: c::Datatype = 3;
+
+pub const INT64_T: c::Datatype = 4;
+
+pub const UINT64_T: c::Datatype = 5;
+
+pub const CHAR: c::Datatype = 6;
+
+pub const SIGNED_CHAR: c::Datatype = 7;
+
+pub const UNSIGNED_CHAR: c::Datatype = 8;
+
+pub const SHORT: c::Datatype = 9;
+
+pub const UNSIGNED_SHORT: c::Datatype = 10;
+
+pub const INT: c::Datatype = 11;
+
+pub const UNSIGNED: c::Datatype = 12;
+
+pub const LONG: c::Datatype = 13;
+
+pub const UNSIGNED_LONG: c::Datatype = 14;
+
+pub const LONG_LONG: c::Datatype = 15;
+
+pub const UNSIGNED_LONG_LONG: c::Datatype = 16;
+
+pub const FLOAT: c::Datatype = 17;
+
+pub const DOUBLE: c::Datatype = 18;
+
+pub const INT8_T: c::Datatype = 19;
+
+pub const UINT8_T: c::Datatype = 20;
+
+pub const INT16_T: c::Datatype = 21;
+
+pub const UINT16_T: c::Datatype = 22;
+
+pub const MAX_PREDEFINED: c::Datatype = 22;
 
 pub const ANY_SOURCE: c_int = -1;
+
+pub const UNDEFINED: c_int = -32766;
+
+pub const REQUEST_NULL: c::Request = -1;
+
+pub const WIN_NULL: c::Win = -1;
+
+pub const OP_NULL: c::Op = 0;
+
+pub const SUM: c::Op = 1;
+
+pub const PROD: c::Op = 2;
+
+pub const MIN: c::Op = 3;
+
+pub const MAX: c::Op = 4;
+
+pub const BAND: c::Op = 5;
+
+pub const BOR: c::Op = 6;
+
+pub const BXOR: c::Op = 7;
+
+pub const REPLACE: c::Op = 8;
+
+pub const NO_OP: c::Op = 9;
+
+pub const MAX_PREDEFINED_OP: c::Op = 9;
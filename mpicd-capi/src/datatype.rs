@@ -1,8 +1,9 @@
 //! Datatype management code.
 use std::ffi::{c_void, c_int};
+use std::rc::Rc;
 use crate::c;
 use mpicd::datatype::{DatatypeResult, DatatypeError, MessageBuffer, MessageCount, MessagePointer, PackMethod, UnpackMethod, PackedSize};
-use crate::{consts, with_context};
+use crate::{ccontext::CContext, consts, with_context};
 
 /// Custom buffer type for utilizing pack and unpack functions in C.
 pub(crate) struct CustomBuffer {
@@ -14,22 +15,14 @@ pub(crate) struct CustomBuffer {
 
     /// Custom datatype handling functions.
     pub(crate) custom_datatype: CustomDatatype,
+
+    /// All custom datatypes, for memory regions of other custom types.
+    pub(crate) datatypes: Rc<Vec<CustomDatatype>>,
 }
 
 impl CustomBuffer {
-    unsafe fn state(&self) -> DatatypeResult<*mut c_void> {
-        let mut state: *mut c_void = std::ptr::null_mut();
-        let ret = if let Some(func) = self.custom_datatype.vtable.statefn {
-            func(self.custom_datatype.context, self.ptr as *const _, self.len, &mut state)
-        } else {
-            0
-        };
-
-        if ret == 0 {
-            Ok(state)
-        } else {
-            Err(DatatypeError::StateError)
-        }
+    unsafe fn method(&self) -> DatatypeResult<CustomPackMethod> {
+        CustomPackMethod::new(self.custom_datatype, Rc::clone(&self.datatypes), self.ptr as *const _, self.len, 0)
     }
 }
 
@@ -51,33 +44,15 @@ impl MessageCount for CustomBuffer {
 
 impl MessageBuffer for CustomBuffer {
     unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
-        let state = self.state();
-        if let Err(err) = state {
-            return Some(Err(err));
-        }
-        let state = state.expect("expected state pointer");
-
-        Some(Ok(Box::new(CustomPackMethod {
-            custom_datatype: self.custom_datatype,
-            state,
-            ptr: self.ptr as *const _,
-            count: self.len,
-        })))
+        Some(self.method().map(|method| Box::new(method) as Box<dyn PackMethod>))
     }
 
     unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
-        let state = self.state();
-        if let Err(err) = state {
-            return Some(Err(err));
-        }
-        let state = state.expect("expected state pointer");
+        Some(self.method().map(|method| Box::new(method) as Box<dyn UnpackMethod>))
+    }
 
-        Some(Ok(Box::new(CustomPackMethod {
-            custom_datatype: self.custom_datatype,
-            state,
-            ptr: self.ptr as *const _,
-            count: self.len,
-        })))
+    fn signature(&self) -> Option<u64> {
+        self.custom_datatype.signature()
     }
 }
 
@@ -90,6 +65,7 @@ pub(crate) struct CustomDatatypeVTable {
     unpackfn: c::UnpackFn,
     region_countfn: c::RegionCountFn,
     regionfn: c::RegionFn,
+    signaturefn: c::SignatureFn,
 }
 
 /// Custom datatype vtable and context info.
@@ -97,6 +73,108 @@ pub(crate) struct CustomDatatypeVTable {
 pub(crate) struct CustomDatatype {
     vtable: CustomDatatypeVTable,
     context: *mut c_void,
+
+    /// Extent of each element, if set with MPI_Type_create_resized().
+    pub(crate) extent: Option<usize>,
+}
+
+impl CustomDatatype {
+    unsafe fn state(&self, ptr: *const c_void, count: usize) -> DatatypeResult<*mut c_void> {
+        let mut state: *mut c_void = std::ptr::null_mut();
+        let ret = if let Some(func) = self.vtable.statefn {
+            func(self.context, ptr, count, &mut state)
+        } else {
+            0
+        };
+
+        if ret == 0 {
+            Ok(state)
+        } else {
+            Err(DatatypeError::StateError)
+        }
+    }
+
+    /// Get the signature from the signature function, if there is one. A
+    /// signature of 0 or a failing function disables the check.
+    fn signature(&self) -> Option<u64> {
+        let func = self.vtable.signaturefn?;
+        let mut signature = 0;
+        let ret = unsafe { func(self.context, &mut signature) };
+        if ret == 0 && signature != 0 {
+            Some(signature)
+        } else {
+            None
+        }
+    }
+}
+
+/// Call the function with the Rust type corresponding to the predefined
+/// datatype, evaluating the default for other datatypes.
+macro_rules! with_predefined_type {
+    ($datatype:expr, $f:ident($($arg:expr),*), $default:expr) => {
+        match $datatype {
+            consts::BYTE | consts::UNSIGNED_CHAR | consts::UINT8_T => $f::<u8>($($arg),*),
+            consts::CHAR => $f::<std::ffi::c_char>($($arg),*),
+            consts::SIGNED_CHAR | consts::INT8_T => $f::<i8>($($arg),*),
+            consts::SHORT => $f::<std::ffi::c_short>($($arg),*),
+            consts::UNSIGNED_SHORT => $f::<std::ffi::c_ushort>($($arg),*),
+            consts::INT16_T => $f::<i16>($($arg),*),
+            consts::UINT16_T => $f::<u16>($($arg),*),
+            consts::INT => $f::<std::ffi::c_int>($($arg),*),
+            consts::UNSIGNED => $f::<std::ffi::c_uint>($($arg),*),
+            consts::INT32_T => $f::<i32>($($arg),*),
+            consts::UINT32_T => $f::<u32>($($arg),*),
+            consts::LONG => $f::<std::ffi::c_long>($($arg),*),
+            consts::UNSIGNED_LONG => $f::<std::ffi::c_ulong>($($arg),*),
+            consts::LONG_LONG => $f::<std::ffi::c_longlong>($($arg),*),
+            consts::UNSIGNED_LONG_LONG => $f::<std::ffi::c_ulonglong>($($arg),*),
+            consts::INT64_T => $f::<i64>($($arg),*),
+            consts::UINT64_T => $f::<u64>($($arg),*),
+            consts::FLOAT => $f::<std::ffi::c_float>($($arg),*),
+            consts::DOUBLE => $f::<std::ffi::c_double>($($arg),*),
+            _ => $default,
+        }
+    };
+}
+pub(crate) use with_predefined_type;
+
+fn type_size<T>() -> Option<usize> {
+    Some(std::mem::size_of::<T>())
+}
+
+/// Return the element size of a predefined datatype.
+pub(crate) fn predefined_size(datatype: c::Datatype) -> Option<usize> {
+    with_predefined_type!(datatype, type_size(), None)
+}
+
+/// Return the extent of the datatype, which is only known for custom
+/// datatypes once they've been resized.
+pub(crate) fn extent(cctx: &CContext, datatype: c::Datatype) -> Option<usize> {
+    predefined_size(datatype).or_else(|| cctx.get_custom_datatype(datatype)?.extent)
+}
+
+/// Return true if the datatype is a floating point type.
+pub(crate) fn is_float(datatype: c::Datatype) -> bool {
+    datatype == consts::FLOAT || datatype == consts::DOUBLE
+}
+
+/// Return true if the datatype is either predefined or a custom datatype.
+pub(crate) fn is_supported(cctx: &CContext, datatype: c::Datatype) -> bool {
+    predefined_size(datatype).is_some() || cctx.get_custom_datatype(datatype).is_some()
+}
+
+/// Maximum nesting depth of regions of custom datatypes, which stops types
+/// whose regions (directly or indirectly) hold their own type.
+const MAX_REGION_DEPTH: usize = 16;
+
+/// Memory region returned by the region function.
+enum Region {
+    /// Region of a predefined datatype, sent as is.
+    Memory(*mut u8, usize),
+
+    /// Region of elements of another custom datatype, expanded through that
+    /// type's own callbacks.
+    Custom(CustomPackMethod),
 }
 
 struct CustomPackMethod {
@@ -104,10 +182,36 @@ struct CustomPackMethod {
     state: *mut c_void,
     ptr: *const c_void,
     count: usize,
+    regions: Vec<Region>,
 }
 
 impl CustomPackMethod {
-    unsafe fn regions(&self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
+    /// Create the method, getting the memory regions of the buffer up front so
+    /// that regions of custom datatypes get their own state. The depth is the
+    /// number of enclosing regions of custom datatypes.
+    unsafe fn new(
+        custom_datatype: CustomDatatype,
+        datatypes: Rc<Vec<CustomDatatype>>,
+        ptr: *const c_void,
+        count: usize,
+        depth: usize,
+    ) -> DatatypeResult<CustomPackMethod> {
+        if depth > MAX_REGION_DEPTH {
+            return Err(DatatypeError::RegionError);
+        }
+        let state = custom_datatype.state(ptr, count)?;
+        let mut method = CustomPackMethod {
+            custom_datatype,
+            state,
+            ptr,
+            count,
+            regions: vec![],
+        };
+        method.regions = method.get_regions(&datatypes, depth)?;
+        Ok(method)
+    }
+
+    unsafe fn get_regions(&self, datatypes: &Rc<Vec<CustomDatatype>>, depth: usize) -> DatatypeResult<Vec<Region>> {
         if self.custom_datatype.vtable.region_countfn.is_none() {
             return Ok(vec![]);
         }
@@ -122,7 +226,7 @@ impl CustomPackMethod {
         let regionfn = self.custom_datatype.vtable.regionfn.expect("missing memory region function");
         let mut reg_lens = vec![0; region_count];
         let mut reg_bases = vec![std::ptr::null_mut(); region_count];
-        // Ignore the types for now.
+        // Regions default to bytes if the function doesn't set the types.
         let mut types = vec![consts::BYTE; region_count];
         let ret = regionfn(
             self.state,
@@ -137,18 +241,56 @@ impl CustomPackMethod {
             return Err(DatatypeError::RegionError);
         }
 
-        Ok(
-            reg_bases
-                .iter()
-                .zip(reg_lens.iter())
-                .map(|(ptr, len)| (*ptr as *mut u8, *len))
-                .collect()
-        )
+        reg_bases
+            .iter()
+            .zip(reg_lens.iter())
+            .zip(types.iter())
+            .map(|((ptr, len), datatype)| {
+                if let Some(size) = predefined_size(*datatype) {
+                    // Lengths of predefined types are in bytes and must hold
+                    // whole elements.
+                    if len % size == 0 {
+                        Ok(Region::Memory(*ptr as *mut u8, *len))
+                    } else {
+                        Err(DatatypeError::RegionError)
+                    }
+                } else if *datatype > consts::MAX_PREDEFINED {
+                    // Lengths of custom types are element counts.
+                    let i: usize = (datatype - consts::MAX_PREDEFINED - 1).try_into().unwrap();
+                    let custom_datatype = datatypes.get(i).copied().ok_or(DatatypeError::RegionError)?;
+                    CustomPackMethod::new(custom_datatype, Rc::clone(datatypes), *ptr as *const _, *len, depth + 1)
+                        .map(Region::Custom)
+                } else {
+                    Err(DatatypeError::RegionError)
+                }
+            })
+            .collect()
     }
-}
 
-impl PackedSize for CustomPackMethod {
-    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+    /// Return the memory regions, with regions of custom types replaced by
+    /// their own memory regions.
+    fn memory_regions(&self) -> Vec<(*mut u8, usize)> {
+        let mut regions = vec![];
+        for region in &self.regions {
+            match region {
+                Region::Memory(ptr, len) => regions.push((*ptr, *len)),
+                Region::Custom(method) => regions.extend(method.memory_regions()),
+            }
+        }
+        regions
+    }
+
+    /// Return the nested methods of regions of custom types, whose packed
+    /// parts follow this type's own packed part.
+    fn nested(&mut self) -> impl Iterator<Item = &mut CustomPackMethod> {
+        self.regions.iter_mut().filter_map(|region| match region {
+            Region::Custom(method) => Some(method),
+            Region::Memory(..) => None,
+        })
+    }
+
+    /// Get the packed size of this type alone, without nested regions.
+    unsafe fn own_packed_size(&self) -> DatatypeResult<usize> {
         if let Some(func) = self.custom_datatype.vtable.queryfn {
             let mut packed_size = 0;
             let ret = func(self.state, self.ptr as *const _, self.count, &mut packed_size);
@@ -161,10 +303,22 @@ impl PackedSize for CustomPackMethod {
             Ok(0)
         }
     }
-}
 
-impl UnpackMethod for CustomPackMethod {
-    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
+    unsafe fn own_pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        if let Some(func) = self.custom_datatype.vtable.packfn {
+            let mut used = 0;
+            let ret = func(self.state, self.ptr, self.count, offset, dst as *mut _, dst_size, &mut used);
+            if ret == 0 {
+                Ok(used)
+            } else {
+                Err(DatatypeError::PackError)
+            }
+        } else {
+            Ok(0)
+        }
+    }
+
+    unsafe fn own_unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
         if let Some(func) = self.custom_datatype.vtable.unpackfn {
             let ret = func(self.state, self.ptr as *mut _, self.count, offset, src as *const _, src_size);
             if ret == 0 {
@@ -176,30 +330,83 @@ impl UnpackMethod for CustomPackMethod {
             Ok(())
         }
     }
+}
+
+impl PackedSize for CustomPackMethod {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        let mut packed_size = self.own_packed_size()?;
+        for region in &self.regions {
+            if let Region::Custom(method) = region {
+                packed_size += method.packed_size()?;
+            }
+        }
+        Ok(packed_size)
+    }
+}
+
+impl UnpackMethod for CustomPackMethod {
+    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
+        let mut start = 0;
+        let mut used = 0;
+        let size = self.own_packed_size()?;
+        if offset < size {
+            used = (size - offset).min(src_size);
+            self.own_unpack(offset, src, used)?;
+        }
+        start += size;
+        for method in self.nested() {
+            let size = method.packed_size()?;
+            let pos = offset + used;
+            if used < src_size && pos < start + size {
+                let len = (start + size - pos).min(src_size - used);
+                UnpackMethod::unpack(method, pos - start, src.add(used), len)?;
+                used += len;
+            }
+            start += size;
+        }
+        Ok(())
+    }
 
     unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
-        self.regions()
+        Ok(CustomPackMethod::memory_regions(self))
     }
 }
 
 impl PackMethod for CustomPackMethod {
     unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
-        if let Some(func) = self.custom_datatype.vtable.packfn {
-            let mut used = 0;
-            let ret = func(self.state, self.ptr, self.count, offset, dst as *mut _, dst_size, &mut used);
-            if ret == 0 {
-                Ok(used)
-            } else {
-                Err(DatatypeError::PackError)
+        let mut start = 0;
+        let mut used = 0;
+        let size = self.own_packed_size()?;
+        if offset < size {
+            used = self.own_pack(offset, dst, (size - offset).min(dst_size))?;
+            if offset + used < size {
+                return Ok(used);
             }
-        } else {
-            Ok(0)
         }
+        start += size;
+        for method in self.nested() {
+            let size = method.packed_size()?;
+            let pos = offset + used;
+            if used < dst_size && pos < start + size {
+                let len = (start + size - pos).min(dst_size - used);
+                let nested_used = PackMethod::pack(method, pos - start, dst.add(used), len)?;
+                used += nested_used;
+                if nested_used < len {
+                    break;
+                }
+            }
+            start += size;
+        }
+        Ok(used)
     }
 
     unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
-        self.regions()
-            .map(|v| v.iter().map(|(ptr, count)| (*ptr as *const _, *count)).collect())
+        Ok(
+            CustomPackMethod::memory_regions(self)
+                .iter()
+                .map(|(ptr, count)| (*ptr as *const _, *count))
+                .collect()
+        )
     }
 }
 
@@ -216,7 +423,7 @@ impl Drop for CustomPackMethod {
     }
 }
 
-/// Send buffer to be used for MPI_BYTE types.
+/// Send buffer to be used for predefined types, which are sent as raw bytes.
 pub(crate) struct ByteBuffer {
     pub(crate) ptr: *mut u8,
     pub(crate) size: usize,
@@ -240,6 +447,81 @@ impl MessageCount for ByteBuffer {
 
 impl MessageBuffer for ByteBuffer {}
 
+/// Buffer for either a custom datatype or a predefined type.
+pub(crate) enum AnyBuffer {
+    Custom(CustomBuffer),
+    Byte(ByteBuffer),
+}
+
+impl AnyBuffer {
+    /// Create the buffer for the datatype, returning None if the datatype is
+    /// not supported.
+    pub(crate) fn new(cctx: &CContext, buf: *const c_void, count: c_int, datatype: c::Datatype) -> Option<AnyBuffer> {
+        if let Some(custom_datatype) = cctx.get_custom_datatype(datatype) {
+            Some(AnyBuffer::Custom(CustomBuffer {
+                ptr: buf as *mut _,
+                len: count as usize,
+                custom_datatype,
+                datatypes: cctx.custom_datatypes(),
+            }))
+        } else {
+            let size = predefined_size(datatype)?;
+            Some(AnyBuffer::Byte(ByteBuffer {
+                ptr: buf as *mut _,
+                size: TryInto::<usize>::try_into(count).unwrap() * size,
+            }))
+        }
+    }
+}
+
+impl MessagePointer for AnyBuffer {
+    fn ptr(&self) -> *const u8 {
+        match self {
+            AnyBuffer::Custom(buffer) => buffer.ptr(),
+            AnyBuffer::Byte(buffer) => buffer.ptr(),
+        }
+    }
+
+    fn ptr_mut(&mut self) -> *mut u8 {
+        match self {
+            AnyBuffer::Custom(buffer) => buffer.ptr_mut(),
+            AnyBuffer::Byte(buffer) => buffer.ptr_mut(),
+        }
+    }
+}
+
+impl MessageCount for AnyBuffer {
+    fn count(&self) -> usize {
+        match self {
+            AnyBuffer::Custom(buffer) => buffer.count(),
+            AnyBuffer::Byte(buffer) => buffer.count(),
+        }
+    }
+}
+
+impl MessageBuffer for AnyBuffer {
+    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
+        match self {
+            AnyBuffer::Custom(buffer) => buffer.pack(),
+            AnyBuffer::Byte(buffer) => buffer.pack(),
+        }
+    }
+
+    unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
+        match self {
+            AnyBuffer::Custom(buffer) => buffer.unpack(),
+            AnyBuffer::Byte(buffer) => buffer.unpack(),
+        }
+    }
+
+    fn signature(&self) -> Option<u64> {
+        match self {
+            AnyBuffer::Custom(buffer) => buffer.signature(),
+            AnyBuffer::Byte(buffer) => buffer.signature(),
+        }
+    }
+}
+
 /// Create a non-dynamic custom MPI_Datatype.
 #[no_mangle]
 pub unsafe extern "C" fn MPI_Type_create_custom(
@@ -250,6 +532,7 @@ pub unsafe extern "C" fn MPI_Type_create_custom(
     unpackfn: c::UnpackFn,
     region_countfn: c::RegionCountFn,
     regionfn: c::RegionFn,
+    signaturefn: c::SignatureFn,
     context: *mut c_void,
     _inorder: c_int,
     datatype: *mut c::Datatype,
@@ -264,9 +547,61 @@ pub unsafe extern "C" fn MPI_Type_create_custom(
                 unpackfn,
                 region_countfn,
                 regionfn,
+                signaturefn,
             },
             context,
+            extent: None,
         });
         consts::SUCCESS
     })
 }
+
+/// Get the size in bytes of a predefined datatype.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Type_size(datatype: c::Datatype, size: *mut c_int) -> c::ReturnStatus {
+    match predefined_size(datatype) {
+        Some(type_size) => {
+            *size = type_size.try_into().unwrap();
+            consts::SUCCESS
+        }
+        None => consts::ERR_TYPE,
+    }
+}
+
+/// Get the lower bound and extent of a datatype. The lower bound is always 0,
+/// while custom datatypes only have an extent once they've been resized.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Type_get_extent(
+    datatype: c::Datatype,
+    lb: *mut c::Aint,
+    extent: *mut c::Aint,
+) -> c::ReturnStatus {
+    with_context(move |_, cctx| match self::extent(cctx, datatype) {
+        Some(type_extent) => {
+            *lb = 0;
+            *extent = type_extent.try_into().unwrap();
+            consts::SUCCESS
+        }
+        None => consts::ERR_TYPE,
+    })
+}
+
+/// Create a copy of a custom datatype with the given extent, which is needed
+/// to split buffers of it into per-process parts in collectives. Only a lower
+/// bound of 0 is supported.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Type_create_resized(
+    oldtype: c::Datatype,
+    lb: c::Aint,
+    extent: c::Aint,
+    newtype: *mut c::Datatype,
+) -> c::ReturnStatus {
+    with_context(move |_, cctx| {
+        let (Some(mut custom_datatype), 0, Ok(extent)) = (cctx.get_custom_datatype(oldtype), lb, extent.try_into()) else {
+            return consts::ERR_TYPE;
+        };
+        custom_datatype.extent = Some(extent);
+        *newtype = cctx.add_custom_datatype(custom_datatype);
+        consts::SUCCESS
+    })
+}
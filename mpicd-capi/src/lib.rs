@@ -1,12 +1,19 @@
 //! Experimental Rust API for custom datatype serialization.
+// The C entry points follow the MPI standard's requirements on their arguments.
+#![allow(clippy::missing_safety_doc)]
 use log::info;
 use mpicd::communicator::Communicator;
 use std::ffi::{c_char, c_int};
+use std::ptr::{addr_of, addr_of_mut};
 use std::sync::Once;
 
 mod consts;
 mod datatype;
 mod p2p;
+mod collective;
+mod reduce;
+mod rma;
+mod atomic;
 mod c;
 mod ccontext;
 use ccontext::CContext;
@@ -27,7 +34,7 @@ pub(crate) unsafe fn with_context<F, R>(f: F) -> R
 where
     F: FnOnce(&mut mpicd::Context, &mut CContext) -> R,
 {
-    let ctx = CONTEXT.as_mut().unwrap();
+    let ctx = (*addr_of_mut!(CONTEXT)).as_mut().unwrap();
     f(&mut ctx.0, &mut ctx.1)
 }
 
@@ -39,7 +46,7 @@ pub unsafe extern "C" fn MPI_Init(_argc: *mut c_int, _argv: *mut *mut *mut c_cha
 
     info!("MPI_Init()");
     CONTEXT_START.call_once(|| {
-        let _ = CONTEXT.insert((mpicd::init().expect("failed to initailize the MPI context"), CContext::new()));
+        let _ = (*addr_of_mut!(CONTEXT)).insert((mpicd::init().expect("failed to initailize the MPI context"), CContext::new()));
     });
     consts::SUCCESS
 }
@@ -48,7 +55,7 @@ pub unsafe extern "C" fn MPI_Init(_argc: *mut c_int, _argv: *mut *mut *mut c_cha
 #[no_mangle]
 pub unsafe extern "C" fn MPI_Finalize() -> c::ReturnStatus {
     info!("MPI_Finalize()");
-    let _ = CONTEXT.take();
+    let _ = (*addr_of_mut!(CONTEXT)).take();
     consts::SUCCESS
 }
 
@@ -60,7 +67,7 @@ pub unsafe extern "C" fn MPI_Comm_size(comm: c::Comm, size: *mut c_int) -> c::Re
     // Assume MPI_COMM_WORLD.
     assert_eq!(comm, consts::COMM_WORLD);
 
-    if let Some((ctx, _)) = CONTEXT.as_ref() {
+    if let Some((ctx, _)) = (*addr_of!(CONTEXT)).as_ref() {
         *size = ctx.size();
         consts::SUCCESS
     } else {
@@ -76,7 +83,7 @@ pub unsafe extern "C" fn MPI_Comm_rank(comm: c::Comm, rank: *mut c_int) -> c::Re
     // Assume MPI_COMM_WORLD.
     assert_eq!(comm, consts::COMM_WORLD);
 
-    if let Some((ctx, _)) = CONTEXT.as_ref() {
+    if let Some((ctx, _)) = (*addr_of!(CONTEXT)).as_ref() {
         *rank = ctx.rank();
         consts::SUCCESS
     } else {
@@ -98,7 +105,7 @@ pub unsafe extern "C" fn MPI_Wtime() -> f64 {
 #[no_mangle]
 pub unsafe extern "C" fn MPI_Barrier(comm: c::Comm) -> c::ReturnStatus {
     assert_eq!(comm, consts::COMM_WORLD);
-    if let Some((ctx, _)) = CONTEXT.as_ref() {
+    if let Some((ctx, _)) = (*addr_of!(CONTEXT)).as_ref() {
         ctx.barrier();
         consts::SUCCESS
     } else {
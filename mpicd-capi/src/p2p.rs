@@ -1,12 +1,67 @@
 use mpicd::{
-    communicator::Communicator,
+    communicator::{Communicator, Error},
+    Status,
 };
 use std::ffi::{c_int, c_void};
 use crate::{
-    datatype::{CustomBuffer, ByteBuffer},
-    c, consts, with_context,
+    ccontext::CContext,
+    datatype::{self, AnyBuffer},
+    collective, reduce, c, consts, with_context,
 };
 
+/// Info of a receive request, used to fill in its status on completion.
+#[derive(Copy, Clone)]
+pub(crate) struct RecvInfo {
+    source: c_int,
+    tag: c_int,
+    datatype: c::Datatype,
+    count: c_int,
+    pub(crate) persistent: bool,
+}
+
+/// Return the status of a request without data, such as a send.
+fn empty_status() -> c::Status {
+    c::Status {
+        count: 0,
+        cancelled: 0,
+        source: consts::ANY_SOURCE,
+        tag: consts::UNDEFINED,
+        error: consts::SUCCESS,
+        datatype: consts::BYTE,
+    }
+}
+
+/// Fill in the status of a completed request. A receive only completes once
+/// its buffer was filled exactly, so the count is the count it was posted
+/// with, in elements of its datatype.
+unsafe fn set_status(cctx: &mut CContext, req: usize, result: Option<&Status>, status: *mut c::Status) {
+    let info = cctx.complete_receive(req);
+    if status.is_null() {
+        return;
+    }
+    match info {
+        Some(info) => recv_status(status, info.source, info.tag, info.datatype, info.count),
+        None => *status = empty_status(),
+    }
+    if !matches!(result, Some(Status::Complete)) {
+        (*status).error = consts::ERR_INTERNAL;
+    }
+}
+
+/// Fill in the status of a blocking receive that completed.
+unsafe fn recv_status(status: *mut c::Status, source: c_int, tag: c_int, datatype: c::Datatype, count: c_int) {
+    if !status.is_null() {
+        *status = c::Status {
+            count,
+            cancelled: 0,
+            source,
+            tag,
+            error: consts::SUCCESS,
+            datatype,
+        };
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn MPI_Send(
     buf: *const c_void,
@@ -16,13 +71,20 @@ pub unsafe extern "C" fn MPI_Send(
     tag: c_int,
     comm: c::Comm,
 ) -> c::ReturnStatus {
-    let req = isend(buf, count, datatype, dest, tag, comm);
+    let req = match isend(buf, count, datatype, dest, tag, comm, false) {
+        Ok(req) => req,
+        Err(status) => return status,
+    };
     with_context(move |ctx, _cctx| {
-        let _ = ctx.waitall(&[req.try_into().unwrap()]);
+        let Ok(req) = req.try_into() else {
+            return consts::ERR_REQUEST;
+        };
+        let _ = ctx.waitall(&[req]);
         consts::SUCCESS
     })
 }
 
+/// Start a send, using a synchronous send if sync is true.
 unsafe fn isend(
     buf: *const c_void,
     count: c_int,
@@ -30,33 +92,20 @@ unsafe fn isend(
     dest: c_int,
     tag: c_int,
     comm: c::Comm,
-) -> c::Request {
+    sync: bool,
+) -> Result<c::Request, c::ReturnStatus> {
     assert_eq!(comm, consts::COMM_WORLD);
 
     with_context(move |ctx, cctx| {
-        let req = if let Some(custom_datatype) = cctx.get_custom_datatype(datatype) {
-            let buffer = CustomBuffer {
-                ptr: buf as *mut _,
-                len: count as usize,
-                custom_datatype,
-            };
-            ctx
-                .isend(&buffer, dest, tag)
-                .expect("failed to send request")
+        let buffer = AnyBuffer::new(cctx, buf, count, datatype).ok_or(consts::ERR_TYPE)?;
+        let req = if sync {
+            ctx.issend(&buffer, dest, tag)
         } else {
-            // Assume MPI_BYTE
-            assert_eq!(datatype, consts::BYTE);
-
-            let buffer = ByteBuffer {
-                ptr: buf as *mut _,
-                size: count.try_into().unwrap(),
-            };
-            ctx
-                .isend(&buffer, dest, tag)
-                .expect("failed to send request")
-        };
+            ctx.isend(&buffer, dest, tag)
+        }
+        .map_err(collective::error_status)?;
 
-        req.try_into().unwrap()
+        req.try_into().map_err(|_| consts::ERR_REQUEST)
     })
 }
 
@@ -68,10 +117,18 @@ pub unsafe extern "C" fn MPI_Recv(
     source: c_int,
     tag: c_int,
     comm: c::Comm,
+    status: *mut c::Status,
 ) -> c::ReturnStatus {
-    let req = irecv(buf, count, datatype, source, tag, comm);
-    with_context(move |ctx, _cctx| {
-        let _ = ctx.waitall(&[req.try_into().unwrap()]);
+    let req = match irecv(buf, count, datatype, source, tag, comm) {
+        Ok(req) => req,
+        Err(status) => return status,
+    };
+    with_context(move |ctx, cctx| {
+        let Ok(req) = req.try_into() else {
+            return consts::ERR_REQUEST;
+        };
+        let result = ctx.waitall(&[req]).ok();
+        set_status(cctx, req, result.as_ref().and_then(|statuses| statuses.first()), status);
         consts::SUCCESS
     })
 }
@@ -84,33 +141,15 @@ unsafe fn irecv(
     source: c_int,
     tag: c_int,
     comm: c::Comm,
-) -> c::Request {
+) -> Result<c::Request, c::ReturnStatus> {
     assert_eq!(comm, consts::COMM_WORLD);
 
     with_context(move |ctx, cctx| {
-        let req = if let Some(custom_datatype) = cctx.get_custom_datatype(datatype) {
-            let mut buffer = CustomBuffer {
-                ptr: buf as *mut _,
-                len: count as usize,
-                custom_datatype,
-            };
-            ctx
-                .irecv(&mut buffer, source, tag)
-                .expect("failed to receive request")
-        } else {
-            // Assume MPI_BYTE
-            assert_eq!(datatype, consts::BYTE);
-
-            let mut buffer = ByteBuffer {
-                ptr: buf as *mut _,
-                size: count.try_into().unwrap(),
-            };
-            ctx
-                .irecv(&mut buffer, source, tag)
-                .expect("failed to receive request")
-        };
+        let mut buffer = AnyBuffer::new(cctx, buf, count, datatype).ok_or(consts::ERR_TYPE)?;
+        let req = ctx.irecv(&mut buffer, source, tag).map_err(collective::error_status)?;
+        cctx.add_receive(req, RecvInfo { source, tag, datatype, count, persistent: false });
 
-        req.try_into().expect("failed to convert to isize")
+        req.try_into().map_err(|_| consts::ERR_REQUEST)
     })
 }
 
@@ -125,8 +164,55 @@ pub unsafe extern "C" fn MPI_Isend(
     comm: c::Comm,
     request: *mut c::Request,
 ) -> c::ReturnStatus {
-    *request = isend(buf, count, datatype, dest, tag, comm);
-    consts::SUCCESS
+    match isend(buf, count, datatype, dest, tag, comm, false) {
+        Ok(req) => {
+            *request = req;
+            consts::SUCCESS
+        }
+        Err(status) => status,
+    }
+}
+
+/// Synchronous send, returning once the receiver has matched the message.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Ssend(
+    buf: *const c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    dest: c_int,
+    tag: c_int,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    let req = match isend(buf, count, datatype, dest, tag, comm, true) {
+        Ok(req) => req,
+        Err(status) => return status,
+    };
+    with_context(move |ctx, _cctx| {
+        let Ok(req) = req.try_into() else {
+            return consts::ERR_REQUEST;
+        };
+        let _ = ctx.waitall(&[req]);
+        consts::SUCCESS
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Issend(
+    buf: *const c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    dest: c_int,
+    tag: c_int,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    match isend(buf, count, datatype, dest, tag, comm, true) {
+        Ok(req) => {
+            *request = req;
+            consts::SUCCESS
+        }
+        Err(status) => status,
+    }
 }
 
 #[no_mangle]
@@ -139,8 +225,13 @@ pub unsafe extern "C" fn MPI_Irecv(
     comm: c::Comm,
     request: *mut c::Request,
 ) -> c::ReturnStatus {
-    *request = irecv(buf, count, datatype, source, tag, comm);
-    consts::SUCCESS
+    match irecv(buf, count, datatype, source, tag, comm) {
+        Ok(req) => {
+            *request = req;
+            consts::SUCCESS
+        }
+        Err(status) => status,
+    }
 }
 
 #[no_mangle]
@@ -154,12 +245,14 @@ pub unsafe extern "C" fn MPI_Probe(
 
     with_context(move |ctx, _cctx| {
         let source = if source == consts::ANY_SOURCE { None } else { Some(source) };
-        let probe_result = ctx.probe(source, tag).expect("missing matching message for probe");
-        (*status).count = probe_result.size as c_int;
-        (*status).cancelled = 0;
-        (*status).source = probe_result.source;
-        (*status).tag = tag;
-        (*status).error = 0;
+        let probe_result = match ctx.probe(source, tag) {
+            Ok(probe_result) => probe_result,
+            Err(err) => return collective::error_status(err),
+        };
+        let Ok(count) = probe_result.size.try_into() else {
+            return consts::ERR_COUNT;
+        };
+        recv_status(status, probe_result.source, tag, consts::BYTE, count);
         consts::SUCCESS
     })
 }
@@ -167,24 +260,45 @@ pub unsafe extern "C" fn MPI_Probe(
 #[no_mangle]
 pub unsafe extern "C" fn MPI_Get_count(
     status: *mut c::Status,
-    _datatype: c::Datatype,
+    datatype: c::Datatype,
     count: *mut c_int,
 ) -> c::ReturnStatus {
-    *count = (*status).count;
+    // The status count is in elements of the status datatype, which converts
+    // to other predefined types through their sizes. Custom datatypes don't
+    // have a fixed size, so they only have a count in their own elements.
+    let status_count = (*status).count as usize;
+    *count = if datatype == (*status).datatype {
+        (*status).count
+    } else {
+        match (datatype::predefined_size((*status).datatype), datatype::predefined_size(datatype)) {
+            (Some(status_size), Some(size)) if (status_count * status_size) % size == 0 => {
+                (status_count * status_size / size) as c_int
+            }
+            _ => consts::UNDEFINED,
+        }
+    };
     consts::SUCCESS
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn MPI_Wait(
     request: *mut c::Request,
-    _status: *mut c::Status,
+    status: *mut c::Status,
 ) -> c::ReturnStatus {
-    with_context(move |ctx, _cctx| {
-        let req: usize = (*request)
-            .try_into()
-            .expect("failed to cast request value to usize");
-        let _ = ctx.waitall(&[req]);
-        consts::SUCCESS
+    if *request == consts::REQUEST_NULL {
+        if !status.is_null() {
+            *status = empty_status();
+        }
+        return consts::SUCCESS;
+    }
+
+    with_context(move |ctx, cctx| {
+        let Ok(req) = (*request).try_into() else {
+            return consts::ERR_REQUEST;
+        };
+        let result = ctx.waitall(&[req]).ok();
+        set_status(cctx, req, result.as_ref().and_then(|statuses| statuses.first()), status);
+        reduce::complete_request(cctx, req)
     })
 }
 
@@ -192,15 +306,244 @@ pub unsafe extern "C" fn MPI_Wait(
 pub unsafe extern "C" fn MPI_Waitall(
     count: c_int,
     array_of_requests: *mut c::Request,
-    _array_of_statuses: *mut c::Status,
+    array_of_statuses: *mut c::Status,
 ) -> c::ReturnStatus {
+    with_context(move |ctx, cctx| {
+        let Ok(count) = count.try_into() else {
+            return consts::ERR_COUNT;
+        };
+        let mut reqs = vec![];
+        // Index of each request in the status array.
+        let mut indices = vec![];
+        for i in 0..count {
+            let req = *array_of_requests.add(i);
+            if req != consts::REQUEST_NULL {
+                let Ok(req) = req.try_into() else {
+                    return consts::ERR_REQUEST;
+                };
+                reqs.push(req);
+                indices.push(i);
+            } else if !array_of_statuses.is_null() {
+                *array_of_statuses.add(i) = empty_status();
+            }
+        }
+        let results = ctx.waitall(&reqs).ok();
+        for (j, (req, i)) in reqs.iter().zip(indices).enumerate() {
+            let status = if array_of_statuses.is_null() {
+                std::ptr::null_mut()
+            } else {
+                array_of_statuses.add(i)
+            };
+            set_status(cctx, *req, results.as_ref().and_then(|results| results.get(j)), status);
+        }
+        reqs.iter().fold(consts::SUCCESS, |status, req| {
+            let req_status = reduce::complete_request(cctx, *req);
+            if status == consts::SUCCESS { req_status } else { status }
+        })
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Send_init(
+    buf: *const c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    dest: c_int,
+    tag: c_int,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let Some(buffer) = AnyBuffer::new(cctx, buf, count, datatype) else {
+            return consts::ERR_TYPE;
+        };
+        let result = ctx.send_init(&buffer, dest, tag);
+        collective::start_status(result, request)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Recv_init(
+    buf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    source: c_int,
+    tag: c_int,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let Some(mut buffer) = AnyBuffer::new(cctx, buf, count, datatype) else {
+            return consts::ERR_TYPE;
+        };
+        let result = ctx.recv_init(&mut buffer, source, tag);
+        if let Ok(req) = result {
+            cctx.add_receive(req, RecvInfo { source, tag, datatype, count, persistent: true });
+        }
+        collective::start_status(result, request)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Start(request: *mut c::Request) -> c::ReturnStatus {
+    MPI_Startall(1, request)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Startall(count: c_int, array_of_requests: *mut c::Request) -> c::ReturnStatus {
     with_context(move |ctx, _cctx| {
-        let count: isize = count.try_into().unwrap();
+        let Ok(count) = count.try_into() else {
+            return consts::ERR_COUNT;
+        };
         let mut reqs = vec![];
         for i in 0..count {
-            reqs.push((*array_of_requests.offset(i)).try_into().unwrap());
+            let req = *array_of_requests.add(i);
+            if req != consts::REQUEST_NULL {
+                let Ok(req) = req.try_into() else {
+                    return consts::ERR_REQUEST;
+                };
+                reqs.push(req);
+            }
+        }
+        match ctx.startall(&reqs) {
+            Ok(_) => consts::SUCCESS,
+            Err(_) => consts::ERR_REQUEST,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Request_free(request: *mut c::Request) -> c::ReturnStatus {
+    with_context(move |ctx, cctx| {
+        let Ok(req) = (*request).try_into() else {
+            return consts::ERR_REQUEST;
+        };
+        match ctx.request_free(req) {
+            Ok(_) => {
+                cctx.free_receive(req);
+                *request = consts::REQUEST_NULL;
+                consts::SUCCESS
+            }
+            Err(_) => consts::ERR_REQUEST,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Buffer_attach(buffer: *mut c_void, size: c_int) -> c::ReturnStatus {
+    with_context(move |ctx, _cctx| {
+        let Ok(size) = size.try_into() else {
+            return consts::ERR_BUFFER;
+        };
+        match ctx.buffer_attach(buffer as *mut _, size) {
+            Ok(_) => consts::SUCCESS,
+            Err(_) => consts::ERR_BUFFER,
+        }
+    })
+}
+
+/// Detach the buffer, storing its address in buffer_addr (which is really a
+/// void **, as in the MPI standard).
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Buffer_detach(buffer_addr: *mut c_void, size: *mut c_int) -> c::ReturnStatus {
+    with_context(move |ctx, _cctx| {
+        match ctx.buffer_detach() {
+            Ok((ptr, len)) => {
+                *(buffer_addr as *mut *mut c_void) = ptr as *mut _;
+                // The length always fits, since it was attached with a c_int.
+                *size = len.try_into().unwrap_or(c_int::MAX);
+                consts::SUCCESS
+            }
+            Err(_) => consts::ERR_BUFFER,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Bsend(
+    buf: *const c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    dest: c_int,
+    tag: c_int,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let Some(buffer) = AnyBuffer::new(cctx, buf, count, datatype) else {
+            return consts::ERR_TYPE;
+        };
+        let result = ctx.bsend(&buffer, dest, tag);
+        match result {
+            Ok(_) => consts::SUCCESS,
+            Err(Error::BufferExhausted) => consts::ERR_BUFFER,
+            Err(_) => consts::ERR_INTERNAL,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Sendrecv(
+    sendbuf: *const c_void,
+    sendcount: c_int,
+    sendtype: c::Datatype,
+    dest: c_int,
+    sendtag: c_int,
+    recvbuf: *mut c_void,
+    recvcount: c_int,
+    recvtype: c::Datatype,
+    source: c_int,
+    recvtag: c_int,
+    comm: c::Comm,
+    status: *mut c::Status,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let sbuf = AnyBuffer::new(cctx, sendbuf, sendcount, sendtype);
+        let rbuf = AnyBuffer::new(cctx, recvbuf, recvcount, recvtype);
+        let (Some(sbuf), Some(mut rbuf)) = (sbuf, rbuf) else {
+            return consts::ERR_TYPE;
+        };
+        match ctx.sendrecv(&sbuf, dest, sendtag, &mut rbuf, source, recvtag) {
+            Ok(_) => {
+                recv_status(status, source, recvtag, recvtype, recvcount);
+                consts::SUCCESS
+            }
+            Err(_) => consts::ERR_INTERNAL,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Sendrecv_replace(
+    buf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    dest: c_int,
+    sendtag: c_int,
+    source: c_int,
+    recvtag: c_int,
+    comm: c::Comm,
+    status: *mut c::Status,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        let Some(mut buffer) = AnyBuffer::new(cctx, buf, count, datatype) else {
+            return consts::ERR_TYPE;
+        };
+        match ctx.sendrecv_replace(&mut buffer, dest, sendtag, source, recvtag) {
+            Ok(_) => {
+                recv_status(status, source, recvtag, datatype, count);
+                consts::SUCCESS
+            }
+            Err(_) => consts::ERR_INTERNAL,
         }
-        let _ = ctx.waitall(&reqs);
-        consts::SUCCESS
     })
 }
@@ -0,0 +1,546 @@
+//! Reduction functions and user-defined operations.
+//!
+//! Reductions are done on the packed representation of the buffers: for
+//! predefined types this is just the buffer itself, while for custom datatypes
+//! this is the packed part followed by the contents of each memory region. The
+//! result is then unpacked into the receive buffer with the datatype's unpack
+//! functions. Arithmetic operations on predefined types read the elements out
+//! of the packed bytes, since these aren't necessarily aligned. User functions
+//! are given elements instead, so for custom datatypes both operands are
+//! unpacked into temporary elements and the result is packed again.
+use mpicd::{
+    communicator::Communicator,
+    datatype::{self, DatatypeError, DatatypeResult},
+    op::{self, ReduceOp},
+};
+use std::cell::Cell;
+use std::ffi::{c_int, c_void};
+use std::rc::Rc;
+use crate::{
+    datatype::{self as cdatatype, with_predefined_type, AnyBuffer, CustomBuffer, CustomDatatype},
+    ccontext::CContext,
+    collective, c, consts, with_context,
+};
+
+/// User-defined operation created with MPI_Op_create().
+#[derive(Copy, Clone)]
+pub(crate) struct UserOp {
+    func: c::UserFunction,
+    _commute: bool,
+}
+
+/// Operation applied to packed byte buffers.
+pub(crate) enum PackedOp {
+    BitAnd,
+    BitOr,
+    BitXor,
+    /// Arithmetic operation on elements of a predefined type.
+    Typed {
+        op: c::Op,
+        datatype: c::Datatype,
+    },
+    User {
+        func: c::UserFunction,
+        datatype: c::Datatype,
+        len: c_int,
+        /// Element type to unpack custom datatypes into.
+        elements: Option<CustomElements>,
+    },
+}
+
+impl PackedOp {
+    /// Check that the operation didn't fail on any of the buffers.
+    pub(crate) fn check(&self) -> Result<(), c::ReturnStatus> {
+        match self {
+            PackedOp::User { elements: Some(elements), .. } if elements.failed.get() => Err(consts::ERR_INTERNAL),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Custom datatype elements that a user function is applied to.
+///
+/// This only works for datatypes with an extent and without memory regions,
+/// since the elements are unpacked into temporary storage.
+pub(crate) struct CustomElements {
+    custom_datatype: CustomDatatype,
+    datatypes: Rc<Vec<CustomDatatype>>,
+    count: usize,
+    extent: usize,
+    failed: Cell<bool>,
+}
+
+impl CustomElements {
+    /// Create the temporary storage for the elements, as u128 so that it's
+    /// aligned for any C type.
+    fn storage(&self) -> Vec<u128> {
+        vec![0; (self.count * self.extent).div_ceil(std::mem::size_of::<u128>())]
+    }
+
+    /// Get a buffer for the elements in the storage.
+    fn buffer(&self, storage: &mut [u128]) -> CustomBuffer {
+        CustomBuffer {
+            ptr: storage.as_mut_ptr() as *mut u8,
+            len: self.count,
+            custom_datatype: self.custom_datatype,
+            datatypes: Rc::clone(&self.datatypes),
+        }
+    }
+
+    /// Unpack both operands, apply the function to the elements and pack the
+    /// result back into inout.
+    unsafe fn apply(
+        &self,
+        func: unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_int, *mut c::Datatype),
+        mut datatype: c::Datatype,
+        input: &[u8],
+        inout: &mut [u8],
+    ) -> DatatypeResult<()> {
+        let mut input_storage = self.storage();
+        let mut inout_storage = self.storage();
+        let mut input_buffer = self.buffer(&mut input_storage);
+        let mut inout_buffer = self.buffer(&mut inout_storage);
+        datatype::unpack_from_slice(&mut input_buffer, input)?;
+        datatype::unpack_from_slice(&mut inout_buffer, inout)?;
+
+        let mut len = self.count as c_int;
+        func(input_storage.as_mut_ptr() as *mut _, inout_storage.as_mut_ptr() as *mut _, &mut len, &mut datatype);
+
+        let packed = datatype::pack_to_vec(&inout_buffer)?;
+        if packed.len() != inout.len() {
+            return Err(DatatypeError::PackError);
+        }
+        inout.copy_from_slice(&packed);
+        Ok(())
+    }
+}
+
+impl ReduceOp<[u8]> for PackedOp {
+    fn apply(&self, input: &[u8], inout: &mut [u8]) {
+        match self {
+            PackedOp::BitAnd => op::BitAnd.apply(input, inout),
+            PackedOp::BitOr => op::BitOr.apply(input, inout),
+            PackedOp::BitXor => op::BitXor.apply(input, inout),
+            PackedOp::Typed { op, datatype } => {
+                with_predefined_type!(*datatype, apply_typed(*op, input, inout), unreachable!())
+            }
+            PackedOp::User { func, datatype, len, elements } => unsafe {
+                let func = func.expect("missing user function");
+                match elements {
+                    Some(elements) => {
+                        if elements.apply(func, *datatype, input, inout).is_err() {
+                            elements.failed.set(true);
+                        }
+                    }
+                    None => {
+                        let mut len = *len;
+                        let mut datatype = *datatype;
+                        func(input.as_ptr() as *mut _, inout.as_mut_ptr() as *mut _, &mut len, &mut datatype);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Get the operation to use for the datatype, returning None if the
+/// combination is not supported.
+///
+/// User operations on custom datatypes need an extent and a buffer without
+/// memory regions, so that the elements can be unpacked.
+fn packed_op(cctx: &CContext, op: c::Op, datatype: c::Datatype, count: c_int, has_regions: bool) -> Option<PackedOp> {
+    if let Some(user_op) = cctx.get_user_op(op) {
+        let elements = match cctx.get_custom_datatype(datatype) {
+            Some(_) if has_regions => return None,
+            Some(custom_datatype) => Some(CustomElements {
+                custom_datatype,
+                datatypes: cctx.custom_datatypes(),
+                count: count.try_into().ok()?,
+                extent: custom_datatype.extent?,
+                failed: Cell::new(false),
+            }),
+            None => None,
+        };
+        return Some(PackedOp::User {
+            func: user_op.func,
+            datatype,
+            len: count,
+            elements,
+        });
+    }
+
+    // Only user operations are valid for custom datatypes.
+    cdatatype::predefined_size(datatype)?;
+    match op {
+        // Bitwise operations work the same on any integer bytes.
+        consts::BAND if !cdatatype::is_float(datatype) => Some(PackedOp::BitAnd),
+        consts::BOR if !cdatatype::is_float(datatype) => Some(PackedOp::BitOr),
+        consts::BXOR if !cdatatype::is_float(datatype) => Some(PackedOp::BitXor),
+        // MPI_BYTE is untyped, so it doesn't support arithmetic.
+        consts::SUM | consts::PROD | consts::MIN | consts::MAX if datatype != consts::BYTE => {
+            Some(PackedOp::Typed { op, datatype })
+        }
+        _ => None,
+    }
+}
+
+/// Read the elements out of packed bytes.
+fn read_elements<T: Copy>(bytes: &[u8]) -> Vec<T> {
+    bytes
+        .chunks_exact(std::mem::size_of::<T>())
+        .map(|chunk| unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const T) })
+        .collect()
+}
+
+/// Apply an arithmetic operation to packed elements of the type.
+fn apply_typed<T: Copy>(op: c::Op, input: &[u8], inout: &mut [u8])
+where
+    op::Sum: ReduceOp<[T]>,
+    op::Prod: ReduceOp<[T]>,
+    op::Min: ReduceOp<[T]>,
+    op::Max: ReduceOp<[T]>,
+{
+    let input: Vec<T> = read_elements(input);
+    let mut output: Vec<T> = read_elements(inout);
+    match op {
+        consts::SUM => op::Sum.apply(&input[..], &mut output[..]),
+        consts::PROD => op::Prod.apply(&input[..], &mut output[..]),
+        consts::MIN => op::Min.apply(&input[..], &mut output[..]),
+        consts::MAX => op::Max.apply(&input[..], &mut output[..]),
+        _ => unreachable!(),
+    }
+    for (chunk, value) in inout.chunks_exact_mut(std::mem::size_of::<T>()).zip(output) {
+        unsafe { std::ptr::write_unaligned(chunk.as_mut_ptr() as *mut T, value) };
+    }
+}
+
+/// Pack the buffer into a contiguous byte vector.
+pub(crate) unsafe fn pack_buffer(
+    cctx: &CContext,
+    buf: *const c_void,
+    count: c_int,
+    datatype: c::Datatype,
+) -> DatatypeResult<Vec<u8>> {
+    let buffer = AnyBuffer::new(cctx, buf, count, datatype).ok_or(DatatypeError::PackError)?;
+    datatype::pack_to_vec(&buffer)
+}
+
+/// Unpack the contiguous byte data into the buffer.
+unsafe fn unpack_buffer(
+    cctx: &CContext,
+    buf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    packed: &[u8],
+) -> DatatypeResult<()> {
+    let mut buffer = AnyBuffer::new(cctx, buf as *const _, count, datatype).ok_or(DatatypeError::UnpackError)?;
+    datatype::unpack_from_slice(&mut buffer, packed)
+}
+
+/// Kind of reduction to perform.
+#[derive(Copy, Clone)]
+enum Reduction {
+    Reduce(c_int),
+    Allreduce,
+    Scan,
+    Exscan,
+}
+
+/// Pack the send buffer and get the operation to use for a reduction.
+pub(crate) unsafe fn prepare(
+    cctx: &CContext,
+    sendbuf: *const c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+) -> Result<(Vec<u8>, PackedOp), c::ReturnStatus> {
+    if !cdatatype::is_supported(cctx, datatype) {
+        return Err(consts::ERR_TYPE);
+    }
+    let buffer = AnyBuffer::new(cctx, sendbuf, count, datatype).ok_or(consts::ERR_TYPE)?;
+    let (shape, sbuf) = datatype::pack_with_shape(&buffer).map_err(|_| consts::ERR_INTERNAL)?;
+    let op = packed_op(cctx, op, datatype, count, !shape.region_lens.is_empty()).ok_or(consts::ERR_OP)?;
+    Ok((sbuf, op))
+}
+
+unsafe fn reduction(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    kind: Reduction,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Reduction::Reduce(root) = kind {
+            if let Err(status) = collective::check_root(ctx, root) {
+                return status;
+            }
+        }
+        let (sbuf, op) = match prepare(cctx, sendbuf, count, datatype, op) {
+            Ok(prepared) => prepared,
+            Err(status) => return status,
+        };
+
+        let mut rbuf = vec![0; sbuf.len()];
+        let rank = ctx.rank();
+        let (result, has_result) = match kind {
+            Reduction::Reduce(root) => (ctx.reduce(&sbuf[..], &mut rbuf[..], &op, root), rank == root),
+            Reduction::Allreduce => (ctx.allreduce(&sbuf[..], &mut rbuf[..], &op), true),
+            Reduction::Scan => (ctx.scan(&sbuf[..], &mut rbuf[..], &op), true),
+            Reduction::Exscan => (ctx.exscan(&sbuf[..], &mut rbuf[..], &op), rank > 0),
+        };
+        if let Err(err) = result {
+            return collective::error_status(err);
+        }
+        if let Err(status) = op.check() {
+            return status;
+        }
+
+        if has_result && unpack_buffer(cctx, recvbuf, count, datatype, &rbuf).is_err() {
+            return consts::ERR_INTERNAL;
+        }
+        consts::SUCCESS
+    })
+}
+
+/// Reduction started by a non-blocking call.
+///
+/// This keeps the packed buffers and the operation alive until the request
+/// completes, when the result is unpacked into the receive buffer.
+pub(crate) struct PendingReduction {
+    sbuf: Vec<u8>,
+    rbuf: Vec<u8>,
+    op: PackedOp,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    has_result: bool,
+}
+
+/// Start a non-blocking reduction, returning the request.
+unsafe fn ireduction(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    kind: Reduction,
+    comm: c::Comm,
+) -> Result<usize, c::ReturnStatus> {
+    assert_eq!(comm, consts::COMM_WORLD);
+
+    with_context(move |ctx, cctx| {
+        if let Reduction::Reduce(root) = kind {
+            collective::check_root(ctx, root)?;
+        }
+        let (sbuf, op) = prepare(cctx, sendbuf, count, datatype, op)?;
+
+        let rank = ctx.rank();
+        // The boxed buffers and operation are borrowed by the request until
+        // it completes.
+        let mut pending = Box::new(PendingReduction {
+            rbuf: vec![0; sbuf.len()],
+            sbuf,
+            op,
+            recvbuf,
+            count,
+            datatype,
+            has_result: match kind {
+                Reduction::Reduce(root) => rank == root,
+                Reduction::Allreduce | Reduction::Scan => true,
+                Reduction::Exscan => rank > 0,
+            },
+        });
+        let data = &mut *pending;
+        let result = match kind {
+            Reduction::Reduce(root) => ctx.ireduce(&data.sbuf[..], &mut data.rbuf[..], &data.op, root),
+            Reduction::Allreduce => ctx.iallreduce(&data.sbuf[..], &mut data.rbuf[..], &data.op),
+            Reduction::Scan => ctx.iscan(&data.sbuf[..], &mut data.rbuf[..], &data.op),
+            Reduction::Exscan => ctx.iexscan(&data.sbuf[..], &mut data.rbuf[..], &data.op),
+        };
+        let req = result.map_err(collective::error_status)?;
+        cctx.add_pending_reduction(req, pending);
+        Ok(req)
+    })
+}
+
+/// Finish the reduction attached to a completed request, if there is one.
+pub(crate) unsafe fn complete_request(cctx: &mut CContext, req: usize) -> c::ReturnStatus {
+    match cctx.take_pending_reduction(req) {
+        Some(pending) => {
+            if let Err(status) = pending.op.check() {
+                status
+            } else if pending.has_result
+                && unpack_buffer(cctx, pending.recvbuf, pending.count, pending.datatype, &pending.rbuf).is_err()
+            {
+                consts::ERR_INTERNAL
+            } else {
+                consts::SUCCESS
+            }
+        }
+        None => consts::SUCCESS,
+    }
+}
+
+/// Create a user-defined operation.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Op_create(
+    user_fn: c::UserFunction,
+    commute: c_int,
+    op: *mut c::Op,
+) -> c::ReturnStatus {
+    if user_fn.is_none() {
+        return consts::ERR_OP;
+    }
+
+    with_context(move |_, cctx| {
+        *op = cctx.add_user_op(UserOp {
+            func: user_fn,
+            _commute: commute != 0,
+        });
+        consts::SUCCESS
+    })
+}
+
+/// Free a user-defined operation.
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Op_free(op: *mut c::Op) -> c::ReturnStatus {
+    with_context(move |_, cctx| {
+        if cctx.free_user_op(*op) {
+            *op = consts::OP_NULL;
+            consts::SUCCESS
+        } else {
+            consts::ERR_OP
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Reduce(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    root: c_int,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    reduction(sendbuf, recvbuf, count, datatype, op, Reduction::Reduce(root), comm)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Allreduce(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    reduction(sendbuf, recvbuf, count, datatype, op, Reduction::Allreduce, comm)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Scan(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    reduction(sendbuf, recvbuf, count, datatype, op, Reduction::Scan, comm)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Exscan(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    comm: c::Comm,
+) -> c::ReturnStatus {
+    reduction(sendbuf, recvbuf, count, datatype, op, Reduction::Exscan, comm)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Ireduce(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    root: c_int,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    match ireduction(sendbuf, recvbuf, count, datatype, op, Reduction::Reduce(root), comm) {
+        Ok(req) => {
+            *request = req.try_into().unwrap();
+            consts::SUCCESS
+        }
+        Err(status) => status,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Iallreduce(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    match ireduction(sendbuf, recvbuf, count, datatype, op, Reduction::Allreduce, comm) {
+        Ok(req) => {
+            *request = req.try_into().unwrap();
+            consts::SUCCESS
+        }
+        Err(status) => status,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Iscan(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    match ireduction(sendbuf, recvbuf, count, datatype, op, Reduction::Scan, comm) {
+        Ok(req) => {
+            *request = req.try_into().unwrap();
+            consts::SUCCESS
+        }
+        Err(status) => status,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Iexscan(
+    sendbuf: *const c_void,
+    recvbuf: *mut c_void,
+    count: c_int,
+    datatype: c::Datatype,
+    op: c::Op,
+    comm: c::Comm,
+    request: *mut c::Request,
+) -> c::ReturnStatus {
+    match ireduction(sendbuf, recvbuf, count, datatype, op, Reduction::Exscan, comm) {
+        Ok(req) => {
+            *request = req.try_into().unwrap();
+            consts::SUCCESS
+        }
+        Err(status) => status,
+    }
+}
@@ -0,0 +1,156 @@
+//! One-sided communication functions.
+use mpicd::communicator::{Communicator, Error};
+use std::ffi::{c_int, c_void};
+use crate::{
+    datatype::AnyBuffer,
+    c, consts, with_context,
+};
+
+/// RMA window with the displacement units of every process.
+pub(crate) struct CWindow {
+    pub(crate) window: mpicd::Window,
+    pub(crate) disp_units: Vec<c_int>,
+}
+
+impl CWindow {
+    /// Return the byte offset for a displacement on the target, or None if
+    /// the target or displacement is invalid.
+    pub(crate) fn offset(&self, target_rank: c_int, target_disp: c::Aint) -> Option<usize> {
+        let disp_unit = *self.disp_units.get(usize::try_from(target_rank).ok()?)?;
+        let disp: usize = target_disp.try_into().ok()?;
+        disp.checked_mul(disp_unit.try_into().ok()?)
+    }
+}
+
+/// Convert the result of an RMA operation into a C return status.
+pub(crate) fn rma_status(result: mpicd::communicator::Result<()>) -> c::ReturnStatus {
+    match result {
+        Ok(_) => consts::SUCCESS,
+        Err(Error::OutOfBounds) => consts::ERR_RMA_RANGE,
+        Err(_) => consts::ERR_INTERNAL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Win_create(
+    base: *mut c_void,
+    size: c::Aint,
+    disp_unit: c_int,
+    _info: c::Info,
+    comm: c::Comm,
+    win: *mut c::Win,
+) -> c::ReturnStatus {
+    assert_eq!(comm, consts::COMM_WORLD);
+    let Ok(size) = usize::try_from(size) else {
+        return consts::ERR_INTERNAL;
+    };
+
+    with_context(move |ctx, cctx| {
+        // Displacements are scaled by the disp_unit of the target.
+        let send = [disp_unit];
+        let mut disp_units = vec![[0]; ctx.size() as usize];
+        let mut rbufs: Vec<&mut [c_int]> = disp_units.iter_mut().map(|unit| &mut unit[..]).collect();
+        if ctx.allgather(&send[..], &mut rbufs).is_err() {
+            return consts::ERR_INTERNAL;
+        }
+        let disp_units = disp_units.iter().map(|unit| unit[0]).collect();
+
+        match ctx.win_create(base as *mut u8, size) {
+            Ok(window) => {
+                *win = cctx.add_window(CWindow { window, disp_units });
+                consts::SUCCESS
+            }
+            Err(_) => consts::ERR_INTERNAL,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Win_free(win: *mut c::Win) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        match cctx.take_window(*win) {
+            Some(cwin) => {
+                *win = consts::WIN_NULL;
+                rma_status(cwin.window.free())
+            }
+            None => consts::ERR_WIN,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Put(
+    origin_addr: *const c_void,
+    origin_count: c_int,
+    origin_datatype: c::Datatype,
+    target_rank: c_int,
+    target_disp: c::Aint,
+    _target_count: c_int,
+    target_datatype: c::Datatype,
+    win: c::Win,
+) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        // The target receives the packed representation of the origin.
+        if target_datatype != origin_datatype && target_datatype != consts::BYTE {
+            return consts::ERR_TYPE;
+        }
+        let Some(buffer) = AnyBuffer::new(cctx, origin_addr, origin_count, origin_datatype) else {
+            return consts::ERR_TYPE;
+        };
+        let Some(cwin) = cctx.get_window(win) else {
+            return consts::ERR_WIN;
+        };
+        let Some(offset) = cwin.offset(target_rank, target_disp) else {
+            return consts::ERR_RMA_RANGE;
+        };
+        rma_status(cwin.window.put(&buffer, target_rank, offset))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Get(
+    origin_addr: *mut c_void,
+    origin_count: c_int,
+    origin_datatype: c::Datatype,
+    target_rank: c_int,
+    target_disp: c::Aint,
+    _target_count: c_int,
+    target_datatype: c::Datatype,
+    win: c::Win,
+) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        if target_datatype != origin_datatype && target_datatype != consts::BYTE {
+            return consts::ERR_TYPE;
+        }
+        let Some(mut buffer) = AnyBuffer::new(cctx, origin_addr, origin_count, origin_datatype) else {
+            return consts::ERR_TYPE;
+        };
+        let Some(cwin) = cctx.get_window(win) else {
+            return consts::ERR_WIN;
+        };
+        let Some(offset) = cwin.offset(target_rank, target_disp) else {
+            return consts::ERR_RMA_RANGE;
+        };
+        rma_status(cwin.window.get(&mut buffer, target_rank, offset))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Win_fence(_assert: c_int, win: c::Win) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        match cctx.get_window(win) {
+            Some(cwin) => rma_status(cwin.window.fence()),
+            None => consts::ERR_WIN,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn MPI_Win_flush(rank: c_int, win: c::Win) -> c::ReturnStatus {
+    with_context(move |_ctx, cctx| {
+        match cctx.get_window(win) {
+            Some(cwin) => rma_status(cwin.window.flush(rank)),
+            None => consts::ERR_WIN,
+        }
+    })
+}
@@ -0,0 +1,235 @@
+//! Derive macro for mpicd message buffers.
+//!
+//! `#[derive(MessageBuffer)]` on a struct implements MessageCount,
+//! MessagePointer, MessageBuffer and Packable, so that the struct can be sent
+//! directly or used as a field or element of another derived struct. Each
+//! field is handled according to its strategy:
+//!
+//! * `#[mpicd(packed)]`: packed into the packed part of the message through
+//!   Packable (primitives are stored in big-endian order). This is the
+//!   default for all fields other than `Vec`s.
+//! * `#[mpicd(region)]`: sent directly as a memory region through
+//!   RegionField. This is the default for `Vec` fields, which must then hold
+//!   `Copy` elements.
+//! * `#[mpicd(skip)]`: not sent at all; the receiver's value is left as is.
+//!
+//! The packed part holds the packed fields in declaration order and the memory
+//! regions follow in the same order, including those of nested packed fields.
+//! The count is 1, except for wrappers of a single `#[mpicd(packed)]` `Vec`
+//! field, which forward the count of elements in the `Vec`.
+//! The type's signature is a hash of its name and of the name, strategy and
+//! type of each field (or of its full type name for generic structs, which is
+//! only consistent between processes running the same build).
+//!
+//! ```ignore
+//! #[derive(MessageBuffer)]
+//! struct StructVec {
+//!     a: i32,
+//!     d: f64,
+//!     #[mpicd(region)]
+//!     data: [i32; 2048],
+//! }
+//!
+//! #[derive(MessageBuffer)]
+//! struct StructVecArray(#[mpicd(packed)] Vec<StructVec>);
+//! ```
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Field, Fields, Index, Type};
+
+/// How a field is transferred.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Strategy {
+    /// Packed into the packed part of the message.
+    Packed,
+
+    /// Sent as a memory region.
+    Region,
+
+    /// Not sent.
+    Skip,
+}
+
+#[proc_macro_derive(MessageBuffer, attributes(mpicd))]
+pub fn derive_message_buffer(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(input.span(), "MessageBuffer can only be derived for structs"));
+    };
+    let fields: Vec<&Field> = match &data.fields {
+        Fields::Named(fields) => fields.named.iter().collect(),
+        Fields::Unnamed(fields) => fields.unnamed.iter().collect(),
+        Fields::Unit => vec![],
+    };
+
+    let mut packed_size = vec![];
+    let mut pack = vec![];
+    let mut unpack = vec![];
+    let mut regions = vec![];
+    let mut regions_mut = vec![];
+    let mut signature = vec![];
+    let mut count = quote! { 1 };
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => quote! { #ident },
+            None => {
+                let index = Index::from(i);
+                quote! { #index }
+            }
+        };
+        let strategy = strategy(field)?;
+        let ty = &field.ty;
+        signature.push(format!("{}:{:?}:{}", member, strategy, quote! { #ty }));
+        match strategy {
+            Strategy::Packed => {
+                if fields.len() == 1 && is_vec(ty) {
+                    count = quote! { self.#member.len() };
+                }
+                packed_size.push(quote! {
+                    + ::mpicd::datatype::Packable::packed_size(&self.#member)
+                });
+                pack.push(quote! {
+                    let size = ::mpicd::datatype::Packable::packed_size(&self.#member);
+                    ::mpicd::datatype::Packable::pack_into(&self.#member, &mut dst[pos..pos + size]);
+                    pos += size;
+                });
+                unpack.push(quote! {
+                    let size = ::mpicd::datatype::Packable::packed_size(&self.#member);
+                    ::mpicd::datatype::Packable::unpack_from(&mut self.#member, &src[pos..pos + size]);
+                    pos += size;
+                });
+                regions.push(quote! {
+                    ::mpicd::datatype::Packable::memory_regions(&self.#member, regions);
+                });
+                regions_mut.push(quote! {
+                    ::mpicd::datatype::Packable::memory_regions_mut(&mut self.#member, regions);
+                });
+            }
+            Strategy::Region => {
+                regions.push(quote! {
+                    regions.push(::mpicd::datatype::RegionField::region(&self.#member));
+                });
+                regions_mut.push(quote! {
+                    regions.push(::mpicd::datatype::RegionField::region_mut(&mut self.#member));
+                });
+            }
+            Strategy::Skip => (),
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let signature = if input.generics.params.is_empty() {
+        let signature = format!("{}{{{}}}", name, signature.join(","));
+        quote! { ::mpicd::datatype::signature_hash(#signature) }
+    } else {
+        quote! { ::mpicd::datatype::signature_of::<Self>() }
+    };
+    Ok(quote! {
+        impl #impl_generics ::mpicd::datatype::Packable for #name #ty_generics #where_clause {
+            fn packed_size(&self) -> usize {
+                0 #(#packed_size)*
+            }
+
+            #[allow(unused_assignments, unused_mut, unused_variables)]
+            fn pack_into(&self, dst: &mut [u8]) {
+                let mut pos = 0;
+                #(#pack)*
+            }
+
+            #[allow(unused_assignments, unused_mut, unused_variables)]
+            fn unpack_from(&mut self, src: &[u8]) {
+                let mut pos = 0;
+                #(#unpack)*
+            }
+
+            #[allow(unused_variables)]
+            fn memory_regions(&self, regions: &mut Vec<(*const u8, usize)>) {
+                #(#regions)*
+            }
+
+            #[allow(unused_variables)]
+            fn memory_regions_mut(&mut self, regions: &mut Vec<(*mut u8, usize)>) {
+                #(#regions_mut)*
+            }
+        }
+
+        impl #impl_generics ::mpicd::datatype::MessageCount for #name #ty_generics #where_clause {
+            fn count(&self) -> usize {
+                #count
+            }
+        }
+
+        impl #impl_generics ::mpicd::datatype::MessagePointer for #name #ty_generics #where_clause {
+            fn ptr(&self) -> *const u8 {
+                self as *const Self as *const u8
+            }
+
+            fn ptr_mut(&mut self) -> *mut u8 {
+                self as *mut Self as *mut u8
+            }
+        }
+
+        impl #impl_generics ::mpicd::datatype::MessageBuffer for #name #ty_generics #where_clause {
+            unsafe fn pack(
+                &self,
+            ) -> Option<::mpicd::datatype::DatatypeResult<Box<dyn ::mpicd::datatype::PackMethod>>> {
+                Some(Ok(Box::new(::mpicd::datatype::PackableState::new(self as *const Self as *mut Self))))
+            }
+
+            unsafe fn unpack(
+                &mut self,
+            ) -> Option<::mpicd::datatype::DatatypeResult<Box<dyn ::mpicd::datatype::UnpackMethod>>> {
+                Some(Ok(Box::new(::mpicd::datatype::PackableState::new(self as *mut Self))))
+            }
+
+            fn signature(&self) -> Option<u64> {
+                Some(#signature)
+            }
+        }
+    })
+}
+
+/// Get the strategy of the field from its attributes, defaulting to a memory
+/// region for Vecs and packing otherwise.
+fn strategy(field: &Field) -> syn::Result<Strategy> {
+    let mut strategy = None;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("mpicd")) {
+        attr.parse_nested_meta(|meta| {
+            let value = if meta.path.is_ident("packed") {
+                Strategy::Packed
+            } else if meta.path.is_ident("region") {
+                Strategy::Region
+            } else if meta.path.is_ident("skip") {
+                Strategy::Skip
+            } else {
+                return Err(meta.error("expected `packed`, `region` or `skip`"));
+            };
+            if strategy.replace(value).is_some() {
+                return Err(meta.error("only one strategy can be given per field"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(strategy.unwrap_or_else(|| if is_vec(&field.ty) { Strategy::Region } else { Strategy::Packed }))
+}
+
+/// Check if the type is a Vec.
+fn is_vec(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident == "Vec")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
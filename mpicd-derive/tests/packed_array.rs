@@ -0,0 +1,92 @@
+//! Check that derived wrappers of a packed Vec match the hand-written
+//! benchmark impls they replaced, in count and wire layout.
+use mpicd::datatype::{self, MessageBuffer, MessageCount};
+
+#[derive(MessageBuffer)]
+struct Simple {
+    a: i32,
+    b: i32,
+    c: i32,
+    d: f64,
+}
+
+#[derive(MessageBuffer)]
+struct SimpleArray(#[mpicd(packed)] Vec<Simple>);
+
+#[derive(MessageBuffer)]
+struct WithRegion {
+    a: i32,
+    d: f64,
+    #[mpicd(region)]
+    data: [i32; 4],
+}
+
+#[derive(MessageBuffer)]
+struct WithRegionArray(#[mpicd(packed)] Vec<WithRegion>);
+
+fn simple(i: i32) -> Simple {
+    Simple { a: i, b: -2332 * i, c: 2293, d: 1.9 * i as f64 }
+}
+
+fn with_region(i: i32) -> WithRegion {
+    WithRegion { a: i, d: -0.5 * i as f64, data: [i, i + 1, i + 2, i + 3] }
+}
+
+/// Layout of the old hand-written impl: the big-endian fields of each
+/// element in order.
+fn simple_layout(elems: &[Simple]) -> Vec<u8> {
+    let mut packed = vec![];
+    for elem in elems {
+        packed.extend_from_slice(&elem.a.to_be_bytes());
+        packed.extend_from_slice(&elem.b.to_be_bytes());
+        packed.extend_from_slice(&elem.c.to_be_bytes());
+        packed.extend_from_slice(&elem.d.to_be_bytes());
+    }
+    packed
+}
+
+/// Layout of the old hand-written impl: the big-endian fields of each
+/// element, followed by the array of each element as a memory region.
+fn with_region_layout(elems: &[WithRegion]) -> Vec<u8> {
+    let mut packed = vec![];
+    for elem in elems {
+        packed.extend_from_slice(&elem.a.to_be_bytes());
+        packed.extend_from_slice(&elem.d.to_be_bytes());
+    }
+    for elem in elems {
+        for value in elem.data {
+            packed.extend_from_slice(&value.to_ne_bytes());
+        }
+    }
+    packed
+}
+
+#[test]
+fn count_is_element_count() {
+    assert_eq!(SimpleArray((0..3).map(simple).collect()).count(), 3);
+    assert_eq!(SimpleArray(vec![]).count(), 0);
+    assert_eq!(WithRegionArray((0..5).map(with_region).collect()).count(), 5);
+    assert_eq!(simple(1).count(), 1);
+}
+
+#[test]
+fn simple_layout_matches() {
+    let array = SimpleArray((0..3).map(simple).collect());
+    let packed = unsafe { datatype::pack_to_vec(&array) }.unwrap();
+    assert_eq!(packed, simple_layout(&array.0));
+
+    let mut received = SimpleArray((0..3).map(|_| simple(0)).collect());
+    unsafe { datatype::unpack_from_slice(&mut received, &packed) }.unwrap();
+    assert_eq!(simple_layout(&received.0), packed);
+}
+
+#[test]
+fn region_layout_matches() {
+    let array = WithRegionArray((1..4).map(with_region).collect());
+    let packed = unsafe { datatype::pack_to_vec(&array) }.unwrap();
+    assert_eq!(packed, with_region_layout(&array.0));
+
+    let mut received = WithRegionArray((0..3).map(|_| with_region(0)).collect());
+    unsafe { datatype::unpack_from_slice(&mut received, &packed) }.unwrap();
+    assert_eq!(with_region_layout(&received.0), packed);
+}
@@ -1,8 +1,8 @@
 use clap::Parser;
 use mpi::traits::*;
-use mpicd_rust_benchmarks::{
-    RsmpiArgs, RsmpiDatatype, RsmpiDatatypeBuffer, BandwidthOptions, BandwidthBenchmark,
-    StructVecArray, StructSimpleArray, StructSimpleNoGapArray,
+use mpicd_rust_benchmarks::{BandwidthOptions, BandwidthBenchmark};
+use mpicd_rsmpi_benchmarks::{
+    RsmpiArgs, RsmpiDatatype, RsmpiDatatypeBuffer, StructVec, StructSimple, StructSimpleNoGap,
 };
 
 fn bandwidth<C, B>(rank: i32, comm: &C, buffers: &mut Vec<B>)
@@ -50,15 +50,15 @@ impl<C: Communicator> BandwidthBenchmark for RsmpiBenchmark<C> {
                 let _ = buffers.insert(buf);
             }
             RsmpiDatatypeBuffer::StructVec(ref mut buffers) => {
-                let buf = (0..window_size).map(|_| StructVecArray::new(size).0).collect();
+                let buf = (0..window_size).map(|_| StructVec::array(size)).collect();
                 let _ = buffers.insert(buf);
             }
             RsmpiDatatypeBuffer::StructSimple(ref mut buffers) => {
-                let buf = (0..window_size).map(|_| StructSimpleArray::new(size).0).collect();
+                let buf = (0..window_size).map(|_| StructSimple::array(size)).collect();
                 let _ = buffers.insert(buf);
             }
             RsmpiDatatypeBuffer::StructSimpleNoGap(ref mut buffers) => {
-                let buf = (0..window_size).map(|_| StructSimpleNoGapArray::new(size).0).collect();
+                let buf = (0..window_size).map(|_| StructSimpleNoGap::array(size)).collect();
                 let _ = buffers.insert(buf);
             }
         }
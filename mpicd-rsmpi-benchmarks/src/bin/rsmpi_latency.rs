@@ -1,7 +1,7 @@
 use clap::Parser;
-use mpicd_rust_benchmarks::{
-    RsmpiArgs, RsmpiDatatype, RsmpiLatencyBenchmarkBuffer, LatencyBenchmark,
-    LatencyOptions, StructVecArray, StructSimpleArray, StructSimpleNoGapArray,
+use mpicd_rust_benchmarks::{LatencyBenchmark, LatencyOptions};
+use mpicd_rsmpi_benchmarks::{
+    RsmpiArgs, RsmpiDatatype, RsmpiLatencyBenchmarkBuffer, StructVec, StructSimple, StructSimpleNoGap,
 };
 use mpi::traits::*;
 use mpi::topology::Process;
@@ -34,13 +34,13 @@ impl<C: Communicator> LatencyBenchmark for Benchmark<C> {
                 let _ = buffers.insert((vec![0; size], vec![0; size]));
             }
             RsmpiLatencyBenchmarkBuffer::StructVec(ref mut buffers) => {
-                let _ = buffers.insert((StructVecArray::new(size).0, StructVecArray::new(size).0));
+                let _ = buffers.insert((StructVec::array(size), StructVec::array(size)));
             }
             RsmpiLatencyBenchmarkBuffer::StructSimple(ref mut buffers) => {
-                let _ = buffers.insert((StructSimpleArray::new(size).0, StructSimpleArray::new(size).0));
+                let _ = buffers.insert((StructSimple::array(size), StructSimple::array(size)));
             }
             RsmpiLatencyBenchmarkBuffer::StructSimpleNoGap(ref mut buffers) => {
-                let _ = buffers.insert((StructSimpleNoGapArray::new(size).0, StructSimpleNoGapArray::new(size).0));
+                let _ = buffers.insert((StructSimpleNoGap::array(size), StructSimpleNoGap::array(size)));
             }
         }
     }
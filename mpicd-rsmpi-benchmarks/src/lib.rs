@@ -0,0 +1,151 @@
+//! rsmpi versions of the Rust benchmarks, for comparison.
+//!
+//! This crate is kept out of the workspace, since rsmpi needs an MPI
+//! installation to build. The datatypes mirror those of mpicd-rust-benchmarks,
+//! but implement Equivalence instead of MessageBuffer.
+use clap::{Parser, ValueEnum};
+use mpi::traits::*;
+use mpicd_rust_benchmarks::{
+    STRUCT_SIMPLE_NO_GAP_PACKED_SIZE, STRUCT_SIMPLE_PACKED_SIZE, STRUCT_VEC_DATA_COUNT,
+    STRUCT_VEC_PACKED_SIZE_TOTAL,
+};
+
+/// RSMPI specific args.
+#[derive(Parser)]
+pub struct RsmpiArgs {
+    /// Path for benchmark options file.
+    #[arg(short, long)]
+    pub options_path: String,
+
+    /// Datatype to use.
+    #[arg(short, long)]
+    pub datatype: RsmpiDatatype,
+}
+
+/// Datatype to use for the rsmpi benchmarks.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum RsmpiDatatype {
+    /// Plain bytes datatype.
+    Bytes,
+
+    /// Struct vec datatype with Equivalence implementation.
+    StructVec,
+
+    /// Use the simple struct type.
+    StructSimple,
+
+    /// Use the simple struct type without a gap.
+    StructSimpleNoGap,
+}
+
+/// Datatype buffer for rsmpi benchmarks.
+pub enum RsmpiDatatypeBuffer {
+    /// Bytes type.
+    Bytes(Option<Vec<Vec<u8>>>),
+
+    /// StructVec type.
+    StructVec(Option<Vec<Vec<StructVec>>>),
+
+    /// StructSimple type.
+    StructSimple(Option<Vec<Vec<StructSimple>>>),
+
+    /// StructSimpleNoGap type.
+    StructSimpleNoGap(Option<Vec<Vec<StructSimpleNoGap>>>),
+}
+
+/// Latency benchmark buffer holder.
+pub enum RsmpiLatencyBenchmarkBuffer {
+    /// Plain byte buffers.
+    Bytes(Option<(Vec<u8>, Vec<u8>)>),
+
+    /// StructVec type.
+    StructVec(Option<(Vec<StructVec>, Vec<StructVec>)>),
+
+    /// StructSimple type.
+    StructSimple(Option<(Vec<StructSimple>, Vec<StructSimple>)>),
+
+    /// StructSimple type.
+    StructSimpleNoGap(Option<(Vec<StructSimpleNoGap>, Vec<StructSimpleNoGap>)>),
+}
+
+/// Create the number of elements for a buffer of the given packed size.
+fn array<T>(size: usize, elem_size: usize, new: fn() -> T) -> Vec<T> {
+    assert_eq!(size % elem_size, 0);
+    assert!(size >= elem_size);
+    (0..size / elem_size).map(|_| new()).collect()
+}
+
+#[derive(Equivalence)]
+#[repr(C)]
+pub struct StructVec {
+    a: i32,
+    b: i32,
+    c: i32,
+    d: f64,
+    data: [i32; STRUCT_VEC_DATA_COUNT],
+}
+
+impl StructVec {
+    pub fn new() -> StructVec {
+        StructVec {
+            a: 34,
+            b: -2332,
+            c: 2293,
+            d: 1.9,
+            data: [123; STRUCT_VEC_DATA_COUNT],
+        }
+    }
+
+    /// Create an array with the same size as StructVecArray::new(size).
+    pub fn array(size: usize) -> Vec<StructVec> {
+        array(size, STRUCT_VEC_PACKED_SIZE_TOTAL, StructVec::new)
+    }
+}
+
+#[derive(Equivalence)]
+#[repr(C)]
+pub struct StructSimple {
+    a: i32,
+    b: i32,
+    c: i32,
+    d: f64,
+}
+
+impl StructSimple {
+    pub fn new() -> StructSimple {
+        StructSimple {
+            a: 34,
+            b: -2332,
+            c: 2293,
+            d: 1.9,
+        }
+    }
+
+    /// Create an array with the same size as StructSimpleArray::new(size).
+    pub fn array(size: usize) -> Vec<StructSimple> {
+        array(size, STRUCT_SIMPLE_PACKED_SIZE, StructSimple::new)
+    }
+}
+
+#[derive(Equivalence)]
+#[repr(C)]
+pub struct StructSimpleNoGap {
+    a: i32,
+    b: i32,
+    c: f64,
+}
+
+impl StructSimpleNoGap {
+    pub fn new() -> StructSimpleNoGap {
+        StructSimpleNoGap {
+            a: 34,
+            b: -2332,
+            c: 1.9,
+        }
+    }
+
+    /// Create an array with the same size as StructSimpleNoGapArray::new(size).
+    pub fn array(size: usize) -> Vec<StructSimpleNoGap> {
+        array(size, STRUCT_SIMPLE_NO_GAP_PACKED_SIZE, StructSimpleNoGap::new)
+    }
+}
@@ -1,5 +1,4 @@
 //! Datatypes used for benchmarking.
-use mpi::traits::*;
 use mpicd::datatype::{DatatypeResult, MessageCount, MessagePointer, MessageBuffer, PackedSize, PackMethod, UnpackMethod};
 
 /// Benchmark datatype buffer holder.
@@ -17,21 +16,6 @@ pub enum BenchmarkDatatypeBuffer {
     StructSimpleNoGap(Option<Vec<StructSimpleNoGapArray>>),
 }
 
-/// Datatype buffer for rsmpi benchmarks.
-pub enum RsmpiDatatypeBuffer {
-    /// Bytes type.
-    Bytes(Option<Vec<Vec<u8>>>),
-
-    /// StructVec type.
-    StructVec(Option<Vec<Vec<StructVec>>>),
-
-    /// StructSimple type.
-    StructSimple(Option<Vec<Vec<StructSimple>>>),
-
-    /// StructSimpleNoGap type.
-    StructSimpleNoGap(Option<Vec<Vec<StructSimpleNoGap>>>),
-}
-
 /// Latency benchmark buffer holder.
 pub enum LatencyBenchmarkBuffer {
     /// ComplexVec type.
@@ -47,21 +31,6 @@ pub enum LatencyBenchmarkBuffer {
     StructSimpleNoGap(Option<(StructSimpleNoGapArray, StructSimpleNoGapArray)>),
 }
 
-/// Latency benchmark buffer holder.
-pub enum RsmpiLatencyBenchmarkBuffer {
-    /// Plain byte buffers.
-    Bytes(Option<(Vec<u8>, Vec<u8>)>),
-
-    /// StructVec type.
-    StructVec(Option<(Vec<StructVec>, Vec<StructVec>)>),
-
-    /// StructSimple type.
-    StructSimple(Option<(Vec<StructSimple>, Vec<StructSimple>)>),
-
-    /// StructSimple type.
-    StructSimpleNoGap(Option<(Vec<StructSimpleNoGap>, Vec<StructSimpleNoGap>)>),
-}
-
 /// Trait for implementing manual unpack.
 pub trait ManualPack {
     /// Return the size of a packed buffer.
@@ -123,7 +92,7 @@ impl ComplexVec {
         self.0
             .iter()
             .flatten()
-            .map(|i| *i)
+            .copied()
             .collect()
     }
 
@@ -151,8 +120,7 @@ impl ManualPack for ComplexVec {
         self.0
             .iter()
             .flatten()
-            .map(|i| i32::to_be_bytes(*i))
-            .flatten()
+            .flat_map(|i| i32::to_be_bytes(*i))
             .collect()
     }
 
@@ -228,7 +196,7 @@ impl PackMethod for State {
         Ok(
             (0..self.count)
                 .map(|i| {
-                    let v = outer_vec.offset(i as isize);
+                    let v = outer_vec.add(i);
                     ((*v).as_ptr() as *const _, (*v).len() * std::mem::size_of::<i32>())
                 })
                 .collect()
@@ -246,7 +214,7 @@ impl UnpackMethod for State {
         Ok(
             (0..self.count)
                 .map(|i| {
-                    let v = outer_vec.offset(i as isize);
+                    let v = outer_vec.add(i);
                     ((*v).as_mut_ptr() as *mut _, (*v).len() * std::mem::size_of::<i32>())
                 })
                 .collect()
@@ -257,21 +225,19 @@ impl UnpackMethod for State {
 /// Number of elements in data array.
 pub const STRUCT_VEC_DATA_COUNT: usize = 2048;
 
-/// Packed size for custom API.
-pub const STRUCT_VEC_PACKED_SIZE: usize = 3 * std::mem::size_of::<i32>() + std::mem::size_of::<f64>();
-
 /// Packed size for manual packing.
 pub const STRUCT_VEC_PACKED_SIZE_TOTAL: usize = 3 * std::mem::size_of::<i32>()
                                                  + std::mem::size_of::<f64>()
                                                  + STRUCT_VEC_DATA_COUNT * std::mem::size_of::<i32>();
 
-#[derive(Equivalence)]
+#[derive(MessageBuffer)]
 #[repr(C)]
 pub struct StructVec {
     a: i32,
     b: i32,
     c: i32,
     d: f64,
+    #[mpicd(region)]
     data: [i32; STRUCT_VEC_DATA_COUNT],
 }
 
@@ -287,7 +253,8 @@ impl StructVec {
     }
 }
 
-pub struct StructVecArray(pub Vec<StructVec>);
+#[derive(MessageBuffer)]
+pub struct StructVecArray(#[mpicd(packed)] pub Vec<StructVec>);
 
 impl StructVecArray {
     pub fn new(size: usize) -> StructVecArray {
@@ -309,36 +276,6 @@ impl StructVecArray {
     }
 }
 
-impl MessageCount for StructVecArray {
-    fn count(&self) -> usize {
-        self.0.len()
-    }
-}
-
-impl MessagePointer for StructVecArray {
-    fn ptr(&self) -> *const u8 {
-        self.0.as_ptr() as *const _
-    }
-
-    fn ptr_mut(&mut self) -> *mut u8 {
-        self.0.as_mut_ptr() as *mut _
-    }
-}
-
-impl MessageBuffer for StructVecArray {
-    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
-        Some(Ok(Box::new(StructVecState {
-            data: (&self.0 as *const Vec<StructVec>) as *mut _,
-        })))
-    }
-
-    unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
-        Some(Ok(Box::new(StructVecState {
-            data: &mut self.0,
-        })))
-    }
-}
-
 impl ManualPack for StructVecArray {
     fn packed_size(&self) -> usize {
         self.0.len() * STRUCT_VEC_PACKED_SIZE_TOTAL
@@ -360,7 +297,7 @@ impl ManualPack for StructVecArray {
             unsafe {
                 std::ptr::copy_nonoverlapping(
                     elem.data.as_ptr() as *const u8,
-                    data.as_mut_ptr().offset(pos as isize),
+                    data.as_mut_ptr().add(pos),
                     array_len,
                 );
             }
@@ -385,7 +322,7 @@ impl ManualPack for StructVecArray {
             pos += 8;
             unsafe {
                 std::ptr::copy_nonoverlapping(
-                    data.as_ptr().offset(pos as isize),
+                    data.as_ptr().add(pos),
                     elem.data.as_mut_ptr() as *mut u8,
                     array_len,
                 );
@@ -395,90 +332,10 @@ impl ManualPack for StructVecArray {
     }
 }
 
-pub struct StructVecState {
-    data: *mut Vec<StructVec>,
-}
-
-impl PackedSize for StructVecState {
-    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
-        Ok((*self.data).len() * STRUCT_VEC_PACKED_SIZE)
-    }
-}
-
-impl PackMethod for StructVecState {
-    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
-        let mut i = offset / STRUCT_VEC_PACKED_SIZE;
-        let mut pos: isize = 0;
-        while i < (*self.data).len() && (pos + STRUCT_VEC_PACKED_SIZE as isize) <= dst_size as isize {
-            let elem = &(*self.data)[i];
-            std::ptr::copy_nonoverlapping(elem.a.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.b.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.c.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.d.to_be_bytes().as_ptr(), dst.offset(pos), 8);
-            pos += 8;
-            i += 1;
-        }
-        Ok(pos.try_into().expect("failed to convert pos from isize to usize"))
-    }
-
-    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
-        Ok(
-            (*self.data)
-                .iter()
-                .map(|elem| {
-                    let len = elem.data.len() * std::mem::size_of::<i32>();
-                    (elem.data.as_ptr() as *const u8, len)
-                })
-                .collect()
-        )
-    }
-}
-
-impl UnpackMethod for StructVecState {
-    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
-        // Assume we get a full non-fragmented buffer for now.
-        assert_eq!(offset, 0);
-        let used = (*self.data).len() * STRUCT_VEC_PACKED_SIZE;
-        let mut tmp = [0; 8];
-        assert_eq!(src_size, used);
-        let mut pos = 0;
-        for elem in &mut (*self.data) {
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.a = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.b = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.c = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 8);
-            pos += 8;
-            elem.d = f64::from_be_bytes(tmp[..8].try_into().unwrap());
-        }
-        Ok(())
-    }
-
-    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
-        Ok(
-            (*self.data)
-                .iter_mut()
-                .map(|elem| {
-                    let len = elem.data.len() * std::mem::size_of::<i32>();
-                    (elem.data.as_mut_ptr() as *mut u8, len)
-                })
-                .collect()
-        )
-    }
-}
-
 /// Packed size for the simple struct.
 pub const STRUCT_SIMPLE_PACKED_SIZE: usize = 3 * std::mem::size_of::<i32>() + std::mem::size_of::<f64>();
 
-#[derive(Equivalence)]
+#[derive(MessageBuffer)]
 #[repr(C)]
 pub struct StructSimple {
     a: i32,
@@ -498,7 +355,8 @@ impl StructSimple {
     }
 }
 
-pub struct StructSimpleArray(pub Vec<StructSimple>);
+#[derive(MessageBuffer)]
+pub struct StructSimpleArray(#[mpicd(packed)] pub Vec<StructSimple>);
 
 impl StructSimpleArray {
     pub fn new(size: usize) -> StructSimpleArray {
@@ -520,36 +378,6 @@ impl StructSimpleArray {
     }
 }
 
-impl MessageCount for StructSimpleArray {
-    fn count(&self) -> usize {
-        self.0.len()
-    }
-}
-
-impl MessagePointer for StructSimpleArray {
-    fn ptr(&self) -> *const u8 {
-        self.0.as_ptr() as *const _
-    }
-
-    fn ptr_mut(&mut self) -> *mut u8 {
-        self.0.as_mut_ptr() as *mut _
-    }
-}
-
-impl MessageBuffer for StructSimpleArray {
-    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
-        Some(Ok(Box::new(StructSimpleState {
-            data: (&self.0 as *const Vec<StructSimple>) as *mut _,
-        })))
-    }
-
-    unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
-        Some(Ok(Box::new(StructSimpleState {
-            data: &mut self.0,
-        })))
-    }
-}
-
 impl ManualPack for StructSimpleArray {
     fn packed_size(&self) -> usize {
         self.0.len() * STRUCT_SIMPLE_PACKED_SIZE
@@ -588,74 +416,10 @@ impl ManualPack for StructSimpleArray {
     }
 }
 
-pub struct StructSimpleState {
-    data: *mut Vec<StructSimple>,
-}
-
-impl PackedSize for StructSimpleState {
-    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
-        Ok((*self.data).len() * STRUCT_SIMPLE_PACKED_SIZE)
-    }
-}
-
-impl PackMethod for StructSimpleState {
-    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
-        let mut i = offset / STRUCT_SIMPLE_PACKED_SIZE;
-        let mut pos: isize = 0;
-        while i < (*self.data).len() && (pos + STRUCT_SIMPLE_PACKED_SIZE as isize) <= dst_size as isize {
-            let elem = &(*self.data)[i];
-            std::ptr::copy_nonoverlapping(elem.a.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.b.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.c.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.d.to_be_bytes().as_ptr(), dst.offset(pos), 8);
-            pos += 8;
-            i += 1;
-        }
-        Ok(pos.try_into().expect("failed to convert pos from isize to usize"))
-    }
-
-    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
-        Ok(vec![])
-    }
-}
-
-impl UnpackMethod for StructSimpleState {
-    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
-        // Assume we get a full non-fragmented buffer for now.
-        assert_eq!(offset, 0);
-        let used = (*self.data).len() * STRUCT_SIMPLE_PACKED_SIZE;
-        let mut tmp = [0; 8];
-        assert_eq!(src_size, used);
-        let mut pos = 0;
-        for elem in &mut (*self.data) {
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.a = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.b = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.c = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 8);
-            pos += 8;
-            elem.d = f64::from_be_bytes(tmp[..8].try_into().unwrap());
-        }
-        Ok(())
-    }
-
-    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
-        Ok(vec![])
-    }
-}
-
 /// Packed size for the simple struct without gap.
 pub const STRUCT_SIMPLE_NO_GAP_PACKED_SIZE: usize = 2 * std::mem::size_of::<i32>() + std::mem::size_of::<f64>();
 
-#[derive(Equivalence)]
+#[derive(MessageBuffer)]
 #[repr(C)]
 pub struct StructSimpleNoGap {
     a: i32,
@@ -673,7 +437,8 @@ impl StructSimpleNoGap {
     }
 }
 
-pub struct StructSimpleNoGapArray(pub Vec<StructSimpleNoGap>);
+#[derive(MessageBuffer)]
+pub struct StructSimpleNoGapArray(#[mpicd(packed)] pub Vec<StructSimpleNoGap>);
 
 impl StructSimpleNoGapArray {
     pub fn new(size: usize) -> StructSimpleNoGapArray {
@@ -695,36 +460,6 @@ impl StructSimpleNoGapArray {
     }
 }
 
-impl MessageCount for StructSimpleNoGapArray {
-    fn count(&self) -> usize {
-        self.0.len()
-    }
-}
-
-impl MessagePointer for StructSimpleNoGapArray {
-    fn ptr(&self) -> *const u8 {
-        self.0.as_ptr() as *const _
-    }
-
-    fn ptr_mut(&mut self) -> *mut u8 {
-        self.0.as_mut_ptr() as *mut _
-    }
-}
-
-impl MessageBuffer for StructSimpleNoGapArray {
-    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
-        Some(Ok(Box::new(StructSimpleNoGapState {
-            data: (&self.0 as *const Vec<StructSimpleNoGap>) as *mut _,
-        })))
-    }
-
-    unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
-        Some(Ok(Box::new(StructSimpleNoGapState {
-            data: &mut self.0,
-        })))
-    }
-}
-
 impl ManualPack for StructSimpleNoGapArray {
     fn packed_size(&self) -> usize {
         self.0.len() * STRUCT_SIMPLE_NO_GAP_PACKED_SIZE
@@ -758,62 +493,3 @@ impl ManualPack for StructSimpleNoGapArray {
         }
     }
 }
-
-pub struct StructSimpleNoGapState {
-    data: *mut Vec<StructSimpleNoGap>,
-}
-
-impl PackedSize for StructSimpleNoGapState {
-    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
-        Ok((*self.data).len() * STRUCT_SIMPLE_NO_GAP_PACKED_SIZE)
-    }
-}
-
-impl PackMethod for StructSimpleNoGapState {
-    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
-        let mut i = offset / STRUCT_SIMPLE_NO_GAP_PACKED_SIZE;
-        let mut pos: isize = 0;
-        while i < (*self.data).len() && (pos + STRUCT_SIMPLE_NO_GAP_PACKED_SIZE as isize) <= dst_size as isize {
-            let elem = &(*self.data)[i];
-            std::ptr::copy_nonoverlapping(elem.a.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.b.to_be_bytes().as_ptr(), dst.offset(pos), 4);
-            pos += 4;
-            std::ptr::copy_nonoverlapping(elem.c.to_be_bytes().as_ptr(), dst.offset(pos), 8);
-            pos += 8;
-            i += 1;
-        }
-        Ok(pos.try_into().expect("failed to convert pos from isize to usize"))
-    }
-
-    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
-        Ok(vec![])
-    }
-}
-
-impl UnpackMethod for StructSimpleNoGapState {
-    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
-        // Assume we get a full non-fragmented buffer for now.
-        assert_eq!(offset, 0);
-        let used = (*self.data).len() * STRUCT_SIMPLE_NO_GAP_PACKED_SIZE;
-        let mut tmp = [0; 8];
-        assert_eq!(src_size, used);
-        let mut pos = 0;
-        for elem in &mut (*self.data) {
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.a = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 4);
-            pos += 4;
-            elem.b = i32::from_be_bytes(tmp[..4].try_into().unwrap());
-            std::ptr::copy_nonoverlapping(src.offset(pos), tmp.as_mut_ptr(), 8);
-            pos += 8;
-            elem.c = f64::from_be_bytes(tmp[..8].try_into().unwrap());
-        }
-        Ok(())
-    }
-
-    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
-        Ok(vec![])
-    }
-}
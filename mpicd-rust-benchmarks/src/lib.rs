@@ -9,9 +9,9 @@ pub use bw::{bw, BandwidthOptions, BandwidthBenchmark};
 mod datatype;
 pub use datatype::{
     ManualPack, ComplexVec, StructVecArray, StructSimpleArray,
-    StructSimpleNoGapArray, BenchmarkDatatypeBuffer, RsmpiDatatypeBuffer,
-    LatencyBenchmarkBuffer, RsmpiLatencyBenchmarkBuffer, STRUCT_VEC_DATA_COUNT,
-    STRUCT_VEC_PACKED_SIZE_TOTAL,
+    StructSimpleNoGapArray, BenchmarkDatatypeBuffer, LatencyBenchmarkBuffer,
+    STRUCT_VEC_DATA_COUNT, STRUCT_VEC_PACKED_SIZE_TOTAL, STRUCT_SIMPLE_PACKED_SIZE,
+    STRUCT_SIMPLE_NO_GAP_PACKED_SIZE,
 };
 
 /// Generic benchmark args.
@@ -34,18 +34,6 @@ pub struct BenchmarkArgs {
     pub subvector_size: usize,
 }
 
-/// RSMPI specific args.
-#[derive(Parser)]
-pub struct RsmpiArgs {
-    /// Path for benchmark options file.
-    #[arg(short, long)]
-    pub options_path: String,
-
-    /// Datatype to use.
-    #[arg(short, long)]
-    pub datatype: RsmpiDatatype,
-}
-
 /// Kind of benchmark to run.
 #[derive(Copy, Clone, Debug, ValueEnum)]
 pub enum BenchmarkKind {
@@ -72,22 +60,6 @@ pub enum BenchmarkDatatype {
     StructSimpleNoGap,
 }
 
-/// Datatype to use for the rsmpi benchmarks.
-#[derive(Copy, Clone, Debug, ValueEnum)]
-pub enum RsmpiDatatype {
-    /// Plain bytes datatype.
-    Bytes,
-
-    /// Struct vec datatype with Equivalence implementation.
-    StructVec,
-
-    /// Use the simple struct type.
-    StructSimple,
-
-    /// Use the simple struct type without a gap.
-    StructSimpleNoGap,
-}
-
 /// Load benchmark options from a file path.
 pub fn load_options<P, T>(path: P) -> T
 where
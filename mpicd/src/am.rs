@@ -0,0 +1,315 @@
+//! Active messages on top of UCP_FEATURE_AM.
+//!
+//! Every active message carries the rank of the sender as a 4-byte prefix of
+//! the header, followed by the user header. Incoming messages are queued by
+//! the UCX callback and handlers are only called from Context::am_progress(),
+//! so that handlers are free to send messages or register other handlers.
+use std::collections::{HashMap, VecDeque};
+use std::ffi::c_void;
+use log::error;
+use mpicd_ucx_sys::{
+    rust_ucp_dt_make_contig, rust_ucs_ptr_is_ptr, rust_ucs_ptr_status, ucp_am_handler_param_t,
+    ucp_am_recv_data_nbx, ucp_am_recv_param_t, ucp_request_check_status, ucp_request_free,
+    ucp_request_param_t, ucp_worker_h, ucp_worker_progress, ucp_worker_set_am_recv_handler,
+    ucs_status_ptr_t, ucs_status_t, UCP_AM_HANDLER_PARAM_FIELD_ARG, UCP_AM_HANDLER_PARAM_FIELD_CB,
+    UCP_AM_HANDLER_PARAM_FIELD_ID, UCP_AM_RECV_ATTR_FLAG_RNDV, UCP_OP_ATTR_FIELD_DATATYPE,
+    UCS_INPROGRESS, UCS_OK,
+};
+use crate::{
+    communicator,
+    datatype::{self, DatatypeResult, MessageBuffer},
+    message::{Message, SendLayout},
+    request::Request,
+    status_to_string, Status, System,
+};
+
+/// Size of the sender rank prefix in the header.
+const RANK_PREFIX_SIZE: usize = std::mem::size_of::<i32>();
+
+/// Handler called for incoming active messages.
+pub trait AmHandler {
+    /// Handle an incoming message.
+    fn handle(&mut self, msg: &AmMessage);
+}
+
+impl<F: FnMut(&AmMessage)> AmHandler for F {
+    fn handle(&mut self, msg: &AmMessage) {
+        self(msg)
+    }
+}
+
+/// Incoming active message.
+pub struct AmMessage {
+    /// Rank of the sending process.
+    pub source: i32,
+
+    /// User header sent with the message.
+    pub header: Vec<u8>,
+
+    /// Payload data as sent (packed data followed by memory regions for
+    /// custom buffers).
+    payload: Vec<u8>,
+}
+
+impl AmMessage {
+    /// Return the raw payload.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Unpack the payload into the buffer, which must have the same shape as
+    /// the buffer that was sent.
+    pub fn unpack<B: MessageBuffer + ?Sized>(&self, data: &mut B) -> DatatypeResult<()> {
+        unsafe { datatype::unpack_from_slice(data, &self.payload) }
+    }
+}
+
+/// Message that has been received, but not yet handled.
+pub(crate) struct Incoming {
+    /// Handler id.
+    id: u16,
+
+    /// Message contents.
+    msg: AmMessage,
+
+    /// Pending rendezvous receive of the payload.
+    req: Option<ucs_status_ptr_t>,
+}
+
+/// Argument passed to the UCX callback for a handler id.
+struct AmSlot {
+    /// Handler id.
+    id: u16,
+
+    /// Worker used for rendezvous receives.
+    worker: ucp_worker_h,
+
+    /// Queue of incoming messages, owned by the AmState.
+    incoming: *mut VecDeque<Incoming>,
+}
+
+/// Active message state of a handle.
+pub(crate) struct AmState {
+    /// Registered handlers.
+    handlers: HashMap<u16, Box<dyn AmHandler>>,
+
+    /// Callback arguments for each registered id.
+    slots: HashMap<u16, Box<AmSlot>>,
+
+    /// Queue of incoming messages. This is a raw pointer since it's written
+    /// to from the UCX callback.
+    incoming: *mut VecDeque<Incoming>,
+}
+
+impl AmState {
+    pub(crate) fn new() -> AmState {
+        AmState {
+            handlers: HashMap::new(),
+            slots: HashMap::new(),
+            incoming: Box::into_raw(Box::new(VecDeque::new())),
+        }
+    }
+
+    /// Register the handler for the id, replacing any previous handler.
+    pub(crate) unsafe fn register(
+        &mut self,
+        worker: ucp_worker_h,
+        id: u16,
+        handler: Box<dyn AmHandler>,
+    ) -> communicator::Result<()> {
+        if !self.slots.contains_key(&id) {
+            let slot = Box::new(AmSlot {
+                id,
+                worker,
+                incoming: self.incoming,
+            });
+            let param = ucp_am_handler_param_t {
+                field_mask: (UCP_AM_HANDLER_PARAM_FIELD_ID
+                    | UCP_AM_HANDLER_PARAM_FIELD_CB
+                    | UCP_AM_HANDLER_PARAM_FIELD_ARG).into(),
+                id: id.into(),
+                cb: Some(am_recv_callback),
+                arg: &*slot as *const AmSlot as *mut _,
+                ..Default::default()
+            };
+            let status = ucp_worker_set_am_recv_handler(worker, &param);
+            if status != UCS_OK {
+                error!("Failed to set active message handler: {}", status_to_string(status));
+                return Err(communicator::Error::InternalError);
+            }
+            self.slots.insert(id, slot);
+        }
+        self.handlers.insert(id, handler);
+        Ok(())
+    }
+
+    /// Take the handler for the id, so that it can be called without holding
+    /// a borrow on the handle.
+    pub(crate) fn take_handler(&mut self, id: u16) -> Option<Box<dyn AmHandler>> {
+        self.handlers.remove(&id)
+    }
+
+    /// Put back a handler, unless another one was registered in the meantime.
+    pub(crate) fn restore_handler(&mut self, id: u16, handler: Box<dyn AmHandler>) {
+        self.handlers.entry(id).or_insert(handler);
+    }
+
+    /// Remove and return all messages that are ready to be handled, in order
+    /// of arrival.
+    pub(crate) unsafe fn take_ready(&mut self) -> Vec<(u16, AmMessage)> {
+        let incoming = &mut *self.incoming;
+        let mut ready = vec![];
+        let mut pending = VecDeque::new();
+        while let Some(mut inc) = incoming.pop_front() {
+            if let Some(req) = inc.req {
+                let status = ucp_request_check_status(req);
+                if status == UCS_INPROGRESS {
+                    pending.push_back(inc);
+                    continue;
+                }
+                ucp_request_free(req);
+                inc.req = None;
+                if status != UCS_OK {
+                    error!("Failed to receive active message data: {}", status_to_string(status));
+                    continue;
+                }
+            }
+            ready.push((inc.id, inc.msg));
+        }
+        *incoming = pending;
+        ready
+    }
+
+    /// Free pending receive requests (this must be done before the worker is
+    /// destroyed).
+    pub(crate) unsafe fn clear(&mut self) {
+        for inc in (*self.incoming).drain(..) {
+            if let Some(req) = inc.req {
+                ucp_request_free(req);
+            }
+        }
+    }
+}
+
+impl Drop for AmState {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = Box::from_raw(self.incoming);
+        }
+    }
+}
+
+/// UCX callback for incoming active messages.
+unsafe extern "C" fn am_recv_callback(
+    arg: *mut c_void,
+    header: *const c_void,
+    header_length: usize,
+    data: *mut c_void,
+    length: usize,
+    param: *const ucp_am_recv_param_t,
+) -> ucs_status_t {
+    let slot = &*(arg as *const AmSlot);
+    if header_length < RANK_PREFIX_SIZE {
+        error!("Dropping active message without a rank prefix");
+        return UCS_OK;
+    }
+    let header = std::slice::from_raw_parts(header as *const u8, header_length);
+    let source = i32::from_ne_bytes(header[..RANK_PREFIX_SIZE].try_into().unwrap());
+    let mut payload = vec![0; length];
+
+    let req = if ((*param).recv_attr & u64::from(UCP_AM_RECV_ATTR_FLAG_RNDV)) != 0 {
+        // The payload has to be fetched from the sender.
+        let recv_param = ucp_request_param_t {
+            op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE,
+            datatype: rust_ucp_dt_make_contig(1),
+            ..Default::default()
+        };
+        let req = ucp_am_recv_data_nbx(slot.worker, data, payload.as_mut_ptr() as *mut _, length, &recv_param);
+        if rust_ucs_ptr_is_ptr(req) != 0 {
+            Some(req)
+        } else {
+            let status = rust_ucs_ptr_status(req);
+            if status != UCS_OK {
+                error!("Failed to receive active message data: {}", status_to_string(status));
+                return UCS_OK;
+            }
+            None
+        }
+    } else {
+        if length > 0 {
+            std::ptr::copy_nonoverlapping(data as *const u8, payload.as_mut_ptr(), length);
+        }
+        None
+    };
+
+    (*slot.incoming).push_back(Incoming {
+        id: slot.id,
+        msg: AmMessage {
+            source,
+            header: header[RANK_PREFIX_SIZE..].to_vec(),
+            payload,
+        },
+        req,
+    });
+    UCS_OK
+}
+
+/// Active message send.
+pub(crate) struct AmSendMessage {
+    /// Layout of the payload.
+    layout: SendLayout,
+
+    /// Destination rank.
+    dest: usize,
+
+    /// Handler id.
+    id: u16,
+
+    /// Header with the rank prefix.
+    header: Vec<u8>,
+
+    /// Pending request.
+    req: Option<Request>,
+}
+
+impl AmSendMessage {
+    pub(crate) unsafe fn new<B: MessageBuffer + ?Sized>(
+        data: &B,
+        source: i32,
+        dest: i32,
+        id: u16,
+        header: &[u8],
+    ) -> DatatypeResult<AmSendMessage> {
+        let mut full_header = Vec::with_capacity(RANK_PREFIX_SIZE + header.len());
+        full_header.extend_from_slice(&source.to_ne_bytes());
+        full_header.extend_from_slice(header);
+        Ok(AmSendMessage {
+            layout: SendLayout::new(data)?,
+            dest: dest as usize,
+            id,
+            header: full_header,
+            req: None,
+        })
+    }
+}
+
+impl Message for AmSendMessage {
+    unsafe fn progress(&mut self, system: &mut System) -> Status {
+        if let Some(req) = self.req.as_ref() {
+            ucp_worker_progress(system.worker);
+            req.status()
+        } else {
+            self.layout.pack().expect("failed to pack buffer");
+            let (ptr, count, datatype) = self.layout.ucx_data();
+            let _ = self.req.insert(Request::am_send_nb(
+                system.endpoints[self.dest],
+                self.id,
+                &self.header,
+                ptr,
+                count,
+                datatype,
+            ));
+            Status::InProgress
+        }
+    }
+}
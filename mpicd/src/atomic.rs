@@ -0,0 +1,198 @@
+//! Remote atomic operations on RMA windows.
+//!
+//! Operations on 32- and 64-bit integers are done with ucp_atomic_op_nbx().
+//! Other accumulate operations, such as user-defined operations over custom
+//! datatypes, fall back to a get-combine-put sequence done while holding the
+//! lock word of the target process.
+use std::borrow::{Borrow, BorrowMut};
+use mpicd_ucx_sys::{
+    rust_ucp_dt_make_contig, ucp_atomic_op_nbx, ucp_atomic_op_t, ucp_ep_h, ucp_request_param_t,
+    ucp_rkey_h, ucp_worker_progress, ucs_status_ptr_t, UCP_ATOMIC_OP_ADD, UCP_ATOMIC_OP_AND, UCP_ATOMIC_OP_CSWAP,
+    UCP_ATOMIC_OP_OR, UCP_ATOMIC_OP_SWAP, UCP_ATOMIC_OP_XOR, UCP_OP_ATTR_FIELD_DATATYPE,
+    UCP_OP_ATTR_FIELD_REPLY_BUFFER,
+};
+use crate::{
+    communicator,
+    datatype::MessageBuffer,
+    op::ReduceOp,
+    rma::{check_status, push_request, Window},
+    util::wait_request,
+};
+
+/// Maximum number of worker progress calls between attempts to take the lock
+/// of a target.
+const MAX_LOCK_BACKOFF: usize = 1024;
+
+/// Integer types supported by the remote atomic operations.
+pub trait AtomicInt: Copy + Default {
+    /// Return the native-endian bytes of the value.
+    fn to_bytes(self) -> Vec<u8>;
+}
+
+macro_rules! impl_atomic_int {
+    ($ty:ty) => {
+        impl AtomicInt for $ty {
+            fn to_bytes(self) -> Vec<u8> {
+                self.to_ne_bytes().to_vec()
+            }
+        }
+    };
+}
+
+impl_atomic_int!(u32);
+impl_atomic_int!(i32);
+impl_atomic_int!(u64);
+impl_atomic_int!(i64);
+
+/// Operation for remote atomics.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AtomicOp {
+    /// Add the value (wrapping on overflow).
+    Add,
+
+    /// Bitwise and with the value.
+    And,
+
+    /// Bitwise or with the value.
+    Or,
+
+    /// Bitwise xor with the value.
+    Xor,
+
+    /// Replace with the value.
+    Swap,
+}
+
+impl AtomicOp {
+    fn opcode(self) -> ucp_atomic_op_t {
+        match self {
+            AtomicOp::Add => UCP_ATOMIC_OP_ADD,
+            AtomicOp::And => UCP_ATOMIC_OP_AND,
+            AtomicOp::Or => UCP_ATOMIC_OP_OR,
+            AtomicOp::Xor => UCP_ATOMIC_OP_XOR,
+            AtomicOp::Swap => UCP_ATOMIC_OP_SWAP,
+        }
+    }
+}
+
+/// Submit an atomic operation on a single value of type T, storing the
+/// previous remote value in reply, if given.
+unsafe fn atomic_nbx<T: AtomicInt>(
+    endpoint: ucp_ep_h,
+    opcode: ucp_atomic_op_t,
+    value: *const T,
+    reply: Option<*mut T>,
+    addr: u64,
+    rkey: ucp_rkey_h,
+) -> ucs_status_ptr_t {
+    let mut param = ucp_request_param_t {
+        op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE,
+        datatype: rust_ucp_dt_make_contig(std::mem::size_of::<T>()),
+        ..Default::default()
+    };
+    if let Some(reply) = reply {
+        param.op_attr_mask |= UCP_OP_ATTR_FIELD_REPLY_BUFFER;
+        param.reply_buffer = reply as *mut _;
+    }
+    ucp_atomic_op_nbx(endpoint, opcode, value as *const _, 1, addr, rkey, &param)
+}
+
+impl Window {
+    /// Apply the operation to the value at the byte offset on the target,
+    /// returning the previous value.
+    pub fn fetch_and_op<T: AtomicInt>(&self, value: T, op: AtomicOp, target: i32, offset: usize) -> communicator::Result<T> {
+        let (endpoint, addr, rkey) = self.remote(target, offset, std::mem::size_of::<T>())?;
+        let mut result = T::default();
+        unsafe {
+            let req = atomic_nbx(endpoint, op.opcode(), &value, Some(&mut result), addr, rkey);
+            check_status(wait_request(self.worker(), req))?;
+        }
+        Ok(result)
+    }
+
+    /// Replace the value at the byte offset on the target with value if it's
+    /// equal to compare, returning the previous value.
+    pub fn compare_and_swap<T: AtomicInt>(&self, compare: T, value: T, target: i32, offset: usize) -> communicator::Result<T> {
+        let (endpoint, addr, rkey) = self.remote(target, offset, std::mem::size_of::<T>())?;
+        unsafe { compare_and_swap(self, endpoint, addr, rkey, compare, value) }
+    }
+
+    /// Apply the operation to the value at the byte offset on the target
+    /// without fetching the result. The operation is complete after the next
+    /// flush.
+    pub fn atomic_op<T: AtomicInt>(&self, value: T, op: AtomicOp, target: i32, offset: usize) -> communicator::Result<()> {
+        let (endpoint, addr, rkey) = self.remote(target, offset, std::mem::size_of::<T>())?;
+        // Keep the operand alive until the flush. Swaps always return the
+        // previous value, so they get a scratch reply slot after the operand.
+        let mut buffer = value.to_bytes();
+        let reply = if op == AtomicOp::Swap {
+            buffer.extend_from_slice(&T::default().to_bytes());
+            Some(unsafe { buffer.as_mut_ptr().add(std::mem::size_of::<T>()) } as *mut T)
+        } else {
+            None
+        };
+        let mut reqs = vec![];
+        unsafe {
+            let req = atomic_nbx(endpoint, op.opcode(), buffer.as_ptr() as *const T, reply, addr, rkey);
+            let result = push_request(&mut reqs, req);
+            self.add_pending(target, reqs, buffer);
+            result
+        }
+    }
+
+    /// Accumulate the buffer into the memory of the target at the byte
+    /// offset, storing `data op target` on the target.
+    ///
+    /// This is done in software: the lock word of the target is acquired, the
+    /// target memory is read into a copy of the buffer, combined with the
+    /// operation and then written back. All accumulates to the target are
+    /// serialized, but plain puts and gets are not.
+    pub unsafe fn accumulate<B, O>(&self, data: &B, op: &O, target: i32, offset: usize) -> communicator::Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        let (endpoint, lock_addr, lock_rkey) = self.remote_lock(target)?;
+        let owner = self.rank() as u64 + 1;
+        // Spin until the lock is free, progressing the worker between attempts
+        // and backing off exponentially to limit traffic to the target.
+        let mut backoff = 1;
+        while compare_and_swap(self, endpoint, lock_addr, lock_rkey, 0, owner)? != 0 {
+            for _ in 0..backoff {
+                ucp_worker_progress(self.worker());
+            }
+            backoff = (backoff * 2).min(MAX_LOCK_BACKOFF);
+        }
+
+        let mut remote = data.to_owned();
+        let mut result = self.get(remote.borrow_mut(), target, offset).and_then(|_| self.flush(target));
+        if result.is_ok() {
+            op.apply(data, remote.borrow_mut());
+            result = self.put(remote.borrow(), target, offset).and_then(|_| self.flush(target));
+        }
+
+        // Always release the lock.
+        let prev = compare_and_swap(self, endpoint, lock_addr, lock_rkey, owner, 0)?;
+        if prev != owner {
+            return Err(communicator::Error::InternalError);
+        }
+        result
+    }
+}
+
+/// Compare and swap a remote value, returning the previous value.
+unsafe fn compare_and_swap<T: AtomicInt>(
+    window: &Window,
+    endpoint: ucp_ep_h,
+    addr: u64,
+    rkey: ucp_rkey_h,
+    compare: T,
+    value: T,
+) -> communicator::Result<T> {
+    // The reply buffer holds the compare value and receives the old value.
+    let mut result = compare;
+    let req = atomic_nbx(endpoint, UCP_ATOMIC_OP_CSWAP, &value, Some(&mut result), addr, rkey);
+    check_status(wait_request(window.worker(), req))?;
+    Ok(result)
+}
@@ -0,0 +1,114 @@
+//! Barrier algorithms.
+use mpicd_ucx_sys::{
+    rust_ucp_dt_make_contig, ucp_request_param_t, ucp_tag_recv_nbx, ucp_tag_send_nbx,
+    UCP_OP_ATTR_FIELD_DATATYPE,
+};
+use crate::{
+    communicator::Communicator,
+    request::{encode_tag, BARRIER_TAG, TAG_MASK},
+    util::wait_request,
+    Context, Status, System,
+};
+
+/// Environment variable used to select the barrier algorithm.
+const BARRIER_ALGORITHM_VAR: &str = "MPICD_BARRIER_ALGORITHM";
+
+/// Algorithm to use for barriers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BarrierAlgorithm {
+    /// Linear fan-in/fan-out through rank 0.
+    Linear,
+
+    /// Dissemination barrier, completing in ceil(log2(n)) rounds.
+    Dissemination,
+}
+
+impl BarrierAlgorithm {
+    /// Get the algorithm set by the MPICD_BARRIER_ALGORITHM environment
+    /// variable ("linear" or "dissemination"), defaulting to dissemination.
+    pub(crate) fn from_env() -> BarrierAlgorithm {
+        match std::env::var(BARRIER_ALGORITHM_VAR).as_deref() {
+            Ok("linear") => BarrierAlgorithm::Linear,
+            _ => BarrierAlgorithm::Dissemination,
+        }
+    }
+}
+
+/// Linear barrier.
+///
+/// Uses a simple O(n) algorithm.
+pub(crate) unsafe fn linear(ctx: &Context) {
+    let size = ctx.size();
+    let rank = ctx.rank();
+    if rank == 0 {
+        let mut buf = [0; 1];
+        let mut reqs = vec![];
+        for i in 1..size {
+            reqs.push(ctx.internal_isend(&buf[..], i, encode_tag(BARRIER_TAG, 0, 0)).expect("failed to get send request"));
+        }
+        ctx.waitall(&reqs).expect("failed to wait for send requests");
+
+        reqs.clear();
+        for i in 1..size {
+            reqs.push(ctx.internal_irecv(&mut buf[..], encode_tag(BARRIER_TAG, i, 0)).expect("failed to get recv request"));
+        }
+        ctx.waitall(&reqs).expect("failed to wait for recv requests");
+    } else {
+        let mut buf = [0; 1];
+        let req = ctx.internal_irecv(&mut buf[..], encode_tag(BARRIER_TAG, 0, 0)).expect("failed to get recv request");
+        ctx.waitall(&[req]).expect("failed to wait for recv request");
+        let req = ctx.internal_isend(&buf[..], 0, encode_tag(BARRIER_TAG, rank, 0)).expect("failed to get send request");
+        ctx.waitall(&[req]).expect("failed to wait for send request");
+    }
+}
+
+/// Dissemination barrier.
+///
+/// In round k each process sends to rank + 2^k and receives from rank - 2^k,
+/// with the round number stored in the tag. The zero-byte messages are
+/// submitted directly to UCX and polled with ucp_request_check_status(), so
+/// that no message objects or buffers need to be allocated.
+pub(crate) unsafe fn dissemination(system: &System) -> Status {
+    let size = system.size;
+    let rank = system.rank;
+    let param = ucp_request_param_t {
+        op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE,
+        datatype: rust_ucp_dt_make_contig(1),
+        ..Default::default()
+    };
+
+    let mut dist = 1;
+    let mut round = 0;
+    while dist < size {
+        let dest = (rank + dist) % size;
+        let source = (rank + size - dist) % size;
+        let recv_req = ucp_tag_recv_nbx(
+            system.worker,
+            std::ptr::null_mut(),
+            0,
+            encode_tag(BARRIER_TAG, source as i32, round),
+            TAG_MASK,
+            &param,
+        );
+        let send_req = ucp_tag_send_nbx(
+            system.endpoints[dest],
+            std::ptr::null(),
+            0,
+            encode_tag(BARRIER_TAG, rank as i32, round),
+            &param,
+        );
+
+        let recv_status = wait_request(system.worker, recv_req);
+        let send_status = wait_request(system.worker, send_req);
+        if recv_status != Status::Complete {
+            return recv_status;
+        }
+        if send_status != Status::Complete {
+            return send_status;
+        }
+
+        dist <<= 1;
+        round += 1;
+    }
+    Status::Complete
+}
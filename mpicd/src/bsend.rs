@@ -0,0 +1,159 @@
+//! Buffered send mode using a user-attached buffer.
+//!
+//! Buffered sends fully pack the object, including its memory regions, into
+//! the attached buffer and send it from there as contiguous data, so the
+//! caller's object can be modified as soon as bsend() returns. Receivers see
+//! the same bytes as for a standard send. Types with a header (or a signature)
+//! are sent from the copy with their original shape, with the packed part
+//! going in the header as usual.
+use crate::{
+    communicator,
+    datatype::{DatatypeError, MessageBuffer, PackedShape},
+    framing::PackedCopy,
+    message::{ContiguousSendMessage, Message, PackSendMessage},
+    signature::{message_signature, signed_pack},
+    Handle, Status,
+};
+
+/// Buffer attached for buffered sends.
+pub(crate) struct BsendBuffer {
+    /// Start of the attached memory.
+    ptr: *mut u8,
+
+    /// Size of the attached memory.
+    size: usize,
+
+    /// Parts of the buffer used by pending messages, as (offset, length,
+    /// message id), sorted by offset.
+    pending: Vec<(usize, usize, usize)>,
+}
+
+impl BsendBuffer {
+    /// Create a new attached buffer.
+    pub(crate) fn new(ptr: *mut u8, size: usize) -> BsendBuffer {
+        BsendBuffer {
+            ptr,
+            size,
+            pending: vec![],
+        }
+    }
+
+    /// Return the attached memory.
+    pub(crate) fn memory(&self) -> (*mut u8, usize) {
+        (self.ptr, self.size)
+    }
+
+    /// Return true if no messages are using the buffer.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Find the first free part of the buffer that can hold len bytes.
+    fn find_space(&self, len: usize) -> Option<usize> {
+        let mut start = 0;
+        for (offset, used, _) in &self.pending {
+            if offset - start >= len {
+                return Some(start);
+            }
+            start = offset + used;
+        }
+        if self.size - start >= len {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Progress the pending messages, releasing the space of those that have
+    /// completed.
+    pub(crate) unsafe fn reclaim(&mut self, handle: &mut Handle) {
+        self.pending.retain(|(_, _, msg_id)| {
+            match handle.message_progress(*msg_id) {
+                Status::InProgress => true,
+                _ => {
+                    handle.remove_message(*msg_id);
+                    false
+                }
+            }
+        });
+    }
+
+    /// Pack the data into the buffer and start sending it.
+    pub(crate) unsafe fn send<B: MessageBuffer + ?Sized>(
+        &mut self,
+        handle: &mut Handle,
+        data: &B,
+        dest: i32,
+        tag: u64,
+    ) -> communicator::Result<()> {
+        let datatype_err = communicator::Error::Datatype;
+        let signature = message_signature(data, tag);
+        let mut pack_method = match signed_pack(data, signature) {
+            Some(pack_method) => Some(pack_method.map_err(datatype_err)?),
+            None => None,
+        };
+        let (packed_size, regions) = if let Some(pack_method) = pack_method.as_ref() {
+            (pack_method.packed_size().map_err(datatype_err)?, pack_method.memory_regions().map_err(datatype_err)?)
+        } else {
+            (data.count(), vec![])
+        };
+        let total = packed_size + regions.iter().map(|(_, len)| len).sum::<usize>();
+
+        let offset = match self.find_space(total) {
+            Some(offset) => offset,
+            None => {
+                self.reclaim(handle);
+                self.find_space(total).ok_or(communicator::Error::BufferExhausted)?
+            }
+        };
+
+        // Copy everything into the buffer.
+        let dst = self.ptr.add(offset);
+        if let Some(pack_method) = pack_method.as_mut() {
+            if packed_size > 0 {
+                let used = pack_method.pack(0, dst, packed_size).map_err(datatype_err)?;
+                if used != packed_size {
+                    return Err(communicator::Error::Datatype(DatatypeError::PackError));
+                }
+            }
+        } else {
+            std::ptr::copy_nonoverlapping(data.ptr(), dst, packed_size);
+        }
+        let region_lens = regions.iter().map(|(_, len)| *len).collect();
+        let mut pos = packed_size;
+        for (ptr, len) in regions {
+            std::ptr::copy_nonoverlapping(ptr, dst.add(pos), len);
+            pos += len;
+        }
+
+        let message: Box<dyn Message> = match pack_method.as_ref() {
+            Some(pack_method) if pack_method.header() || signature.is_some() => {
+                let copy = PackedCopy {
+                    ptr: dst,
+                    shape: PackedShape { packed_size, region_lens },
+                    header: pack_method.header(),
+                };
+                Box::new(PackSendMessage::new(Box::new(copy), dest, tag, false, signature))
+            }
+            _ => Box::new(ContiguousSendMessage::new(dst, total, dest, tag, false)),
+        };
+
+        // Progress once to submit the message right away.
+        let msg_id = handle.add_message(message);
+        match handle.message_progress(msg_id) {
+            Status::InProgress => {
+                let i = self.pending.partition_point(|(other, _, _)| *other < offset);
+                self.pending.insert(i, (offset, total, msg_id));
+                Ok(())
+            }
+            Status::Complete => {
+                handle.remove_message(msg_id);
+                Ok(())
+            }
+            Status::Error(_) => {
+                handle.remove_message(msg_id);
+                Err(communicator::Error::InternalError)
+            }
+        }
+    }
+}
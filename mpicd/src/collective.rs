@@ -0,0 +1,495 @@
+//! Blocking collective operations built on top of the tagged p2p code.
+//!
+//! All messages sent here use the internal COLLECTIVE_TAG class with the
+//! application tag part holding the operation, so that they can never match
+//! user point-to-point messages.
+use std::borrow::{Borrow, BorrowMut};
+use crate::{
+    communicator::{self, Communicator},
+    datatype::{self, MessageBuffer, PackedShape},
+    op::ReduceOp,
+    request::{encode_tag, COLLECTIVE_TAG},
+    Context, Status,
+};
+
+/// Operation tags stored in the application part of the tag.
+const BCAST_OP: i32 = 0;
+const GATHER_OP: i32 = 1;
+const SCATTER_OP: i32 = 2;
+const ALLGATHER_OP: i32 = 3;
+const ALLTOALL_OP: i32 = 4;
+const REDUCE_OP: i32 = 5;
+const SCAN_OP: i32 = 6;
+const EXSCAN_OP: i32 = 7;
+const GATHERV_OP: i32 = 8;
+const ALLGATHERV_OP: i32 = 9;
+const ALLTOALLV_OP: i32 = 10;
+
+/// Encode a collective tag for a message coming from the rank.
+#[inline]
+pub(crate) fn collective_tag(rank: i32, op: i32) -> u64 {
+    encode_tag(COLLECTIVE_TAG, rank, op)
+}
+
+/// Check that the root is a valid rank of the communicator.
+pub(crate) fn check_root(ctx: &Context, root: i32) -> communicator::Result<()> {
+    if root >= 0 && root < ctx.size() {
+        Ok(())
+    } else {
+        Err(communicator::Error::InvalidRoot)
+    }
+}
+
+/// Check that there is one buffer per process of the communicator.
+pub(crate) fn check_count(ctx: &Context, count: usize) -> communicator::Result<()> {
+    if count == ctx.size() as usize {
+        Ok(())
+    } else {
+        Err(communicator::Error::InvalidBufferCount)
+    }
+}
+
+/// Post requests with the closure and wait on all of them.
+///
+/// If posting fails part way through, the requests already posted are still
+/// waited on before returning the error, so that none are left behind.
+unsafe fn post_and_wait<F>(ctx: &Context, post: F) -> communicator::Result<()>
+where
+    F: FnOnce(&mut Vec<usize>) -> communicator::Result<()>,
+{
+    let mut reqs = vec![];
+    let posted = post(&mut reqs);
+    let waited = wait(ctx, &reqs);
+    posted.and(waited)
+}
+
+/// Wait on all requests, returning an error if any of them failed.
+pub(crate) unsafe fn wait(ctx: &Context, reqs: &[usize]) -> communicator::Result<()> {
+    let statuses = ctx.waitall(reqs)?;
+    if statuses.iter().all(|status| *status == Status::Complete) {
+        Ok(())
+    } else {
+        Err(communicator::Error::InternalError)
+    }
+}
+
+/// Copy one buffer into another locally by packing and unpacking it.
+pub(crate) unsafe fn copy<S: MessageBuffer + ?Sized, D: MessageBuffer + ?Sized>(
+    src: &S,
+    dst: &mut D,
+) -> communicator::Result<()> {
+    let packed = datatype::pack_to_vec(src).map_err(communicator::Error::Datatype)?;
+    datatype::unpack_from_slice(dst, &packed).map_err(communicator::Error::Datatype)
+}
+
+/// Broadcast the buffer from the root to all other processes.
+///
+/// Uses a simple linear algorithm.
+pub(crate) unsafe fn bcast<B: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    data: &mut B,
+    root: i32,
+) -> communicator::Result<()> {
+    let size = ctx.size();
+    let rank = ctx.rank();
+    check_root(ctx, root)?;
+
+    post_and_wait(ctx, |reqs| {
+        if rank == root {
+            for i in (0..size).filter(|i| *i != root) {
+                reqs.push(ctx.internal_isend(data, i, collective_tag(rank, BCAST_OP))?);
+            }
+        } else {
+            reqs.push(ctx.internal_irecv(data, collective_tag(root, BCAST_OP))?);
+        }
+        Ok(())
+    })
+}
+
+/// Gather buffers from all processes onto the root.
+pub(crate) unsafe fn gather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbuf: &S,
+    rbufs: &mut [&mut R],
+    root: i32,
+) -> communicator::Result<()> {
+    let rank = ctx.rank();
+    check_root(ctx, root)?;
+    if rank == root {
+        check_count(ctx, rbufs.len())?;
+    }
+
+    post_and_wait(ctx, |reqs| {
+        if rank == root {
+            for (i, rbuf) in rbufs.iter_mut().enumerate() {
+                reqs.push(ctx.internal_irecv(*rbuf, collective_tag(i as i32, GATHER_OP))?);
+            }
+        }
+        reqs.push(ctx.internal_isend(sbuf, root, collective_tag(rank, GATHER_OP))?);
+        Ok(())
+    })
+}
+
+/// Scatter buffers from the root to all processes.
+pub(crate) unsafe fn scatter<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbufs: &[&S],
+    rbuf: &mut R,
+    root: i32,
+) -> communicator::Result<()> {
+    let rank = ctx.rank();
+    check_root(ctx, root)?;
+    if rank == root {
+        check_count(ctx, sbufs.len())?;
+    }
+
+    post_and_wait(ctx, |reqs| {
+        reqs.push(ctx.internal_irecv(rbuf, collective_tag(root, SCATTER_OP))?);
+        if rank == root {
+            for (i, sbuf) in sbufs.iter().enumerate() {
+                reqs.push(ctx.internal_isend(*sbuf, i as i32, collective_tag(rank, SCATTER_OP))?);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Gather buffers from all processes onto all processes.
+///
+/// Every process sends its buffer directly to every other process.
+pub(crate) unsafe fn allgather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbuf: &S,
+    rbufs: &mut [&mut R],
+) -> communicator::Result<()> {
+    let size = ctx.size();
+    let rank = ctx.rank();
+    check_count(ctx, rbufs.len())?;
+
+    post_and_wait(ctx, |reqs| {
+        for (i, rbuf) in rbufs.iter_mut().enumerate() {
+            reqs.push(ctx.internal_irecv(*rbuf, collective_tag(i as i32, ALLGATHER_OP))?);
+        }
+        for i in 0..size {
+            reqs.push(ctx.internal_isend(sbuf, i, collective_tag(rank, ALLGATHER_OP))?);
+        }
+        Ok(())
+    })
+}
+
+/// Exchange a separate buffer between every pair of processes.
+pub(crate) unsafe fn alltoall<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbufs: &[&S],
+    rbufs: &mut [&mut R],
+) -> communicator::Result<()> {
+    let rank = ctx.rank();
+    check_count(ctx, sbufs.len())?;
+    check_count(ctx, rbufs.len())?;
+
+    post_and_wait(ctx, |reqs| {
+        for (i, rbuf) in rbufs.iter_mut().enumerate() {
+            reqs.push(ctx.internal_irecv(*rbuf, collective_tag(i as i32, ALLTOALL_OP))?);
+        }
+        for (i, sbuf) in sbufs.iter().enumerate() {
+            reqs.push(ctx.internal_isend(*sbuf, i as i32, collective_tag(rank, ALLTOALL_OP))?);
+        }
+        Ok(())
+    })
+}
+
+/// Packed buffer sent by the v-collectives.
+///
+/// The data holds the region lengths followed by the fully packed buffer, while
+/// the fixed-size header holds the packed part size, the region count and the
+/// length of the data, so that the receiver can allocate staging for it.
+struct VImage {
+    header: [u64; 3],
+    data: Vec<u8>,
+}
+
+impl VImage {
+    /// Pack the buffer into a new image.
+    unsafe fn new<S: MessageBuffer + ?Sized>(sbuf: &S) -> communicator::Result<VImage> {
+        let (shape, packed) = datatype::pack_with_shape(sbuf).map_err(communicator::Error::Datatype)?;
+        let mut data: Vec<u8> = shape
+            .region_lens
+            .iter()
+            .flat_map(|len| (*len as u64).to_ne_bytes())
+            .collect();
+        data.extend_from_slice(&packed);
+        Ok(VImage {
+            header: [shape.packed_size as u64, shape.region_lens.len() as u64, data.len() as u64],
+            data,
+        })
+    }
+}
+
+/// Create the receive object from a received header and staging data.
+unsafe fn v_deliver<R, F>(
+    rank: i32,
+    header: &[u64; 3],
+    data: &[u8],
+    recv: &mut F,
+) -> communicator::Result<R>
+where
+    R: MessageBuffer,
+    F: FnMut(i32, &PackedShape) -> R,
+{
+    let (lens, packed) = data.split_at(header[1] as usize * std::mem::size_of::<u64>());
+    let shape = PackedShape {
+        packed_size: header[0] as usize,
+        region_lens: lens
+            .chunks_exact(std::mem::size_of::<u64>())
+            .map(|len| u64::from_ne_bytes(len.try_into().unwrap()) as usize)
+            .collect(),
+    };
+    let mut rbuf = recv(rank, &shape);
+    datatype::unpack_from_slice(&mut rbuf, packed).map_err(communicator::Error::Datatype)?;
+    Ok(rbuf)
+}
+
+/// Receive images from each of the sources, after first posting the sends.
+///
+/// Sends are given as (destination, image) pairs. Headers are exchanged in a
+/// first round and then the data is received into staging buffers sized from
+/// the headers.
+unsafe fn v_exchange<R, F>(
+    ctx: &Context,
+    sends: &[(i32, &VImage)],
+    sources: &[i32],
+    op_tag: i32,
+    mut recv: F,
+) -> communicator::Result<Vec<R>>
+where
+    R: MessageBuffer,
+    F: FnMut(i32, &PackedShape) -> R,
+{
+    let rank = ctx.rank();
+
+    let mut headers = vec![[0u64; 3]; sources.len()];
+    post_and_wait(ctx, |reqs| {
+        for (source, header) in sources.iter().zip(headers.iter_mut()) {
+            reqs.push(ctx.internal_irecv(&mut header[..], collective_tag(*source, op_tag))?);
+        }
+        for (dest, image) in sends {
+            reqs.push(ctx.internal_isend(&image.header[..], *dest, collective_tag(rank, op_tag))?);
+        }
+        Ok(())
+    })?;
+
+    let mut staging: Vec<Vec<u8>> = headers.iter().map(|header| vec![0; header[2] as usize]).collect();
+    post_and_wait(ctx, |reqs| {
+        for (source, data) in sources.iter().zip(staging.iter_mut()) {
+            reqs.push(ctx.internal_irecv(&mut data[..], collective_tag(*source, op_tag))?);
+        }
+        for (dest, image) in sends {
+            reqs.push(ctx.internal_isend(&image.data[..], *dest, collective_tag(rank, op_tag))?);
+        }
+        Ok(())
+    })?;
+
+    sources
+        .iter()
+        .zip(headers.iter().zip(staging.iter()))
+        .map(|(source, (header, data))| v_deliver(*source, header, data, &mut recv))
+        .collect()
+}
+
+/// Gather variable-size buffers onto the root.
+pub(crate) unsafe fn gatherv<S, R, F>(
+    ctx: &Context,
+    sbuf: &S,
+    root: i32,
+    recv: F,
+) -> communicator::Result<Vec<R>>
+where
+    S: MessageBuffer + ?Sized,
+    R: MessageBuffer,
+    F: FnMut(i32, &PackedShape) -> R,
+{
+    let size = ctx.size();
+    let rank = ctx.rank();
+    check_root(ctx, root)?;
+
+    let image = VImage::new(sbuf)?;
+    let sources: Vec<i32> = if rank == root { (0..size).collect() } else { vec![] };
+    v_exchange(ctx, &[(root, &image)], &sources, GATHERV_OP, recv)
+}
+
+/// Gather variable-size buffers onto all processes.
+pub(crate) unsafe fn allgatherv<S, R, F>(
+    ctx: &Context,
+    sbuf: &S,
+    recv: F,
+) -> communicator::Result<Vec<R>>
+where
+    S: MessageBuffer + ?Sized,
+    R: MessageBuffer,
+    F: FnMut(i32, &PackedShape) -> R,
+{
+    let size = ctx.size();
+
+    let image = VImage::new(sbuf)?;
+    let sends: Vec<(i32, &VImage)> = (0..size).map(|i| (i, &image)).collect();
+    let sources: Vec<i32> = (0..size).collect();
+    v_exchange(ctx, &sends, &sources, ALLGATHERV_OP, recv)
+}
+
+/// Exchange variable-size buffers between every pair of processes.
+pub(crate) unsafe fn alltoallv<S, R, F>(
+    ctx: &Context,
+    sbufs: &[&S],
+    recv: F,
+) -> communicator::Result<Vec<R>>
+where
+    S: MessageBuffer + ?Sized,
+    R: MessageBuffer,
+    F: FnMut(i32, &PackedShape) -> R,
+{
+    let size = ctx.size();
+    check_count(ctx, sbufs.len())?;
+
+    let images = sbufs
+        .iter()
+        .map(|sbuf| VImage::new(*sbuf))
+        .collect::<communicator::Result<Vec<VImage>>>()?;
+    let sends: Vec<(i32, &VImage)> = images.iter().enumerate().map(|(i, image)| (i as i32, image)).collect();
+    let sources: Vec<i32> = (0..size).collect();
+    v_exchange(ctx, &sends, &sources, ALLTOALLV_OP, recv)
+}
+
+/// Reduce buffers from all processes onto the root.
+///
+/// The root receives every contribution and then applies the operation in rank
+/// order, so this needs a temporary buffer per process on the root.
+pub(crate) unsafe fn reduce<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+    root: i32,
+) -> communicator::Result<()>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    let size = ctx.size();
+    let rank = ctx.rank();
+    check_root(ctx, root)?;
+
+    if rank != root {
+        let req = ctx.internal_isend(sbuf, root, collective_tag(rank, REDUCE_OP))?;
+        return wait(ctx, &[req]);
+    }
+
+    // The contribution from the last rank goes directly into rbuf, all others
+    // need a temporary buffer (the root's own is just a copy of sbuf).
+    let last = size - 1;
+    let mut temps: Vec<B::Owned> = (0..last).map(|_| sbuf.to_owned()).collect();
+    post_and_wait(ctx, |reqs| {
+        for (i, temp) in temps.iter_mut().enumerate() {
+            if i as i32 != root {
+                let temp: &mut B = temp.borrow_mut();
+                reqs.push(ctx.internal_irecv(temp, collective_tag(i as i32, REDUCE_OP))?);
+            }
+        }
+        if root == last {
+            copy(sbuf, &mut *rbuf)
+        } else {
+            reqs.push(ctx.internal_irecv(&mut *rbuf, collective_tag(last, REDUCE_OP))?);
+            Ok(())
+        }
+    })?;
+
+    for temp in temps.iter().rev() {
+        op.apply(temp.borrow(), rbuf);
+    }
+    Ok(())
+}
+
+/// Reduce buffers from all processes onto all processes.
+pub(crate) unsafe fn allreduce<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+) -> communicator::Result<()>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    reduce(ctx, sbuf, rbuf, op, 0)?;
+    bcast(ctx, rbuf, 0)
+}
+
+/// Inclusive prefix reduction.
+///
+/// Uses a simple linear algorithm, passing the partial result along from rank
+/// to rank.
+pub(crate) unsafe fn scan<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+) -> communicator::Result<()>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    let size = ctx.size();
+    let rank = ctx.rank();
+
+    if rank > 0 {
+        let mut prefix = sbuf.to_owned();
+        let req = ctx.internal_irecv(prefix.borrow_mut(), collective_tag(rank - 1, SCAN_OP))?;
+        wait(ctx, &[req])?;
+        copy(sbuf, rbuf)?;
+        op.apply(prefix.borrow(), rbuf);
+    } else {
+        copy(sbuf, rbuf)?;
+    }
+
+    if rank < (size - 1) {
+        let req = ctx.internal_isend(rbuf, rank + 1, collective_tag(rank, SCAN_OP))?;
+        wait(ctx, &[req])?;
+    }
+    Ok(())
+}
+
+/// Exclusive prefix reduction.
+pub(crate) unsafe fn exscan<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+) -> communicator::Result<()>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    let size = ctx.size();
+    let rank = ctx.rank();
+
+    if rank > 0 {
+        let req = ctx.internal_irecv(rbuf, collective_tag(rank - 1, EXSCAN_OP))?;
+        wait(ctx, &[req])?;
+    }
+
+    if rank < (size - 1) {
+        if rank == 0 {
+            let req = ctx.internal_isend(sbuf, rank + 1, collective_tag(rank, EXSCAN_OP))?;
+            wait(ctx, &[req])?;
+        } else {
+            let mut next = sbuf.to_owned();
+            op.apply(rbuf, next.borrow_mut());
+            let req = ctx.internal_isend(next.borrow(), rank + 1, collective_tag(rank, EXSCAN_OP))?;
+            wait(ctx, &[req])?;
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,9 @@
 //! Code abstracting out Rust communicators.
+use std::borrow::BorrowMut;
 use crate::Status;
-use crate::datatype::MessageBuffer;
+use crate::datatype::{DatatypeError, FromPacked, MessageBuffer, PackedShape};
+use crate::descriptor::RawMessage;
+use crate::op::ReduceOp;
 
 #[derive(Copy, Clone, Debug)]
 pub enum Error {
@@ -9,6 +12,28 @@ pub enum Error {
 
     /// No message was found during a probe operation.
     NoProbeMessage,
+
+    /// A datatype error occured while packing or unpacking a buffer.
+    Datatype(DatatypeError),
+
+    /// The request is not valid for this operation.
+    InvalidRequest,
+
+    /// There is not enough space left in the attached buffer.
+    BufferExhausted,
+
+    /// No buffer is attached, or a buffer is already attached.
+    InvalidBuffer,
+
+    /// An RMA operation falls outside of the target window.
+    OutOfBounds,
+
+    /// The root of a collective operation is not a valid rank.
+    InvalidRoot,
+
+    /// The number of buffers passed to a collective operation doesn't match
+    /// the communicator size.
+    InvalidBufferCount,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -36,12 +61,253 @@ pub trait Communicator {
     /// Perform a barrier on the processes.
     fn barrier(&self);
 
+    /// Broadcast the data buffer from the root to all other processes.
+    fn bcast<B: MessageBuffer + ?Sized>(&self, data: &mut B, root: i32) -> Result<()>;
+
+    /// Gather a buffer from every process into the per-rank receive buffers on
+    /// the root (`rbufs` is ignored on non-root processes).
+    fn gather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+        root: i32,
+    ) -> Result<()>;
+
+    /// Scatter the per-rank send buffers on the root to every process (`sbufs`
+    /// is ignored on non-root processes).
+    fn scatter<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbuf: &mut R,
+        root: i32,
+    ) -> Result<()>;
+
+    /// Gather a buffer from every process into the per-rank receive buffers on
+    /// all processes.
+    fn allgather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+    ) -> Result<()>;
+
+    /// Send the i-th send buffer to process i and receive the i-th receive
+    /// buffer from process i.
+    fn alltoall<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbufs: &mut [&mut R],
+    ) -> Result<()>;
+
+    /// Gather variable-size buffers from every process onto the root.
+    ///
+    /// Packed shapes are exchanged first, so the root doesn't need to know the
+    /// size of each contribution in advance. The receive object for each rank
+    /// is created by calling `recv` with the rank and the sender's packed
+    /// shape, and the contribution is then unpacked into it. Returns the
+    /// receive objects on the root and an empty vector elsewhere.
+    fn gatherv<S, R, F>(&self, sbuf: &S, root: i32, recv: F) -> Result<Vec<R>>
+    where
+        S: MessageBuffer + ?Sized,
+        R: MessageBuffer,
+        F: FnMut(i32, &PackedShape) -> R;
+
+    /// Gather variable-size buffers from every process onto all processes (see
+    /// gatherv()).
+    fn allgatherv<S, R, F>(&self, sbuf: &S, recv: F) -> Result<Vec<R>>
+    where
+        S: MessageBuffer + ?Sized,
+        R: MessageBuffer,
+        F: FnMut(i32, &PackedShape) -> R;
+
+    /// Send the i-th variable-size buffer to process i, returning the receive
+    /// objects created for each process (see gatherv()).
+    fn alltoallv<S, R, F>(&self, sbufs: &[&S], recv: F) -> Result<Vec<R>>
+    where
+        S: MessageBuffer + ?Sized,
+        R: MessageBuffer,
+        F: FnMut(i32, &PackedShape) -> R;
+
+    /// Reduce the send buffers of all processes with the operation, storing the
+    /// result in the receive buffer on the root (`rbuf` is ignored on non-root
+    /// processes).
+    fn reduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O, root: i32) -> Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
+    /// Reduce the send buffers of all processes with the operation, storing the
+    /// result in the receive buffer on all processes.
+    fn allreduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
+    /// Inclusive prefix reduction: rank i receives the reduction of the send
+    /// buffers of ranks 0 to i.
+    fn scan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
+    /// Exclusive prefix reduction: rank i receives the reduction of the send
+    /// buffers of ranks 0 to i - 1 (`rbuf` is left untouched on rank 0).
+    fn exscan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
+    /// Start a non-blocking barrier.
+    unsafe fn ibarrier(&self) -> Result<Self::Request>;
+
+    /// Start a non-blocking broadcast (see bcast()).
+    unsafe fn ibcast<B: MessageBuffer + ?Sized>(&self, data: &mut B, root: i32) -> Result<Self::Request>;
+
+    /// Start a non-blocking gather (see gather()).
+    unsafe fn igather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+        root: i32,
+    ) -> Result<Self::Request>;
+
+    /// Start a non-blocking scatter (see scatter()).
+    unsafe fn iscatter<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbuf: &mut R,
+        root: i32,
+    ) -> Result<Self::Request>;
+
+    /// Start a non-blocking allgather (see allgather()).
+    unsafe fn iallgather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+    ) -> Result<Self::Request>;
+
+    /// Start a non-blocking alltoall (see alltoall()).
+    unsafe fn ialltoall<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbufs: &mut [&mut R],
+    ) -> Result<Self::Request>;
+
+    /// Start a non-blocking reduction (see reduce()). The operation must also
+    /// stay alive until the request completes.
+    unsafe fn ireduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O, root: i32) -> Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
+    /// Start a non-blocking allreduce (see allreduce()). The operation must
+    /// also stay alive until the request completes.
+    unsafe fn iallreduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
+    /// Start a non-blocking inclusive prefix reduction (see scan()). The
+    /// operation must also stay alive until the request completes.
+    unsafe fn iscan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
+    /// Start a non-blocking exclusive prefix reduction (see exscan()). The
+    /// operation must also stay alive until the request completes.
+    unsafe fn iexscan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized;
+
     /// Do a non-blocking send of data to the destination with specified tag.
     unsafe fn isend<B: MessageBuffer + ?Sized>(&self, data: &B, dest: i32, tag: i32) -> Result<Self::Request>;
 
+    /// Do a non-blocking synchronous send, which only completes once the
+    /// receiver has matched the message.
+    unsafe fn issend<B: MessageBuffer + ?Sized>(&self, data: &B, dest: i32, tag: i32) -> Result<Self::Request>;
+
+    /// Send a buffer and receive another one, waiting for both to complete.
+    /// The receive is always posted before the send, so that pairs of
+    /// processes can't deadlock.
+    fn sendrecv<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        dest: i32,
+        sendtag: i32,
+        rbuf: &mut R,
+        source: i32,
+        recvtag: i32,
+    ) -> Result<()>;
+
+    /// Send the buffer and then replace it with the received data. The
+    /// outgoing data is fully packed before anything is unpacked into the
+    /// buffer.
+    fn sendrecv_replace<B: MessageBuffer + ?Sized>(
+        &self,
+        data: &mut B,
+        dest: i32,
+        sendtag: i32,
+        source: i32,
+        recvtag: i32,
+    ) -> Result<()>;
+
+    /// Attach a buffer used for buffered sends. The memory must stay valid
+    /// until it's detached.
+    unsafe fn buffer_attach(&self, ptr: *mut u8, size: usize) -> Result<()>;
+
+    /// Detach the buffer, waiting for all pending buffered sends to complete,
+    /// and return it.
+    fn buffer_detach(&self) -> Result<(*mut u8, usize)>;
+
+    /// Do a buffered send of data. The buffer, including any memory regions,
+    /// is fully packed into the attached buffer, so it can be modified as soon
+    /// as this returns.
+    fn bsend<B: MessageBuffer + ?Sized>(&self, data: &B, dest: i32, tag: i32) -> Result<()>;
+
     /// Do a non-blocking recv of data from the source with the specified tag.
     unsafe fn irecv<B: MessageBuffer + ?Sized>(&self, data: &mut B, source: i32, tag: i32) -> Result<Self::Request>;
 
+    /// Receive a message from the source with the specified tag into a new
+    /// value built from the message itself, so the receiver doesn't need to
+    /// know its size or shape beforehand. Messages sent with a header (e.g.
+    /// with Framed) are shaped from the packed part in the header and their
+    /// memory regions are received directly into the value. Otherwise the
+    /// value is built from the probed size if possible, or from the whole
+    /// message received into a temporary buffer.
+    fn recv_owned<T: FromPacked>(&self, source: i32, tag: i32) -> Result<T>;
+
+    /// Receive a self-describing message, sent with Described, from the source
+    /// with the specified tag, returning its descriptor and raw data.
+    fn recv_described(&self, source: i32, tag: i32) -> Result<RawMessage>;
+
+    /// Create a persistent send request for the data, which is inactive until
+    /// started with start(). The memory regions of the buffer must not change
+    /// between starts.
+    unsafe fn send_init<B: MessageBuffer + ?Sized>(&self, data: &B, dest: i32, tag: i32) -> Result<Self::Request>;
+
+    /// Create a persistent receive request for the data (see send_init()).
+    unsafe fn recv_init<B: MessageBuffer + ?Sized>(&self, data: &mut B, source: i32, tag: i32) -> Result<Self::Request>;
+
+    /// Start an inactive persistent request.
+    unsafe fn start(&self, request: &Self::Request) -> Result<()>;
+
+    /// Start all of the persistent requests. If any of them is invalid or
+    /// already active, none are started.
+    unsafe fn startall(&self, requests: &[Self::Request]) -> Result<()>;
+
+    /// Free a persistent request once it's no longer needed. Active requests
+    /// must be completed first.
+    fn request_free(&self, request: Self::Request) -> Result<()>;
+
     /// Probe for an incoming message.
     fn probe(&self, source: Option<i32>, tag: i32) -> Result<ProbeResult>;
 
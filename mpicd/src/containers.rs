@@ -0,0 +1,663 @@
+//! MessageBuffer implementations for common containers.
+//!
+//! Containers are described by their Parts: metadata, such as lengths, Option
+//! tags and map keys, and the contiguous data of their elements. The packed
+//! part of a message holds the metadata followed by any small data blocks,
+//! while data blocks of at least CONTAINER_REGION_THRESHOLD bytes are sent and
+//! received directly as memory regions.
+//!
+//! The receiving container must already have the same shape as the sent one
+//! (the same lengths, Option variants and map keys). The metadata is only used
+//! to check this, failing the unpack if it doesn't match. Alternatively,
+//! containers implement FromPacked, reading the metadata at the start of a
+//! received message to build a new container of the sent shape, which is then
+//! unpacked into as usual.
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use crate::datatype::{
+    signature_of, unpack_from_slice, DatatypeError, DatatypeResult, FromPacked, MessageBuffer, MessageCount,
+    MessagePointer, PackMethod, PackedShape, PackedSize, UnpackMethod,
+};
+
+/// Minimum size of a contiguous data block for it to be sent as a memory
+/// region instead of being packed.
+pub const CONTAINER_REGION_THRESHOLD: usize = 2048;
+
+/// Collects the metadata and data blocks of a value.
+#[derive(Default)]
+pub struct PartsBuilder {
+    /// Packed metadata.
+    meta: Vec<u8>,
+
+    /// Data blocks in order, with adjacent blocks merged.
+    blocks: Vec<(*mut u8, usize)>,
+
+    /// Strings to check for valid UTF-8 after receiving.
+    strings: Vec<*mut String>,
+}
+
+impl PartsBuilder {
+    /// Add metadata bytes.
+    pub fn meta(&mut self, bytes: &[u8]) {
+        self.meta.extend_from_slice(bytes);
+    }
+
+    /// Add a length as metadata.
+    pub fn len(&mut self, len: usize) {
+        self.meta(&(len as u64).to_be_bytes());
+    }
+
+    /// Add a block of data to send. The memory must stay valid while the
+    /// value is being sent.
+    pub fn data(&mut self, ptr: *const u8, len: usize) {
+        self.data_mut(ptr as *mut u8, len);
+    }
+
+    /// Add a block of data to receive into. The memory must stay valid while
+    /// the value is being received.
+    pub fn data_mut(&mut self, ptr: *mut u8, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if let Some((last_ptr, last_len)) = self.blocks.last_mut() {
+            if last_ptr.wrapping_add(*last_len) == ptr {
+                *last_len += len;
+                return;
+            }
+        }
+        self.blocks.push((ptr, len));
+    }
+
+    /// Add the whole value as metadata, including its data.
+    fn meta_value<T: Parts + ?Sized>(&mut self, value: &T) {
+        let mut inner = PartsBuilder::default();
+        value.parts(&mut inner);
+        self.meta.extend_from_slice(&inner.meta);
+        for (ptr, len) in inner.blocks {
+            self.meta.extend_from_slice(unsafe { std::slice::from_raw_parts(ptr, len) });
+        }
+    }
+}
+
+/// Value that can be described as metadata and data blocks.
+pub trait Parts {
+    /// Add the metadata and data blocks of the value to send.
+    fn parts(&self, builder: &mut PartsBuilder);
+
+    /// Add the metadata and data blocks of the value to receive into. The
+    /// metadata must be the same as added by parts().
+    fn parts_mut(&mut self, builder: &mut PartsBuilder);
+
+    /// Add the parts of all elements of a slice, which plain data types can
+    /// do with a single block.
+    fn slice_parts(slice: &[Self], builder: &mut PartsBuilder)
+    where
+        Self: Sized,
+    {
+        for elem in slice {
+            elem.parts(builder);
+        }
+    }
+
+    /// Add the parts of all elements of a slice to receive into.
+    fn slice_parts_mut(slice: &mut [Self], builder: &mut PartsBuilder)
+    where
+        Self: Sized,
+    {
+        for elem in slice {
+            elem.parts_mut(builder);
+        }
+    }
+}
+
+/// Reads the metadata at the start of a packed message, in the order that it
+/// was added by a PartsBuilder.
+pub struct MetaReader<'a> {
+    /// Whole packed message.
+    data: &'a [u8],
+
+    /// Current position in the message.
+    pos: usize,
+}
+
+impl<'a> MetaReader<'a> {
+    /// Create a reader for the packed message.
+    pub fn new(data: &'a [u8]) -> MetaReader<'a> {
+        MetaReader { data, pos: 0 }
+    }
+
+    /// Read metadata bytes.
+    pub fn read_bytes(&mut self, len: usize) -> DatatypeResult<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(DatatypeError::UnpackError)?;
+        let bytes = self.data.get(self.pos..end).ok_or(DatatypeError::UnpackError)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Read a length. Every element takes up at least one byte of the
+    /// message, so lengths larger than the message are rejected before
+    /// anything gets allocated for them.
+    pub fn read_len(&mut self) -> DatatypeResult<usize> {
+        let bytes = self.read_bytes(std::mem::size_of::<u64>())?;
+        let len = u64::from_be_bytes(bytes.try_into().expect("length should be 8 bytes"));
+        if len > self.data.len() as u64 {
+            return Err(DatatypeError::UnpackError);
+        }
+        Ok(len as usize)
+    }
+
+    /// Read a whole value added as metadata, including its data.
+    fn meta_value<T: FromParts>(&mut self) -> DatatypeResult<T> {
+        let mut value = T::from_meta(self)?;
+        let mut builder = PartsBuilder::default();
+        value.parts_mut(&mut builder);
+        let mut result = Ok(());
+        for (ptr, len) in builder.blocks {
+            match self.read_bytes(len) {
+                Ok(bytes) => unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, len) },
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        // Never leave invalid UTF-8 behind, even if reading failed halfway.
+        if unsafe { clear_invalid_strings(&builder.strings) } {
+            result = Err(DatatypeError::UnpackError);
+        }
+        result.map(|_| value)
+    }
+}
+
+/// Value that can be built from its metadata alone, with the same shape as
+/// the value that was sent. The data is left zeroed, to be unpacked into.
+pub trait FromParts: Parts + Sized {
+    /// Build a value from the metadata read from the reader.
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self>;
+}
+
+macro_rules! impl_parts_primitive {
+    ($ty:ty) => {
+        impl Parts for $ty {
+            fn parts(&self, builder: &mut PartsBuilder) {
+                builder.data(self as *const $ty as *const u8, std::mem::size_of::<$ty>());
+            }
+
+            fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+                builder.data_mut(self as *mut $ty as *mut u8, std::mem::size_of::<$ty>());
+            }
+
+            fn slice_parts(slice: &[$ty], builder: &mut PartsBuilder) {
+                builder.data(slice.as_ptr() as *const u8, std::mem::size_of_val(slice));
+            }
+
+            fn slice_parts_mut(slice: &mut [$ty], builder: &mut PartsBuilder) {
+                builder.data_mut(slice.as_mut_ptr() as *mut u8, std::mem::size_of_val(slice));
+            }
+        }
+
+        impl FromParts for $ty {
+            fn from_meta(_reader: &mut MetaReader) -> DatatypeResult<Self> {
+                Ok(<$ty>::default())
+            }
+        }
+    };
+}
+
+impl_parts_primitive!(u8);
+impl_parts_primitive!(u16);
+impl_parts_primitive!(u32);
+impl_parts_primitive!(u64);
+impl_parts_primitive!(i8);
+impl_parts_primitive!(i16);
+impl_parts_primitive!(i32);
+impl_parts_primitive!(i64);
+impl_parts_primitive!(f32);
+impl_parts_primitive!(f64);
+
+/// Strings are received directly into their bytes, so they're recorded to be
+/// checked for valid UTF-8 (and cleared if invalid) once receiving is done.
+impl Parts for String {
+    fn parts(&self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        builder.data(self.as_ptr(), self.len());
+    }
+
+    fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        // The bytes are only modified through the raw pointer, and checked
+        // once receiving is done.
+        let bytes = unsafe { self.as_mut_vec() };
+        builder.data_mut(bytes.as_mut_ptr(), bytes.len());
+        builder.strings.push(self as *mut String);
+    }
+}
+
+impl<T: Parts> Parts for Vec<T> {
+    fn parts(&self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        T::slice_parts(self, builder);
+    }
+
+    fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        T::slice_parts_mut(self, builder);
+    }
+}
+
+impl<T: Parts> Parts for Box<[T]> {
+    fn parts(&self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        T::slice_parts(self, builder);
+    }
+
+    fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        T::slice_parts_mut(self, builder);
+    }
+}
+
+impl<T: Parts, const N: usize> Parts for [T; N] {
+    fn parts(&self, builder: &mut PartsBuilder) {
+        T::slice_parts(self, builder);
+    }
+
+    fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+        T::slice_parts_mut(self, builder);
+    }
+}
+
+impl<T: Parts> Parts for Option<T> {
+    fn parts(&self, builder: &mut PartsBuilder) {
+        match self {
+            Some(value) => {
+                builder.meta(&[1]);
+                value.parts(builder);
+            }
+            None => builder.meta(&[0]),
+        }
+    }
+
+    fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+        match self {
+            Some(value) => {
+                builder.meta(&[1]);
+                value.parts_mut(builder);
+            }
+            None => builder.meta(&[0]),
+        }
+    }
+}
+
+/// Map keys are sent as metadata, so that the receiver's map must have the
+/// same keys, and only the values are received into.
+impl<K: Parts + Ord, V: Parts> Parts for BTreeMap<K, V> {
+    fn parts(&self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        for (key, value) in self {
+            builder.meta_value(key);
+            value.parts(builder);
+        }
+    }
+
+    fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+        builder.len(self.len());
+        for (key, value) in self {
+            builder.meta_value(key);
+            value.parts_mut(builder);
+        }
+    }
+}
+
+/// Entries are sent in key order, since the iteration order of hash maps
+/// differs between processes.
+impl<K: Parts + Ord + Hash, V: Parts, S: BuildHasher> Parts for HashMap<K, V, S> {
+    fn parts(&self, builder: &mut PartsBuilder) {
+        let mut entries: Vec<(&K, &V)> = self.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        builder.len(entries.len());
+        for (key, value) in entries {
+            builder.meta_value(key);
+            value.parts(builder);
+        }
+    }
+
+    fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+        let mut entries: Vec<(&K, &mut V)> = self.iter_mut().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        builder.len(entries.len());
+        for (key, value) in entries {
+            builder.meta_value(key);
+            value.parts_mut(builder);
+        }
+    }
+}
+
+macro_rules! impl_parts_tuple {
+    ($($name:ident $index:tt),+) => {
+        impl<$($name: Parts),+> Parts for ($($name,)+) {
+            fn parts(&self, builder: &mut PartsBuilder) {
+                $(self.$index.parts(builder);)+
+            }
+
+            fn parts_mut(&mut self, builder: &mut PartsBuilder) {
+                $(self.$index.parts_mut(builder);)+
+            }
+        }
+    };
+}
+
+impl_parts_tuple!(A 0);
+impl_parts_tuple!(A 0, B 1);
+impl_parts_tuple!(A 0, B 1, C 2);
+impl_parts_tuple!(A 0, B 1, C 2, D 3);
+
+impl FromParts for String {
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+        String::from_utf8(vec![0; reader.read_len()?]).map_err(|_| DatatypeError::UnpackError)
+    }
+}
+
+impl<T: FromParts> FromParts for Vec<T> {
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+        let len = reader.read_len()?;
+        (0..len).map(|_| T::from_meta(reader)).collect()
+    }
+}
+
+impl<T: FromParts> FromParts for Box<[T]> {
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+        Vec::from_meta(reader).map(Vec::into_boxed_slice)
+    }
+}
+
+impl<T: FromParts, const N: usize> FromParts for [T; N] {
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+        let elems = (0..N).map(|_| T::from_meta(reader)).collect::<DatatypeResult<Vec<T>>>()?;
+        elems.try_into().map_err(|_| DatatypeError::UnpackError)
+    }
+}
+
+impl<T: FromParts> FromParts for Option<T> {
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+        match reader.read_bytes(1)?[0] {
+            0 => Ok(None),
+            1 => T::from_meta(reader).map(Some),
+            _ => Err(DatatypeError::UnpackError),
+        }
+    }
+}
+
+impl<K: FromParts + Ord, V: FromParts> FromParts for BTreeMap<K, V> {
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+        let len = reader.read_len()?;
+        let mut map = BTreeMap::new();
+        for _ in 0..len {
+            let key = reader.meta_value()?;
+            map.insert(key, V::from_meta(reader)?);
+        }
+        Ok(map)
+    }
+}
+
+impl<K, V, S> FromParts for HashMap<K, V, S>
+where
+    K: FromParts + Ord + Hash,
+    V: FromParts,
+    S: BuildHasher + Default,
+{
+    fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+        let len = reader.read_len()?;
+        let mut map = HashMap::default();
+        for _ in 0..len {
+            let key = reader.meta_value()?;
+            map.insert(key, V::from_meta(reader)?);
+        }
+        Ok(map)
+    }
+}
+
+macro_rules! impl_from_parts_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: FromParts),+> FromParts for ($($name,)+) {
+            fn from_meta(reader: &mut MetaReader) -> DatatypeResult<Self> {
+                Ok(($($name::from_meta(reader)?,)+))
+            }
+        }
+    };
+}
+
+impl_from_parts_tuple!(A);
+impl_from_parts_tuple!(A, B);
+impl_from_parts_tuple!(A, B, C);
+impl_from_parts_tuple!(A, B, C, D);
+
+/// Pack and unpack state for a value described by its Parts.
+struct PartsState {
+    /// Metadata of the value (what's expected when receiving).
+    meta: Vec<u8>,
+
+    /// Received metadata.
+    received_meta: Vec<u8>,
+
+    /// Small data blocks packed after the metadata.
+    packed: Vec<(*mut u8, usize)>,
+
+    /// Large data blocks sent as regions.
+    regions: Vec<(*mut u8, usize)>,
+
+    /// Strings to check after receiving.
+    strings: Vec<*mut String>,
+
+    /// Number of packed bytes received so far.
+    received: usize,
+}
+
+impl PartsState {
+    /// Create the state to send the value.
+    fn new<T: Parts + ?Sized>(value: &T) -> PartsState {
+        let mut builder = PartsBuilder::default();
+        value.parts(&mut builder);
+        PartsState::from_builder(builder)
+    }
+
+    /// Create the state to receive into the value.
+    fn new_mut<T: Parts + ?Sized>(value: &mut T) -> PartsState {
+        let mut builder = PartsBuilder::default();
+        value.parts_mut(&mut builder);
+        PartsState::from_builder(builder)
+    }
+
+    fn from_builder(builder: PartsBuilder) -> PartsState {
+        let (regions, packed) = builder
+            .blocks
+            .into_iter()
+            .partition(|(_, len)| *len >= CONTAINER_REGION_THRESHOLD);
+        PartsState {
+            received_meta: vec![0; builder.meta.len()],
+            meta: builder.meta,
+            packed,
+            regions,
+            strings: builder.strings,
+            received: 0,
+        }
+    }
+
+    fn total_packed(&self) -> usize {
+        self.meta.len() + self.packed.iter().map(|(_, len)| len).sum::<usize>()
+    }
+
+    /// Call f with the pointer, position in the packed data and length of
+    /// each piece overlapping [offset, offset + size), where the first block
+    /// is the metadata buffer.
+    unsafe fn for_each_piece<F: FnMut(*mut u8, usize, usize)>(
+        &self,
+        meta: *mut u8,
+        offset: usize,
+        size: usize,
+        mut f: F,
+    ) {
+        let end = offset + size;
+        let mut pos = 0;
+        let blocks = std::iter::once((meta, self.meta.len())).chain(self.packed.iter().copied());
+        for (ptr, len) in blocks {
+            let block_end = pos + len;
+            if block_end > offset && pos < end {
+                let start = offset.max(pos);
+                let stop = end.min(block_end);
+                f(ptr.add(start - pos), start - offset, stop - start);
+            }
+            if block_end >= end {
+                break;
+            }
+            pos = block_end;
+        }
+    }
+
+    /// Check the received metadata and strings.
+    unsafe fn check(&mut self) -> DatatypeResult<()> {
+        let mut result = if self.received_meta == self.meta {
+            Ok(())
+        } else {
+            Err(DatatypeError::UnpackError)
+        };
+        if clear_invalid_strings(&self.strings) {
+            result = Err(DatatypeError::UnpackError);
+        }
+        result
+    }
+}
+
+/// Check the strings that were received into, in case the receive failed or
+/// was never completed.
+impl Drop for PartsState {
+    fn drop(&mut self) {
+        unsafe { clear_invalid_strings(&self.strings) };
+    }
+}
+
+/// Clear any of the strings that don't hold valid UTF-8, returning true if
+/// there were any.
+unsafe fn clear_invalid_strings(strings: &[*mut String]) -> bool {
+    let mut invalid = false;
+    for s in strings {
+        let s = &mut **s;
+        if std::str::from_utf8(s.as_bytes()).is_err() {
+            s.as_mut_vec().clear();
+            invalid = true;
+        }
+    }
+    invalid
+}
+
+impl PackedSize for PartsState {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok(self.total_packed())
+    }
+}
+
+impl PackMethod for PartsState {
+    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        let total = self.total_packed();
+        if offset > total {
+            return Err(DatatypeError::PackError);
+        }
+        let used = dst_size.min(total - offset);
+        let meta = self.meta.as_mut_ptr();
+        self.for_each_piece(meta, offset, used, |src, pos, len| {
+            std::ptr::copy_nonoverlapping(src, dst.add(pos), len);
+        });
+        Ok(used)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        Ok(self.regions.iter().map(|(ptr, len)| (*ptr as *const u8, *len)).collect())
+    }
+}
+
+impl UnpackMethod for PartsState {
+    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
+        let total = self.total_packed();
+        if offset + src_size > total {
+            return Err(DatatypeError::UnpackError);
+        }
+        let meta = self.received_meta.as_mut_ptr();
+        self.for_each_piece(meta, offset, src_size, |dst, pos, len| {
+            std::ptr::copy_nonoverlapping(src.add(pos), dst, len);
+        });
+        self.received += src_size;
+        if self.received == total {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
+        Ok(self.regions.clone())
+    }
+}
+
+macro_rules! impl_buffer_parts {
+    ([$($generics:tt)*] $ty:ty $(where $($bounds:tt)*)?) => {
+        impl<$($generics)*> MessageCount for $ty $(where $($bounds)*)? {
+            fn count(&self) -> usize {
+                1
+            }
+        }
+
+        impl<$($generics)*> MessagePointer for $ty $(where $($bounds)*)? {
+            fn ptr(&self) -> *const u8 {
+                self as *const Self as *const u8
+            }
+
+            fn ptr_mut(&mut self) -> *mut u8 {
+                self as *mut Self as *mut u8
+            }
+        }
+
+        impl<$($generics)*> MessageBuffer for $ty $(where $($bounds)*)? {
+            unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
+                Some(Ok(Box::new(PartsState::new(self))))
+            }
+
+            unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
+                Some(Ok(Box::new(PartsState::new_mut(self))))
+            }
+
+            fn signature(&self) -> Option<u64> {
+                Some(signature_of::<Self>())
+            }
+        }
+
+        /// The metadata is at the start of the packed part, so the value can
+        /// be shaped before unpacking the whole message into it.
+        impl<$($generics)*> FromPacked for $ty where $ty: FromParts, $($($bounds)*)? {
+            fn from_packed(packed: &[u8]) -> DatatypeResult<Self> {
+                let mut value = <$ty>::from_meta(&mut MetaReader::new(packed))?;
+                unsafe { unpack_from_slice(&mut value, packed)? };
+                Ok(value)
+            }
+
+            fn from_header(_shape: &PackedShape, packed: &[u8]) -> DatatypeResult<Self> {
+                <$ty>::from_meta(&mut MetaReader::new(packed))
+            }
+
+            fn type_signature() -> Option<u64> {
+                Some(signature_of::<Self>())
+            }
+        }
+    };
+}
+
+impl_buffer_parts!([T: Parts] Vec<Vec<T>>);
+impl_buffer_parts!([] Vec<String>);
+impl_buffer_parts!([] String);
+impl_buffer_parts!([T: Parts] Box<[T]>);
+impl_buffer_parts!([T: Parts, const N: usize] [T; N]);
+impl_buffer_parts!([K: Parts + Ord, V: Parts] BTreeMap<K, V>);
+impl_buffer_parts!([K: Parts + Ord + Hash, V: Parts, S: BuildHasher] HashMap<K, V, S>);
+impl_buffer_parts!([T: Parts] Option<T>);
+impl_buffer_parts!([A: Parts] (A,));
+impl_buffer_parts!([A: Parts, B: Parts] (A, B));
+impl_buffer_parts!([A: Parts, B: Parts, C: Parts] (A, B, C));
+impl_buffer_parts!([A: Parts, B: Parts, C: Parts, D: Parts] (A, B, C, D));
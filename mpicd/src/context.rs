@@ -1,16 +1,42 @@
 //! Context handle code for an MPI application.
 use crate::{
+    am::{AmHandler, AmSendMessage},
+    barrier::{self, BarrierAlgorithm},
+    bsend::BsendBuffer,
+    collective,
     communicator::{self, Communicator},
-    datatype::MessageBuffer,
-    message::{PackSendMessage, PackRecvMessage, ContiguousSendMessage, ContiguousRecvMessage},
-    request::{encode_tag, decode_tag, BARRIER_TAG, PROBE_TAG_MASK, TAG_MASK},
+    datatype::{DatatypeError, FromPacked, MessageBuffer, PackedShape},
+    descriptor::RawMessage,
+    future::RequestFuture,
+    op::ReduceOp,
+    message::{
+        pack_copy, probe_header, send_message, recv_message, recv_message_with_header, PackSendMessage,
+        PersistentMessage,
+    },
+    nbc,
+    rma::Window,
+    scope::Scope,
+    signature,
+    stream::Stream,
+    request::{encode_tag, decode_tag, PROBE_TAG_MASK, TAG_MASK},
     Handle, Status,
 };
 use mpicd_ucx_sys::{ucp_tag_probe_nb, ucp_worker_progress};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::mem::MaybeUninit;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::rc::Rc;
 
+/// User message found by Context::probe_owned().
+enum OwnedProbe {
+    /// The message was sent with a header, which has been received, and its
+    /// data has the given tag.
+    Header(Vec<u8>, u64),
+
+    /// The message has no header and holds the given number of bytes.
+    Data(usize),
+}
+
 /// Context handle.
 ///
 /// This implements Communicator and also acts as MPI_COMM_WORLD would in a
@@ -18,15 +44,34 @@ use std::rc::Rc;
 pub struct Context {
     /// Handle with ucx info.
     handle: Rc<RefCell<Handle>>,
+
+    /// Algorithm used for barriers.
+    barrier_algorithm: BarrierAlgorithm,
+
+    /// Sequence number of the next non-blocking collective.
+    collective_seq: Cell<i32>,
+
+    /// Buffer attached for buffered sends.
+    bsend_buffer: RefCell<Option<BsendBuffer>>,
 }
 
 impl Context {
     /// Create a new context.
     pub(crate) fn new(handle: Rc<RefCell<Handle>>) -> Context {
-        Context { handle }
+        Context {
+            handle,
+            barrier_algorithm: BarrierAlgorithm::from_env(),
+            collective_seq: Cell::new(0),
+            bsend_buffer: RefCell::new(None),
+        }
     }
 
-    unsafe fn internal_isend<B: MessageBuffer + ?Sized>(
+    /// Select the algorithm to use for barriers (mainly for benchmarking).
+    pub fn set_barrier_algorithm(&mut self, algorithm: BarrierAlgorithm) {
+        self.barrier_algorithm = algorithm;
+    }
+
+    pub(crate) unsafe fn internal_isend<B: MessageBuffer + ?Sized>(
         &self,
         data: &B,
         dest: i32,
@@ -35,32 +80,174 @@ impl Context {
         let mut handle = self.handle.borrow_mut();
         assert!(dest < (handle.system.size as i32));
 
-        if let Some(packer) = data.pack() {
-            let packer = packer.expect("failed to initialize PackState");
-            let request = PackSendMessage::new(packer, dest, tag);
-            Ok(handle.add_message(request))
-        } else {
-            let request = ContiguousSendMessage::new(data.ptr() as *const _, data.count(), dest, tag);
-            Ok(handle.add_message(request))
-        }
+        let message = send_message(data, dest, tag, false).map_err(communicator::Error::Datatype)?;
+        Ok(handle.add_message(message))
     }
 
-    unsafe fn internal_irecv<B: MessageBuffer + ?Sized>(
+    pub(crate) unsafe fn internal_irecv<B: MessageBuffer + ?Sized>(
         &self,
         data: &mut B,
         tag: u64,
     ) -> communicator::Result<<Self as Communicator>::Request> {
         let mut handle = self.handle.borrow_mut();
+        let message = recv_message(data, tag).map_err(communicator::Error::Datatype)?;
+        Ok(handle.add_message(message))
+    }
+
+    /// Wait for a user message with the encoded tag, receiving its header if
+    /// it was sent with one.
+    fn probe_owned(&self, tag: u64) -> communicator::Result<OwnedProbe> {
+        let handle = self.handle.borrow();
+        let worker = handle.system.worker;
+        let mut info = MaybeUninit::uninit();
+        unsafe {
+            loop {
+                if let Some((header, req, data_tag)) = probe_header(worker, tag) {
+                    loop {
+                        match req.status() {
+                            Status::InProgress => {
+                                ucp_worker_progress(worker);
+                            }
+                            Status::Complete => return Ok(OwnedProbe::Header(header, data_tag)),
+                            Status::Error(_) => return Err(communicator::Error::InternalError),
+                        }
+                    }
+                }
+                if !ucp_tag_probe_nb(worker, tag, TAG_MASK, 0, info.as_mut_ptr()).is_null() {
+                    return Ok(OwnedProbe::Data(info.assume_init().length));
+                }
+                ucp_worker_progress(worker);
+            }
+        }
+    }
 
-        if let Some(unpack_method) = data.unpack() {
-            let unpack_method = unpack_method
-                .expect("failed to initialize pack method");
-            let request = PackRecvMessage::new(unpack_method, tag);
-            Ok(handle.add_message(request))
-        } else {
-            let request = ContiguousRecvMessage::new(data.ptr_mut(), data.count(), tag);
-            Ok(handle.add_message(request))
+    /// Receive a message of the given size with the encoded tag into a scratch
+    /// buffer, so that a rejected message doesn't get matched by a later
+    /// receive.
+    fn recv_discard(&self, tag: u64, size: usize) -> communicator::Result<()> {
+        let mut scratch = vec![0u8; size];
+        unsafe {
+            let req = self.internal_irecv(&mut scratch[..], tag)?;
+            collective::wait(self, &[req])
+        }
+    }
+
+    /// Probe for a message and receive all of its bytes.
+    fn recv_probed(&self, source: i32, tag: i32) -> communicator::Result<Vec<u8>> {
+        let probe = self.probe(Some(source), tag)?;
+        let mut data = vec![0u8; probe.size];
+        unsafe {
+            let req = self.irecv(&mut data[..], source, tag)?;
+            collective::wait(self, &[req])?;
         }
+        Ok(data)
+    }
+
+    /// Get the sequence number for the next non-blocking collective.
+    pub(crate) fn next_collective_seq(&self) -> i32 {
+        let seq = self.collective_seq.get();
+        self.collective_seq.set((seq + 1) & nbc::MAX_SEQ);
+        seq
+    }
+
+    /// Add a non-blocking collective, returning its request.
+    fn add_collective(&self, message: nbc::CollectiveMessage) -> usize {
+        self.handle.borrow_mut().add_message(Box::new(message))
+    }
+
+    /// Return a future completing with the status of the request, for use
+    /// from async code instead of waitall().
+    pub fn wait_async(&self, req: <Self as Communicator>::Request) -> RequestFuture {
+        RequestFuture::new(Rc::clone(&self.handle), req)
+    }
+
+    /// Create a scope for safe non-blocking requests, which borrow their
+    /// buffers until the end of the scope. All requests that haven't been
+    /// waited on are completed before this returns.
+    ///
+    /// Panics if one of these remaining requests fails.
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope::new(self);
+        let result = catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // Buffers may only be released once everything has completed.
+        let wait_result = scope.wait_all();
+        match result {
+            Ok(value) => {
+                wait_result.expect("failed to wait for scoped requests");
+                value
+            }
+            Err(err) => resume_unwind(err),
+        }
+    }
+
+    /// Register a handler for active messages with the id, replacing any
+    /// previous handler.
+    pub fn register_am_handler<H: AmHandler + 'static>(&self, id: u16, handler: H) -> communicator::Result<()> {
+        let mut handle = self.handle.borrow_mut();
+        let worker = handle.system.worker;
+        unsafe { handle.am.register(worker, id, Box::new(handler)) }
+    }
+
+    /// Send an active message with the header and data to the handler
+    /// registered under id on the destination. The returned request can be
+    /// waited on with waitall().
+    pub unsafe fn am_send<B: MessageBuffer + ?Sized>(
+        &self,
+        dest: i32,
+        id: u16,
+        header: &[u8],
+        data: &B,
+    ) -> communicator::Result<<Self as Communicator>::Request> {
+        let mut handle = self.handle.borrow_mut();
+        assert!(dest < (handle.system.size as i32));
+
+        let rank = handle.system.rank as i32;
+        let message = AmSendMessage::new(data, rank, dest, id, header).map_err(communicator::Error::Datatype)?;
+        Ok(handle.add_message(Box::new(message)))
+    }
+
+    /// Collectively create an RMA window over the local memory, which must
+    /// stay valid until the window is dropped.
+    pub unsafe fn win_create(&self, base: *mut u8, size: usize) -> communicator::Result<Window> {
+        Window::create(self, Rc::clone(&self.handle), base, size)
+    }
+
+    /// Open a byte stream to the peer process. Data written to it is read from
+    /// the stream to this process opened on the peer.
+    pub fn stream(&self, peer: i32) -> communicator::Result<Stream> {
+        let size = self.handle.borrow().system.size;
+        let peer = usize::try_from(peer)
+            .ok()
+            .filter(|peer| *peer < size)
+            .ok_or(communicator::Error::InternalError)?;
+        Ok(Stream::new(Rc::clone(&self.handle), peer))
+    }
+
+    /// Progress the worker and call the handlers for all incoming active
+    /// messages that are ready, returning the number of messages handled.
+    pub fn am_progress(&self) -> usize {
+        let ready = {
+            let mut handle = self.handle.borrow_mut();
+            unsafe {
+                ucp_worker_progress(handle.system.worker);
+                handle.am.take_ready()
+            }
+        };
+
+        let count = ready.len();
+        for (id, msg) in ready {
+            // Handlers are removed while called, so that they can use the
+            // context themselves.
+            let handler = self.handle.borrow_mut().am.take_handler(id);
+            if let Some(mut handler) = handler {
+                handler.handle(&msg);
+                self.handle.borrow_mut().am.restore_handler(id, handler);
+            }
+        }
+        count
     }
 }
 
@@ -77,34 +264,200 @@ impl Communicator for Context {
 
     /// Barrier operation on all processes.
     ///
-    /// Uses a simple O(n) algorithm.
+    /// Uses the algorithm selected with set_barrier_algorithm().
     fn barrier(&self) {
         unsafe {
-            let size = self.handle.borrow().system.size as i32;
-            let rank = self.handle.borrow().system.rank as i32;
-            if rank == 0 {
-                let mut buf = vec![0; 1];
-                let mut reqs = vec![];
-                for i in 1..size {
-                    reqs.push(self.internal_isend(&buf[..], i, encode_tag(BARRIER_TAG, 0, 0)).expect("failed to get send request"));
-                }
-                self.waitall(&reqs).expect("failed to wait for send requests");
-
-                reqs.clear();
-                for i in 1..size {
-                    reqs.push(self.internal_irecv(&mut buf[..], encode_tag(BARRIER_TAG, i, 0)).expect("failed to get recv request"));
+            match self.barrier_algorithm {
+                BarrierAlgorithm::Linear => barrier::linear(self),
+                BarrierAlgorithm::Dissemination => {
+                    let handle = self.handle.borrow();
+                    if let Status::Error(err) = barrier::dissemination(&handle.system) {
+                        panic!("failed to complete barrier: {}", err);
+                    }
                 }
-                self.waitall(&reqs).expect("failed to wait for recv requests");
-            } else {
-                let mut buf = vec![0; 1];
-                let req = self.internal_irecv(&mut buf[..], encode_tag(BARRIER_TAG, 0, 0)).expect("failed to get recv request");
-                self.waitall(&[req]).expect("failed to wait for recv request");
-                let req = self.internal_isend(&buf[..], 0, encode_tag(BARRIER_TAG, rank, 0)).expect("failed to get send request");
-                self.waitall(&[req]).expect("failed to wait for send request");
             }
         }
     }
 
+    fn bcast<B: MessageBuffer + ?Sized>(&self, data: &mut B, root: i32) -> communicator::Result<()> {
+        unsafe { collective::bcast(self, data, root) }
+    }
+
+    fn gather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+        root: i32,
+    ) -> communicator::Result<()> {
+        unsafe { collective::gather(self, sbuf, rbufs, root) }
+    }
+
+    fn scatter<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbuf: &mut R,
+        root: i32,
+    ) -> communicator::Result<()> {
+        unsafe { collective::scatter(self, sbufs, rbuf, root) }
+    }
+
+    fn allgather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+    ) -> communicator::Result<()> {
+        unsafe { collective::allgather(self, sbuf, rbufs) }
+    }
+
+    fn alltoall<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbufs: &mut [&mut R],
+    ) -> communicator::Result<()> {
+        unsafe { collective::alltoall(self, sbufs, rbufs) }
+    }
+
+    fn gatherv<S, R, F>(&self, sbuf: &S, root: i32, recv: F) -> communicator::Result<Vec<R>>
+    where
+        S: MessageBuffer + ?Sized,
+        R: MessageBuffer,
+        F: FnMut(i32, &PackedShape) -> R,
+    {
+        unsafe { collective::gatherv(self, sbuf, root, recv) }
+    }
+
+    fn allgatherv<S, R, F>(&self, sbuf: &S, recv: F) -> communicator::Result<Vec<R>>
+    where
+        S: MessageBuffer + ?Sized,
+        R: MessageBuffer,
+        F: FnMut(i32, &PackedShape) -> R,
+    {
+        unsafe { collective::allgatherv(self, sbuf, recv) }
+    }
+
+    fn alltoallv<S, R, F>(&self, sbufs: &[&S], recv: F) -> communicator::Result<Vec<R>>
+    where
+        S: MessageBuffer + ?Sized,
+        R: MessageBuffer,
+        F: FnMut(i32, &PackedShape) -> R,
+    {
+        unsafe { collective::alltoallv(self, sbufs, recv) }
+    }
+
+    fn reduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O, root: i32) -> communicator::Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        unsafe { collective::reduce(self, sbuf, rbuf, op, root) }
+    }
+
+    fn allreduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> communicator::Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        unsafe { collective::allreduce(self, sbuf, rbuf, op) }
+    }
+
+    fn scan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> communicator::Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        unsafe { collective::scan(self, sbuf, rbuf, op) }
+    }
+
+    fn exscan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> communicator::Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        unsafe { collective::exscan(self, sbuf, rbuf, op) }
+    }
+
+    unsafe fn ibarrier(&self) -> communicator::Result<Self::Request> {
+        Ok(self.add_collective(nbc::ibarrier(self)))
+    }
+
+    unsafe fn ibcast<B: MessageBuffer + ?Sized>(&self, data: &mut B, root: i32) -> communicator::Result<Self::Request> {
+        Ok(self.add_collective(nbc::ibcast(self, data, root)?))
+    }
+
+    unsafe fn igather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+        root: i32,
+    ) -> communicator::Result<Self::Request> {
+        Ok(self.add_collective(nbc::igather(self, sbuf, rbufs, root)?))
+    }
+
+    unsafe fn iscatter<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbuf: &mut R,
+        root: i32,
+    ) -> communicator::Result<Self::Request> {
+        Ok(self.add_collective(nbc::iscatter(self, sbufs, rbuf, root)?))
+    }
+
+    unsafe fn iallgather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        rbufs: &mut [&mut R],
+    ) -> communicator::Result<Self::Request> {
+        Ok(self.add_collective(nbc::iallgather(self, sbuf, rbufs)?))
+    }
+
+    unsafe fn ialltoall<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbufs: &[&S],
+        rbufs: &mut [&mut R],
+    ) -> communicator::Result<Self::Request> {
+        Ok(self.add_collective(nbc::ialltoall(self, sbufs, rbufs)?))
+    }
+
+    unsafe fn ireduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O, root: i32) -> communicator::Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        Ok(self.add_collective(nbc::ireduce(self, sbuf, rbuf, op, root)?))
+    }
+
+    unsafe fn iallreduce<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> communicator::Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        Ok(self.add_collective(nbc::iallreduce(self, sbuf, rbuf, op)?))
+    }
+
+    unsafe fn iscan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> communicator::Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        Ok(self.add_collective(nbc::iscan(self, sbuf, rbuf, op)?))
+    }
+
+    unsafe fn iexscan<B, O>(&self, sbuf: &B, rbuf: &mut B, op: &O) -> communicator::Result<Self::Request>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: std::borrow::BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        Ok(self.add_collective(nbc::iexscan(self, sbuf, rbuf, op)?))
+    }
+
     unsafe fn isend<B: MessageBuffer + ?Sized>(
         &self,
         data: &B,
@@ -115,6 +468,93 @@ impl Communicator for Context {
         self.internal_isend(data, dest, encode_tag(0, rank, tag))
     }
 
+    unsafe fn issend<B: MessageBuffer + ?Sized>(
+        &self,
+        data: &B,
+        dest: i32,
+        tag: i32,
+    ) -> communicator::Result<Self::Request> {
+        let mut handle = self.handle.borrow_mut();
+        assert!(dest < (handle.system.size as i32));
+        let rank = handle.system.rank as i32;
+
+        let message = send_message(data, dest, encode_tag(0, rank, tag), true)
+            .map_err(communicator::Error::Datatype)?;
+        Ok(handle.add_message(message))
+    }
+
+    fn sendrecv<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+        &self,
+        sbuf: &S,
+        dest: i32,
+        sendtag: i32,
+        rbuf: &mut R,
+        source: i32,
+        recvtag: i32,
+    ) -> communicator::Result<()> {
+        unsafe {
+            let rreq = self.irecv(rbuf, source, recvtag)?;
+            let sreq = self.isend(sbuf, dest, sendtag)?;
+            collective::wait(self, &[rreq, sreq])
+        }
+    }
+
+    fn sendrecv_replace<B: MessageBuffer + ?Sized>(
+        &self,
+        data: &mut B,
+        dest: i32,
+        sendtag: i32,
+        source: i32,
+        recvtag: i32,
+    ) -> communicator::Result<()> {
+        unsafe {
+            // The receiver sees the same message as if the buffer was sent
+            // directly.
+            let signature = signature::user_signature(data);
+            let (_copy, pack_method) = pack_copy(data, signature).map_err(communicator::Error::Datatype)?;
+            let rreq = self.irecv(data, source, recvtag)?;
+            let sreq = {
+                let mut handle = self.handle.borrow_mut();
+                assert!(dest < (handle.system.size as i32));
+                let tag = encode_tag(0, handle.system.rank as i32, sendtag);
+                let message = PackSendMessage::new(Box::new(pack_method), dest, tag, false, signature);
+                handle.add_message(Box::new(message))
+            };
+            collective::wait(self, &[rreq, sreq])
+        }
+    }
+
+    unsafe fn buffer_attach(&self, ptr: *mut u8, size: usize) -> communicator::Result<()> {
+        let mut bsend_buffer = self.bsend_buffer.borrow_mut();
+        if bsend_buffer.is_some() {
+            return Err(communicator::Error::InvalidBuffer);
+        }
+        let _ = bsend_buffer.insert(BsendBuffer::new(ptr, size));
+        Ok(())
+    }
+
+    fn buffer_detach(&self) -> communicator::Result<(*mut u8, usize)> {
+        let mut bsend_buffer = self.bsend_buffer.borrow_mut();
+        let buffer = bsend_buffer.as_mut().ok_or(communicator::Error::InvalidBuffer)?;
+        let mut handle = self.handle.borrow_mut();
+        while !buffer.is_empty() {
+            unsafe { buffer.reclaim(&mut handle) };
+        }
+        let memory = buffer.memory();
+        let _ = bsend_buffer.take();
+        Ok(memory)
+    }
+
+    fn bsend<B: MessageBuffer + ?Sized>(&self, data: &B, dest: i32, tag: i32) -> communicator::Result<()> {
+        let mut bsend_buffer = self.bsend_buffer.borrow_mut();
+        let buffer = bsend_buffer.as_mut().ok_or(communicator::Error::BufferExhausted)?;
+        let mut handle = self.handle.borrow_mut();
+        assert!(dest < (handle.system.size as i32));
+        let rank = handle.system.rank as i32;
+
+        unsafe { buffer.send(&mut handle, data, dest, encode_tag(0, rank, tag)) }
+    }
+
     unsafe fn irecv<B: MessageBuffer + ?Sized>(
         &self,
         data: &mut B,
@@ -125,6 +565,123 @@ impl Communicator for Context {
         self.internal_irecv(data, encode_tag(0, source, tag))
     }
 
+    fn recv_owned<T: FromPacked>(&self, source: i32, tag: i32) -> communicator::Result<T> {
+        assert!(source < (self.handle.borrow().system.size as i32));
+        let tag = encode_tag(0, source, tag);
+        let signature = if cfg!(debug_assertions) { T::type_signature() } else { None };
+        let datatype_err = communicator::Error::Datatype;
+        match self.probe_owned(tag)? {
+            OwnedProbe::Header(header, data_tag) => {
+                // Shape the value from the packed part, then receive the
+                // memory regions directly into it.
+                let (shape, header_signature, packed) = PackedShape::from_header(&header).map_err(datatype_err)?;
+                let value = if header_signature == signature {
+                    T::from_header(&shape, packed)
+                } else {
+                    Err(DatatypeError::DatatypeMismatch)
+                };
+                let mut value = match value {
+                    Ok(value) => value,
+                    Err(err) => {
+                        self.recv_discard(data_tag, shape.region_lens.iter().sum())?;
+                        return Err(datatype_err(err));
+                    }
+                };
+                unsafe {
+                    let message = recv_message_with_header(&mut value, tag, header, data_tag).map_err(datatype_err)?;
+                    let req = self.handle.borrow_mut().add_message(message);
+                    collective::wait(self, &[req])?;
+                }
+                Ok(value)
+            }
+            OwnedProbe::Data(size) => {
+                if signature.is_some() {
+                    // Signatures are always sent with a header, so this came
+                    // from a buffer of another type.
+                    self.recv_discard(tag, size)?;
+                    return Err(datatype_err(DatatypeError::DatatypeMismatch));
+                }
+                if let Some(value) = T::with_size(size) {
+                    let mut value = value.map_err(datatype_err)?;
+                    unsafe {
+                        let req = self.internal_irecv(&mut value, tag)?;
+                        collective::wait(self, &[req])?;
+                    }
+                    return Ok(value);
+                }
+                // The value can only be shaped from the whole message.
+                let mut packed = vec![0u8; size];
+                unsafe {
+                    let req = self.internal_irecv(&mut packed[..], tag)?;
+                    collective::wait(self, &[req])?;
+                }
+                T::from_packed(&packed).map_err(datatype_err)
+            }
+        }
+    }
+
+    fn recv_described(&self, source: i32, tag: i32) -> communicator::Result<RawMessage> {
+        let data = self.recv_probed(source, tag)?;
+        RawMessage::from_bytes(data).map_err(communicator::Error::Datatype)
+    }
+
+    unsafe fn send_init<B: MessageBuffer + ?Sized>(
+        &self,
+        data: &B,
+        dest: i32,
+        tag: i32,
+    ) -> communicator::Result<Self::Request> {
+        let mut handle = self.handle.borrow_mut();
+        assert!(dest < (handle.system.size as i32));
+        let rank = handle.system.rank as i32;
+
+        let message = send_message(data, dest, encode_tag(0, rank, tag), false)
+            .map_err(communicator::Error::Datatype)?;
+        Ok(handle.add_message(Box::new(PersistentMessage::new(message))))
+    }
+
+    unsafe fn recv_init<B: MessageBuffer + ?Sized>(
+        &self,
+        data: &mut B,
+        source: i32,
+        tag: i32,
+    ) -> communicator::Result<Self::Request> {
+        let mut handle = self.handle.borrow_mut();
+        assert!(source < (handle.system.size as i32));
+
+        let message = recv_message(data, encode_tag(0, source, tag))
+            .map_err(communicator::Error::Datatype)?;
+        Ok(handle.add_message(Box::new(PersistentMessage::new(message))))
+    }
+
+    unsafe fn start(&self, request: &Self::Request) -> communicator::Result<()> {
+        self.startall(std::slice::from_ref(request))
+    }
+
+    unsafe fn startall(&self, requests: &[Self::Request]) -> communicator::Result<()> {
+        let mut handle = self.handle.borrow_mut();
+        // Check every request first, so that none are started on failure.
+        let mut seen = std::collections::HashSet::new();
+        if !requests.iter().all(|req| seen.insert(*req) && handle.inactive_persistent(*req)) {
+            return Err(communicator::Error::InvalidRequest);
+        }
+        for req in requests {
+            let started = handle.start_message(*req);
+            assert!(started);
+        }
+        Ok(())
+    }
+
+    fn request_free(&self, request: Self::Request) -> communicator::Result<()> {
+        let mut handle = self.handle.borrow_mut();
+        // Active requests are still being read or written by UCX.
+        if !handle.inactive_persistent(request) {
+            return Err(communicator::Error::InvalidRequest);
+        }
+        handle.remove_message(request);
+        Ok(())
+    }
+
     fn probe(&self, source: Option<i32>, tag: i32) -> communicator::Result<communicator::ProbeResult> {
         unsafe {
             let mut info = MaybeUninit::uninit();
@@ -137,7 +694,7 @@ impl Communicator for Context {
 
             loop {
                 let result = ucp_tag_probe_nb(handle.system.worker, tag, tag_mask, 0, info.as_mut_ptr());
-                if result != std::ptr::null_mut() {
+                if !result.is_null() {
                     let info = info.assume_init();
                     let (_, source, _) = decode_tag(info.sender_tag);
                     return Ok(communicator::ProbeResult {
@@ -170,7 +727,7 @@ impl Communicator for Context {
                     Status::InProgress => (),
                     status => {
                         statuses[i] = status;
-                        handle.remove_message(*req);
+                        handle.complete_message(*req);
                         complete += 1;
                     }
                 }
@@ -1,3 +1,9 @@
+pub use mpicd_derive::MessageBuffer;
+pub use crate::containers::{FromParts, MetaReader, Parts, PartsBuilder, CONTAINER_REGION_THRESHOLD};
+pub use crate::framing::Framed;
+pub use crate::layout::{Layout, LayoutBuffer, LAYOUT_REGION_THRESHOLD};
+pub use crate::signature::{signature_hash, signature_of};
+
 #[derive(Copy, Clone, Debug)]
 pub enum DatatypeError {
     PackError,
@@ -5,6 +11,7 @@ pub enum DatatypeError {
     PackedSizeError,
     StateError,
     RegionError,
+    DatatypeMismatch,
 }
 
 pub type DatatypeResult<T> = std::result::Result<T, DatatypeError>;
@@ -38,6 +45,13 @@ pub trait MessageBuffer: MessageCount + MessagePointer {
     unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
         None
     }
+
+    /// Return a hash of the type's signature, if it has one. In debug builds
+    /// this is sent in the header of point-to-point messages and checked by
+    /// the receiver before the data arrives.
+    fn signature(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub trait PackMethod: PackedSize {
@@ -46,6 +60,12 @@ pub trait PackMethod: PackedSize {
 
     /// If possible, return memory regions that can be sent directly.
     unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>>;
+
+    /// Return true if the message should be preceded by a header holding its
+    /// shape. The receiving type must also use a header.
+    fn header(&self) -> bool {
+        false
+    }
 }
 
 pub trait UnpackMethod: PackedSize {
@@ -54,6 +74,140 @@ pub trait UnpackMethod: PackedSize {
 
     /// If possible, return memory regions that can be received into.
     unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>>;
+
+    /// Return true if the message is expected to be preceded by a header
+    /// holding its shape (see PackMethod::header()).
+    fn header(&self) -> bool {
+        false
+    }
+
+    /// Prepare to receive a message of the sender's shape, as read from the
+    /// header, before any of its data arrives. This may resize the buffer to
+    /// fit or fail to reject the message. By default the shape must match the
+    /// packed size and memory regions exactly.
+    unsafe fn prepare(&mut self, shape: &PackedShape) -> DatatypeResult<()> {
+        let region_lens = self.memory_regions()?.into_iter().map(|(_, len)| len);
+        if self.packed_size()? == shape.packed_size && region_lens.eq(shape.region_lens.iter().copied()) {
+            Ok(())
+        } else {
+            Err(DatatypeError::UnpackError)
+        }
+    }
+}
+
+/// Shape of a packed buffer: the size of the packed part and the length of
+/// each memory region.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackedShape {
+    /// Size of the packed part in bytes.
+    pub packed_size: usize,
+
+    /// Length of each memory region in bytes.
+    pub region_lens: Vec<usize>,
+}
+
+impl PackedShape {
+    /// Return the total number of bytes, including memory regions.
+    pub fn total_size(&self) -> usize {
+        self.packed_size + self.region_lens.iter().sum::<usize>()
+    }
+}
+
+/// Pack the whole buffer into a contiguous byte vector, with the packed part
+/// first, followed by the contents of each memory region in order.
+pub unsafe fn pack_to_vec<B: MessageBuffer + ?Sized>(data: &B) -> DatatypeResult<Vec<u8>> {
+    pack_with_shape(data).map(|(_, packed)| packed)
+}
+
+/// Pack the whole buffer like pack_to_vec(), also returning its shape.
+pub unsafe fn pack_with_shape<B: MessageBuffer + ?Sized>(data: &B) -> DatatypeResult<(PackedShape, Vec<u8>)> {
+    if let Some(pack_method) = data.pack() {
+        let mut pack_method = pack_method?;
+        let packed_size = pack_method.packed_size()?;
+        let mut packed = vec![0; packed_size];
+        if packed_size > 0 {
+            let used = pack_method.pack(0, packed.as_mut_ptr(), packed_size)?;
+            if used != packed_size {
+                return Err(DatatypeError::PackError);
+            }
+        }
+        let mut region_lens = vec![];
+        for (ptr, len) in pack_method.memory_regions()? {
+            packed.extend_from_slice(std::slice::from_raw_parts(ptr, len));
+            region_lens.push(len);
+        }
+        Ok((PackedShape { packed_size, region_lens }, packed))
+    } else {
+        let packed = std::slice::from_raw_parts(data.ptr(), data.count()).to_vec();
+        Ok((PackedShape { packed_size: packed.len(), region_lens: vec![] }, packed))
+    }
+}
+
+/// Unpack a contiguous byte buffer, as produced by pack_to_vec(), into the
+/// buffer.
+pub unsafe fn unpack_from_slice<B: MessageBuffer + ?Sized>(data: &mut B, packed: &[u8]) -> DatatypeResult<()> {
+    if let Some(unpack_method) = data.unpack() {
+        let mut unpack_method = unpack_method?;
+        let packed_size = unpack_method.packed_size()?;
+        let regions = unpack_method.memory_regions()?;
+        let total = packed_size + regions.iter().map(|(_, len)| len).sum::<usize>();
+        if total != packed.len() {
+            return Err(DatatypeError::UnpackError);
+        }
+        if packed_size > 0 {
+            unpack_method.unpack(0, packed.as_ptr(), packed_size)?;
+        }
+        let mut pos = packed_size;
+        for (ptr, len) in regions {
+            std::ptr::copy_nonoverlapping(packed[pos..].as_ptr(), ptr, len);
+            pos += len;
+        }
+        Ok(())
+    } else {
+        if data.count() != packed.len() {
+            return Err(DatatypeError::UnpackError);
+        }
+        std::ptr::copy_nonoverlapping(packed.as_ptr(), data.ptr_mut(), packed.len());
+        Ok(())
+    }
+}
+
+/// Buffer that can be built from a received message, when the receiver
+/// doesn't know its shape beforehand. The packed bytes are the whole message,
+/// as produced by pack_to_vec(), so anything needed to shape the value (such
+/// as lengths) must be read from the message itself.
+///
+/// Values are received directly when possible: with with_size() for types
+/// shaped by their total size alone, and with from_header() for messages sent
+/// with a header (see Framed), whose packed part arrives before the memory
+/// regions.
+pub trait FromPacked: MessageBuffer + Sized {
+    /// Build a new value from the packed message.
+    fn from_packed(packed: &[u8]) -> DatatypeResult<Self>;
+
+    /// Build a value to receive a message of the total size in bytes into,
+    /// if the size alone is enough to shape it. Otherwise the whole message
+    /// is received first and passed to from_packed().
+    fn with_size(_size: usize) -> Option<DatatypeResult<Self>> {
+        None
+    }
+
+    /// Build a value to receive a message into, given the shape and packed
+    /// part from its header. By default the value is built with with_size(),
+    /// or from the packed part alone if the message has no memory regions.
+    fn from_header(shape: &PackedShape, packed: &[u8]) -> DatatypeResult<Self> {
+        match Self::with_size(shape.total_size()) {
+            Some(value) => value,
+            None if shape.region_lens.is_empty() => Self::from_packed(packed),
+            None => Err(DatatypeError::UnpackError),
+        }
+    }
+
+    /// Return the signature of the type, which must match the signature of
+    /// its values (see MessageBuffer::signature()).
+    fn type_signature() -> Option<u64> {
+        None
+    }
 }
 
 macro_rules! impl_buffer_primitive {
@@ -75,6 +229,42 @@ macro_rules! impl_buffer_primitive {
         }
 
         impl MessageBuffer for [$ty] {}
+
+        impl MessagePointer for Vec<$ty> {
+            fn ptr(&self) -> *const u8 {
+                self.as_ptr() as *const _
+            }
+
+            fn ptr_mut(&mut self) -> *mut u8 {
+                self.as_mut_ptr() as *mut _
+            }
+        }
+
+        impl MessageCount for Vec<$ty> {
+            fn count(&self) -> usize {
+                self.len() * std::mem::size_of::<$ty>()
+            }
+        }
+
+        impl MessageBuffer for Vec<$ty> {}
+
+        impl FromPacked for Vec<$ty> {
+            fn from_packed(packed: &[u8]) -> DatatypeResult<Self> {
+                if packed.len() % std::mem::size_of::<$ty>() != 0 {
+                    return Err(DatatypeError::UnpackError);
+                }
+                let mut data = vec![<$ty>::default(); packed.len() / std::mem::size_of::<$ty>()];
+                unsafe { unpack_from_slice(&mut data, packed)? };
+                Ok(data)
+            }
+
+            fn with_size(size: usize) -> Option<DatatypeResult<Self>> {
+                if size % std::mem::size_of::<$ty>() != 0 {
+                    return Some(Err(DatatypeError::UnpackError));
+                }
+                Some(Ok(vec![<$ty>::default(); size / std::mem::size_of::<$ty>()]))
+            }
+        }
     };
 }
 
@@ -88,3 +278,266 @@ impl_buffer_primitive!(i32);
 impl_buffer_primitive!(i64);
 impl_buffer_primitive!(f32);
 impl_buffer_primitive!(f64);
+
+/// Value that can be packed into the packed part of a message, along with
+/// any memory regions that it holds. Primitives are packed in big-endian
+/// order. This is implemented by #[derive(MessageBuffer)] for structs, so
+/// that derived structs can be nested or used as Vec elements.
+pub trait Packable {
+    /// Return the packed size in bytes.
+    fn packed_size(&self) -> usize;
+
+    /// Pack the value into dst, which is exactly packed_size() bytes long.
+    fn pack_into(&self, dst: &mut [u8]);
+
+    /// Unpack the value from src, which is exactly packed_size() bytes long.
+    fn unpack_from(&mut self, src: &[u8]);
+
+    /// Add the memory regions to send after the packed part.
+    fn memory_regions(&self, _regions: &mut Vec<(*const u8, usize)>) {}
+
+    /// Add the memory regions to receive into after the packed part.
+    fn memory_regions_mut(&mut self, _regions: &mut Vec<(*mut u8, usize)>) {}
+}
+
+macro_rules! impl_packable_primitive {
+    ($ty:ty) => {
+        impl Packable for $ty {
+            fn packed_size(&self) -> usize {
+                std::mem::size_of::<$ty>()
+            }
+
+            fn pack_into(&self, dst: &mut [u8]) {
+                dst.copy_from_slice(&self.to_be_bytes());
+            }
+
+            fn unpack_from(&mut self, src: &[u8]) {
+                *self = <$ty>::from_be_bytes(src.try_into().expect("invalid packed size"));
+            }
+        }
+    };
+}
+
+impl_packable_primitive!(u8);
+impl_packable_primitive!(u16);
+impl_packable_primitive!(u32);
+impl_packable_primitive!(u64);
+impl_packable_primitive!(i8);
+impl_packable_primitive!(i16);
+impl_packable_primitive!(i32);
+impl_packable_primitive!(i64);
+impl_packable_primitive!(f32);
+impl_packable_primitive!(f64);
+
+impl Packable for bool {
+    fn packed_size(&self) -> usize {
+        1
+    }
+
+    fn pack_into(&self, dst: &mut [u8]) {
+        dst[0] = *self as u8;
+    }
+
+    fn unpack_from(&mut self, src: &[u8]) {
+        *self = src[0] != 0;
+    }
+}
+
+/// Pack each element in order.
+fn pack_elements<T: Packable>(elements: &[T], dst: &mut [u8]) {
+    let mut pos = 0;
+    for elem in elements {
+        let size = elem.packed_size();
+        elem.pack_into(&mut dst[pos..pos + size]);
+        pos += size;
+    }
+}
+
+/// Unpack each element in order.
+fn unpack_elements<T: Packable>(elements: &mut [T], src: &[u8]) {
+    let mut pos = 0;
+    for elem in elements {
+        let size = elem.packed_size();
+        elem.unpack_from(&src[pos..pos + size]);
+        pos += size;
+    }
+}
+
+impl<T: Packable, const N: usize> Packable for [T; N] {
+    fn packed_size(&self) -> usize {
+        self.iter().map(|elem| elem.packed_size()).sum()
+    }
+
+    fn pack_into(&self, dst: &mut [u8]) {
+        pack_elements(self, dst);
+    }
+
+    fn unpack_from(&mut self, src: &[u8]) {
+        unpack_elements(self, src);
+    }
+
+    fn memory_regions(&self, regions: &mut Vec<(*const u8, usize)>) {
+        for elem in self {
+            elem.memory_regions(regions);
+        }
+    }
+
+    fn memory_regions_mut(&mut self, regions: &mut Vec<(*mut u8, usize)>) {
+        for elem in self {
+            elem.memory_regions_mut(regions);
+        }
+    }
+}
+
+/// Vecs are packed element by element. The receiving Vec must already have
+/// the right length, since the length itself isn't sent.
+impl<T: Packable> Packable for Vec<T> {
+    fn packed_size(&self) -> usize {
+        self.iter().map(|elem| elem.packed_size()).sum()
+    }
+
+    fn pack_into(&self, dst: &mut [u8]) {
+        pack_elements(self, dst);
+    }
+
+    fn unpack_from(&mut self, src: &[u8]) {
+        unpack_elements(self, src);
+    }
+
+    fn memory_regions(&self, regions: &mut Vec<(*const u8, usize)>) {
+        for elem in self {
+            elem.memory_regions(regions);
+        }
+    }
+
+    fn memory_regions_mut(&mut self, regions: &mut Vec<(*mut u8, usize)>) {
+        for elem in self {
+            elem.memory_regions_mut(regions);
+        }
+    }
+}
+
+/// Field that can be sent directly as a memory region, without packing.
+pub trait RegionField {
+    /// Return the pointer and length in bytes of the region.
+    fn region(&self) -> (*const u8, usize);
+
+    /// Return the mutable pointer and length in bytes of the region.
+    fn region_mut(&mut self) -> (*mut u8, usize);
+}
+
+impl<T: Copy> RegionField for Vec<T> {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr() as *const u8, self.len() * std::mem::size_of::<T>())
+    }
+
+    fn region_mut(&mut self) -> (*mut u8, usize) {
+        (self.as_mut_ptr() as *mut u8, self.len() * std::mem::size_of::<T>())
+    }
+}
+
+impl<T: Copy> RegionField for Box<[T]> {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr() as *const u8, self.len() * std::mem::size_of::<T>())
+    }
+
+    fn region_mut(&mut self) -> (*mut u8, usize) {
+        (self.as_mut_ptr() as *mut u8, self.len() * std::mem::size_of::<T>())
+    }
+}
+
+impl<T: Copy, const N: usize> RegionField for [T; N] {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr() as *const u8, N * std::mem::size_of::<T>())
+    }
+
+    fn region_mut(&mut self) -> (*mut u8, usize) {
+        (self.as_mut_ptr() as *mut u8, N * std::mem::size_of::<T>())
+    }
+}
+
+/// Pack and unpack state for Packable values, used by #[derive(MessageBuffer)].
+pub struct PackableState<T: Packable> {
+    /// Pointer to the value.
+    data: *mut T,
+
+    /// Packed data for partial packs and unpacks.
+    buffer: Vec<u8>,
+
+    /// Number of bytes received for partial unpacks.
+    received: usize,
+}
+
+impl<T: Packable> PackableState<T> {
+    /// Create the state for the value. The value must outlive the state and
+    /// must only be modified through it.
+    pub fn new(data: *mut T) -> PackableState<T> {
+        PackableState {
+            data,
+            buffer: vec![],
+            received: 0,
+        }
+    }
+}
+
+impl<T: Packable> PackedSize for PackableState<T> {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok((*self.data).packed_size())
+    }
+}
+
+impl<T: Packable> PackMethod for PackableState<T> {
+    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        let packed_size = (*self.data).packed_size();
+        if offset > packed_size {
+            return Err(DatatypeError::PackError);
+        }
+        let dst = std::slice::from_raw_parts_mut(dst, dst_size);
+        if offset == 0 && dst_size >= packed_size {
+            (*self.data).pack_into(&mut dst[..packed_size]);
+            return Ok(packed_size);
+        }
+        // Pack everything into the buffer once and copy out fragments.
+        if self.buffer.len() != packed_size {
+            self.buffer.resize(packed_size, 0);
+            (*self.data).pack_into(&mut self.buffer);
+        }
+        let used = dst_size.min(packed_size - offset);
+        dst[..used].copy_from_slice(&self.buffer[offset..offset + used]);
+        Ok(used)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        let mut regions = vec![];
+        (*self.data).memory_regions(&mut regions);
+        Ok(regions)
+    }
+}
+
+impl<T: Packable> UnpackMethod for PackableState<T> {
+    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
+        let packed_size = (*self.data).packed_size();
+        if offset + src_size > packed_size {
+            return Err(DatatypeError::UnpackError);
+        }
+        let src = std::slice::from_raw_parts(src, src_size);
+        if offset == 0 && src_size == packed_size {
+            (*self.data).unpack_from(src);
+            return Ok(());
+        }
+        // Collect fragments and unpack once everything has arrived.
+        self.buffer.resize(packed_size, 0);
+        self.buffer[offset..offset + src_size].copy_from_slice(src);
+        self.received += src_size;
+        if self.received == packed_size {
+            (*self.data).unpack_from(&self.buffer);
+        }
+        Ok(())
+    }
+
+    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
+        let mut regions = vec![];
+        (*self.data).memory_regions_mut(&mut regions);
+        Ok(regions)
+    }
+}
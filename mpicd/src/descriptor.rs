@@ -0,0 +1,514 @@
+//! Self-describing messages with runtime type descriptors.
+//!
+//! Tools and language bindings often don't know the datatype of a message at
+//! compile time. A TypeDescriptor describes a packed buffer at runtime: its
+//! name, the byte order of its data, the element types of the packed part and
+//! the element type and length of each memory region. Sending a buffer wrapped
+//! in Described prefixes the packed part with the encoded descriptor, so that
+//! recv_described() can receive any such message as a RawMessage, holding the
+//! descriptor and the raw packed part and regions. An UnpackRegistry maps
+//! descriptor names to factories of local receive buffers, to unpack raw
+//! messages into typed values.
+//!
+//! The message starts with the length of the encoded descriptor as a u64,
+//! followed by the descriptor, the packed part and the memory regions in
+//! order. The descriptor is encoded as:
+//!
+//! * the format version (1) and the byte order (0 for little-endian, 1 for
+//!   big-endian) as single bytes;
+//! * the name as a u64 length followed by UTF-8 bytes;
+//! * the number of packed blocks as a u64, followed by each block's element
+//!   type as a byte and element count as a u64;
+//! * the number of memory regions as a u64, followed by each region's element
+//!   type as a byte and length in bytes as a u64.
+//!
+//! All integers are big-endian.
+//!
+//! ```ignore
+//! let data = vec![1.0f64; 1024];
+//! let req = ctx.isend(&Described::new(&data[..]), 1, 0)?;
+//!
+//! // On the receiver.
+//! let message = ctx.recv_described(0, 0)?;
+//! let data: Vec<f64> = UnpackRegistry::new().unpack_as(message)?;
+//! ```
+use std::any::Any;
+use std::collections::HashMap;
+use crate::datatype::{
+    unpack_from_slice, DatatypeError, DatatypeResult, MessageBuffer, MessageCount, MessagePointer, MetaReader,
+    PackMethod, PackedSize,
+};
+use crate::framing::Contiguous;
+
+/// Version of the descriptor encoding.
+const DESCRIPTOR_VERSION: u8 = 1;
+
+/// Size of the descriptor length prefix.
+const PREFIX_SIZE: usize = std::mem::size_of::<u64>();
+
+/// Byte order of the data of a message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// Return the byte order of this process.
+    pub fn native() -> Endianness {
+        if cfg!(target_endian = "big") {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        }
+    }
+}
+
+/// Type of the elements of a block or region.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ElementType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    /// Opaque bytes, which are never byte swapped.
+    Bytes,
+}
+
+impl ElementType {
+    /// Return the size of an element in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            ElementType::U8 | ElementType::I8 | ElementType::Bytes => 1,
+            ElementType::U16 | ElementType::I16 => 2,
+            ElementType::U32 | ElementType::I32 | ElementType::F32 => 4,
+            ElementType::U64 | ElementType::I64 | ElementType::F64 => 8,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    fn from_byte(byte: u8) -> DatatypeResult<ElementType> {
+        const TYPES: [ElementType; 11] = [
+            ElementType::U8,
+            ElementType::U16,
+            ElementType::U32,
+            ElementType::U64,
+            ElementType::I8,
+            ElementType::I16,
+            ElementType::I32,
+            ElementType::I64,
+            ElementType::F32,
+            ElementType::F64,
+            ElementType::Bytes,
+        ];
+        TYPES.get(byte as usize).copied().ok_or(DatatypeError::UnpackError)
+    }
+
+    /// Reverse the byte order of each element in the data.
+    fn swap_bytes(&self, data: &mut [u8]) {
+        if *self == ElementType::Bytes {
+            return;
+        }
+        for elem in data.chunks_exact_mut(self.size()) {
+            elem.reverse();
+        }
+    }
+}
+
+/// Runtime description of a packed buffer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeDescriptor {
+    /// Name of the type, used to look up unpack factories.
+    pub name: String,
+
+    /// Byte order of the data.
+    pub endianness: Endianness,
+
+    /// Element type and element count of each block of the packed part, in
+    /// order.
+    pub packed: Vec<(ElementType, usize)>,
+
+    /// Element type and length in bytes of each memory region.
+    pub regions: Vec<(ElementType, usize)>,
+}
+
+impl TypeDescriptor {
+    /// Return the size of the packed part in bytes.
+    pub fn packed_size(&self) -> usize {
+        self.packed.iter().map(|(ty, count)| ty.size() * count).sum()
+    }
+
+    /// Encode the descriptor.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![DESCRIPTOR_VERSION, (self.endianness == Endianness::Big) as u8];
+        data.extend_from_slice(&(self.name.len() as u64).to_be_bytes());
+        data.extend_from_slice(self.name.as_bytes());
+        for blocks in [&self.packed, &self.regions] {
+            data.extend_from_slice(&(blocks.len() as u64).to_be_bytes());
+            for (ty, len) in blocks {
+                data.push(ty.to_byte());
+                data.extend_from_slice(&(*len as u64).to_be_bytes());
+            }
+        }
+        data
+    }
+
+    /// Decode a descriptor.
+    fn decode(reader: &mut MetaReader) -> DatatypeResult<TypeDescriptor> {
+        let header = reader.read_bytes(2)?;
+        if header[0] != DESCRIPTOR_VERSION {
+            return Err(DatatypeError::UnpackError);
+        }
+        let endianness = match header[1] {
+            0 => Endianness::Little,
+            1 => Endianness::Big,
+            _ => return Err(DatatypeError::UnpackError),
+        };
+        let name_len = reader.read_len()?;
+        let name = std::str::from_utf8(reader.read_bytes(name_len)?)
+            .map_err(|_| DatatypeError::UnpackError)?
+            .to_string();
+        let mut blocks = [vec![], vec![]];
+        for blocks in blocks.iter_mut() {
+            let count = reader.read_len()?;
+            for _ in 0..count {
+                let ty = ElementType::from_byte(reader.read_bytes(1)?[0])?;
+                blocks.push((ty, reader.read_len()?));
+            }
+        }
+        let [packed, regions] = blocks;
+        Ok(TypeDescriptor {
+            name,
+            endianness,
+            packed,
+            regions,
+        })
+    }
+
+    /// Check that the descriptor matches a packed size and region lengths.
+    fn check(&self, packed_size: usize, region_lens: impl Iterator<Item = usize>) -> bool {
+        self.packed_size() == packed_size
+            && self.regions.iter().all(|(ty, len)| len % ty.size() == 0)
+            && self.regions.iter().map(|(_, len)| *len).eq(region_lens)
+    }
+}
+
+/// Buffer that can describe itself with a TypeDescriptor.
+pub trait Describe: MessageBuffer {
+    /// Return the descriptor of the buffer.
+    fn descriptor(&self) -> TypeDescriptor;
+}
+
+macro_rules! impl_describe_primitive {
+    ($ty:ty, $elem:expr) => {
+        /// Contiguous buffers are sent as a single region.
+        impl Describe for [$ty] {
+            fn descriptor(&self) -> TypeDescriptor {
+                let len = std::mem::size_of_val(self);
+                TypeDescriptor {
+                    name: concat!("[", stringify!($ty), "]").to_string(),
+                    endianness: Endianness::native(),
+                    packed: vec![],
+                    regions: if len > 0 { vec![($elem, len)] } else { vec![] },
+                }
+            }
+        }
+
+        impl Describe for Vec<$ty> {
+            fn descriptor(&self) -> TypeDescriptor {
+                self[..].descriptor()
+            }
+        }
+    };
+}
+
+impl_describe_primitive!(u8, ElementType::U8);
+impl_describe_primitive!(u16, ElementType::U16);
+impl_describe_primitive!(u32, ElementType::U32);
+impl_describe_primitive!(u64, ElementType::U64);
+impl_describe_primitive!(i8, ElementType::I8);
+impl_describe_primitive!(i16, ElementType::I16);
+impl_describe_primitive!(i32, ElementType::I32);
+impl_describe_primitive!(i64, ElementType::I64);
+impl_describe_primitive!(f32, ElementType::F32);
+impl_describe_primitive!(f64, ElementType::F64);
+
+/// Buffer sent with its descriptor, to be received with recv_described().
+pub struct Described<'a, B: MessageBuffer + ?Sized> {
+    /// Buffer to send.
+    data: &'a B,
+
+    /// Descriptor of the buffer.
+    descriptor: TypeDescriptor,
+}
+
+impl<'a, B: Describe + ?Sized> Described<'a, B> {
+    /// Wrap the buffer with its own descriptor.
+    pub fn new(data: &'a B) -> Described<'a, B> {
+        Described {
+            descriptor: data.descriptor(),
+            data,
+        }
+    }
+}
+
+impl<'a, B: MessageBuffer + ?Sized> Described<'a, B> {
+    /// Wrap the buffer with a given descriptor, which must match the buffer's
+    /// packed size and memory regions.
+    pub fn with_descriptor(data: &'a B, descriptor: TypeDescriptor) -> Described<'a, B> {
+        Described { data, descriptor }
+    }
+}
+
+impl<B: MessageBuffer + ?Sized> MessageCount for Described<'_, B> {
+    fn count(&self) -> usize {
+        self.data.count()
+    }
+}
+
+impl<B: MessageBuffer + ?Sized> MessagePointer for Described<'_, B> {
+    fn ptr(&self) -> *const u8 {
+        self.data.ptr()
+    }
+
+    /// Described buffers can only be sent.
+    fn ptr_mut(&mut self) -> *mut u8 {
+        std::ptr::null_mut()
+    }
+}
+
+impl<B: MessageBuffer + ?Sized> MessageBuffer for Described<'_, B> {
+    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
+        let inner = match self.data.pack() {
+            Some(Ok(pack_method)) => pack_method,
+            Some(Err(err)) => return Some(Err(err)),
+            None => Box::new(Contiguous {
+                ptr: self.data.ptr() as *mut u8,
+                len: self.data.count(),
+            }),
+        };
+        let regions = match inner.memory_regions() {
+            Ok(regions) => regions,
+            Err(err) => return Some(Err(err)),
+        };
+        match inner.packed_size() {
+            Ok(packed_size) if self.descriptor.check(packed_size, regions.iter().map(|(_, len)| *len)) => (),
+            Ok(_) => return Some(Err(DatatypeError::PackError)),
+            Err(err) => return Some(Err(err)),
+        }
+        let descriptor = self.descriptor.encode();
+        let mut prefix = (descriptor.len() as u64).to_be_bytes().to_vec();
+        prefix.extend_from_slice(&descriptor);
+        Some(Ok(Box::new(DescribedPack { inner, prefix })))
+    }
+}
+
+/// Pack method sending the encoded descriptor before the inner packed part.
+struct DescribedPack {
+    inner: Box<dyn PackMethod>,
+    prefix: Vec<u8>,
+}
+
+impl PackedSize for DescribedPack {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok(self.prefix.len() + self.inner.packed_size()?)
+    }
+}
+
+impl PackMethod for DescribedPack {
+    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        let mut used = 0;
+        if offset < self.prefix.len() {
+            used = (self.prefix.len() - offset).min(dst_size);
+            std::ptr::copy_nonoverlapping(self.prefix[offset..].as_ptr(), dst, used);
+        }
+        if used < dst_size && offset + used < self.packed_size()? {
+            let inner_offset = offset + used - self.prefix.len();
+            used += self.inner.pack(inner_offset, dst.add(used), dst_size - used)?;
+        }
+        Ok(used)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        self.inner.memory_regions()
+    }
+}
+
+/// Message received with its descriptor.
+pub struct RawMessage {
+    /// Descriptor of the message.
+    descriptor: TypeDescriptor,
+
+    /// Whole received message.
+    data: Vec<u8>,
+
+    /// Offset of the packed part in the data.
+    offset: usize,
+}
+
+impl RawMessage {
+    /// Parse a received self-describing message.
+    pub fn from_bytes(data: Vec<u8>) -> DatatypeResult<RawMessage> {
+        let mut reader = MetaReader::new(&data);
+        let descriptor_len = reader.read_len()?;
+        let descriptor = TypeDescriptor::decode(&mut reader)?;
+        let offset = PREFIX_SIZE + descriptor_len;
+        let region_lens = descriptor.regions.iter().map(|(_, len)| *len);
+        if !descriptor.check(descriptor.packed_size(), region_lens.clone())
+            || offset + descriptor.packed_size() + region_lens.sum::<usize>() != data.len()
+        {
+            return Err(DatatypeError::UnpackError);
+        }
+        Ok(RawMessage {
+            descriptor,
+            data,
+            offset,
+        })
+    }
+
+    /// Return the descriptor of the message.
+    pub fn descriptor(&self) -> &TypeDescriptor {
+        &self.descriptor
+    }
+
+    /// Return the packed part followed by the memory regions, as produced by
+    /// pack_to_vec().
+    pub fn body(&self) -> &[u8] {
+        &self.data[self.offset..]
+    }
+
+    /// Return the packed part.
+    pub fn packed(&self) -> &[u8] {
+        &self.body()[..self.descriptor.packed_size()]
+    }
+
+    /// Return the contents of each memory region.
+    pub fn regions(&self) -> Vec<&[u8]> {
+        let mut pos = self.offset + self.descriptor.packed_size();
+        self.descriptor
+            .regions
+            .iter()
+            .map(|(_, len)| {
+                let region = &self.data[pos..pos + len];
+                pos += len;
+                region
+            })
+            .collect()
+    }
+
+    /// Convert the data to the byte order of this process.
+    pub fn to_native(&mut self) {
+        if self.descriptor.endianness == Endianness::native() {
+            return;
+        }
+        let mut pos = self.offset;
+        let blocks = self
+            .descriptor
+            .packed
+            .iter()
+            .map(|(ty, count)| (*ty, ty.size() * count))
+            .chain(self.descriptor.regions.iter().copied());
+        for (ty, len) in blocks {
+            ty.swap_bytes(&mut self.data[pos..pos + len]);
+            pos += len;
+        }
+        self.descriptor.endianness = Endianness::native();
+    }
+}
+
+/// Unpack a raw message into a new value.
+type UnpackFactory = Box<dyn Fn(&RawMessage) -> DatatypeResult<Box<dyn Any>>>;
+
+/// Registry mapping descriptor names to factories of local receive buffers.
+pub struct UnpackRegistry {
+    factories: HashMap<String, UnpackFactory>,
+}
+
+impl UnpackRegistry {
+    /// Create a registry with factories for the descriptors of primitive
+    /// slices and vectors, which are unpacked into vectors.
+    pub fn new() -> UnpackRegistry {
+        let mut registry = UnpackRegistry::empty();
+        registry.register_primitive::<u8>();
+        registry.register_primitive::<u16>();
+        registry.register_primitive::<u32>();
+        registry.register_primitive::<u64>();
+        registry.register_primitive::<i8>();
+        registry.register_primitive::<i16>();
+        registry.register_primitive::<i32>();
+        registry.register_primitive::<i64>();
+        registry.register_primitive::<f32>();
+        registry.register_primitive::<f64>();
+        registry
+    }
+
+    /// Create an empty registry.
+    pub fn empty() -> UnpackRegistry {
+        UnpackRegistry {
+            factories: HashMap::new(),
+        }
+    }
+
+    /// Register a factory for the descriptor name. The factory creates a
+    /// buffer shaped for the descriptor, whose UnpackMethod is then used to
+    /// unpack the message into it.
+    pub fn register<T, F>(&mut self, name: &str, factory: F)
+    where
+        T: MessageBuffer + 'static,
+        F: Fn(&TypeDescriptor) -> DatatypeResult<T> + 'static,
+    {
+        let unpack = move |message: &RawMessage| {
+            let mut value = factory(message.descriptor())?;
+            unsafe { unpack_from_slice(&mut value, message.body())? };
+            Ok(Box::new(value) as Box<dyn Any>)
+        };
+        self.factories.insert(name.to_string(), Box::new(unpack));
+    }
+
+    fn register_primitive<T>(&mut self)
+    where
+        T: Default + Clone + 'static,
+        [T]: Describe,
+        Vec<T>: MessageBuffer,
+    {
+        let name = <[T]>::descriptor(&[]).name;
+        self.register(&name, |descriptor| {
+            let len: usize = descriptor.regions.iter().map(|(_, len)| len).sum();
+            Ok(vec![T::default(); len / std::mem::size_of::<T>()])
+        });
+    }
+
+    /// Unpack the message with the factory registered for its descriptor,
+    /// converting it to the byte order of this process first.
+    pub fn unpack(&self, mut message: RawMessage) -> DatatypeResult<Box<dyn Any>> {
+        let factory = self
+            .factories
+            .get(&message.descriptor.name)
+            .ok_or(DatatypeError::DatatypeMismatch)?;
+        message.to_native();
+        factory(&message)
+    }
+
+    /// Unpack the message into a value of type T (see unpack()).
+    pub fn unpack_as<T: 'static>(&self, message: RawMessage) -> DatatypeResult<T> {
+        self.unpack(message)?
+            .downcast()
+            .map(|value| *value)
+            .map_err(|_| DatatypeError::DatatypeMismatch)
+    }
+}
+
+impl Default for UnpackRegistry {
+    fn default() -> UnpackRegistry {
+        UnpackRegistry::new()
+    }
+}
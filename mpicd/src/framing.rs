@@ -0,0 +1,269 @@
+//! Message headers carrying the shape of a packed buffer.
+//!
+//! Normally the sender and receiver must agree on the packed size and the
+//! exact memory region lengths of a message, since the receive iovec list is
+//! built purely from the receiver's UnpackMethod. Types can instead opt in to
+//! a header by returning true from PackMethod::header() and
+//! UnpackMethod::header(). The sender then first sends a header message,
+//! holding a signature flag, the signature (see the signature module), the
+//! packed size, the region count and the region lengths as u64s in
+//! big-endian order, followed by the packed part itself. The data message
+//! only holds the memory regions. Both are sent with the FRAMED_TAG bit and a
+//! per-sender sequence number in the internal tag, with the HEADER_TAG bit
+//! also set on the header. The receiver claims a header with a matched probe,
+//! which removes it from the unexpected queue, and then receives the data with
+//! the sequence number of that header, so that concurrent receives with the
+//! same tag can't take each other's data. Since the internal tag differs,
+//! probe() doesn't see these messages. The receiver passes the shape to
+//! UnpackMethod::prepare(), which can validate it, resize the buffer or reject
+//! the message before any data lands. Messages with a different signature are
+//! rejected before prepare() is called. Rejected messages are still received,
+//! into a scratch buffer, so that they don't linger unmatched.
+//!
+//! Since the packed part arrives with the header, Communicator::recv_owned()
+//! can shape a new value from it (see FromPacked::from_header()) and receive
+//! the memory regions directly into the value.
+//!
+//! Since both sides need to agree on whether there's a header, this is
+//! decided per datatype, and types with a fixed shape keep the header-free
+//! path. Framed enables a header for any other buffer.
+//!
+//! ```ignore
+//! // The receiver doesn't need to know the size of the value beforehand.
+//! let mut buf = Framed(SerdeBuffer::<Vec<String>>::with_size(0));
+//! let req = ctx.irecv(&mut buf, 0, 0)?;
+//! ctx.waitall(&[req])?;
+//! ```
+use crate::datatype::{
+    DatatypeError, DatatypeResult, MessageBuffer, MessageCount, MessagePointer, PackMethod, PackedShape,
+    PackedSize, UnpackMethod,
+};
+
+/// Size of each field of the header.
+const FIELD_SIZE: usize = std::mem::size_of::<u64>();
+
+impl PackedShape {
+    /// Encode the shape, the signature and the packed part as a message
+    /// header.
+    pub(crate) fn to_header(&self, signature: Option<u64>, packed: &[u8]) -> Vec<u8> {
+        assert_eq!(packed.len(), self.packed_size);
+        let mut header = Vec::with_capacity((4 + self.region_lens.len()) * FIELD_SIZE + packed.len());
+        header.extend_from_slice(&(signature.is_some() as u64).to_be_bytes());
+        header.extend_from_slice(&signature.unwrap_or(0).to_be_bytes());
+        header.extend_from_slice(&(self.packed_size as u64).to_be_bytes());
+        header.extend_from_slice(&(self.region_lens.len() as u64).to_be_bytes());
+        for len in &self.region_lens {
+            header.extend_from_slice(&(*len as u64).to_be_bytes());
+        }
+        header.extend_from_slice(packed);
+        header
+    }
+
+    /// Decode a shape, the signature and the packed part from a message
+    /// header.
+    pub(crate) fn from_header(header: &[u8]) -> DatatypeResult<(PackedShape, Option<u64>, &[u8])> {
+        let mut pos = 0;
+        let mut field = || -> DatatypeResult<u64> {
+            let bytes = header.get(pos..pos + FIELD_SIZE).ok_or(DatatypeError::UnpackError)?;
+            pos += FIELD_SIZE;
+            Ok(u64::from_be_bytes(bytes.try_into().expect("field should be 8 bytes")))
+        };
+        let has_signature = field()?;
+        let signature = field()?;
+        let signature = match has_signature {
+            0 => None,
+            1 => Some(signature),
+            _ => return Err(DatatypeError::UnpackError),
+        };
+        let mut size_field = || usize::try_from(field()?).map_err(|_| DatatypeError::UnpackError);
+        let packed_size = size_field()?;
+        let region_count = size_field()?;
+        // Every region length takes up a field, so this can't be too large.
+        if region_count > header.len() / FIELD_SIZE {
+            return Err(DatatypeError::UnpackError);
+        }
+        let region_lens = (0..region_count).map(|_| size_field()).collect::<DatatypeResult<Vec<usize>>>()?;
+        let packed = &header[pos..];
+        if packed.len() != packed_size {
+            return Err(DatatypeError::UnpackError);
+        }
+        Ok((PackedShape { packed_size, region_lens }, signature, packed))
+    }
+}
+
+/// Buffer wrapper that sends and receives the inner buffer with a header, so
+/// that the receiver learns the sender's shape before any data arrives. Both
+/// sides must use Framed.
+pub struct Framed<B>(pub B);
+
+impl<B: MessageCount> MessageCount for Framed<B> {
+    fn count(&self) -> usize {
+        self.0.count()
+    }
+}
+
+impl<B: MessagePointer> MessagePointer for Framed<B> {
+    fn ptr(&self) -> *const u8 {
+        self.0.ptr()
+    }
+
+    fn ptr_mut(&mut self) -> *mut u8 {
+        self.0.ptr_mut()
+    }
+}
+
+impl<B: MessageBuffer> MessageBuffer for Framed<B> {
+    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
+        let inner = match self.0.pack() {
+            Some(pack_method) => pack_method,
+            None => Ok(Box::new(Contiguous {
+                ptr: self.0.ptr() as *mut u8,
+                len: self.0.count(),
+            }) as Box<dyn PackMethod>),
+        };
+        Some(inner.map(|inner| Box::new(FramedPack(inner)) as Box<dyn PackMethod>))
+    }
+
+    unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
+        let inner = match self.0.unpack() {
+            Some(unpack_method) => unpack_method,
+            None => Ok(Box::new(Contiguous {
+                ptr: self.0.ptr_mut(),
+                len: self.0.count(),
+            }) as Box<dyn UnpackMethod>),
+        };
+        Some(inner.map(|inner| Box::new(FramedUnpack(inner)) as Box<dyn UnpackMethod>))
+    }
+
+    fn signature(&self) -> Option<u64> {
+        self.0.signature()
+    }
+}
+
+/// Pack method of a contiguous buffer, sent as a single memory region.
+pub(crate) struct Contiguous {
+    pub(crate) ptr: *mut u8,
+    pub(crate) len: usize,
+}
+
+impl Contiguous {
+    fn regions(&self) -> Vec<(*mut u8, usize)> {
+        if self.len > 0 {
+            vec![(self.ptr, self.len)]
+        } else {
+            vec![]
+        }
+    }
+}
+
+impl PackedSize for Contiguous {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok(0)
+    }
+}
+
+impl PackMethod for Contiguous {
+    unsafe fn pack(&mut self, _offset: usize, _dst: *mut u8, _dst_size: usize) -> DatatypeResult<usize> {
+        Ok(0)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        Ok(self.regions().into_iter().map(|(ptr, len)| (ptr as *const u8, len)).collect())
+    }
+}
+
+impl UnpackMethod for Contiguous {
+    unsafe fn unpack(&mut self, _offset: usize, _src: *const u8, _src_size: usize) -> DatatypeResult<()> {
+        Ok(())
+    }
+
+    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
+        Ok(self.regions())
+    }
+}
+
+/// Pack method sending a copy of a whole packed buffer (as made by
+/// pack_with_shape()) with the shape and header flag of the original buffer,
+/// so that the receiver sees the same message as for the original.
+pub(crate) struct PackedCopy {
+    pub(crate) ptr: *const u8,
+    pub(crate) shape: PackedShape,
+    pub(crate) header: bool,
+}
+
+impl PackedSize for PackedCopy {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok(self.shape.packed_size)
+    }
+}
+
+impl PackMethod for PackedCopy {
+    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        let used = self.shape.packed_size.saturating_sub(offset).min(dst_size);
+        std::ptr::copy_nonoverlapping(self.ptr.add(offset), dst, used);
+        Ok(used)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        let mut pos = self.shape.packed_size;
+        Ok(self.shape.region_lens.iter().map(|len| {
+            let region = (self.ptr.add(pos), *len);
+            pos += len;
+            region
+        }).collect())
+    }
+
+    fn header(&self) -> bool {
+        self.header
+    }
+}
+
+/// Pack method of a Framed buffer.
+struct FramedPack(Box<dyn PackMethod>);
+
+impl PackedSize for FramedPack {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        self.0.packed_size()
+    }
+}
+
+impl PackMethod for FramedPack {
+    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        self.0.pack(offset, dst, dst_size)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        self.0.memory_regions()
+    }
+
+    fn header(&self) -> bool {
+        true
+    }
+}
+
+/// Unpack method of a Framed buffer.
+struct FramedUnpack(Box<dyn UnpackMethod>);
+
+impl PackedSize for FramedUnpack {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        self.0.packed_size()
+    }
+}
+
+impl UnpackMethod for FramedUnpack {
+    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
+        self.0.unpack(offset, src, src_size)
+    }
+
+    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
+        self.0.memory_regions()
+    }
+
+    fn header(&self) -> bool {
+        true
+    }
+
+    unsafe fn prepare(&mut self, shape: &PackedShape) -> DatatypeResult<()> {
+        self.0.prepare(shape)
+    }
+}
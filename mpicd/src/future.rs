@@ -0,0 +1,196 @@
+//! Future-based requests for async code.
+//!
+//! Polling a RequestFuture progresses the worker and the message. If the
+//! request is still in progress, the worker is armed with ucp_worker_arm() and
+//! the waker is handed over to a helper thread which blocks on the worker's
+//! event fd, waking all registered tasks once there's new activity. The helper
+//! thread never touches the worker itself, so this works with any executor
+//! running on the thread that owns the context.
+use std::cell::RefCell;
+use std::ffi::c_int;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{self, Poll, Waker};
+use std::thread::JoinHandle;
+use log::error;
+use mpicd_ucx_sys::{ucp_worker_arm, ucp_worker_get_efd, ucp_worker_h, UCS_ERR_BUSY, UCS_OK};
+use nix::poll::{poll, PollFd, PollFlags};
+use crate::{status_to_string, Handle, Status};
+
+/// Timeout in milliseconds for polling the event fd, after which the helper
+/// thread checks whether it should stop.
+const POLL_TIMEOUT: c_int = 100;
+
+/// Data shared with the helper thread.
+#[derive(Default)]
+struct Shared {
+    /// Wakers waiting for worker events.
+    wakers: Mutex<Vec<Waker>>,
+
+    /// Signaled when wakers are added or the thread should stop.
+    cond: Condvar,
+
+    /// Set when the thread should stop.
+    stop: AtomicBool,
+}
+
+/// Event fd handling for futures.
+pub(crate) struct Wakeup {
+    /// Shared state (None if the worker has no event fd).
+    shared: Option<Arc<Shared>>,
+
+    /// Helper thread polling the event fd.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Wakeup {
+    /// Get the event fd of the worker and start the helper thread.
+    pub(crate) unsafe fn new(worker: ucp_worker_h) -> Wakeup {
+        let mut efd = MaybeUninit::<c_int>::uninit();
+        let status = ucp_worker_get_efd(worker, efd.as_mut_ptr());
+        if status != UCS_OK {
+            // Futures will just keep waking themselves.
+            error!("Failed to get worker event fd: {}", status_to_string(status));
+            return Wakeup {
+                shared: None,
+                thread: None,
+            };
+        }
+        let efd = efd.assume_init();
+        let shared = Arc::new(Shared::default());
+        let thread_shared = Arc::clone(&shared);
+        let thread = std::thread::spawn(move || event_loop(efd, &thread_shared));
+        Wakeup {
+            shared: Some(shared),
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop the helper thread (this must be done before the worker is
+    /// destroyed).
+    pub(crate) fn stop(&mut self) {
+        if let Some(shared) = self.shared.as_ref() {
+            shared.stop.store(true, Ordering::SeqCst);
+            shared.cond.notify_one();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Arrange for the waker to be called on the next worker event.
+    unsafe fn register(&self, worker: ucp_worker_h, waker: &Waker) {
+        let Some(shared) = self.shared.as_ref() else {
+            waker.wake_by_ref();
+            return;
+        };
+        match ucp_worker_arm(worker) {
+            UCS_OK => {
+                let mut wakers = shared.wakers.lock().unwrap();
+                if !wakers.iter().any(|other| other.will_wake(waker)) {
+                    wakers.push(waker.clone());
+                }
+                shared.cond.notify_one();
+            }
+            // There are unprocessed events, so poll again right away.
+            UCS_ERR_BUSY => waker.wake_by_ref(),
+            status => {
+                error!("Failed to arm worker: {}", status_to_string(status));
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+impl Drop for Wakeup {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Helper thread waiting for events and waking the registered tasks.
+fn event_loop(efd: RawFd, shared: &Shared) {
+    loop {
+        // Sleep until there's something to wake.
+        {
+            let mut wakers = shared.wakers.lock().unwrap();
+            while wakers.is_empty() && !shared.stop.load(Ordering::SeqCst) {
+                wakers = shared.cond.wait(wakers).unwrap();
+            }
+        }
+        if shared.stop.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut fds = [PollFd::new(efd, PollFlags::POLLIN)];
+        match poll(&mut fds, POLL_TIMEOUT) {
+            Ok(n) if n > 0 => {
+                let wakers: Vec<Waker> = shared.wakers.lock().unwrap().drain(..).collect();
+                for waker in wakers {
+                    waker.wake();
+                }
+            }
+            Ok(_) | Err(nix::errno::Errno::EINTR) => (),
+            Err(err) => {
+                error!("Failed to poll worker event fd: {}", err);
+                // Fall back to waking everything.
+                let wakers: Vec<Waker> = shared.wakers.lock().unwrap().drain(..).collect();
+                for waker in wakers {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Future completing with the status of a request.
+///
+/// Futures hold a reference to the context's handle and aren't Send, so they
+/// must be polled on the thread owning the context. Dropping the future before
+/// completion leaves the request pending; it can still be waited on with
+/// waitall().
+#[must_use = "futures do nothing unless polled"]
+pub struct RequestFuture {
+    /// Handle with ucx info.
+    handle: Rc<RefCell<Handle>>,
+
+    /// Request id, None once complete.
+    req: Option<usize>,
+}
+
+impl RequestFuture {
+    pub(crate) fn new(handle: Rc<RefCell<Handle>>, req: usize) -> RequestFuture {
+        RequestFuture {
+            handle,
+            req: Some(req),
+        }
+    }
+}
+
+impl Future for RequestFuture {
+    type Output = Status;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Status> {
+        let req = self.req.expect("request future polled after completion");
+        let handle = Rc::clone(&self.handle);
+        let mut handle = handle.borrow_mut();
+        unsafe {
+            match handle.message_progress(req) {
+                Status::InProgress => {
+                    handle.wakeup.register(handle.system.worker, cx.waker());
+                    Poll::Pending
+                }
+                status => {
+                    handle.complete_message(req);
+                    self.req = None;
+                    Poll::Ready(status)
+                }
+            }
+        }
+    }
+}
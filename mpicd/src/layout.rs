@@ -0,0 +1,376 @@
+//! Derived datatype layouts, similar to MPI's type constructors.
+//!
+//! A Layout describes where the bytes of one element lie relative to its
+//! start, built up from basic types with contiguous(), vector(), indexed(),
+//! structure() and friends. Layouts are flattened into a list of
+//! (displacement, length) blocks in typemap order when they're constructed,
+//! with adjacent blocks merged, so nesting doesn't cost anything when packing.
+//!
+//! A LayoutBuffer combines a layout with a buffer and an element count and
+//! implements MessageBuffer. Blocks of at least the region threshold are sent
+//! and received directly as memory regions, while smaller blocks are packed.
+//! Both sides must therefore use layouts with the same block structure.
+//!
+//! ```ignore
+//! // Send column 2 of a row-major 4x4 matrix of doubles.
+//! let column = Layout::vector(4, 1, 4, &Layout::of::<f64>());
+//! let buf = LayoutBuffer::from_slice(&matrix[2..], 1, &column)?;
+//! ctx.send(&buf, 1, 0)?;
+//! ```
+use std::marker::PhantomData;
+use crate::datatype::{
+    DatatypeError, DatatypeResult, MessageBuffer, MessageCount, MessagePointer, PackMethod, PackedSize,
+    UnpackMethod,
+};
+
+/// Default minimum size of a block for it to be sent as a memory region.
+pub const LAYOUT_REGION_THRESHOLD: usize = 2048;
+
+/// Typemap of a derived datatype.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// Blocks as (byte displacement, length) pairs, in typemap order.
+    blocks: Vec<(isize, usize)>,
+
+    /// Lower bound.
+    lb: isize,
+
+    /// Upper bound.
+    ub: isize,
+}
+
+impl Layout {
+    /// Basic layout of size contiguous bytes.
+    pub fn bytes(size: usize) -> Layout {
+        let mut layout = Layout {
+            blocks: vec![],
+            lb: 0,
+            ub: size as isize,
+        };
+        layout.push(0, size);
+        layout
+    }
+
+    /// Basic layout of a Copy type, sent as its raw bytes.
+    pub fn of<T: Copy>() -> Layout {
+        Layout::bytes(std::mem::size_of::<T>())
+    }
+
+    /// Count consecutive copies of the inner layout.
+    pub fn contiguous(count: usize, inner: &Layout) -> Layout {
+        Layout::hvector(count, 1, inner.extent() as isize, inner)
+    }
+
+    /// Count blocks of blocklen copies of the inner layout, with the starts
+    /// of the blocks stride extents of the inner layout apart.
+    pub fn vector(count: usize, blocklen: usize, stride: isize, inner: &Layout) -> Layout {
+        Layout::hvector(count, blocklen, stride * inner.extent() as isize, inner)
+    }
+
+    /// Like vector(), with the stride given in bytes.
+    pub fn hvector(count: usize, blocklen: usize, stride: isize, inner: &Layout) -> Layout {
+        let blocks: Vec<(usize, isize)> = (0..count as isize).map(|i| (blocklen, i * stride)).collect();
+        Layout::hindexed(&blocks, inner)
+    }
+
+    /// Blocks given as (blocklen, displacement) pairs of the inner layout,
+    /// with displacements in extents of the inner layout.
+    pub fn indexed(blocks: &[(usize, isize)], inner: &Layout) -> Layout {
+        let extent = inner.extent() as isize;
+        let blocks: Vec<(usize, isize)> = blocks.iter().map(|(blocklen, disp)| (*blocklen, disp * extent)).collect();
+        Layout::hindexed(&blocks, inner)
+    }
+
+    /// Like indexed(), with displacements given in bytes.
+    pub fn hindexed(blocks: &[(usize, isize)], inner: &Layout) -> Layout {
+        let fields: Vec<(usize, isize, &Layout)> = blocks.iter().map(|(blocklen, disp)| (*blocklen, *disp, inner)).collect();
+        Layout::structure(&fields)
+    }
+
+    /// Fields given as (blocklen, byte displacement, layout), where each
+    /// field may have a different layout.
+    pub fn structure(fields: &[(usize, isize, &Layout)]) -> Layout {
+        let mut layout = Layout {
+            blocks: vec![],
+            lb: 0,
+            ub: 0,
+        };
+        let mut bounds: Option<(isize, isize)> = None;
+        for (blocklen, disp, inner) in fields {
+            if *blocklen == 0 {
+                continue;
+            }
+            let extent = inner.extent() as isize;
+            for j in 0..*blocklen as isize {
+                let base = disp + j * extent;
+                for (block_disp, len) in &inner.blocks {
+                    layout.push(base + block_disp, *len);
+                }
+            }
+            let lb = disp + inner.lb;
+            let ub = disp + (*blocklen as isize - 1) * extent + inner.ub;
+            bounds = Some(match bounds {
+                Some((min, max)) => (min.min(lb), max.max(ub)),
+                None => (lb, ub),
+            });
+        }
+        if let Some((lb, ub)) = bounds {
+            layout.lb = lb;
+            layout.ub = ub;
+        }
+        layout
+    }
+
+    /// Return a copy of the layout with a new lower bound and extent.
+    pub fn resized(&self, lb: isize, extent: usize) -> Layout {
+        Layout {
+            blocks: self.blocks.clone(),
+            lb,
+            ub: lb + extent as isize,
+        }
+    }
+
+    /// Return the number of data bytes in one element.
+    pub fn size(&self) -> usize {
+        self.blocks.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Return the distance between consecutive elements.
+    pub fn extent(&self) -> usize {
+        (self.ub - self.lb).max(0) as usize
+    }
+
+    /// Return the lower bound.
+    pub fn lb(&self) -> isize {
+        self.lb
+    }
+
+    /// Return the flattened (byte displacement, length) blocks.
+    pub fn blocks(&self) -> &[(isize, usize)] {
+        &self.blocks[..]
+    }
+
+    /// Return the lowest and highest byte touched by one element, if any.
+    fn true_bounds(&self) -> Option<(isize, isize)> {
+        let lb = self.blocks.iter().map(|(disp, _)| *disp).min()?;
+        let ub = self.blocks.iter().map(|(disp, len)| disp + *len as isize).max()?;
+        Some((lb, ub))
+    }
+
+    /// Add a block, merging it with the previous one if they're adjacent.
+    fn push(&mut self, disp: isize, len: usize) {
+        push_block(&mut self.blocks, disp, len);
+    }
+}
+
+/// Add a block to the list, merging it with the last one if they're adjacent.
+fn push_block(blocks: &mut Vec<(isize, usize)>, disp: isize, len: usize) {
+    if len == 0 {
+        return;
+    }
+    if let Some((last_disp, last_len)) = blocks.last_mut() {
+        if *last_disp + *last_len as isize == disp {
+            *last_len += len;
+            return;
+        }
+    }
+    blocks.push((disp, len));
+}
+
+/// Buffer of count elements described by a layout.
+pub struct LayoutBuffer<'a> {
+    /// Start of the buffer (displacements are relative to this).
+    ptr: *mut u8,
+
+    /// Whether the buffer may be received into.
+    writable: bool,
+
+    /// Number of elements.
+    count: usize,
+
+    /// Layout of each element.
+    layout: Layout,
+
+    /// Minimum block size for memory regions.
+    region_threshold: usize,
+
+    /// Borrow of the underlying buffer.
+    data: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> LayoutBuffer<'a> {
+    /// Create a buffer to send count elements from the slice.
+    pub fn from_slice<T: Copy>(data: &'a [T], count: usize, layout: &Layout) -> DatatypeResult<LayoutBuffer<'a>> {
+        check_bounds(std::mem::size_of_val(data), count, layout)?;
+        Ok(unsafe { LayoutBuffer::new(data.as_ptr() as *mut u8, false, count, layout) })
+    }
+
+    /// Create a buffer to send or receive count elements in the slice.
+    pub fn from_mut_slice<T: Copy>(data: &'a mut [T], count: usize, layout: &Layout) -> DatatypeResult<LayoutBuffer<'a>> {
+        check_bounds(std::mem::size_of_val(data), count, layout)?;
+        Ok(unsafe { LayoutBuffer::new(data.as_mut_ptr() as *mut u8, true, count, layout) })
+    }
+
+    /// Create a buffer from a raw pointer. All bytes covered by the layout
+    /// must be valid for count elements starting at ptr, for the lifetime of
+    /// the buffer.
+    pub unsafe fn from_raw(ptr: *mut u8, count: usize, layout: &Layout) -> LayoutBuffer<'a> {
+        LayoutBuffer::new(ptr, true, count, layout)
+    }
+
+    unsafe fn new(ptr: *mut u8, writable: bool, count: usize, layout: &Layout) -> LayoutBuffer<'a> {
+        LayoutBuffer {
+            ptr,
+            writable,
+            count,
+            layout: layout.clone(),
+            region_threshold: LAYOUT_REGION_THRESHOLD,
+            data: PhantomData,
+        }
+    }
+
+    /// Set the minimum block size for sending blocks as memory regions
+    /// (usize::MAX packs everything).
+    pub fn region_threshold(mut self, threshold: usize) -> LayoutBuffer<'a> {
+        self.region_threshold = threshold;
+        self
+    }
+
+    /// Split all blocks of the buffer into packed blocks and regions.
+    fn plan(&self) -> LayoutState {
+        let extent = self.layout.extent() as isize;
+        let mut blocks = vec![];
+        for i in 0..self.count as isize {
+            for (disp, len) in &self.layout.blocks {
+                push_block(&mut blocks, i * extent + disp, *len);
+            }
+        }
+        let (regions, packed): (Vec<_>, Vec<_>) =
+            blocks.into_iter().partition(|(_, len)| *len >= self.region_threshold);
+        LayoutState {
+            ptr: self.ptr,
+            packed_size: packed.iter().map(|(_, len)| len).sum(),
+            packed,
+            regions,
+        }
+    }
+}
+
+/// Check that count elements of the layout fit in a buffer of size bytes.
+fn check_bounds(size: usize, count: usize, layout: &Layout) -> DatatypeResult<()> {
+    let Some((lb, ub)) = layout.true_bounds() else {
+        return Ok(());
+    };
+    if count == 0 {
+        return Ok(());
+    }
+    let last = (count as isize - 1) * layout.extent() as isize;
+    if lb < 0 || last + ub > size as isize {
+        return Err(DatatypeError::RegionError);
+    }
+    Ok(())
+}
+
+impl<'a> MessageCount for LayoutBuffer<'a> {
+    fn count(&self) -> usize {
+        self.count * self.layout.size()
+    }
+}
+
+impl<'a> MessagePointer for LayoutBuffer<'a> {
+    fn ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    fn ptr_mut(&mut self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+impl<'a> MessageBuffer for LayoutBuffer<'a> {
+    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
+        Some(Ok(Box::new(self.plan())))
+    }
+
+    unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
+        if !self.writable {
+            return Some(Err(DatatypeError::StateError));
+        }
+        Some(Ok(Box::new(self.plan())))
+    }
+}
+
+/// Pack and unpack state of a LayoutBuffer.
+struct LayoutState {
+    /// Start of the buffer.
+    ptr: *mut u8,
+
+    /// Small blocks that are packed, in order.
+    packed: Vec<(isize, usize)>,
+
+    /// Large blocks sent as memory regions.
+    regions: Vec<(isize, usize)>,
+
+    /// Total size of the packed blocks.
+    packed_size: usize,
+}
+
+impl LayoutState {
+    /// Call f with the buffer pointer, position in the packed data and length
+    /// of each piece of the packed blocks overlapping [offset, offset + size).
+    unsafe fn for_each_piece<F: FnMut(*mut u8, usize, usize)>(&self, offset: usize, size: usize, mut f: F) {
+        let end = offset + size;
+        let mut pos = 0;
+        for (disp, len) in &self.packed {
+            let block_end = pos + len;
+            if block_end > offset && pos < end {
+                let start = offset.max(pos);
+                let stop = end.min(block_end);
+                f(self.ptr.offset(disp + (start - pos) as isize), start - offset, stop - start);
+            }
+            if block_end >= end {
+                break;
+            }
+            pos = block_end;
+        }
+    }
+}
+
+impl PackedSize for LayoutState {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok(self.packed_size)
+    }
+}
+
+impl PackMethod for LayoutState {
+    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        if offset > self.packed_size {
+            return Err(DatatypeError::PackError);
+        }
+        let used = dst_size.min(self.packed_size - offset);
+        self.for_each_piece(offset, used, |src, pos, len| {
+            std::ptr::copy_nonoverlapping(src, dst.add(pos), len);
+        });
+        Ok(used)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        Ok(self.regions.iter().map(|(disp, len)| (self.ptr.offset(*disp) as *const u8, *len)).collect())
+    }
+}
+
+impl UnpackMethod for LayoutState {
+    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
+        if offset + src_size > self.packed_size {
+            return Err(DatatypeError::UnpackError);
+        }
+        self.for_each_piece(offset, src_size, |dst, pos, len| {
+            std::ptr::copy_nonoverlapping(src.add(pos), dst, len);
+        });
+        Ok(())
+    }
+
+    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
+        Ok(self.regions.iter().map(|(disp, len)| (self.ptr.offset(*disp), *len)).collect())
+    }
+}
@@ -1,4 +1,6 @@
 //! mpicd library code and entry points.
+// Unsafe functions document what they need in their summaries.
+#![allow(clippy::missing_safety_doc)]
 use log::{error, info};
 use mpicd_ucx_sys::{
     rust_ucp_init, ucp_address_t, ucp_cleanup, ucp_context_h, ucp_ep_close_nb,
@@ -7,7 +9,9 @@ use mpicd_ucx_sys::{
     ucp_worker_params_t, ucp_worker_release_address, ucs_status_string,
     ucs_status_t, UCP_EP_CLOSE_MODE_FORCE, UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE,
     UCP_EP_PARAM_FIELD_REMOTE_ADDRESS, UCP_ERR_HANDLING_MODE_PEER,
-    UCP_FEATURE_STREAM, UCP_FEATURE_TAG, UCP_PARAM_FIELD_FEATURES, UCP_PARAM_FIELD_MT_WORKERS_SHARED,
+    UCP_FEATURE_AM, UCP_FEATURE_AMO32, UCP_FEATURE_AMO64, UCP_FEATURE_RMA,
+    UCP_FEATURE_STREAM, UCP_FEATURE_TAG, UCP_FEATURE_WAKEUP,
+    UCP_PARAM_FIELD_FEATURES, UCP_PARAM_FIELD_MT_WORKERS_SHARED,
     UCP_WORKER_PARAM_FIELD_THREAD_MODE, UCS_OK, UCS_THREAD_MODE_SINGLE,
 };
 use std::cell::RefCell;
@@ -18,15 +22,41 @@ use std::rc::Rc;
 pub type Tag = ucp_tag_t;
 
 pub mod communicator;
+mod am;
+pub use am::{AmHandler, AmMessage};
+use am::AmState;
+mod barrier;
+pub use barrier::BarrierAlgorithm;
+mod bsend;
+mod collective;
+mod nbc;
 mod context;
 pub use context::Context;
 mod util;
 use util::wait_loop;
 pub mod datatype;
+mod layout;
+mod framing;
+mod signature;
+mod containers;
+pub mod op;
+pub mod serialize;
+pub mod descriptor;
 mod pmi;
 use pmi::PMI;
 mod request;
 mod message;
+mod rma;
+pub use rma::Window;
+mod atomic;
+pub use atomic::{AtomicInt, AtomicOp};
+mod stream;
+pub use stream::Stream;
+mod scope;
+pub use scope::{RecvRequest, Scope, SendRequest};
+mod future;
+pub use future::RequestFuture;
+use future::Wakeup;
 use message::Message;
 
 /// Status value for requests and messages.
@@ -88,6 +118,9 @@ pub(crate) struct System {
 
     /// Rank of this process.
     pub rank: usize,
+
+    /// Sequence number of the next message sent with a header.
+    pub header_seq: u8,
 }
 
 impl Drop for System {
@@ -98,7 +131,7 @@ impl Drop for System {
                 // For some reason UCP_EP_CLOSE_MODE_FLUSH is causing an
                 // infinite loop with two nodes.
                 // let req = ucp_ep_close_nb(endpoint, UCP_EP_CLOSE_MODE_FLUSH);
-                let req = ucp_ep_close_nb(ep.clone(), UCP_EP_CLOSE_MODE_FORCE);
+                let req = ucp_ep_close_nb(*ep, UCP_EP_CLOSE_MODE_FORCE);
                 wait_loop(self.worker, req, || false).unwrap();
             }
             ucp_worker_destroy(self.worker);
@@ -117,12 +150,17 @@ pub(crate) struct Handle {
 
     /// Index of free messages.
     pub free_messages: Vec<usize>,
+
+    /// Active message handlers and queued incoming messages.
+    pub am: AmState,
+
+    /// Event fd handling for request futures.
+    pub wakeup: Wakeup,
 }
 
 impl Handle {
     /// Add a new request pointer.
-    pub(crate) fn add_message(&mut self, message: impl Message + 'static) -> usize {
-        let message = Box::new(message);
+    pub(crate) fn add_message(&mut self, message: Box<dyn Message>) -> usize {
         if let Some(i) = self.free_messages.pop() {
             assert!(self.messages[i].is_none());
             let _ = self.messages[i].insert(message);
@@ -145,6 +183,32 @@ impl Handle {
         let _ = self.messages[msg_id].take();
         self.free_messages.push(msg_id);
     }
+
+    /// Handle a completed message, removing it unless it's persistent.
+    pub(crate) fn complete_message(&mut self, msg_id: usize) {
+        let msg = self.messages[msg_id].as_ref().expect("request is missing");
+        if !msg.persistent() {
+            self.remove_message(msg_id);
+        }
+    }
+
+    /// Return true if the message is persistent and inactive, so that it can
+    /// be started or freed.
+    pub(crate) fn inactive_persistent(&self, msg_id: usize) -> bool {
+        match self.messages.get(msg_id) {
+            Some(Some(msg)) => msg.persistent() && !msg.active(),
+            _ => false,
+        }
+    }
+
+    /// Start a persistent message, returning false if it's missing, not
+    /// persistent or already active.
+    pub(crate) fn start_message(&mut self, msg_id: usize) -> bool {
+        match self.messages.get_mut(msg_id) {
+            Some(Some(msg)) => msg.start(),
+            _ => false,
+        }
+    }
 }
 
 impl Drop for Handle {
@@ -155,6 +219,10 @@ impl Drop for Handle {
                 drop(msg);
             }
         }
+        unsafe {
+            self.am.clear();
+        }
+        self.wakeup.stop();
         // System data should be dropped here.
     }
 }
@@ -170,7 +238,13 @@ pub fn init() -> Result<Context> {
         let mut context = MaybeUninit::<ucp_context_h>::uninit();
         let params = ucp_params_t {
             field_mask: (UCP_PARAM_FIELD_FEATURES | UCP_PARAM_FIELD_MT_WORKERS_SHARED).into(),
-            features: (UCP_FEATURE_TAG | UCP_FEATURE_STREAM).into(),
+            features: (UCP_FEATURE_TAG
+                | UCP_FEATURE_STREAM
+                | UCP_FEATURE_AM
+                | UCP_FEATURE_RMA
+                | UCP_FEATURE_AMO32
+                | UCP_FEATURE_AMO64
+                | UCP_FEATURE_WAKEUP).into(),
             mt_workers_shared: 0,
             ..Default::default()
         };
@@ -200,7 +274,7 @@ pub fn init() -> Result<Context> {
                 if ep_rank == rank {
                     endpoints.push(create_endpoint(worker, &worker_addr));
                 } else {
-                    let addr: Vec<u8> = pmi.get(ep_rank as u32, "UCP_WORKER_ADDR");
+                    let addr: Vec<u8> = pmi.get(ep_rank, "UCP_WORKER_ADDR");
                     info!(
                         "(rank = {}) Got address for other proc: {:?}",
                         rank,
@@ -218,9 +292,12 @@ pub fn init() -> Result<Context> {
                     endpoints,
                     size: size as usize,
                     rank: rank as usize,
+                    header_seq: 0,
                 },
                 messages: vec![],
                 free_messages: vec![],
+                am: AmState::new(),
+                wakeup: Wakeup::new(worker),
             }))))
         }
     }
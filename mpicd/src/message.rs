@@ -1,14 +1,136 @@
 //! Request object.
+use std::mem::MaybeUninit;
 use mpicd_ucx_sys::{
-    rust_ucp_dt_make_contig, rust_ucp_dt_make_iov, ucp_dt_iov_t, ucp_worker_progress,
+    rust_ucp_dt_make_contig, rust_ucp_dt_make_iov, ucp_datatype_t, ucp_dt_iov_t, ucp_tag_probe_nb,
+    ucp_worker_h, ucp_worker_progress,
 };
 use crate::{Status, System};
-use crate::request::Request;
-use crate::datatype::{PackMethod, UnpackMethod};
+use crate::request::{data_tag, framed_tag, header_tag, Request, HEADER_PROBE_MASK};
+use crate::datatype::{DatatypeError, DatatypeResult, MessageBuffer, PackMethod, PackedShape, UnpackMethod};
+use crate::framing::{Contiguous, PackedCopy};
+use crate::signature::{message_signature, signed_pack, signed_unpack};
 
 pub(crate) trait Message {
     /// Progress the message and return the status.
     unsafe fn progress(&mut self, system: &mut System) -> Status;
+
+    /// Return true if the message should be kept after completion, so that it
+    /// can be started again.
+    fn persistent(&self) -> bool {
+        false
+    }
+
+    /// Start an inactive persistent message, returning false if this is not
+    /// possible.
+    fn start(&mut self) -> bool {
+        false
+    }
+
+    /// Return true if this is a persistent message that has been started and
+    /// not yet completed.
+    fn active(&self) -> bool {
+        false
+    }
+
+    /// Reset a completed message, so that it can be progressed again from the
+    /// start with the same buffer (see PersistentMessage).
+    fn reset(&mut self) {}
+}
+
+/// Create a send message for the buffer, packing it if necessary.
+pub(crate) unsafe fn send_message<B: MessageBuffer + ?Sized>(
+    data: &B,
+    dest: i32,
+    tag: u64,
+    sync: bool,
+) -> DatatypeResult<Box<dyn Message>> {
+    let signature = message_signature(data, tag);
+    if let Some(packer) = signed_pack(data, signature) {
+        Ok(Box::new(PackSendMessage::new(packer?, dest, tag, sync, signature)))
+    } else {
+        Ok(Box::new(ContiguousSendMessage::new(data.ptr() as *const _, data.count(), dest, tag, sync)))
+    }
+}
+
+/// Create a receive message for the buffer, unpacking it if necessary.
+pub(crate) unsafe fn recv_message<B: MessageBuffer + ?Sized>(
+    data: &mut B,
+    tag: u64,
+) -> DatatypeResult<Box<dyn Message>> {
+    let signature = message_signature(data, tag);
+    if let Some(unpack_method) = signed_unpack(data, signature) {
+        Ok(Box::new(PackRecvMessage::new(unpack_method?, tag, signature)))
+    } else {
+        Ok(Box::new(ContiguousRecvMessage::new(data.ptr_mut(), data.count(), tag)))
+    }
+}
+
+/// Claim the header of a message with the tag, if it has arrived, returning
+/// the header buffer, the request receiving it and the tag of the message
+/// data. The header is removed from the unexpected queue as it's matched, so
+/// that no other receive can claim it, and the data tag holds the sequence
+/// number of the message, so that only this receive gets its data.
+pub(crate) unsafe fn probe_header(worker: ucp_worker_h, tag: u64) -> Option<(Vec<u8>, Request, u64)> {
+    let mut info = MaybeUninit::uninit();
+    let msg = ucp_tag_probe_nb(worker, header_tag(framed_tag(tag, 0)), HEADER_PROBE_MASK, 1, info.as_mut_ptr());
+    if msg.is_null() {
+        return None;
+    }
+    let info = info.assume_init();
+    let mut buffer = vec![0; info.length];
+    let req = Request::msg_recv_nb(worker, msg, buffer.as_mut_ptr(), buffer.len(), rust_ucp_dt_make_contig(1));
+    Some((buffer, req, data_tag(info.sender_tag)))
+}
+
+/// Create a receive message for the buffer, whose header has already been
+/// received with probe_header() (see Communicator::recv_owned()).
+pub(crate) unsafe fn recv_message_with_header<B: MessageBuffer + ?Sized>(
+    data: &mut B,
+    tag: u64,
+    header: Vec<u8>,
+    data_tag: u64,
+) -> DatatypeResult<Box<dyn Message>> {
+    let signature = message_signature(data, tag);
+    let unpack_method = match signed_unpack(data, signature) {
+        Some(unpack_method) => unpack_method?,
+        None => Box::new(Contiguous {
+            ptr: data.ptr_mut(),
+            len: data.count(),
+        }),
+    };
+    Ok(Box::new(PackRecvMessage::with_header(unpack_method, tag, signature, header, data_tag)))
+}
+
+/// Pack the whole buffer into a copy like pack_with_shape(), returning the
+/// copy and a pack method sending it the way the buffer itself would be sent
+/// with the signature.
+pub(crate) unsafe fn pack_copy<B: MessageBuffer + ?Sized>(
+    data: &B,
+    signature: Option<u64>,
+) -> DatatypeResult<(Vec<u8>, PackedCopy)> {
+    let Some(pack_method) = signed_pack(data, signature) else {
+        let copy = std::slice::from_raw_parts(data.ptr(), data.count()).to_vec();
+        let shape = PackedShape { packed_size: copy.len(), region_lens: vec![] };
+        let ptr = copy.as_ptr();
+        return Ok((copy, PackedCopy { ptr, shape, header: false }));
+    };
+    let mut pack_method = pack_method?;
+    let packed_size = pack_method.packed_size()?;
+    let mut copy = vec![0; packed_size];
+    if packed_size > 0 {
+        let used = pack_method.pack(0, copy.as_mut_ptr(), packed_size)?;
+        if used != packed_size {
+            return Err(DatatypeError::PackError);
+        }
+    }
+    let mut region_lens = vec![];
+    for (ptr, len) in pack_method.memory_regions()? {
+        copy.extend_from_slice(std::slice::from_raw_parts(ptr, len));
+        region_lens.push(len);
+    }
+    let shape = PackedShape { packed_size, region_lens };
+    let ptr = copy.as_ptr();
+    Ok((copy, PackedCopy { ptr, shape, header: pack_method.header() }))
 }
 
 pub(crate) struct PackSendMessage {
@@ -21,6 +143,12 @@ pub(crate) struct PackSendMessage {
     /// Message tag.
     tag: u64,
 
+    /// Use a synchronous send.
+    sync: bool,
+
+    /// Signature to send in the header, if any.
+    signature: Option<u64>,
+
     /// Packed message buffer.
     packed_buffer: Vec<u8>,
 
@@ -30,12 +158,21 @@ pub(crate) struct PackSendMessage {
     /// Iovec send data.
     iovdata: Option<Vec<ucp_dt_iov_t>>,
 
+    /// Header buffer and pending request, if the type uses a header.
+    header: Option<(Vec<u8>, Request)>,
+
     /// Pending request.
     req: Option<Request>,
 }
 
 impl PackSendMessage {
-    pub(crate) unsafe fn new(pack_method: Box<dyn PackMethod>, dest: i32, tag: u64) -> PackSendMessage {
+    pub(crate) unsafe fn new(
+        pack_method: Box<dyn PackMethod>,
+        dest: i32,
+        tag: u64,
+        sync: bool,
+        signature: Option<u64>,
+    ) -> PackSendMessage {
         // Allocate the packed buffer if necessary.
         let packed_buffer = match pack_method.packed_size() {
             Ok(size) => vec![0; size],
@@ -46,9 +183,12 @@ impl PackSendMessage {
             pack_method,
             dest: dest as usize,
             tag,
+            sync,
+            signature,
             packed_buffer,
             offset: 0,
             iovdata: None,
+            header: None,
             req: None,
         }
     }
@@ -57,48 +197,78 @@ impl PackSendMessage {
 impl Message for PackSendMessage {
     unsafe fn progress(&mut self, system: &mut System) -> Status {
         if let Some(req) = self.req.as_ref() {
-            // Already have a request, just need to wait on it.
+            // Already have a request, just need to wait on it (and the header).
             ucp_worker_progress(system.worker);
-            req.status()
-        } else if self.offset < self.packed_buffer.len() {
-            // Pack the buffer all at once.
-            let dst_size = self.packed_buffer.len();
-            let dst = self.packed_buffer.as_mut_ptr().offset(self.offset as isize);
-            let used = self.pack_method
-                .pack(self.offset, dst, dst_size)
-                .expect("failed to pack buffer");
-            assert!(used == dst_size);
-            self.offset += used;
-
-            // Waiting on packing part.
-            Status::InProgress
+            match self.header.as_ref().map_or(Status::Complete, |(_, req)| req.status()) {
+                Status::Complete => req.status(),
+                status => status,
+            }
         } else {
+            if self.offset < self.packed_buffer.len() {
+                // Pack the buffer all at once.
+                let dst_size = self.packed_buffer.len();
+                let dst = self.packed_buffer.as_mut_ptr().add(self.offset);
+                let used = self.pack_method
+                    .pack(self.offset, dst, dst_size)
+                    .expect("failed to pack buffer");
+                assert!(used == dst_size);
+                self.offset += used;
+            }
+
             // Need to get the iovec data and submit the request.
-            let regions = self.pack_method
+            let regions: Vec<(*mut u8, usize)> = self.pack_method
                 .memory_regions()
-                .expect("failed to get memory regions for type");
+                .expect("failed to get memory regions for type")
+                .into_iter()
+                .map(|(buffer, length)| (buffer as *mut u8, length))
+                .collect();
 
-            let mut iovdata = vec![];
             // TODO: Must be careful about moving the data. Perhaps this
             // should be Pinned in some way?
-            if self.packed_buffer.len() > 0 {
-                iovdata.push(ucp_dt_iov_t {
-                    buffer: self.packed_buffer.as_mut_ptr() as *mut _,
-                    length: self.packed_buffer.len(),
-                });
-            }
-
-            for (buffer, length) in regions {
-                iovdata.push(ucp_dt_iov_t {
-                    buffer: buffer as *mut _,
-                    length,
-                });
-            }
+            let mut tag = self.tag;
+            let iovdata = if self.pack_method.header() || self.signature.is_some() {
+                // The data is bound to the header by a sequence number.
+                let seq = system.header_seq;
+                system.header_seq = seq.wrapping_add(1);
+                tag = framed_tag(self.tag, seq);
+
+                // The packed part is sent with the header, so that the data
+                // message only holds the memory regions. Signatures are
+                // always sent this way, so that they can be checked before
+                // the data is received.
+                let shape = PackedShape {
+                    packed_size: self.packed_buffer.len(),
+                    region_lens: regions.iter().map(|(_, len)| *len).collect(),
+                };
+                let header = shape.to_header(self.signature, &self.packed_buffer);
+                let req = Request::send_nb(
+                    system.endpoints[self.dest],
+                    header.as_ptr(),
+                    header.len(),
+                    rust_ucp_dt_make_contig(1),
+                    header_tag(tag),
+                    false,
+                );
+                let _ = self.header.insert((header, req));
+                build_iovdata(&mut [], regions)
+            } else {
+                build_iovdata(&mut self.packed_buffer, regions)
+            };
 
             let count = iovdata.len();
             let _ = self.iovdata.insert(iovdata);
 
-            if count == 1 {
+            if count == 0 {
+                // Send an empty data message for a header without regions.
+                let _ = self.req.insert(Request::send_nb(
+                    system.endpoints[self.dest],
+                    std::ptr::null(),
+                    0,
+                    rust_ucp_dt_make_contig(1),
+                    tag,
+                    self.sync,
+                ));
+            } else if count == 1 {
                 // Submit as contiguous.
                 let iovdata = self.iovdata.as_ref().expect("missing iovec data");
                 let _ = self.req.insert(Request::send_nb(
@@ -106,7 +276,8 @@ impl Message for PackSendMessage {
                     iovdata[0].buffer as *mut _,
                     iovdata[0].length,
                     rust_ucp_dt_make_contig(1),
-                    self.tag,
+                    tag,
+                    self.sync,
                 ));
             } else {
                 // Submit the request with both packed and memory region data.
@@ -115,12 +286,20 @@ impl Message for PackSendMessage {
                     self.iovdata.as_ref().expect("missing iovec data").as_ptr() as *const _,
                     count,
                     rust_ucp_dt_make_iov(),
-                    self.tag,
+                    tag,
+                    self.sync,
                 ));
             }
             Status::InProgress
         }
     }
+
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.iovdata = None;
+        self.header = None;
+        self.req = None;
+    }
 }
 
 pub(crate) struct PackRecvMessage {
@@ -130,46 +309,195 @@ pub(crate) struct PackRecvMessage {
     /// Message tag.
     tag: u64,
 
+    /// Tag of the message data, which differs from the message tag for
+    /// messages with a header.
+    data_tag: u64,
+
+    /// Signature expected in the header, if any.
+    signature: Option<u64>,
+
     /// Packed message buffer.
     packed_buffer: Vec<u8>,
 
     /// Iovec receive data.
     iovdata: Option<Vec<ucp_dt_iov_t>>,
 
+    /// Header state, if the type uses a header.
+    header: Option<RecvHeader>,
+
     /// Pending request.
     req: Option<Request>,
 }
 
+/// Receive state of a message header.
+enum RecvHeader {
+    /// Waiting for the header to arrive.
+    Probe,
+
+    /// Receiving the header into the buffer.
+    Receive(Vec<u8>, Request),
+
+    /// The header was received, but hasn't been checked yet.
+    Received(Vec<u8>),
+
+    /// The header was accepted, so the memory regions can be received. This
+    /// holds the packed part that came with the header.
+    Accepted(Vec<u8>),
+
+    /// The message was rejected and its data is being received into the
+    /// scratch buffer.
+    Rejected(Vec<u8>, Request, DatatypeError),
+
+    /// The header couldn't be decoded.
+    Invalid(DatatypeError),
+}
+
 impl PackRecvMessage {
-    pub(crate) unsafe fn new(unpack_method: Box<dyn UnpackMethod>, tag: u64) -> PackRecvMessage {
+    pub(crate) unsafe fn new(
+        unpack_method: Box<dyn UnpackMethod>,
+        tag: u64,
+        signature: Option<u64>,
+    ) -> PackRecvMessage {
         // Allocate the packed buffer if necessary.
         let packed_buffer = match unpack_method.packed_size() {
             Ok(size) => vec![0; size],
             Err(err) => panic!("Error occured while getting the packed size of a type: {:?}", err),
         };
+        let header = if unpack_method.header() || signature.is_some() {
+            Some(RecvHeader::Probe)
+        } else {
+            None
+        };
 
         PackRecvMessage {
             unpack_method,
             tag,
+            data_tag: tag,
+            signature,
             packed_buffer,
             iovdata: None,
+            header,
             req: None,
         }
     }
+
+    /// Create a message receiving the data of a message whose header has
+    /// already been received, whether or not the type uses a header.
+    pub(crate) unsafe fn with_header(
+        unpack_method: Box<dyn UnpackMethod>,
+        tag: u64,
+        signature: Option<u64>,
+        header: Vec<u8>,
+        data_tag: u64,
+    ) -> PackRecvMessage {
+        let mut message = PackRecvMessage::new(unpack_method, tag, signature);
+        message.header = Some(RecvHeader::Received(header));
+        message.data_tag = data_tag;
+        message
+    }
+
+    /// Progress the header, returning the status of the message until the
+    /// header has been accepted.
+    unsafe fn progress_header(&mut self, system: &mut System) -> Option<Status> {
+        let (header, status) = match self.header.take()? {
+            RecvHeader::Probe => match probe_header(system.worker, self.tag) {
+                Some((buffer, req, data_tag)) => {
+                    self.data_tag = data_tag;
+                    (RecvHeader::Receive(buffer, req), Some(Status::InProgress))
+                }
+                None => {
+                    ucp_worker_progress(system.worker);
+                    (RecvHeader::Probe, Some(Status::InProgress))
+                }
+            },
+            RecvHeader::Receive(buffer, req) => {
+                ucp_worker_progress(system.worker);
+                match req.status() {
+                    Status::Complete => self.check_header(system, buffer),
+                    status => (RecvHeader::Receive(buffer, req), Some(status)),
+                }
+            }
+            RecvHeader::Received(buffer) => self.check_header(system, buffer),
+            RecvHeader::Accepted(packed) => (RecvHeader::Accepted(packed), None),
+            RecvHeader::Rejected(scratch, req, err) => {
+                ucp_worker_progress(system.worker);
+                let status = match req.status() {
+                    Status::InProgress => Status::InProgress,
+                    _ => Status::Error(format!("message rejected: {:?}", err)),
+                };
+                (RecvHeader::Rejected(scratch, req, err), Some(status))
+            }
+            RecvHeader::Invalid(err) => {
+                let status = Status::Error(format!("invalid message header: {:?}", err));
+                (RecvHeader::Invalid(err), Some(status))
+            }
+        };
+        let _ = self.header.insert(header);
+        status
+    }
+
+    /// Decode a received header, check its signature and prepare for its
+    /// shape, returning the new header state and the status of the message.
+    unsafe fn check_header(&mut self, system: &mut System, buffer: Vec<u8>) -> (RecvHeader, Option<Status>) {
+        match PackedShape::from_header(&buffer) {
+            Ok((shape, signature, packed)) => match self.accept(&shape, signature) {
+                Ok(()) => (RecvHeader::Accepted(packed.to_vec()), None),
+                Err(err) => {
+                    // Receive the memory regions anyway, so that they don't
+                    // get matched by a later receive.
+                    let mut scratch = vec![0; shape.region_lens.iter().sum()];
+                    let req = Request::recv_nb(
+                        system.worker,
+                        scratch.as_mut_ptr(),
+                        scratch.len(),
+                        rust_ucp_dt_make_contig(1),
+                        self.data_tag,
+                    );
+                    (RecvHeader::Rejected(scratch, req, err), Some(Status::InProgress))
+                }
+            },
+            Err(err) => {
+                let status = Status::Error(format!("invalid message header: {:?}", err));
+                (RecvHeader::Invalid(err), Some(status))
+            }
+        }
+    }
+
+    /// Check the signature, then prepare the unpack method for the shape and
+    /// check that its layout holds exactly the incoming data.
+    unsafe fn accept(&mut self, shape: &PackedShape, signature: Option<u64>) -> DatatypeResult<()> {
+        if signature != self.signature {
+            return Err(DatatypeError::DatatypeMismatch);
+        }
+        self.unpack_method.prepare(shape)?;
+        let packed_size = self.unpack_method.packed_size()?;
+        let regions = self.unpack_method.memory_regions()?;
+        if packed_size + regions.iter().map(|(_, len)| len).sum::<usize>() != shape.total_size() {
+            return Err(DatatypeError::UnpackError);
+        }
+        self.packed_buffer = vec![0; packed_size];
+        Ok(())
+    }
 }
 
 impl Message for PackRecvMessage {
     unsafe fn progress(&mut self, system: &mut System) -> Status {
+        if let Some(status) = self.progress_header(system) {
+            return status;
+        }
+
         if let Some(req) = self.req.as_ref() {
             // Progress the existing request.
             ucp_worker_progress(system.worker);
             match req.status() {
                 Status::Complete => {
-                    if self.packed_buffer.len() > 0 {
+                    if !self.packed_buffer.is_empty() {
                         // Now need to unpack the data.
-                        self.unpack_method
-                            .unpack(0, self.packed_buffer.as_ptr(), self.packed_buffer.len())
-                            .expect("failed to unpack the data");
+                        let result = self.unpack_method
+                            .unpack(0, self.packed_buffer.as_ptr(), self.packed_buffer.len());
+                        if let Err(err) = result {
+                            return Status::Error(format!("failed to unpack the data: {:?}", err));
+                        }
                     }
                     Status::Complete
                 }
@@ -181,35 +509,60 @@ impl Message for PackRecvMessage {
                 .memory_regions()
                 .expect("failed to get memory regions for type");
 
-            let mut iovdata = vec![];
             // TODO: Must be careful about moving the data. Perhaps this
             // should be Pinned in some way?
-            if self.packed_buffer.len() > 0 {
-                iovdata.push(ucp_dt_iov_t {
-                    buffer: self.packed_buffer.as_mut_ptr() as *mut _,
-                    length: self.packed_buffer.len(),
-                });
-            }
-
-            for (buffer, length) in regions {
-                iovdata.push(ucp_dt_iov_t {
-                    buffer: buffer as *mut _,
-                    length,
-                });
+            let mut iovdata = build_iovdata(&mut self.packed_buffer, regions);
+            if let Some(RecvHeader::Accepted(packed)) = self.header.as_ref() {
+                // The start of the message came with the header.
+                iovdata = copy_prefix(iovdata, packed);
             }
             let count = iovdata.len();
             let _ = self.iovdata.insert(iovdata);
 
-            let _ = self.req.insert(Request::recv_nb(
-                system.worker,
-                self.iovdata.as_ref().expect("missing iovec data").as_ptr() as *mut _,
-                count,
-                rust_ucp_dt_make_iov(),
-                self.tag,
-            ));
+            let req = if count == 0 {
+                // Receive the empty data message of a header without regions.
+                Request::recv_nb(system.worker, std::ptr::null_mut(), 0, rust_ucp_dt_make_contig(1), self.data_tag)
+            } else {
+                Request::recv_nb(
+                    system.worker,
+                    self.iovdata.as_ref().expect("missing iovec data").as_ptr() as *mut _,
+                    count,
+                    rust_ucp_dt_make_iov(),
+                    self.data_tag,
+                )
+            };
+            let _ = self.req.insert(req);
             Status::InProgress
         }
     }
+
+    fn reset(&mut self) {
+        self.data_tag = self.tag;
+        self.iovdata = None;
+        if self.header.is_some() {
+            self.header = Some(RecvHeader::Probe);
+        }
+        self.req = None;
+    }
+}
+
+/// Copy the data into the start of the memory covered by the iovec list,
+/// returning the entries covering the rest of it.
+unsafe fn copy_prefix(iovdata: Vec<ucp_dt_iov_t>, data: &[u8]) -> Vec<ucp_dt_iov_t> {
+    let mut pos = 0;
+    let mut rest = vec![];
+    for iov in iovdata {
+        let used = (data.len() - pos).min(iov.length);
+        std::ptr::copy_nonoverlapping(data[pos..].as_ptr(), iov.buffer as *mut u8, used);
+        pos += used;
+        if used < iov.length {
+            rest.push(ucp_dt_iov_t {
+                buffer: (iov.buffer as *mut u8).add(used) as *mut _,
+                length: iov.length - used,
+            });
+        }
+    }
+    rest
 }
 
 /// Send message for contiguous data.
@@ -218,16 +571,18 @@ pub(crate) struct ContiguousSendMessage {
     count: usize,
     dest: usize,
     tag: u64,
+    sync: bool,
     req: Option<Request>,
 }
 
 impl ContiguousSendMessage {
-    pub(crate) fn new(ptr: *const u8, count: usize, dest: i32, tag: u64) -> ContiguousSendMessage {
+    pub(crate) fn new(ptr: *const u8, count: usize, dest: i32, tag: u64, sync: bool) -> ContiguousSendMessage {
         ContiguousSendMessage {
             ptr,
             count,
             dest: dest as usize,
             tag,
+            sync,
             req: None,
         }
     }
@@ -246,10 +601,15 @@ impl Message for ContiguousSendMessage {
                 self.count,
                 rust_ucp_dt_make_contig(1),
                 self.tag,
+                self.sync,
             ));
             Status::InProgress
         }
     }
+
+    fn reset(&mut self) {
+        self.req = None;
+    }
 }
 
 /// Send message for contiguous data.
@@ -289,4 +649,145 @@ impl Message for ContiguousRecvMessage {
         }
         // TODO
     }
+
+    fn reset(&mut self) {
+        self.req = None;
+    }
+}
+
+
+/// Build the iovec list for a packed buffer followed by memory regions.
+pub(crate) fn build_iovdata(packed_buffer: &mut [u8], regions: Vec<(*mut u8, usize)>) -> Vec<ucp_dt_iov_t> {
+    let mut iovdata = vec![];
+    if !packed_buffer.is_empty() {
+        iovdata.push(ucp_dt_iov_t {
+            buffer: packed_buffer.as_mut_ptr() as *mut _,
+            length: packed_buffer.len(),
+        });
+    }
+    for (buffer, length) in regions {
+        iovdata.push(ucp_dt_iov_t {
+            buffer: buffer as *mut _,
+            length,
+        });
+    }
+    iovdata
+}
+
+/// Send-side layout of a buffer: the pack method (if it's not contiguous), the
+/// packed buffer and the iovec list covering the packed buffer and memory
+/// regions.
+pub(crate) struct SendLayout {
+    /// Pack method, if this is not a contiguous buffer.
+    pack_method: Option<Box<dyn PackMethod>>,
+
+    /// Packed message buffer.
+    packed_buffer: Vec<u8>,
+
+    /// Iovec send data.
+    iovdata: Vec<ucp_dt_iov_t>,
+}
+
+impl SendLayout {
+    /// Create the layout.
+    pub(crate) unsafe fn new<B: MessageBuffer + ?Sized>(data: &B) -> DatatypeResult<SendLayout> {
+        let (pack_method, mut packed_buffer, regions) = if let Some(pack_method) = data.pack() {
+            let pack_method = pack_method?;
+            let packed_buffer = vec![0; pack_method.packed_size()?];
+            let regions = pack_method
+                .memory_regions()?
+                .into_iter()
+                .map(|(buffer, length)| (buffer as *mut u8, length))
+                .collect();
+            (Some(pack_method), packed_buffer, regions)
+        } else {
+            (None, vec![], vec![(data.ptr() as *mut u8, data.count())])
+        };
+        let iovdata = build_iovdata(&mut packed_buffer, regions);
+
+        Ok(SendLayout {
+            pack_method,
+            packed_buffer,
+            iovdata,
+        })
+    }
+
+    /// Pack the packed part of the buffer.
+    pub(crate) unsafe fn pack(&mut self) -> DatatypeResult<()> {
+        if let Some(pack_method) = self.pack_method.as_mut() {
+            if !self.packed_buffer.is_empty() {
+                let size = self.packed_buffer.len();
+                let used = pack_method.pack(0, self.packed_buffer.as_mut_ptr(), size)?;
+                if used != size {
+                    return Err(DatatypeError::PackError);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the buffer, count and datatype to pass to UCX, using a
+    /// contiguous datatype if there's only one part.
+    pub(crate) unsafe fn ucx_data(&self) -> (*const u8, usize, ucp_datatype_t) {
+        if self.iovdata.len() == 1 {
+            (self.iovdata[0].buffer as *const _, self.iovdata[0].length, rust_ucp_dt_make_contig(1))
+        } else {
+            (self.iovdata.as_ptr() as *const _, self.iovdata.len(), rust_ucp_dt_make_iov())
+        }
+    }
+}
+
+/// Persistent message, wrapping a send or receive message that is reset on
+/// every start.
+///
+/// The pack or unpack method is created once and reused for every start, so
+/// the memory regions of the buffer must stay the same between starts. Only
+/// the packed part is repacked each time, and headers are sent or received
+/// again each time.
+pub(crate) struct PersistentMessage {
+    /// Inner message.
+    inner: Box<dyn Message>,
+
+    /// Whether the message has been started and not yet completed.
+    active: bool,
+}
+
+impl PersistentMessage {
+    pub(crate) fn new(inner: Box<dyn Message>) -> PersistentMessage {
+        PersistentMessage {
+            inner,
+            active: false,
+        }
+    }
+}
+
+impl Message for PersistentMessage {
+    unsafe fn progress(&mut self, system: &mut System) -> Status {
+        if !self.active {
+            // Inactive requests complete immediately.
+            return Status::Complete;
+        }
+        let status = self.inner.progress(system);
+        if status != Status::InProgress {
+            self.active = false;
+        }
+        status
+    }
+
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    fn start(&mut self) -> bool {
+        if self.active {
+            return false;
+        }
+        self.active = true;
+        self.inner.reset();
+        true
+    }
+
+    fn active(&self) -> bool {
+        self.active
+    }
 }
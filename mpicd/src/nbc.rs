@@ -0,0 +1,535 @@
+//! Non-blocking collectives implemented as progressable messages.
+//!
+//! Each operation is turned into a schedule of steps: rounds of point-to-point
+//! messages that must all complete before the next step starts, and local
+//! computations (such as applying a reduction operation) run between rounds.
+//! The whole schedule is stored as a single message in the handle, so it
+//! advances whenever its request is progressed in waitall().
+//!
+//! Messages use the COLLECTIVE_TAG class with a per-context sequence number,
+//! the phase and the operation stored in the application tag, so that
+//! concurrent non-blocking collectives never match each other's messages, nor
+//! those of the blocking collectives.
+use std::borrow::{Borrow, BorrowMut};
+use std::collections::VecDeque;
+use crate::{
+    collective,
+    communicator::{self, Communicator},
+    datatype::MessageBuffer,
+    message::{send_message, recv_message, Message, ContiguousSendMessage, ContiguousRecvMessage},
+    op::ReduceOp,
+    request::{encode_tag, COLLECTIVE_TAG},
+    Context, Status, System,
+};
+
+/// Operations stored in the low byte of the application tag (these must not
+/// overlap with the blocking collective operations).
+const IBARRIER_OP: i32 = 16;
+const IBCAST_OP: i32 = 17;
+const IGATHER_OP: i32 = 18;
+const ISCATTER_OP: i32 = 19;
+const IALLGATHER_OP: i32 = 20;
+const IALLTOALL_OP: i32 = 21;
+const IREDUCE_OP: i32 = 22;
+const IALLREDUCE_OP: i32 = 23;
+const ISCAN_OP: i32 = 24;
+const IEXSCAN_OP: i32 = 25;
+
+/// Maximum sequence number (kept to 15 bits so the tag stays positive).
+pub(crate) const MAX_SEQ: i32 = 0x7FFF;
+
+/// Function creating a deferred round.
+type DeferredFn<'a> = Box<dyn FnOnce() -> communicator::Result<Vec<Box<dyn Message>>> + 'a>;
+
+/// Step of a collective schedule.
+enum Step {
+    /// Messages that all need to complete before moving on.
+    Round(Vec<Box<dyn Message>>),
+
+    /// Local computation to run once the previous rounds have completed.
+    Compute(Box<dyn FnOnce() -> communicator::Result<()>>),
+
+    /// Round of messages that is only created once the previous steps have
+    /// completed, for buffers whose contents (and so packed size and regions)
+    /// are computed by those steps.
+    Deferred(DeferredFn<'static>),
+}
+
+/// Buffer owned by a collective schedule.
+trait OwnedBuffer {}
+
+impl<T: ?Sized> OwnedBuffer for T {}
+
+/// Message progressing a non-blocking collective round by round.
+pub(crate) struct CollectiveMessage {
+    /// Number of processes.
+    size: i32,
+
+    /// Rank of this process.
+    rank: i32,
+
+    /// Sequence number of this collective.
+    seq: i32,
+
+    /// Remaining steps.
+    steps: VecDeque<Step>,
+
+    /// Messages of the current round that haven't completed yet.
+    current: Vec<Box<dyn Message>>,
+
+    /// Temporary buffers, which are only freed along with the schedule so
+    /// that they outlive every step using them, whether or not it completes.
+    /// This comes last so that the messages are dropped first.
+    owned: Vec<Box<dyn OwnedBuffer>>,
+}
+
+impl CollectiveMessage {
+    /// Create a new empty schedule.
+    fn new(ctx: &Context) -> CollectiveMessage {
+        CollectiveMessage {
+            size: ctx.size(),
+            rank: ctx.rank(),
+            seq: ctx.next_collective_seq(),
+            steps: VecDeque::new(),
+            current: vec![],
+            owned: vec![],
+        }
+    }
+
+    /// Get the tag for a message sent from the rank.
+    fn tag(&self, rank: i32, op: i32, phase: i32) -> u64 {
+        assert!(phase < 256);
+        encode_tag(COLLECTIVE_TAG, rank, (self.seq << 16) | (phase << 8) | op)
+    }
+
+    /// Add a round of messages.
+    fn round(&mut self, messages: Vec<Box<dyn Message>>) {
+        if !messages.is_empty() {
+            self.steps.push_back(Step::Round(messages));
+        }
+    }
+
+    /// Add a computation step.
+    ///
+    /// SAFETY: Everything captured by the closure must outlive the request
+    /// (the same requirement as for the buffers passed to isend() and irecv()).
+    unsafe fn compute<'a, F: FnOnce() -> communicator::Result<()> + 'a>(&mut self, f: F) {
+        let f: Box<dyn FnOnce() -> communicator::Result<()> + 'a> = Box::new(f);
+        let f = std::mem::transmute::<
+            Box<dyn FnOnce() -> communicator::Result<()> + 'a>,
+            Box<dyn FnOnce() -> communicator::Result<()>>,
+        >(f);
+        self.steps.push_back(Step::Compute(f));
+    }
+
+    /// Add a round created by the function once the previous steps have
+    /// completed.
+    ///
+    /// SAFETY: As for compute(), everything captured by the closure must
+    /// outlive the request.
+    unsafe fn deferred<'a, F>(&mut self, f: F)
+    where
+        F: FnOnce() -> communicator::Result<Vec<Box<dyn Message>>> + 'a,
+    {
+        let f: DeferredFn<'a> = Box::new(f);
+        let f = std::mem::transmute::<DeferredFn<'a>, DeferredFn<'static>>(f);
+        self.steps.push_back(Step::Deferred(f));
+    }
+
+    /// Keep the buffer until the schedule is dropped, returning a pointer to
+    /// it for the steps using it.
+    ///
+    /// SAFETY: As for compute(), anything borrowed by the buffer must outlive
+    /// the request.
+    unsafe fn hold<'a, T: 'a>(&mut self, buffer: T) -> *mut T {
+        let mut buffer = Box::new(buffer);
+        let ptr: *mut T = &mut *buffer;
+        let buffer: Box<dyn OwnedBuffer + 'a> = buffer;
+        self.owned.push(std::mem::transmute::<Box<dyn OwnedBuffer + 'a>, Box<dyn OwnedBuffer>>(buffer));
+        ptr
+    }
+
+    /// Add the steps for a linear broadcast from the root.
+    unsafe fn bcast<B: MessageBuffer + ?Sized>(
+        &mut self,
+        data: &mut B,
+        root: i32,
+        op: i32,
+        phase: i32,
+    ) -> communicator::Result<()> {
+        let messages = bcast_messages(data, root, self.size, self.rank, self.tag(root, op, phase))?;
+        self.round(messages);
+        Ok(())
+    }
+
+    /// Add the steps for a linear broadcast from the root of data that is
+    /// computed by the previous steps.
+    unsafe fn deferred_bcast<B: MessageBuffer + ?Sized>(&mut self, data: &mut B, root: i32, op: i32, phase: i32) {
+        let (size, rank, tag) = (self.size, self.rank, self.tag(root, op, phase));
+        let data: *mut B = data;
+        self.deferred(move || bcast_messages(&mut *data, root, size, rank, tag));
+    }
+
+    /// Add the steps for a reduction onto the root.
+    ///
+    /// As in the blocking version, the root receives every contribution and
+    /// then applies the operation in rank order.
+    unsafe fn reduce<B, O>(
+        &mut self,
+        sbuf: &B,
+        rbuf: &mut B,
+        op: &O,
+        root: i32,
+        op_tag: i32,
+        phase: i32,
+    ) -> communicator::Result<()>
+    where
+        B: MessageBuffer + ToOwned + ?Sized,
+        B::Owned: BorrowMut<B>,
+        O: ReduceOp<B> + ?Sized,
+    {
+        if self.rank != root {
+            let message = send(sbuf, root, self.tag(self.rank, op_tag, phase))?;
+            self.round(vec![message]);
+            return Ok(());
+        }
+
+        // The contribution from the last rank goes directly into rbuf, all
+        // others need a temporary buffer (the root's own is just a copy of
+        // sbuf). Moving the vector into the closure below doesn't move the
+        // buffers themselves.
+        let last = self.size - 1;
+        let mut temps: Vec<B::Owned> = (0..last).map(|_| sbuf.to_owned()).collect();
+        let mut messages = vec![];
+        for (i, temp) in temps.iter_mut().enumerate() {
+            if i as i32 != root {
+                let temp: &mut B = temp.borrow_mut();
+                messages.push(recv(temp, self.tag(i as i32, op_tag, phase))?);
+            }
+        }
+        if root != last {
+            messages.push(recv(rbuf, self.tag(last, op_tag, phase))?);
+        }
+        self.round(messages);
+
+        let sbuf: *const B = sbuf;
+        let rbuf: *mut B = rbuf;
+        self.compute(move || {
+            let rbuf = &mut *rbuf;
+            if root == last {
+                collective::copy(&*sbuf, rbuf)?;
+            }
+            for temp in temps.iter().rev() {
+                op.apply(temp.borrow(), rbuf);
+            }
+            Ok(())
+        });
+        Ok(())
+    }
+}
+
+impl Message for CollectiveMessage {
+    unsafe fn progress(&mut self, system: &mut System) -> Status {
+        // Progress the current round.
+        let mut i = 0;
+        while i < self.current.len() {
+            match self.current[i].progress(system) {
+                Status::InProgress => i += 1,
+                Status::Complete => {
+                    let _ = self.current.swap_remove(i);
+                }
+                status => return status,
+            }
+        }
+        if !self.current.is_empty() {
+            return Status::InProgress;
+        }
+
+        // Run computations until the next round or the end of the schedule.
+        while let Some(step) = self.steps.pop_front() {
+            match step {
+                Step::Round(messages) => {
+                    self.current = messages;
+                    return Status::InProgress;
+                }
+                Step::Compute(f) => {
+                    if let Err(err) = f() {
+                        return Status::Error(format!("{:?}", err));
+                    }
+                }
+                Step::Deferred(f) => match f() {
+                    Ok(messages) if messages.is_empty() => (),
+                    Ok(messages) => {
+                        self.current = messages;
+                        return Status::InProgress;
+                    }
+                    Err(err) => return Status::Error(format!("{:?}", err)),
+                },
+            }
+        }
+        Status::Complete
+    }
+}
+
+/// Create a send message.
+unsafe fn send<B: MessageBuffer + ?Sized>(data: &B, dest: i32, tag: u64) -> communicator::Result<Box<dyn Message>> {
+    send_message(data, dest, tag, false).map_err(communicator::Error::Datatype)
+}
+
+/// Create a receive message.
+unsafe fn recv<B: MessageBuffer + ?Sized>(data: &mut B, tag: u64) -> communicator::Result<Box<dyn Message>> {
+    recv_message(data, tag).map_err(communicator::Error::Datatype)
+}
+
+/// Create the messages of a linear broadcast from the root.
+unsafe fn bcast_messages<B: MessageBuffer + ?Sized>(
+    data: &mut B,
+    root: i32,
+    size: i32,
+    rank: i32,
+    tag: u64,
+) -> communicator::Result<Vec<Box<dyn Message>>> {
+    let mut messages = vec![];
+    if rank == root {
+        for i in (0..size).filter(|i| *i != root) {
+            messages.push(send(data, i, tag)?);
+        }
+    } else {
+        messages.push(recv(data, tag)?);
+    }
+    Ok(messages)
+}
+
+/// Non-blocking dissemination barrier, using zero-byte messages.
+pub(crate) fn ibarrier(ctx: &Context) -> CollectiveMessage {
+    let mut msg = CollectiveMessage::new(ctx);
+    let size = msg.size;
+    let rank = msg.rank;
+
+    let mut dist = 1;
+    let mut phase = 0;
+    while dist < size {
+        let dest = (rank + dist) % size;
+        let source = (rank + size - dist) % size;
+        msg.round(vec![
+            Box::new(ContiguousRecvMessage::new(std::ptr::null_mut(), 0, msg.tag(source, IBARRIER_OP, phase))),
+            Box::new(ContiguousSendMessage::new(std::ptr::null(), 0, dest, msg.tag(rank, IBARRIER_OP, phase), false)),
+        ]);
+        dist <<= 1;
+        phase += 1;
+    }
+    msg
+}
+
+/// Non-blocking broadcast.
+pub(crate) unsafe fn ibcast<B: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    data: &mut B,
+    root: i32,
+) -> communicator::Result<CollectiveMessage> {
+    let mut msg = CollectiveMessage::new(ctx);
+    collective::check_root(ctx, root)?;
+    msg.bcast(data, root, IBCAST_OP, 0)?;
+    Ok(msg)
+}
+
+/// Non-blocking gather onto the root.
+pub(crate) unsafe fn igather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbuf: &S,
+    rbufs: &mut [&mut R],
+    root: i32,
+) -> communicator::Result<CollectiveMessage> {
+    let mut msg = CollectiveMessage::new(ctx);
+    collective::check_root(ctx, root)?;
+
+    let mut messages = vec![];
+    if msg.rank == root {
+        collective::check_count(ctx, rbufs.len())?;
+        for (i, rbuf) in rbufs.iter_mut().enumerate() {
+            messages.push(recv(*rbuf, msg.tag(i as i32, IGATHER_OP, 0))?);
+        }
+    }
+    messages.push(send(sbuf, root, msg.tag(msg.rank, IGATHER_OP, 0))?);
+    msg.round(messages);
+    Ok(msg)
+}
+
+/// Non-blocking scatter from the root.
+pub(crate) unsafe fn iscatter<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbufs: &[&S],
+    rbuf: &mut R,
+    root: i32,
+) -> communicator::Result<CollectiveMessage> {
+    let mut msg = CollectiveMessage::new(ctx);
+    collective::check_root(ctx, root)?;
+
+    let mut messages = vec![recv(rbuf, msg.tag(root, ISCATTER_OP, 0))?];
+    if msg.rank == root {
+        collective::check_count(ctx, sbufs.len())?;
+        for (i, sbuf) in sbufs.iter().enumerate() {
+            messages.push(send(*sbuf, i as i32, msg.tag(root, ISCATTER_OP, 0))?);
+        }
+    }
+    msg.round(messages);
+    Ok(msg)
+}
+
+/// Non-blocking allgather.
+pub(crate) unsafe fn iallgather<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbuf: &S,
+    rbufs: &mut [&mut R],
+) -> communicator::Result<CollectiveMessage> {
+    let mut msg = CollectiveMessage::new(ctx);
+    collective::check_count(ctx, rbufs.len())?;
+
+    let mut messages = vec![];
+    for (i, rbuf) in rbufs.iter_mut().enumerate() {
+        messages.push(recv(*rbuf, msg.tag(i as i32, IALLGATHER_OP, 0))?);
+    }
+    for i in 0..msg.size {
+        messages.push(send(sbuf, i, msg.tag(msg.rank, IALLGATHER_OP, 0))?);
+    }
+    msg.round(messages);
+    Ok(msg)
+}
+
+/// Non-blocking alltoall.
+pub(crate) unsafe fn ialltoall<S: MessageBuffer + ?Sized, R: MessageBuffer + ?Sized>(
+    ctx: &Context,
+    sbufs: &[&S],
+    rbufs: &mut [&mut R],
+) -> communicator::Result<CollectiveMessage> {
+    let mut msg = CollectiveMessage::new(ctx);
+    collective::check_count(ctx, sbufs.len())?;
+    collective::check_count(ctx, rbufs.len())?;
+
+    let mut messages = vec![];
+    for (i, rbuf) in rbufs.iter_mut().enumerate() {
+        messages.push(recv(*rbuf, msg.tag(i as i32, IALLTOALL_OP, 0))?);
+    }
+    for (i, sbuf) in sbufs.iter().enumerate() {
+        messages.push(send(*sbuf, i as i32, msg.tag(msg.rank, IALLTOALL_OP, 0))?);
+    }
+    msg.round(messages);
+    Ok(msg)
+}
+
+/// Non-blocking reduction onto the root.
+pub(crate) unsafe fn ireduce<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+    root: i32,
+) -> communicator::Result<CollectiveMessage>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    let mut msg = CollectiveMessage::new(ctx);
+    collective::check_root(ctx, root)?;
+    msg.reduce(sbuf, rbuf, op, root, IREDUCE_OP, 0)?;
+    Ok(msg)
+}
+
+/// Non-blocking allreduce: a reduction onto rank 0 followed by a broadcast.
+pub(crate) unsafe fn iallreduce<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+) -> communicator::Result<CollectiveMessage>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    let mut msg = CollectiveMessage::new(ctx);
+    msg.reduce(sbuf, rbuf, op, 0, IALLREDUCE_OP, 0)?;
+    // The result is only known once the reduction is done.
+    msg.deferred_bcast(rbuf, 0, IALLREDUCE_OP, 1);
+    Ok(msg)
+}
+
+/// Non-blocking inclusive prefix reduction, passing the partial result along
+/// from rank to rank as in the blocking version.
+pub(crate) unsafe fn iscan<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+) -> communicator::Result<CollectiveMessage>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    let mut msg = CollectiveMessage::new(ctx);
+    let (size, rank) = (msg.size, msg.rank);
+
+    let mut prefix = None;
+    if rank > 0 {
+        let temp = msg.hold(sbuf.to_owned());
+        msg.round(vec![recv((*temp).borrow_mut(), msg.tag(rank - 1, ISCAN_OP, 0))?]);
+        prefix = Some(temp);
+    }
+    let sbuf: *const B = sbuf;
+    let rbuf: *mut B = rbuf;
+    msg.compute(move || {
+        let rbuf = &mut *rbuf;
+        collective::copy(&*sbuf, rbuf)?;
+        if let Some(prefix) = prefix {
+            op.apply((*prefix).borrow(), rbuf);
+        }
+        Ok(())
+    });
+
+    if rank < (size - 1) {
+        let tag = msg.tag(rank, ISCAN_OP, 0);
+        msg.deferred(move || Ok(vec![send(&*rbuf, rank + 1, tag)?]));
+    }
+    Ok(msg)
+}
+
+/// Non-blocking exclusive prefix reduction (`rbuf` is left untouched on rank
+/// 0).
+pub(crate) unsafe fn iexscan<B, O>(
+    ctx: &Context,
+    sbuf: &B,
+    rbuf: &mut B,
+    op: &O,
+) -> communicator::Result<CollectiveMessage>
+where
+    B: MessageBuffer + ToOwned + ?Sized,
+    B::Owned: BorrowMut<B>,
+    O: ReduceOp<B> + ?Sized,
+{
+    let mut msg = CollectiveMessage::new(ctx);
+    let (size, rank) = (msg.size, msg.rank);
+
+    if rank > 0 {
+        msg.round(vec![recv(rbuf, msg.tag(rank - 1, IEXSCAN_OP, 0))?]);
+    }
+
+    if rank < (size - 1) {
+        let tag = msg.tag(rank, IEXSCAN_OP, 0);
+        if rank == 0 {
+            msg.round(vec![send(sbuf, rank + 1, tag)?]);
+        } else {
+            // The next partial result is owned by the schedule, so that it
+            // outlives the send.
+            let next = msg.hold(sbuf.to_owned());
+            let rbuf: *const B = rbuf;
+            msg.compute(move || {
+                op.apply(&*rbuf, (*next).borrow_mut());
+                Ok(())
+            });
+            msg.deferred(move || Ok(vec![send((*next).borrow(), rank + 1, tag)?]));
+        }
+    }
+    Ok(msg)
+}
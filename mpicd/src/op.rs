@@ -0,0 +1,85 @@
+//! Reduction operations.
+
+/// Operation used for reductions, combining two unpacked values.
+///
+/// Reductions are always applied in rank order, so the operation does not need
+/// to be commutative.
+pub trait ReduceOp<B: ?Sized> {
+    /// Combine the values, storing the result in the second argument (i.e.
+    /// `inout = input op inout`, with input coming from lower ranks).
+    fn apply(&self, input: &B, inout: &mut B);
+}
+
+impl<B: ?Sized, F: Fn(&B, &mut B)> ReduceOp<B> for F {
+    fn apply(&self, input: &B, inout: &mut B) {
+        self(input, inout)
+    }
+}
+
+/// Elementwise sum (integer sums wrap on overflow).
+pub struct Sum;
+
+/// Elementwise product (integer products wrap on overflow).
+pub struct Prod;
+
+/// Elementwise minimum.
+pub struct Min;
+
+/// Elementwise maximum.
+pub struct Max;
+
+/// Elementwise bitwise and.
+pub struct BitAnd;
+
+/// Elementwise bitwise or.
+pub struct BitOr;
+
+/// Elementwise bitwise xor.
+pub struct BitXor;
+
+macro_rules! impl_op_primitive {
+    ($op:ty, $ty:ty, |$a:ident, $b:ident| $expr:expr) => {
+        impl ReduceOp<[$ty]> for $op {
+            fn apply(&self, input: &[$ty], inout: &mut [$ty]) {
+                assert_eq!(input.len(), inout.len());
+                for (x, y) in input.iter().zip(inout.iter_mut()) {
+                    let $a = *x;
+                    let $b = *y;
+                    *y = $expr;
+                }
+            }
+        }
+    };
+}
+
+macro_rules! impl_int_ops {
+    ($ty:ty) => {
+        impl_op_primitive!(Sum, $ty, |a, b| a.wrapping_add(b));
+        impl_op_primitive!(Prod, $ty, |a, b| a.wrapping_mul(b));
+        impl_op_primitive!(Min, $ty, |a, b| a.min(b));
+        impl_op_primitive!(Max, $ty, |a, b| a.max(b));
+        impl_op_primitive!(BitAnd, $ty, |a, b| a & b);
+        impl_op_primitive!(BitOr, $ty, |a, b| a | b);
+        impl_op_primitive!(BitXor, $ty, |a, b| a ^ b);
+    };
+}
+
+macro_rules! impl_float_ops {
+    ($ty:ty) => {
+        impl_op_primitive!(Sum, $ty, |a, b| a + b);
+        impl_op_primitive!(Prod, $ty, |a, b| a * b);
+        impl_op_primitive!(Min, $ty, |a, b| a.min(b));
+        impl_op_primitive!(Max, $ty, |a, b| a.max(b));
+    };
+}
+
+impl_int_ops!(u8);
+impl_int_ops!(u16);
+impl_int_ops!(u32);
+impl_int_ops!(u64);
+impl_int_ops!(i8);
+impl_int_ops!(i16);
+impl_int_ops!(i32);
+impl_int_ops!(i64);
+impl_float_ops!(f32);
+impl_float_ops!(f64);
@@ -72,7 +72,7 @@ unsafe fn get<T: PMIXType>(proc: pmix_proc_t, key: *const c_char) -> T {
         panic!("PMIx_Get failed: {}", pmix_status_to_string(ret));
     }
     let value = value.assume_init();
-    if value == std::ptr::null_mut() {
+    if value.is_null() {
         panic!("PMIx_Get returned NULL value");
     }
     let result = T::unload(value);
@@ -81,6 +81,7 @@ unsafe fn get<T: PMIXType>(proc: pmix_proc_t, key: *const c_char) -> T {
 }
 
 /// PMI handle with additional metadata.
+#[allow(clippy::upper_case_acronyms)]
 pub struct PMI {
     /// Process for this rank.
     proc: pmix_proc_t,
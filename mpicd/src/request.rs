@@ -3,7 +3,8 @@ use std::ffi::c_void;
 use mpicd_ucx_sys::{
     rust_ucs_ptr_is_ptr, rust_ucs_ptr_is_err, rust_ucs_ptr_status,
     ucs_status_t, ucs_status_ptr_t, ucp_ep_h, ucp_worker_h, ucp_datatype_t, ucp_request_param_t,
-    ucp_request_param_t__bindgen_ty_1, ucp_tag_send_nbx, ucp_tag_recv_nbx,
+    ucp_request_param_t__bindgen_ty_1, ucp_tag_send_nbx, ucp_tag_send_sync_nbx, ucp_tag_recv_nbx,
+    ucp_tag_msg_recv_nbx, ucp_tag_message_h, ucp_am_send_nbx,
     ucp_tag_recv_info_t, ucp_request_free, UCP_OP_ATTR_FIELD_DATATYPE, UCP_OP_ATTR_FIELD_CALLBACK,
     UCP_OP_ATTR_FIELD_USER_DATA, UCP_OP_ATTR_FLAG_NO_IMM_CMPL, UCS_OK, UCS_INPROGRESS,
 };
@@ -17,12 +18,16 @@ pub(crate) struct Request {
 
 impl Request {
     /// Initiate a non-blocking send and return the ucx request.
+    ///
+    /// Synchronous sends only complete once the receiver has matched the
+    /// message.
     pub(crate) unsafe fn send_nb(
         endpoint: ucp_ep_h,
         ptr: *const u8,
         count: usize,
         datatype: ucp_datatype_t,
         tag: u64,
+        sync: bool,
     ) -> Request {
         let req_data: *mut RequestData = Box::into_raw(Box::new(RequestData::new(datatype)));
         let param = ucp_request_param_t {
@@ -37,11 +42,60 @@ impl Request {
             ..Default::default()
         };
 
-        let req = ucp_tag_send_nbx(
+        let req = if sync {
+            ucp_tag_send_sync_nbx(
+                endpoint,
+                ptr as *const _,
+                count,
+                tag,
+                &param,
+            )
+        } else {
+            ucp_tag_send_nbx(
+                endpoint,
+                ptr as *const _,
+                count,
+                tag,
+                &param,
+            )
+        };
+
+        Request {
+            req,
+            req_data,
+        }
+    }
+
+    /// Initiate a non-blocking active message send and return the ucx
+    /// request.
+    pub(crate) unsafe fn am_send_nb(
+        endpoint: ucp_ep_h,
+        id: u16,
+        header: &[u8],
+        ptr: *const u8,
+        count: usize,
+        datatype: ucp_datatype_t,
+    ) -> Request {
+        let req_data: *mut RequestData = Box::into_raw(Box::new(RequestData::new(datatype)));
+        let param = ucp_request_param_t {
+            op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE
+                | UCP_OP_ATTR_FIELD_CALLBACK
+                | UCP_OP_ATTR_FIELD_USER_DATA,
+            datatype,
+            cb: ucp_request_param_t__bindgen_ty_1 {
+                send: Some(send_nbx_callback),
+            },
+            user_data: req_data as *mut _,
+            ..Default::default()
+        };
+
+        let req = ucp_am_send_nbx(
             endpoint,
+            id.into(),
+            header.as_ptr() as *const _,
+            header.len(),
             ptr as *const _,
             count,
-            tag,
             &param,
         );
 
@@ -91,6 +145,43 @@ impl Request {
         }
     }
 
+    /// Initiate a non-blocking receive of a message that was removed from
+    /// the unexpected queue by ucp_tag_probe_nb(), and return the ucx request.
+    pub(crate) unsafe fn msg_recv_nb(
+        worker: ucp_worker_h,
+        message: ucp_tag_message_h,
+        ptr: *mut u8,
+        count: usize,
+        datatype: ucp_datatype_t,
+    ) -> Request {
+        let req_data: *mut RequestData = Box::into_raw(Box::new(RequestData::new(datatype)));
+        let param = ucp_request_param_t {
+            op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE
+                | UCP_OP_ATTR_FIELD_CALLBACK
+                | UCP_OP_ATTR_FIELD_USER_DATA
+                | UCP_OP_ATTR_FLAG_NO_IMM_CMPL,
+            datatype,
+            cb: ucp_request_param_t__bindgen_ty_1 {
+                recv: Some(tag_recv_nbx_callback),
+            },
+            user_data: req_data as *mut _,
+            ..Default::default()
+        };
+
+        let req = ucp_tag_msg_recv_nbx(
+            worker,
+            ptr as *mut _,
+            count,
+            message,
+            &param,
+        );
+
+        Request {
+            req,
+            req_data,
+        }
+    }
+
     pub(crate) unsafe fn status(&self) -> Status {
         if rust_ucs_ptr_is_ptr(self.req) == 0 {
             let status = rust_ucs_ptr_status(self.req);
@@ -103,7 +194,7 @@ impl Request {
             Status::Error("Internal pointer failure".to_string())
         } else {
             let status = rust_ucs_ptr_status(self.req);
-            if !(*self.req_data.as_ref().unwrap()).complete {
+            if !self.req_data.as_ref().unwrap().complete {
                 if status == UCS_OK {
                     Status::Complete
                 } else if status == UCS_INPROGRESS {
@@ -158,6 +249,46 @@ pub const PROBE_TAG_MASK: u64 = 0xFF000000FFFFFFFF;
 /// Internal tag to be used for barriers.
 pub const BARRIER_TAG: u8 = 1;
 
+/// Internal tag to be used for other collectives.
+pub const COLLECTIVE_TAG: u8 = 2;
+
+/// Internal tag bit set on message headers, so that they're matched
+/// separately from the data of the message (and never by probes).
+pub const HEADER_TAG: u8 = 0x80;
+
+/// Internal tag bit set on the header and data of messages sent with a
+/// header, so that their data is never matched as a message without one.
+pub const FRAMED_TAG: u8 = 0x40;
+
+/// Internal tag bits holding the sequence number of a message sent with a
+/// header, which binds its data to the header. The sequence number wraps
+/// after 16 messages, and the internal tags above only use the two lowest
+/// bits.
+pub const HEADER_SEQ_MASK: u8 = 0x3C;
+
+/// Tag mask for probing the header of a message with any sequence number.
+pub const HEADER_PROBE_MASK: u64 = !((HEADER_SEQ_MASK as u64) << 56);
+
+/// Return the tag of the header for a message with the given tag.
+#[inline]
+pub fn header_tag(tag: u64) -> u64 {
+    tag | ((HEADER_TAG as u64) << 56)
+}
+
+/// Return the tag of the data of a message with the given tag, sent with a
+/// header under the sequence number.
+#[inline]
+pub fn framed_tag(tag: u64, seq: u8) -> u64 {
+    let internal_tag = FRAMED_TAG | ((seq << 2) & HEADER_SEQ_MASK);
+    tag | ((internal_tag as u64) << 56)
+}
+
+/// Return the tag of the data of a message from the tag of its header.
+#[inline]
+pub fn data_tag(header_tag: u64) -> u64 {
+    header_tag & !((HEADER_TAG as u64) << 56)
+}
+
 /// Request data struct used to hold callback user data for a request.
 pub(crate) struct RequestData {
     /// Request boolean set in the callback.
@@ -0,0 +1,488 @@
+//! One-sided communication through RMA windows.
+//!
+//! A window exposes a local memory region of every process. Windows are
+//! created collectively: each process maps its region with ucp_mem_map() and
+//! the base addresses, sizes and packed remote keys are exchanged with an
+//! allgatherv. Custom buffers are transferred with the same layout that
+//! pack_to_vec() produces, i.e. the packed part first, followed by each memory
+//! region, which are put and got directly without copying.
+//!
+//! Put and get operations are only guaranteed to be complete after a call to
+//! flush(), flush_all() or fence(). Windows are freed collectively with
+//! free(), which completes outstanding operations and synchronizes all
+//! processes before unmapping the memory.
+//!
+//! Each process also maps a lock word, which is used to serialize software
+//! accumulate operations on the target (see atomic.rs).
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::mem::MaybeUninit;
+use std::rc::Rc;
+use log::error;
+use mpicd_ucx_sys::{
+    rust_ucp_dt_make_contig, rust_ucs_ptr_is_ptr, rust_ucs_ptr_status, ucp_context_h,
+    ucp_ep_flush_nbx, ucp_ep_h, ucp_worker_h, ucp_ep_rkey_unpack, ucp_get_nbx, ucp_mem_h, ucp_mem_map,
+    ucp_mem_map_params_t, ucp_mem_unmap, ucp_put_nbx, ucp_request_param_t, ucp_rkey_buffer_release,
+    ucp_rkey_destroy, ucp_rkey_h, ucp_rkey_pack, ucp_worker_flush_nbx, ucs_status_ptr_t,
+    UCP_MEM_MAP_PARAM_FIELD_ADDRESS, UCP_MEM_MAP_PARAM_FIELD_LENGTH, UCP_OP_ATTR_FIELD_DATATYPE,
+    UCS_OK,
+};
+use crate::{
+    barrier,
+    communicator::{self, Communicator},
+    datatype::{DatatypeError, MessageBuffer, UnpackMethod},
+    util::wait_request,
+    status_to_string, Context, Handle, Status,
+};
+
+/// Size of the fixed fields in the exchanged window info (base address,
+/// size, lock address and remote key length).
+const INFO_HEADER_SIZE: usize = 4 * std::mem::size_of::<u64>();
+
+/// Remote memory of a process in the window.
+struct Target {
+    /// Base address of the remote memory.
+    base: u64,
+
+    /// Size of the remote memory.
+    size: usize,
+
+    /// Unpacked remote key (null for empty regions).
+    rkey: ucp_rkey_h,
+
+    /// Address of the remote lock word.
+    lock_addr: u64,
+
+    /// Unpacked remote key of the lock word.
+    lock_rkey: ucp_rkey_h,
+}
+
+/// Put or get operation that hasn't been flushed yet.
+struct PendingOp {
+    /// Target rank.
+    target: usize,
+
+    /// Outstanding UCX requests.
+    reqs: Vec<ucs_status_ptr_t>,
+
+    /// Packed part of the origin buffer.
+    packed_buffer: Vec<u8>,
+
+    /// Unpack method for the origin buffer of a get.
+    unpack_method: Option<Box<dyn UnpackMethod>>,
+}
+
+/// RMA window.
+pub struct Window {
+    /// Handle with ucx info.
+    handle: Rc<RefCell<Handle>>,
+
+    /// Memory handle of the local region (None for empty regions).
+    memh: Option<ucp_mem_h>,
+
+    /// Local lock word.
+    lock: Box<u64>,
+
+    /// Memory handle of the lock word.
+    lock_memh: Option<ucp_mem_h>,
+
+    /// Remote memory of every process.
+    targets: Vec<Target>,
+
+    /// Operations waiting for a flush.
+    pending: RefCell<Vec<PendingOp>>,
+}
+
+impl Window {
+    /// Create the window collectively over the local memory.
+    pub(crate) unsafe fn create(
+        ctx: &Context,
+        handle: Rc<RefCell<Handle>>,
+        base: *mut u8,
+        size: usize,
+    ) -> communicator::Result<Window> {
+        let context = handle.borrow().system.context;
+        let mut window = Window {
+            handle,
+            memh: None,
+            lock: Box::new(0),
+            lock_memh: None,
+            targets: vec![],
+            pending: RefCell::new(vec![]),
+        };
+        let (memh, rkey) = map_memory(context, base, size)?;
+        window.memh = memh;
+        let lock_ptr = &mut *window.lock as *mut u64;
+        let (lock_memh, lock_rkey) = map_memory(context, lock_ptr as *mut u8, std::mem::size_of::<u64>())?;
+        window.lock_memh = lock_memh;
+
+        let mut info = Vec::with_capacity(INFO_HEADER_SIZE + rkey.len() + lock_rkey.len());
+        info.extend_from_slice(&(base as u64).to_ne_bytes());
+        info.extend_from_slice(&(size as u64).to_ne_bytes());
+        info.extend_from_slice(&(lock_ptr as u64).to_ne_bytes());
+        info.extend_from_slice(&(rkey.len() as u64).to_ne_bytes());
+        info.extend_from_slice(&rkey);
+        info.extend_from_slice(&lock_rkey);
+        let infos = ctx.allgatherv(&info[..], |_, shape| vec![0u8; shape.packed_size])?;
+
+        for (i, info) in infos.iter().enumerate() {
+            if info.len() < INFO_HEADER_SIZE {
+                return Err(communicator::Error::InternalError);
+            }
+            let field = |j: usize| u64::from_ne_bytes(info[j * 8..(j + 1) * 8].try_into().unwrap());
+            let rkey_len = field(3) as usize;
+            if info.len() < INFO_HEADER_SIZE + rkey_len {
+                return Err(communicator::Error::InternalError);
+            }
+            let endpoint = window.handle.borrow().system.endpoints[i];
+            let rkey = unpack_rkey(endpoint, &info[INFO_HEADER_SIZE..INFO_HEADER_SIZE + rkey_len])?;
+            // Add the target right away, so that the key is destroyed on error.
+            window.targets.push(Target {
+                base: field(0),
+                size: field(1) as usize,
+                rkey,
+                lock_addr: field(2),
+                lock_rkey: std::ptr::null_mut(),
+            });
+            window.targets[i].lock_rkey = unpack_rkey(endpoint, &info[INFO_HEADER_SIZE + rkey_len..])?;
+        }
+
+        Ok(window)
+    }
+
+    /// Return the endpoint, remote address and remote key for len bytes at
+    /// offset in the memory of the target.
+    pub(crate) fn remote(&self, rank: i32, offset: usize, len: usize) -> communicator::Result<(ucp_ep_h, u64, ucp_rkey_h)> {
+        let target = self.target(rank, offset, len)?;
+        let endpoint = self.handle.borrow().system.endpoints[rank as usize];
+        Ok((endpoint, target.base + offset as u64, target.rkey))
+    }
+
+    /// Return the endpoint, remote address and remote key of the lock word of
+    /// the target.
+    pub(crate) fn remote_lock(&self, rank: i32) -> communicator::Result<(ucp_ep_h, u64, ucp_rkey_h)> {
+        let target = self.target(rank, 0, 0)?;
+        let endpoint = self.handle.borrow().system.endpoints[rank as usize];
+        Ok((endpoint, target.lock_addr, target.lock_rkey))
+    }
+
+    /// Return the rank of this process.
+    pub(crate) fn rank(&self) -> usize {
+        self.handle.borrow().system.rank
+    }
+
+    /// Return the worker.
+    pub(crate) fn worker(&self) -> ucp_worker_h {
+        self.handle.borrow().system.worker
+    }
+
+    /// Keep the requests and buffer of an operation until the next flush.
+    pub(crate) fn add_pending(&self, target: i32, reqs: Vec<ucs_status_ptr_t>, buffer: Vec<u8>) {
+        self.pending.borrow_mut().push(PendingOp {
+            target: target as usize,
+            reqs,
+            packed_buffer: buffer,
+            unpack_method: None,
+        });
+    }
+
+    /// Return the target for the rank, checking that len bytes at offset are
+    /// inside of its memory.
+    fn target(&self, rank: i32, offset: usize, len: usize) -> communicator::Result<&Target> {
+        let target = usize::try_from(rank)
+            .ok()
+            .and_then(|rank| self.targets.get(rank))
+            .ok_or(communicator::Error::InternalError)?;
+        if offset.checked_add(len).filter(|end| *end <= target.size).is_none() {
+            return Err(communicator::Error::OutOfBounds);
+        }
+        Ok(target)
+    }
+
+    /// Put the buffer into the memory of the target process at the byte
+    /// offset. The buffer must not be modified until the next flush.
+    pub unsafe fn put<B: MessageBuffer + ?Sized>(&self, data: &B, target: i32, offset: usize) -> communicator::Result<()> {
+        let datatype_err = communicator::Error::Datatype;
+        let (packed_buffer, regions) = if let Some(pack_method) = data.pack() {
+            let mut pack_method = pack_method.map_err(datatype_err)?;
+            let packed_size = pack_method.packed_size().map_err(datatype_err)?;
+            let mut packed_buffer = vec![0; packed_size];
+            if packed_size > 0 {
+                let used = pack_method.pack(0, packed_buffer.as_mut_ptr(), packed_size).map_err(datatype_err)?;
+                if used != packed_size {
+                    return Err(communicator::Error::Datatype(DatatypeError::PackError));
+                }
+            }
+            (packed_buffer, pack_method.memory_regions().map_err(datatype_err)?)
+        } else {
+            (vec![], vec![(data.ptr(), data.count())])
+        };
+
+        let mut parts = vec![(packed_buffer.as_ptr(), packed_buffer.len())];
+        parts.extend(regions);
+        let total = parts.iter().map(|(_, len)| len).sum();
+        let remote = self.target(target, offset, total)?;
+
+        let handle = self.handle.borrow();
+        let endpoint = handle.system.endpoints[target as usize];
+        let param = rma_param();
+        let mut op = PendingOp {
+            target: target as usize,
+            reqs: vec![],
+            packed_buffer: vec![],
+            unpack_method: None,
+        };
+        let mut pos = offset;
+        let mut result = Ok(());
+        for (ptr, len) in parts {
+            if len > 0 {
+                let req = ucp_put_nbx(endpoint, ptr as *const _, len, remote.base + pos as u64, remote.rkey, &param);
+                if let Err(err) = push_request(&mut op.reqs, req) {
+                    result = Err(err);
+                    break;
+                }
+            }
+            pos += len;
+        }
+        op.packed_buffer = packed_buffer;
+        self.pending.borrow_mut().push(op);
+        result
+    }
+
+    /// Get data from the memory of the target process at the byte offset into
+    /// the buffer. The buffer is only filled in after the next flush.
+    pub unsafe fn get<B: MessageBuffer + ?Sized>(&self, data: &mut B, target: i32, offset: usize) -> communicator::Result<()> {
+        let datatype_err = communicator::Error::Datatype;
+        let (unpack_method, mut packed_buffer, regions) = if let Some(unpack_method) = data.unpack() {
+            let mut unpack_method = unpack_method.map_err(datatype_err)?;
+            let packed_size = unpack_method.packed_size().map_err(datatype_err)?;
+            let regions = unpack_method.memory_regions().map_err(datatype_err)?;
+            (Some(unpack_method), vec![0; packed_size], regions)
+        } else {
+            (None, vec![], vec![(data.ptr_mut(), data.count())])
+        };
+
+        let mut parts = vec![(packed_buffer.as_mut_ptr(), packed_buffer.len())];
+        parts.extend(regions);
+        let total = parts.iter().map(|(_, len)| len).sum();
+        let remote = self.target(target, offset, total)?;
+
+        let handle = self.handle.borrow();
+        let endpoint = handle.system.endpoints[target as usize];
+        let param = rma_param();
+        let mut op = PendingOp {
+            target: target as usize,
+            reqs: vec![],
+            packed_buffer: vec![],
+            unpack_method: None,
+        };
+        let mut pos = offset;
+        let mut result = Ok(());
+        for (ptr, len) in parts {
+            if len > 0 {
+                let req = ucp_get_nbx(endpoint, ptr as *mut _, len, remote.base + pos as u64, remote.rkey, &param);
+                if let Err(err) = push_request(&mut op.reqs, req) {
+                    result = Err(err);
+                    break;
+                }
+            }
+            pos += len;
+        }
+        // Only unpack if everything was submitted.
+        if result.is_ok() {
+            op.unpack_method = unpack_method;
+        }
+        op.packed_buffer = packed_buffer;
+        self.pending.borrow_mut().push(op);
+        result
+    }
+
+    /// Complete all operations to the target process, both locally and
+    /// remotely.
+    pub fn flush(&self, target: i32) -> communicator::Result<()> {
+        let handle = self.handle.borrow();
+        let endpoint = *usize::try_from(target)
+            .ok()
+            .and_then(|rank| handle.system.endpoints.get(rank))
+            .ok_or(communicator::Error::InternalError)?;
+        unsafe {
+            let req = ucp_ep_flush_nbx(endpoint, &rma_param());
+            let flush_status = wait_request(handle.system.worker, req);
+            let result = self.complete(&handle, Some(target as usize));
+            check_status(flush_status)?;
+            result
+        }
+    }
+
+    /// Complete all outstanding operations of this process.
+    pub fn flush_all(&self) -> communicator::Result<()> {
+        let handle = self.handle.borrow();
+        unsafe {
+            let req = ucp_worker_flush_nbx(handle.system.worker, &rma_param());
+            let flush_status = wait_request(handle.system.worker, req);
+            let result = self.complete(&handle, None);
+            check_status(flush_status)?;
+            result
+        }
+    }
+
+    /// Complete all outstanding operations and synchronize with all other
+    /// processes, ending the current access epoch.
+    pub fn fence(&self) -> communicator::Result<()> {
+        self.flush_all()?;
+        let handle = self.handle.borrow();
+        unsafe { check_status(barrier::dissemination(&handle.system)) }
+    }
+
+    /// Free the window collectively. Outstanding operations are completed and
+    /// all processes are synchronized before the memory is unmapped, so that
+    /// no process is still accessing it.
+    pub fn free(self) -> communicator::Result<()> {
+        let result = self.flush_all();
+        let barrier_result = {
+            let handle = self.handle.borrow();
+            unsafe { check_status(barrier::dissemination(&handle.system)) }
+        };
+        drop(self);
+        result.and(barrier_result)
+    }
+
+    /// Wait for pending operations to the target (or all targets), unpacking
+    /// the packed part of gets.
+    unsafe fn complete(&self, handle: &Handle, target: Option<usize>) -> communicator::Result<()> {
+        let mut pending = self.pending.borrow_mut();
+        let mut result = Ok(());
+        let mut i = 0;
+        while i < pending.len() {
+            if target.is_some() && target != Some(pending[i].target) {
+                i += 1;
+                continue;
+            }
+            let mut op = pending.swap_remove(i);
+            for req in op.reqs.drain(..) {
+                if let Err(err) = check_status(wait_request(handle.system.worker, req)) {
+                    result = Err(err);
+                    op.unpack_method = None;
+                }
+            }
+            if let Some(mut unpack_method) = op.unpack_method.take() {
+                if !op.packed_buffer.is_empty() {
+                    if let Err(err) = unpack_method.unpack(0, op.packed_buffer.as_ptr(), op.packed_buffer.len()) {
+                        result = Err(communicator::Error::Datatype(err));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Local cleanup only: other processes may still be accessing the memory, so
+/// windows should be released with free().
+impl Drop for Window {
+    fn drop(&mut self) {
+        // Complete local requests, since they refer to the pending buffers.
+        let _ = self.flush_all();
+        let handle = self.handle.borrow();
+        unsafe {
+            for target in &self.targets {
+                for rkey in [target.rkey, target.lock_rkey] {
+                    if !rkey.is_null() {
+                        ucp_rkey_destroy(rkey);
+                    }
+                }
+            }
+            for memh in [self.memh, self.lock_memh].into_iter().flatten() {
+                ucp_mem_unmap(handle.system.context, memh);
+            }
+        }
+    }
+}
+
+/// Map the local memory, returning the memory handle and packed remote key.
+unsafe fn map_memory(
+    context: ucp_context_h,
+    base: *mut u8,
+    size: usize,
+) -> communicator::Result<(Option<ucp_mem_h>, Vec<u8>)> {
+    if size == 0 {
+        return Ok((None, vec![]));
+    }
+
+    let params = ucp_mem_map_params_t {
+        field_mask: (UCP_MEM_MAP_PARAM_FIELD_ADDRESS | UCP_MEM_MAP_PARAM_FIELD_LENGTH).into(),
+        address: base as *mut _,
+        length: size,
+        ..Default::default()
+    };
+    let mut memh = MaybeUninit::<ucp_mem_h>::uninit();
+    let status = ucp_mem_map(context, &params, memh.as_mut_ptr());
+    if status != UCS_OK {
+        error!("Failed to map window memory: {}", status_to_string(status));
+        return Err(communicator::Error::InternalError);
+    }
+    let memh = memh.assume_init();
+
+    let mut rkey_buffer = MaybeUninit::<*mut c_void>::uninit();
+    let mut rkey_size = MaybeUninit::<usize>::uninit();
+    let status = ucp_rkey_pack(context, memh, rkey_buffer.as_mut_ptr(), rkey_size.as_mut_ptr());
+    if status != UCS_OK {
+        error!("Failed to pack remote key: {}", status_to_string(status));
+        ucp_mem_unmap(context, memh);
+        return Err(communicator::Error::InternalError);
+    }
+    let rkey_buffer = rkey_buffer.assume_init();
+    let rkey = std::slice::from_raw_parts(rkey_buffer as *const u8, rkey_size.assume_init()).to_vec();
+    ucp_rkey_buffer_release(rkey_buffer);
+    Ok((Some(memh), rkey))
+}
+
+/// Unpack a remote key, returning null for an empty key.
+unsafe fn unpack_rkey(endpoint: ucp_ep_h, rkey: &[u8]) -> communicator::Result<ucp_rkey_h> {
+    if rkey.is_empty() {
+        return Ok(std::ptr::null_mut());
+    }
+    let mut rkey_h = MaybeUninit::<ucp_rkey_h>::uninit();
+    let status = ucp_ep_rkey_unpack(endpoint, rkey.as_ptr() as *const _, rkey_h.as_mut_ptr());
+    if status != UCS_OK {
+        error!("Failed to unpack remote key: {}", status_to_string(status));
+        return Err(communicator::Error::InternalError);
+    }
+    Ok(rkey_h.assume_init())
+}
+
+/// Request parameters used for RMA operations and flushes.
+pub(crate) fn rma_param() -> ucp_request_param_t {
+    ucp_request_param_t {
+        op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE,
+        datatype: unsafe { rust_ucp_dt_make_contig(1) },
+        ..Default::default()
+    }
+}
+
+/// Add the request to the list, unless it already completed.
+pub(crate) unsafe fn push_request(reqs: &mut Vec<ucs_status_ptr_t>, req: ucs_status_ptr_t) -> communicator::Result<()> {
+    if rust_ucs_ptr_is_ptr(req) != 0 {
+        reqs.push(req);
+        Ok(())
+    } else {
+        let status = rust_ucs_ptr_status(req);
+        if status != UCS_OK {
+            error!("Failed to submit RMA operation: {}", status_to_string(status));
+            Err(communicator::Error::InternalError)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Convert the status of a completed operation.
+pub(crate) fn check_status(status: Status) -> communicator::Result<()> {
+    match status {
+        Status::Error(err) => {
+            error!("RMA operation failed: {}", err);
+            Err(communicator::Error::InternalError)
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,145 @@
+//! Safe non-blocking communication through request scopes.
+//!
+//! The raw isend()/irecv() calls only keep pointers into the buffers, so the
+//! caller must make sure that buffers outlive the requests. Here, requests are
+//! created inside a scope (similar to std::thread::scope()) and borrow their
+//! buffers for the lifetime of the scope. Any request that hasn't been waited
+//! on is completed before the scope returns, even if the closure panics or a
+//! request is leaked with mem::forget(), so buffers can never be freed or
+//! touched while UCX might still access them.
+//!
+//! ```ignore
+//! let mut rbuf = vec![0u8; 16];
+//! ctx.scope(|s| {
+//!     let send = s.isend(&sbuf[..], 1, 0)?;
+//!     let recv = s.irecv(&mut rbuf[..], 1, 0)?;
+//!     send.wait()?;
+//!     let data = recv.wait()?;
+//!     Ok(())
+//! })?;
+//! ```
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use crate::{
+    collective,
+    communicator::{self, Communicator},
+    datatype::MessageBuffer,
+    Context,
+};
+
+/// Scope for safe non-blocking requests (see Context::scope()).
+pub struct Scope<'scope, 'env: 'scope> {
+    /// Context that the requests belong to.
+    ctx: &'env Context,
+
+    /// Requests that haven't been waited on yet.
+    pending: RefCell<Vec<usize>>,
+
+    /// Invariant lifetimes, as in std::thread::Scope.
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub(crate) fn new(ctx: &'env Context) -> Scope<'scope, 'env> {
+        Scope {
+            ctx,
+            pending: RefCell::new(vec![]),
+            scope: PhantomData,
+            env: PhantomData,
+        }
+    }
+
+    /// Start a non-blocking send of the data, which stays borrowed until the
+    /// end of the scope.
+    pub fn isend<B: MessageBuffer + ?Sized>(
+        &'scope self,
+        data: &'scope B,
+        dest: i32,
+        tag: i32,
+    ) -> communicator::Result<SendRequest<'scope, 'env>> {
+        let id = unsafe { self.ctx.isend(data, dest, tag)? };
+        self.add(id);
+        Ok(SendRequest { scope: self, id })
+    }
+
+    /// Start a non-blocking synchronous send (see isend()).
+    pub fn issend<B: MessageBuffer + ?Sized>(
+        &'scope self,
+        data: &'scope B,
+        dest: i32,
+        tag: i32,
+    ) -> communicator::Result<SendRequest<'scope, 'env>> {
+        let id = unsafe { self.ctx.issend(data, dest, tag)? };
+        self.add(id);
+        Ok(SendRequest { scope: self, id })
+    }
+
+    /// Start a non-blocking receive into the data. The buffer is handed back
+    /// by RecvRequest::wait() once the data has arrived.
+    pub fn irecv<B: MessageBuffer + ?Sized>(
+        &'scope self,
+        data: &'scope mut B,
+        source: i32,
+        tag: i32,
+    ) -> communicator::Result<RecvRequest<'scope, 'env, B>> {
+        let id = unsafe { self.ctx.irecv(data, source, tag)? };
+        self.add(id);
+        Ok(RecvRequest { scope: self, id, data })
+    }
+
+    /// Wait for all requests of the scope that haven't completed yet.
+    pub fn wait_all(&self) -> communicator::Result<()> {
+        let reqs: Vec<usize> = self.pending.borrow_mut().drain(..).collect();
+        unsafe { collective::wait(self.ctx, &reqs) }
+    }
+
+    /// Add a request to wait on at the end of the scope.
+    fn add(&self, id: usize) {
+        self.pending.borrow_mut().push(id);
+    }
+
+    /// Wait for a single request, unless it has already been waited on by
+    /// wait_all().
+    fn wait(&self, id: usize) -> communicator::Result<()> {
+        let mut pending = self.pending.borrow_mut();
+        match pending.iter().position(|req| *req == id) {
+            Some(i) => {
+                pending.swap_remove(i);
+                drop(pending);
+                unsafe { collective::wait(self.ctx, &[id]) }
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Send request borrowing its buffer.
+#[must_use = "requests are only waited on at the end of the scope otherwise"]
+pub struct SendRequest<'scope, 'env> {
+    scope: &'scope Scope<'scope, 'env>,
+    id: usize,
+}
+
+impl<'scope, 'env> SendRequest<'scope, 'env> {
+    /// Wait for the send to complete.
+    pub fn wait(self) -> communicator::Result<()> {
+        self.scope.wait(self.id)
+    }
+}
+
+/// Receive request holding the mutable borrow of its buffer.
+#[must_use = "the received data is only accessible through wait()"]
+pub struct RecvRequest<'scope, 'env, B: ?Sized> {
+    scope: &'scope Scope<'scope, 'env>,
+    id: usize,
+    data: &'scope mut B,
+}
+
+impl<'scope, 'env, B: ?Sized> RecvRequest<'scope, 'env, B> {
+    /// Wait for the receive to complete, returning the buffer.
+    pub fn wait(self) -> communicator::Result<&'scope mut B> {
+        self.scope.wait(self.id)?;
+        Ok(self.data)
+    }
+}
@@ -0,0 +1,830 @@
+//! MessageBuffer wrapper for serde types.
+//!
+//! SerdeBuffer sends any `T: Serialize + DeserializeOwned` through a compact,
+//! non-self-describing binary format:
+//!
+//! * integers and floats are stored with their fixed size in big-endian order
+//!   (bool as one byte, char as a u32);
+//! * lengths and enum variant indices are stored as LEB128 varints;
+//! * structs and tuples are stored as their fields in order, without names;
+//! * options are a 0 or 1 byte followed by the value.
+//!
+//! The packed part starts with its own length as a u64. Byte strings and
+//! strings of at least REGION_THRESHOLD bytes aren't stored in the packed
+//! part, but sent as memory regions after it. Serde doesn't guarantee that
+//! the slices passed to the serializer outlive the call (collect_str(), for
+//! example, passes a temporary), so each region is copied into its own buffer
+//! owned by the pack state. Note that serde serializes Vec<u8> as a sequence
+//! of u8, so large byte buffers need to be wrapped (e.g. with serde_bytes) to
+//! be sent as regions.
+//!
+//! Since the wire data is one contiguous byte stream, the receiver only needs
+//! to know its total size (e.g. from probe() or the PackedShape passed to the
+//! v-collectives) and receives everything into a single buffer, which is
+//! deserialized once complete. Wrapping both sides in Framed sends the size in
+//! a header instead, so the receiver can use a buffer of any size.
+use std::fmt;
+use log::error;
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+};
+use crate::datatype::{
+    signature_of, unpack_from_slice, DatatypeError, DatatypeResult, FromPacked, MessageBuffer, MessageCount,
+    MessagePointer, PackMethod, PackedShape, PackedSize, UnpackMethod,
+};
+
+/// Minimum length for byte strings and strings to be sent as memory regions.
+pub const REGION_THRESHOLD: usize = 4096;
+
+/// Size of the length prefix of the packed part.
+const HEADER_SIZE: usize = std::mem::size_of::<u64>();
+
+/// Buffer holding a serde value to send, or to receive into.
+pub struct SerdeBuffer<T> {
+    /// Value, None until received.
+    value: Option<T>,
+
+    /// Total size to receive.
+    size: usize,
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> SerdeBuffer<T> {
+    /// Wrap a value to send.
+    pub fn new(value: T) -> SerdeBuffer<T> {
+        SerdeBuffer {
+            value: Some(value),
+            size: 0,
+        }
+    }
+
+    /// Create a buffer to receive a value of the total size in bytes.
+    pub fn with_size(size: usize) -> SerdeBuffer<T> {
+        SerdeBuffer {
+            value: None,
+            size,
+        }
+    }
+
+    /// Return a reference to the value, if any.
+    pub fn value(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    /// Return the value, if any.
+    pub fn into_inner(self) -> Option<T> {
+        self.value
+    }
+}
+
+impl<T> MessageCount for SerdeBuffer<T> {
+    fn count(&self) -> usize {
+        self.size
+    }
+}
+
+impl<T> MessagePointer for SerdeBuffer<T> {
+    fn ptr(&self) -> *const u8 {
+        std::ptr::null()
+    }
+
+    fn ptr_mut(&mut self) -> *mut u8 {
+        std::ptr::null_mut()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> MessageBuffer for SerdeBuffer<T> {
+    unsafe fn pack(&self) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
+        let Some(value) = self.value.as_ref() else {
+            return Some(Err(DatatypeError::PackError));
+        };
+        Some(SerdePackState::new(value).map(|state| Box::new(state) as Box<dyn PackMethod>))
+    }
+
+    unsafe fn unpack(&mut self) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
+        Some(Ok(Box::new(SerdeUnpackState {
+            value: &mut self.value,
+            buffer: vec![0; self.size],
+            received: 0,
+        })))
+    }
+
+    fn signature(&self) -> Option<u64> {
+        Some(signature_of::<T>())
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> FromPacked for SerdeBuffer<T> {
+    fn from_packed(packed: &[u8]) -> DatatypeResult<Self> {
+        let mut buffer = SerdeBuffer::with_size(packed.len());
+        unsafe { unpack_from_slice(&mut buffer, packed)? };
+        Ok(buffer)
+    }
+
+    fn with_size(size: usize) -> Option<DatatypeResult<Self>> {
+        Some(Ok(SerdeBuffer::with_size(size)))
+    }
+
+    fn type_signature() -> Option<u64> {
+        Some(signature_of::<T>())
+    }
+}
+
+/// Serialize the value to a contiguous byte vector, with large byte strings
+/// copied after the packed part, as they would be sent.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder::new();
+    value.serialize(&mut encoder)?;
+    let mut data = encoder.finish();
+    for region in encoder.regions {
+        data.extend_from_slice(&region);
+    }
+    Ok(data)
+}
+
+/// Deserialize a value from the contiguous bytes received for it.
+pub fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    let mut decoder = Decoder::new(data)?;
+    let value = T::deserialize(&mut decoder)?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Pack state holding the serialized packed part.
+struct SerdePackState {
+    /// Packed part, including the length prefix.
+    packed: Vec<u8>,
+
+    /// Copies of the large byte strings.
+    regions: Vec<Vec<u8>>,
+}
+
+impl SerdePackState {
+    fn new<T: Serialize + ?Sized>(value: &T) -> DatatypeResult<SerdePackState> {
+        let mut encoder = Encoder::new();
+        value.serialize(&mut encoder).map_err(|err| {
+            error!("Failed to serialize value: {}", err);
+            DatatypeError::PackError
+        })?;
+        let packed = encoder.finish();
+        Ok(SerdePackState {
+            packed,
+            regions: encoder.regions,
+        })
+    }
+}
+
+impl PackedSize for SerdePackState {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok(self.packed.len())
+    }
+}
+
+impl PackMethod for SerdePackState {
+    unsafe fn pack(&mut self, offset: usize, dst: *mut u8, dst_size: usize) -> DatatypeResult<usize> {
+        if offset > self.packed.len() {
+            return Err(DatatypeError::PackError);
+        }
+        let used = dst_size.min(self.packed.len() - offset);
+        std::ptr::copy_nonoverlapping(self.packed[offset..].as_ptr(), dst, used);
+        Ok(used)
+    }
+
+    unsafe fn memory_regions(&self) -> DatatypeResult<Vec<(*const u8, usize)>> {
+        Ok(self.regions.iter().map(|region| (region.as_ptr(), region.len())).collect())
+    }
+}
+
+/// Unpack state collecting the received bytes.
+struct SerdeUnpackState<T> {
+    /// Value to store the result in.
+    value: *mut Option<T>,
+
+    /// Received data.
+    buffer: Vec<u8>,
+
+    /// Number of bytes received so far.
+    received: usize,
+}
+
+impl<T> PackedSize for SerdeUnpackState<T> {
+    unsafe fn packed_size(&self) -> DatatypeResult<usize> {
+        Ok(self.buffer.len())
+    }
+}
+
+impl<T: DeserializeOwned> UnpackMethod for SerdeUnpackState<T> {
+    unsafe fn unpack(&mut self, offset: usize, src: *const u8, src_size: usize) -> DatatypeResult<()> {
+        if offset + src_size > self.buffer.len() {
+            return Err(DatatypeError::UnpackError);
+        }
+        std::ptr::copy_nonoverlapping(src, self.buffer[offset..].as_mut_ptr(), src_size);
+        self.received += src_size;
+        if self.received == self.buffer.len() {
+            let value = from_slice(&self.buffer).map_err(|err| {
+                error!("Failed to deserialize value: {}", err);
+                DatatypeError::UnpackError
+            })?;
+            *self.value = Some(value);
+        }
+        Ok(())
+    }
+
+    unsafe fn memory_regions(&mut self) -> DatatypeResult<Vec<(*mut u8, usize)>> {
+        Ok(vec![])
+    }
+
+    /// Resize the buffer to the sender's total size, so that a Framed
+    /// SerdeBuffer can be received without knowing its size beforehand.
+    unsafe fn prepare(&mut self, shape: &PackedShape) -> DatatypeResult<()> {
+        self.buffer = vec![0; shape.total_size()];
+        self.received = 0;
+        Ok(())
+    }
+}
+
+/// Serialization or deserialization error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<M: fmt::Display>(msg: M) -> Error {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<M: fmt::Display>(msg: M) -> Error {
+        Error(msg.to_string())
+    }
+}
+
+/// Serializer writing the packed part and collecting regions.
+struct Encoder {
+    /// Packed part, starting with space for the length prefix.
+    packed: Vec<u8>,
+
+    /// Copies of the large byte strings sent as regions.
+    regions: Vec<Vec<u8>>,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder {
+            packed: vec![0; HEADER_SIZE],
+            regions: vec![],
+        }
+    }
+
+    /// Fill in the length prefix and return the packed part.
+    fn finish(&mut self) -> Vec<u8> {
+        let len = self.packed.len() as u64;
+        self.packed[..HEADER_SIZE].copy_from_slice(&len.to_be_bytes());
+        std::mem::take(&mut self.packed)
+    }
+
+    fn write_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.packed.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.packed.push(value as u8);
+    }
+
+    fn write_len(&mut self, len: Option<usize>) -> Result<(), Error> {
+        let len = len.ok_or_else(|| Error("sequence length must be known".to_string()))?;
+        self.write_varint(len as u64);
+        Ok(())
+    }
+
+    /// Write a byte string, inline if it's small, otherwise as a region. The
+    /// varint holds the length shifted left by one, with the low bit set for
+    /// regions.
+    fn write_bytes(&mut self, data: &[u8]) {
+        if data.len() >= REGION_THRESHOLD {
+            self.write_varint(((data.len() as u64) << 1) | 1);
+            self.regions.push(data.to_vec());
+        } else {
+            self.write_inline(data);
+        }
+    }
+
+    /// Write a byte string into the packed part, regardless of its length.
+    fn write_inline(&mut self, data: &[u8]) {
+        self.write_varint((data.len() as u64) << 1);
+        self.packed.extend_from_slice(data);
+    }
+}
+
+macro_rules! serialize_be {
+    ($name:ident, $ty:ty) => {
+        fn $name(self, v: $ty) -> Result<(), Error> {
+            self.packed.extend_from_slice(&v.to_be_bytes());
+            Ok(())
+        }
+    };
+}
+
+impl ser::Serializer for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    serialize_be!(serialize_i8, i8);
+    serialize_be!(serialize_i16, i16);
+    serialize_be!(serialize_i32, i32);
+    serialize_be!(serialize_i64, i64);
+    serialize_be!(serialize_i128, i128);
+    serialize_be!(serialize_u8, u8);
+    serialize_be!(serialize_u16, u16);
+    serialize_be!(serialize_u32, u32);
+    serialize_be!(serialize_u64, u64);
+    serialize_be!(serialize_u128, u128);
+    serialize_be!(serialize_f32, f32);
+    serialize_be!(serialize_f64, f64);
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.packed.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_bytes(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_bytes(v);
+        Ok(())
+    }
+
+    /// Format the value and write it inline, instead of going through a
+    /// temporary String and serialize_str().
+    fn collect_str<V: fmt::Display + ?Sized>(self, value: &V) -> Result<(), Error> {
+        self.write_inline(value.to_string().as_bytes());
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.packed.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<V: Serialize + ?Sized>(self, value: &V) -> Result<(), Error> {
+        self.packed.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, index: u32, _variant: &'static str) -> Result<(), Error> {
+        self.write_varint(index as u64);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<V: Serialize + ?Sized>(self, _name: &'static str, value: &V) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<V: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        value: &V,
+    ) -> Result<(), Error> {
+        self.write_varint(index as u64);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+        self.write_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.write_varint(index as u64);
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, Error> {
+        self.write_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, Error> {
+        self.write_varint(index as u64);
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+macro_rules! impl_serialize_compound {
+    ($trait:ident, $method:ident) => {
+        impl ser::$trait for &mut Encoder {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<V: Serialize + ?Sized>(&mut self, value: &V) -> Result<(), Error> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_serialize_compound!(SerializeSeq, serialize_element);
+impl_serialize_compound!(SerializeTuple, serialize_element);
+impl_serialize_compound!(SerializeTupleStruct, serialize_field);
+impl_serialize_compound!(SerializeTupleVariant, serialize_field);
+
+impl ser::SerializeMap for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<V: Serialize + ?Sized>(&mut self, key: &V) -> Result<(), Error> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<V: Serialize + ?Sized>(&mut self, value: &V) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<V: Serialize + ?Sized>(&mut self, _key: &'static str, value: &V) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<V: Serialize + ?Sized>(&mut self, _key: &'static str, value: &V) -> Result<(), Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Deserializer reading the packed part and the regions following it.
+struct Decoder<'de> {
+    /// Packed part, after the length prefix.
+    packed: &'de [u8],
+
+    /// Data of all regions.
+    regions: &'de [u8],
+}
+
+impl<'de> Decoder<'de> {
+    fn new(data: &'de [u8]) -> Result<Decoder<'de>, Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error("missing length prefix".to_string()));
+        }
+        let len = u64::from_be_bytes(data[..HEADER_SIZE].try_into().unwrap()) as usize;
+        if len < HEADER_SIZE || len > data.len() {
+            return Err(Error("invalid length prefix".to_string()));
+        }
+        Ok(Decoder {
+            packed: &data[HEADER_SIZE..len],
+            regions: &data[len..],
+        })
+    }
+
+    /// Check that all data has been consumed.
+    fn finish(&self) -> Result<(), Error> {
+        if self.packed.is_empty() && self.regions.is_empty() {
+            Ok(())
+        } else {
+            Err(Error("trailing data after value".to_string()))
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'de [u8], Error> {
+        if len > self.packed.len() {
+            return Err(Error("unexpected end of packed data".to_string()));
+        }
+        let (data, rest) = self.packed.split_at(len);
+        self.packed = rest;
+        Ok(data)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte()?;
+            if shift >= 64 {
+                return Err(Error("varint too long".to_string()));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_len(&mut self) -> Result<usize, Error> {
+        usize::try_from(self.read_varint()?).map_err(|_| Error("length too large".to_string()))
+    }
+
+    /// Read a byte string written with Encoder::write_bytes().
+    fn read_bytes(&mut self) -> Result<&'de [u8], Error> {
+        let tagged = self.read_varint()?;
+        let len = usize::try_from(tagged >> 1).map_err(|_| Error("length too large".to_string()))?;
+        if tagged & 1 == 0 {
+            return self.take(len);
+        }
+        if len > self.regions.len() {
+            return Err(Error("unexpected end of region data".to_string()));
+        }
+        let (data, rest) = self.regions.split_at(len);
+        self.regions = rest;
+        Ok(data)
+    }
+
+    fn read_str(&mut self) -> Result<&'de str, Error> {
+        std::str::from_utf8(self.read_bytes()?).map_err(|err| Error(err.to_string()))
+    }
+}
+
+macro_rules! deserialize_be {
+    ($name:ident, $visit:ident, $ty:ty) => {
+        fn $name<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            let data = self.take(std::mem::size_of::<$ty>())?;
+            visitor.$visit(<$ty>::from_be_bytes(data.try_into().unwrap()))
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut Decoder<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error("format is not self-describing".to_string()))
+    }
+
+    deserialize_be!(deserialize_i8, visit_i8, i8);
+    deserialize_be!(deserialize_i16, visit_i16, i16);
+    deserialize_be!(deserialize_i32, visit_i32, i32);
+    deserialize_be!(deserialize_i64, visit_i64, i64);
+    deserialize_be!(deserialize_i128, visit_i128, i128);
+    deserialize_be!(deserialize_u8, visit_u8, u8);
+    deserialize_be!(deserialize_u16, visit_u16, u16);
+    deserialize_be!(deserialize_u32, visit_u32, u32);
+    deserialize_be!(deserialize_u64, visit_u64, u64);
+    deserialize_be!(deserialize_u128, visit_u128, u128);
+    deserialize_be!(deserialize_f32, visit_f32, f32);
+    deserialize_be!(deserialize_f64, visit_f64, f64);
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.read_byte()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            _ => Err(Error("invalid bool".to_string())),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let data = self.take(std::mem::size_of::<u32>())?;
+        let c = char::from_u32(u32::from_be_bytes(data.try_into().unwrap()))
+            .ok_or_else(|| Error("invalid char".to_string()))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.read_str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.read_bytes()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.read_byte()? {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            _ => Err(Error("invalid option tag".to_string())),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        visitor.visit_seq(Elements { decoder: self, remaining: len })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Elements { decoder: self, remaining: len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        visitor.visit_map(Elements { decoder: self, remaining: len })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error("format does not store identifiers".to_string()))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error("format is not self-describing".to_string()))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Access to the elements of a sequence, tuple or map.
+struct Elements<'a, 'de> {
+    decoder: &'a mut Decoder<'de>,
+    remaining: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        // Don't trust the length for preallocation.
+        Some(self.remaining.min(4096))
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, Error> {
+        seed.deserialize(&mut *self.decoder)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining.min(4096))
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Decoder<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Self), Error> {
+        let index = u32::try_from(self.read_varint()?).map_err(|_| Error("invalid variant index".to_string()))?;
+        let value = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Decoder<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
@@ -0,0 +1,93 @@
+//! Datatype signature checking.
+//!
+//! A MessageBuffer can return a hash of its type's signature from
+//! MessageBuffer::signature(). In debug builds, point-to-point messages of such
+//! types are always sent with a header (see the framing module) carrying the
+//! hash. The receiver compares it with the hash of its own type as soon as the
+//! header arrives, and rejects the message with
+//! DatatypeError::DatatypeMismatch if they differ, before any of the data is
+//! received into the buffer. Like other framed messages, these aren't seen by
+//! probe(). Collectives and release builds never send signatures, so both
+//! sides of a message must be built the same way.
+//!
+//! The hash of a given string is the same everywhere, but signature_of() hashes
+//! std::any::type_name(), which is only meant for diagnostics and may differ
+//! between compiler versions. Processes sending messages to each other should
+//! run the same build, or types should hash an explicit name with
+//! signature_hash() instead (as the derive macro does for non-generic types).
+//!
+//! Primitive slices and vectors don't have signatures, since they're also
+//! used to send and receive raw packed bytes.
+use crate::datatype::{DatatypeResult, MessageBuffer, PackMethod, UnpackMethod};
+use crate::framing::Contiguous;
+use crate::request::decode_tag;
+
+/// Hash a signature string (with 64-bit FNV-1a, which gives the same hash for
+/// the same string in every process and build).
+pub const fn signature_hash(signature: &str) -> u64 {
+    let bytes = signature.as_bytes();
+    let mut hash = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+
+/// Return a signature hash of the type's name. This is only consistent
+/// between processes running the same build.
+pub fn signature_of<T: ?Sized>() -> u64 {
+    signature_hash(std::any::type_name::<T>())
+}
+
+/// Return the signature to send with a user message of the buffer, if any.
+pub(crate) fn user_signature<B: MessageBuffer + ?Sized>(data: &B) -> Option<u64> {
+    if cfg!(debug_assertions) {
+        data.signature()
+    } else {
+        None
+    }
+}
+
+/// Return the signature to send with a message of the buffer with the
+/// encoded tag, if any. Only user messages carry signatures.
+pub(crate) fn message_signature<B: MessageBuffer + ?Sized>(data: &B, tag: u64) -> Option<u64> {
+    let (internal_tag, _, _) = decode_tag(tag);
+    if internal_tag == 0 {
+        user_signature(data)
+    } else {
+        None
+    }
+}
+
+/// Return the pack method for the buffer. The signature is sent in a header,
+/// so contiguous buffers with a signature are sent as a single memory region.
+pub(crate) unsafe fn signed_pack<B: MessageBuffer + ?Sized>(
+    data: &B,
+    signature: Option<u64>,
+) -> Option<DatatypeResult<Box<dyn PackMethod>>> {
+    match data.pack() {
+        None if signature.is_some() => Some(Ok(Box::new(Contiguous {
+            ptr: data.ptr() as *mut u8,
+            len: data.count(),
+        }))),
+        pack_method => pack_method,
+    }
+}
+
+/// Return the unpack method for the buffer, expecting a header if there's a
+/// signature (see signed_pack()).
+pub(crate) unsafe fn signed_unpack<B: MessageBuffer + ?Sized>(
+    data: &mut B,
+    signature: Option<u64>,
+) -> Option<DatatypeResult<Box<dyn UnpackMethod>>> {
+    match data.unpack() {
+        None if signature.is_some() => Some(Ok(Box::new(Contiguous {
+            ptr: data.ptr_mut(),
+            len: data.count(),
+        }))),
+        unpack_method => unpack_method,
+    }
+}
@@ -0,0 +1,209 @@
+//! Per-peer byte streams on top of UCP_FEATURE_STREAM.
+//!
+//! A stream is an ordered byte channel to one process without any message
+//! boundaries or tags. Plain bytes are sent and received through the
+//! std::io::Read and std::io::Write traits, while write_buffer() and
+//! read_buffer() transfer a MessageBuffer as its packed part followed by its
+//! memory regions. Since there are no headers, both sides must agree on the
+//! shape of buffers sent this way.
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::io;
+use std::rc::Rc;
+use mpicd_ucx_sys::{
+    rust_ucp_dt_make_contig, rust_ucp_dt_make_iov, rust_ucs_ptr_is_ptr, rust_ucs_ptr_status,
+    ucp_datatype_t, ucp_request_free, ucp_request_param_t, ucp_request_param_t__bindgen_ty_1,
+    ucp_stream_recv_nbx, ucp_stream_send_nbx, ucp_worker_progress, ucs_status_t,
+    UCP_OP_ATTR_FIELD_CALLBACK, UCP_OP_ATTR_FIELD_DATATYPE, UCP_OP_ATTR_FIELD_FLAGS,
+    UCP_OP_ATTR_FIELD_USER_DATA, UCP_STREAM_RECV_FLAG_WAITALL, UCS_OK,
+};
+use crate::{
+    communicator,
+    datatype::{DatatypeError, MessageBuffer},
+    message::{build_iovdata, SendLayout},
+    util::wait_request,
+    status_to_string, Handle, Status,
+};
+
+/// Byte stream to a peer process.
+pub struct Stream {
+    /// Handle with ucx info.
+    handle: Rc<RefCell<Handle>>,
+
+    /// Rank of the peer.
+    peer: usize,
+}
+
+/// Completion data of a stream receive.
+#[derive(Default)]
+struct StreamRecvData {
+    /// Status, set once complete.
+    status: Option<ucs_status_t>,
+
+    /// Number of bytes received.
+    length: usize,
+}
+
+/// Callback for stream receives.
+unsafe extern "C" fn stream_recv_callback(
+    _req: *mut c_void,
+    status: ucs_status_t,
+    length: usize,
+    user_data: *mut c_void,
+) {
+    let data = &mut *(user_data as *mut StreamRecvData);
+    data.status = Some(status);
+    data.length = length;
+}
+
+impl Stream {
+    pub(crate) fn new(handle: Rc<RefCell<Handle>>, peer: usize) -> Stream {
+        Stream { handle, peer }
+    }
+
+    /// Return the rank of the peer.
+    pub fn peer(&self) -> i32 {
+        self.peer as i32
+    }
+
+    /// Write the buffer to the stream.
+    pub fn write_buffer<B: MessageBuffer + ?Sized>(&mut self, data: &B) -> communicator::Result<()> {
+        unsafe {
+            let mut layout = SendLayout::new(data).map_err(communicator::Error::Datatype)?;
+            layout.pack().map_err(communicator::Error::Datatype)?;
+            let (ptr, count, datatype) = layout.ucx_data();
+            if count == 0 {
+                return Ok(());
+            }
+            self.send(ptr, count, datatype).map_err(|_| communicator::Error::InternalError)
+        }
+    }
+
+    /// Read data written with write_buffer() into the buffer, waiting until
+    /// all of it has arrived.
+    pub fn read_buffer<B: MessageBuffer + ?Sized>(&mut self, data: &mut B) -> communicator::Result<()> {
+        let datatype_err = communicator::Error::Datatype;
+        unsafe {
+            if let Some(unpack_method) = data.unpack() {
+                let mut unpack_method = unpack_method.map_err(datatype_err)?;
+                let mut packed_buffer = vec![0; unpack_method.packed_size().map_err(datatype_err)?];
+                let regions = unpack_method.memory_regions().map_err(datatype_err)?;
+                let total = packed_buffer.len() + regions.iter().map(|(_, len)| len).sum::<usize>();
+                if total == 0 {
+                    return Ok(());
+                }
+                let iovdata = build_iovdata(&mut packed_buffer, regions);
+                let received = self
+                    .recv(iovdata.as_ptr() as *mut _, iovdata.len(), rust_ucp_dt_make_iov(), true)
+                    .map_err(|_| communicator::Error::InternalError)?;
+                if received != total {
+                    return Err(communicator::Error::Datatype(DatatypeError::UnpackError));
+                }
+                if !packed_buffer.is_empty() {
+                    unpack_method
+                        .unpack(0, packed_buffer.as_ptr(), packed_buffer.len())
+                        .map_err(datatype_err)?;
+                }
+                Ok(())
+            } else {
+                let count = data.count();
+                if count == 0 {
+                    return Ok(());
+                }
+                let received = self
+                    .recv(data.ptr_mut(), count, rust_ucp_dt_make_contig(1), true)
+                    .map_err(|_| communicator::Error::InternalError)?;
+                if received != count {
+                    return Err(communicator::Error::Datatype(DatatypeError::UnpackError));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Send the data and wait for completion.
+    unsafe fn send(&self, ptr: *const u8, count: usize, datatype: ucp_datatype_t) -> io::Result<()> {
+        let handle = self.handle.borrow();
+        let param = ucp_request_param_t {
+            op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE,
+            datatype,
+            ..Default::default()
+        };
+        let req = ucp_stream_send_nbx(handle.system.endpoints[self.peer], ptr as *const _, count, &param);
+        match wait_request(handle.system.worker, req) {
+            Status::Error(err) => Err(io::Error::other(err)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Receive data, returning the number of bytes received. Without waitall,
+    /// this returns as soon as any data is available.
+    unsafe fn recv(&self, ptr: *mut u8, count: usize, datatype: ucp_datatype_t, waitall: bool) -> io::Result<usize> {
+        let handle = self.handle.borrow();
+        let mut data = StreamRecvData::default();
+        let mut length = 0;
+        let param = ucp_request_param_t {
+            op_attr_mask: UCP_OP_ATTR_FIELD_DATATYPE
+                | UCP_OP_ATTR_FIELD_CALLBACK
+                | UCP_OP_ATTR_FIELD_USER_DATA
+                | UCP_OP_ATTR_FIELD_FLAGS,
+            flags: if waitall { UCP_STREAM_RECV_FLAG_WAITALL } else { 0 },
+            datatype,
+            cb: ucp_request_param_t__bindgen_ty_1 {
+                recv_stream: Some(stream_recv_callback),
+            },
+            user_data: &mut data as *mut StreamRecvData as *mut _,
+            ..Default::default()
+        };
+        let req = ucp_stream_recv_nbx(handle.system.endpoints[self.peer], ptr as *mut _, count, &mut length, &param);
+
+        let status = if rust_ucs_ptr_is_ptr(req) == 0 {
+            rust_ucs_ptr_status(req)
+        } else {
+            while data.status.is_none() {
+                ucp_worker_progress(handle.system.worker);
+            }
+            ucp_request_free(req);
+            length = data.length;
+            data.status.unwrap()
+        };
+
+        if status == UCS_OK {
+            Ok(length)
+        } else {
+            Err(io::Error::other(status_to_string(status)))
+        }
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        unsafe { self.send(buf.as_ptr(), buf.len(), rust_ucp_dt_make_contig(1))? };
+        Ok(buf.len())
+    }
+
+    /// Writes complete before returning, so this does nothing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        unsafe { self.recv(buf.as_mut_ptr(), buf.len(), rust_ucp_dt_make_contig(1), false) }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        unsafe { self.recv(buf.as_mut_ptr(), buf.len(), rust_ucp_dt_make_contig(1), true)? };
+        Ok(())
+    }
+}
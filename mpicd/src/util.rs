@@ -1,8 +1,8 @@
-use crate::{Error, Result};
+use crate::{status_to_string, Error, Result, Status};
 use log::info;
 use mpicd_ucx_sys::{
-    rust_ucs_ptr_is_err, rust_ucs_ptr_is_ptr, rust_ucs_ptr_status, ucp_request_free, ucp_worker_h,
-    ucp_worker_progress, UCS_INPROGRESS, UCS_OK,
+    rust_ucs_ptr_is_err, rust_ucs_ptr_is_ptr, rust_ucs_ptr_status, ucp_request_check_status,
+    ucp_request_free, ucp_worker_h, ucp_worker_progress, ucs_status_ptr_t, UCS_INPROGRESS, UCS_OK,
 };
 use std::os::raw::c_void;
 
@@ -50,3 +50,24 @@ where
     ucp_request_free(req);
     Ok(())
 }
+
+/// Wait for a UCX request to complete, freeing it afterwards.
+pub(crate) unsafe fn wait_request(worker: ucp_worker_h, req: ucs_status_ptr_t) -> Status {
+    let status = if rust_ucs_ptr_is_ptr(req) == 0 {
+        rust_ucs_ptr_status(req)
+    } else {
+        let mut status = ucp_request_check_status(req);
+        while status == UCS_INPROGRESS {
+            ucp_worker_progress(worker);
+            status = ucp_request_check_status(req);
+        }
+        ucp_request_free(req);
+        status
+    };
+
+    if status == UCS_OK {
+        Status::Complete
+    } else {
+        Status::Error(status_to_string(status))
+    }
+}